@@ -0,0 +1,36 @@
+use std::path::Path;
+use std::process::Command;
+use std::time::{SystemTime, UNIX_EPOCH};
+
+fn main() {
+    // Short hash of the commit the tool is built from, empty outside a git checkout
+    let hash = Command::new("git")
+        .args(["rev-parse", "--short=12", "HEAD"])
+        .output()
+        .ok()
+        .filter(|output| output.status.success())
+        .map(|output| String::from_utf8_lossy(&output.stdout).trim().to_string())
+        .unwrap_or_default();
+    println!("cargo:rustc-env=MKS_TFT_IMG_GIT_HASH={}", hash);
+
+    // Rebuild when a commit is made, without rerunning for every build outside a checkout
+    let head = Path::new(".git/HEAD");
+    if head.exists() {
+        println!("cargo:rerun-if-changed=.git/HEAD");
+        if let Some(reference) = std::fs::read_to_string(head).ok().and_then(|head| {
+            head.strip_prefix("ref: ").map(|reference| reference.trim().to_string())
+        }) {
+            println!("cargo:rerun-if-changed=.git/{}", reference);
+        }
+    }
+
+    // Build time as a UNIX timestamp, SOURCE_DATE_EPOCH for reproducible builds
+    let date = std::env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.parse::<i64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now().duration_since(UNIX_EPOCH).map_or(0, |since| since.as_secs() as i64)
+        });
+    println!("cargo:rustc-env=MKS_TFT_IMG_BUILD_DATE={}", date);
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+}
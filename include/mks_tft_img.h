@@ -0,0 +1,75 @@
+#ifndef MKS_TFT_IMG_H
+#define MKS_TFT_IMG_H
+
+/* Generated with cbindgen. Do not edit. */
+
+#include <stdbool.h>
+#include <stddef.h>
+#include <stdint.h>
+
+// MKS TFT displays
+#define MKS_TFT_IMG_TARGET_MKS 0
+
+// Anycubic Kobra/Vyper
+#define MKS_TFT_IMG_TARGET_ANYCUBIC 1
+
+// Flashforge
+#define MKS_TFT_IMG_TARGET_FLASHFORGE 3
+
+// Creality Ender-3 V3 and K1
+#define MKS_TFT_IMG_TARGET_CREALITY 4
+
+// Raw RGB565 pixels for custom firmware
+#define MKS_TFT_IMG_TARGET_RAW 5
+
+// The conversion succeeded
+#define MKS_TFT_IMG_OK 0
+
+// An argument is a null pointer or out of range
+#define MKS_TFT_IMG_INVALID_ARGUMENT 1
+
+// There is no thumbnail in the G-code, or it cannot be converted, or the
+// conversion crashed
+#define MKS_TFT_IMG_FAILED 2
+
+// Options of the conversion
+typedef struct MksTftImgOptions {
+  // The display the preview is generated for, one of `MKS_TFT_IMG_TARGET_*`
+  uint32_t target;
+  // The size of the simage
+  uint16_t simage_size;
+  // The size of the gimage
+  uint16_t gimage_size;
+  // Terminate MKS image rows with `\r\n` instead of `\r`
+  bool crlf_row_end;
+  // Fail if the thumbnail doesn't match its `thumbnail begin` line
+  bool strict;
+} MksTftImgOptions;
+
+// Get the default options, the same as the ones of the command line tool
+MksTftImgOptions mks_tft_img_default_options(void);
+
+// Convert the thumbnail of G-code in memory
+//
+// On success, the converted G-code is stored in `output` and `output_len`,
+// and has to be released with [`mks_tft_img_free`].
+//
+// # Safety
+//
+// `gcode` must point to `gcode_len` readable bytes. `options`, `output` and
+// `output_len` must be valid pointers.
+int mks_tft_img_convert(const uint8_t *gcode,
+                        size_t gcode_len,
+                        const MksTftImgOptions *options,
+                        uint8_t **output,
+                        size_t *output_len);
+
+// Release G-code returned by [`mks_tft_img_convert`]
+//
+// # Safety
+//
+// `data` and `len` must be the ones returned by [`mks_tft_img_convert`], and
+// must not be used after this call. Null pointers are ignored.
+void mks_tft_img_free(uint8_t *data, size_t len);
+
+#endif /* MKS_TFT_IMG_H */
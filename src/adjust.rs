@@ -0,0 +1,273 @@
+//! Adjustments of the thumbnail colors, so the preview reads well on the display
+
+use crate::color::with_alpha_of;
+use image::DynamicImage;
+
+/// Stretch the histogram of an image to the full range of values
+///
+/// The same stretch is applied to all channels, so the hues are kept. Fully
+/// transparent pixels are not counted, as their color is not seen.
+///
+/// # Arguments
+///
+/// * `image` - The image
+/// * `clip` - Percentage of the darkest and of the lightest values that are
+///   clipped, so a few stray pixels don't prevent the stretch
+pub fn auto_contrast(image: &DynamicImage, clip: f32) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    let mut histogram = [0u64; 256];
+    for pixel in rgba.pixels().filter(|pixel| pixel[3] > 0) {
+        for &value in &pixel.0[..3] {
+            histogram[value as usize] += 1;
+        }
+    }
+    let total: u64 = histogram.iter().sum();
+    let clipped = (total as f64 * f64::from(clip.clamp(0.0, 50.0)) / 100.0) as u64;
+    let low = bound(&histogram, clipped, 0..256);
+    let high = bound(&histogram, clipped, (0..256).rev());
+    if high <= low {
+        log::debug!("The thumbnail has a single value, its contrast cannot be stretched");
+        return image.clone();
+    }
+    log::debug!("Stretching values {}..={} of the thumbnail", low, high);
+    let scale = 255.0 / (high - low) as f32;
+    for pixel in rgba.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            *value = ((f32::from(*value) - low as f32) * scale).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    with_alpha_of(image, rgba)
+}
+
+/// Find the value where more than `clipped` values have been counted, going
+/// through the values of a histogram in order
+fn bound(histogram: &[u64; 256], clipped: u64, mut values: impl Iterator<Item = usize>) -> usize {
+    let mut count = 0;
+    values
+        .find(|&value| {
+            count += histogram[value];
+            count > clipped
+        })
+        .unwrap_or_default()
+}
+
+/// Change the saturation of an image in HSL space, keeping hue and lightness
+///
+/// # Arguments
+///
+/// * `image` - The image
+/// * `factor` - What the saturation is multiplied by, e.g. `1.3`, or `0` for gray
+pub fn saturate(image: &DynamicImage, factor: f32) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    for pixel in rgba.pixels_mut() {
+        let [r, g, b] = [0, 1, 2].map(|channel| f32::from(pixel[channel]) / 255.0);
+        let (max, min) = (r.max(g).max(b), r.min(g).min(b));
+        let lightness = (max + min) / 2.0;
+        if max == min {
+            continue;
+        }
+        let saturation = match lightness > 0.5 {
+            true => (max - min) / (2.0 - max - min),
+            false => (max - min) / (max + min),
+        };
+        let hue = match max {
+            max if max == r => {
+                (g - b) / (max - min)
+                    + if g < b {
+                        6.0
+                    } else {
+                        0.0
+                    }
+            }
+            max if max == g => (b - r) / (max - min) + 2.0,
+            _ => (r - g) / (max - min) + 4.0,
+        } / 6.0;
+        let rgb = hsl_to_rgb(hue, (saturation * factor).clamp(0.0, 1.0), lightness);
+        for (value, new) in pixel.0[..3].iter_mut().zip(rgb) {
+            *value = (new * 255.0).round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    with_alpha_of(image, rgba)
+}
+
+/// Convert a color from HSL to RGB, all values from 0 to 1
+fn hsl_to_rgb(hue: f32, saturation: f32, lightness: f32) -> [f32; 3] {
+    let q = match lightness < 0.5 {
+        true => lightness * (1.0 + saturation),
+        false => lightness + saturation - lightness * saturation,
+    };
+    let p = 2.0 * lightness - q;
+    let channel = |t: f32| {
+        let t = t.rem_euclid(1.0);
+        match t {
+            t if t < 1.0 / 6.0 => p + (q - p) * 6.0 * t,
+            t if t < 0.5 => q,
+            t if t < 2.0 / 3.0 => p + (q - p) * (2.0 / 3.0 - t) * 6.0,
+            _ => p,
+        }
+    };
+    [channel(hue + 1.0 / 3.0), channel(hue), channel(hue - 1.0 / 3.0)]
+}
+
+/// How much a channel of a pixel may differ from the background color for the
+/// pixel to be part of the background
+const BACKGROUND_TOLERANCE: i16 = 24;
+
+/// Whether the color of a pixel is within the tolerance of a background color
+fn close(a: [u8; 4], b: [u8; 4]) -> bool {
+    a[..3]
+        .iter()
+        .zip(&b[..3])
+        .all(|(&a, &b)| (i16::from(a) - i16::from(b)).abs() <= BACKGROUND_TOLERANCE)
+}
+
+/// Get the color of a flat background from the corners of an image, if at
+/// least three of them agree
+fn flat_background(corners: [[u8; 4]; 4]) -> Option<[u8; 4]> {
+    corners
+        .into_iter()
+        .find(|&corner| corners.iter().filter(|&&other| close(corner, other)).count() >= 3)
+}
+
+/// Trim the empty borders of an image, so the object fills it
+///
+/// The borders are transparent, or of the flat background color sampled from
+/// the corners like [`replace_background`] does. The image is cropped to the
+/// smallest area around the object with the aspect ratio of the image, so
+/// the object is not distorted when resized to the preview sizes.
+///
+/// # Arguments
+///
+/// * `image` - The image
+pub fn autocrop(image: &DynamicImage) -> DynamicImage {
+    let rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+    let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)];
+    let samples = corners.map(|(x, y)| rgba.get_pixel(x, y).0);
+    let is_border: Box<dyn Fn([u8; 4]) -> bool> =
+        if samples.iter().filter(|sample| sample[3] == 0).count() >= 3 {
+            Box::new(|pixel| pixel[3] == 0)
+        } else if let Some(background) = flat_background(samples) {
+            Box::new(move |pixel| close(pixel, background))
+        } else {
+            log::warn!("The thumbnail has no empty borders, it is not cropped");
+            return image.clone();
+        };
+
+    let (mut left, mut top, mut right, mut bottom) = (width, height, 0, 0);
+    for (x, y, pixel) in rgba.enumerate_pixels() {
+        if !is_border(pixel.0) {
+            left = left.min(x);
+            top = top.min(y);
+            right = right.max(x + 1);
+            bottom = bottom.max(y + 1);
+        }
+    }
+    if left >= right {
+        log::warn!("The thumbnail is empty, it is not cropped");
+        return image.clone();
+    }
+
+    // Widen the object to the aspect ratio of the image, around its center
+    let (object_width, object_height) = (u64::from(right - left), u64::from(bottom - top));
+    let (crop_width, crop_height) =
+        match object_width * u64::from(height) < object_height * u64::from(width) {
+            true => ((object_height * u64::from(width)).div_ceil(u64::from(height)), object_height),
+            false => (object_width, (object_width * u64::from(height)).div_ceil(u64::from(width))),
+        };
+    let (crop_width, crop_height) =
+        (crop_width.min(width.into()) as u32, crop_height.min(height.into()) as u32);
+    let place = |start: u32, end: u32, crop: u32, size: u32| {
+        ((start + end) / 2).saturating_sub(crop / 2).min(size - crop)
+    };
+    let x = place(left, right, crop_width, width);
+    let y = place(top, bottom, crop_height, height);
+    log::debug!(
+        "Cropping the thumbnail from {}x{} to {}x{} at {},{}",
+        width,
+        height,
+        crop_width,
+        crop_height,
+        x,
+        y
+    );
+    image.crop_imm(x, y, crop_width, crop_height)
+}
+
+/// Replace the flat background of an image with a color
+///
+/// The background color is sampled from the corners, at least three of which
+/// must agree. The background is what is connected to these corners and
+/// close to that color, so the same color inside the object is kept. A
+/// transparent background is filled with the color instead, blending the
+/// partly transparent edges.
+///
+/// # Arguments
+///
+/// * `image` - The image
+/// * `color` - The new background color
+pub fn replace_background(image: &DynamicImage, color: [u8; 3]) -> DynamicImage {
+    let mut rgba = image.to_rgba8();
+    let (width, height) = rgba.dimensions();
+    if width == 0 || height == 0 {
+        return image.clone();
+    }
+    let corners = [(0, 0), (width - 1, 0), (0, height - 1), (width - 1, height - 1)];
+    let samples = corners.map(|(x, y)| rgba.get_pixel(x, y).0);
+
+    if samples.iter().filter(|sample| sample[3] == 0).count() >= 3 {
+        log::debug!("Filling the transparent background of the thumbnail");
+        for pixel in rgba.pixels_mut() {
+            let alpha = u16::from(pixel[3]);
+            for (value, background) in pixel.0[..3].iter_mut().zip(color) {
+                *value = ((u16::from(*value) * alpha + u16::from(background) * (255 - alpha) + 127)
+                    / 255) as u8;
+            }
+            pixel[3] = 255;
+        }
+        return DynamicImage::ImageRgba8(rgba);
+    }
+
+    let Some(background) = flat_background(samples) else {
+        log::warn!("The thumbnail has no flat background, it is not replaced");
+        return image.clone();
+    };
+    log::debug!("Replacing the background color {:?} of the thumbnail", &background[..3]);
+    let mut seen = vec![false; (width * height) as usize];
+    let mut queue: Vec<(u32, u32)> = corners.to_vec();
+    while let Some((x, y)) = queue.pop() {
+        let index = (y * width + x) as usize;
+        if seen[index] || !close(rgba.get_pixel(x, y).0, background) {
+            continue;
+        }
+        seen[index] = true;
+        let pixel = rgba.get_pixel_mut(x, y);
+        pixel.0[..3].copy_from_slice(&color);
+        if x > 0 {
+            queue.push((x - 1, y));
+        }
+        if x + 1 < width {
+            queue.push((x + 1, y));
+        }
+        if y > 0 {
+            queue.push((x, y - 1));
+        }
+        if y + 1 < height {
+            queue.push((x, y + 1));
+        }
+    }
+    with_alpha_of(image, rgba)
+}
+
+/// Parse a color like `#1e90ff` or `1e90ff`
+pub fn parse_color(hex: &str) -> Result<[u8; 3], String> {
+    let digits = hex.strip_prefix('#').unwrap_or(hex);
+    if digits.len() != 6 || !digits.bytes().all(|b| b.is_ascii_hexdigit()) {
+        return Err(format!("`{}` is not a color like `#1e90ff`", hex));
+    }
+    let channel = |i: usize| u8::from_str_radix(&digits[i..i + 2], 16).expect("Digits are hex");
+    Ok([channel(0), channel(2), channel(4)])
+}
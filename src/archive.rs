@@ -0,0 +1,98 @@
+use crate::encoders::THUMBNAIL_LINE_LENGTH;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use std::io::{Read, Write};
+use std::path;
+
+/// Comment starting the archived original
+const ARCHIVE_START: &str = "; MKS_TFT_ORIGINAL_START";
+
+/// Comment ending the archived original, after which the unchanged rest of
+/// the G-code follows
+const ARCHIVE_END: &str = "; MKS_TFT_ORIGINAL_END";
+
+/// Create a comment section with the archived original beginning of the G-code
+///
+/// The section goes right before the rest of the G-code, which is not changed
+/// by the conversion, so that the original file can be restored from it.
+///
+/// # Arguments
+///
+/// * `original` - The original G-code up to the end of the thumbnail block
+/// * `line_ending` - What the lines of the section end with
+///
+/// # Returns
+///
+/// The comment section with the deflated and base64 encoded original
+pub fn archive_section(original: &[u8], line_ending: &str) -> Result<String, ()> {
+    let mut encoder = flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::best());
+    encoder
+        .write_all(original)
+        .map_err(|e| log::error!("Cannot compress the original G-code: {}", e))?;
+    let compressed =
+        encoder.finish().map_err(|e| log::error!("Cannot compress the original G-code: {}", e))?;
+    let encoded = BASE64_STANDARD.encode(compressed);
+    log::debug!("Archived {} bytes of the original G-code in {}", original.len(), encoded.len());
+
+    let mut section = format!("{}{}", ARCHIVE_START, line_ending);
+    for chunk in encoded.as_bytes().chunks(THUMBNAIL_LINE_LENGTH) {
+        section.push_str("; ");
+        section.push_str(std::str::from_utf8(chunk).expect("base64 output is always ASCII"));
+        section.push_str(line_ending);
+    }
+    section.push_str(ARCHIVE_END);
+    section.push_str(line_ending);
+    Ok(section)
+}
+
+/// Restore the original G-code converted with `--archive-original`
+///
+/// # Arguments
+///
+/// * `path` - Path to the converted G-code file
+pub fn restore(path: &path::Path) -> Result<(), ()> {
+    log::info!("Restoring the original G-code of `{}`", path.display());
+    let gcode = std::fs::read(path)
+        .map_err(|e| log::error!("Cannot read file `{}`: {}", path.display(), e))?;
+    let find = |needle: &str, from: usize| {
+        gcode[from..]
+            .windows(needle.len())
+            .position(|window| window == needle.as_bytes())
+            .map(|pos| from + pos)
+    };
+    let Some(start) = find(ARCHIVE_START, 0) else {
+        log::error!(
+            "There is no archived original in `{}`. Was it converted with `--archive-original`?",
+            path.display()
+        );
+        return Err(());
+    };
+    let Some(end) = find(ARCHIVE_END, start) else {
+        log::error!("The archived original in `{}` is truncated", path.display());
+        return Err(());
+    };
+    let remainder_start =
+        gcode[end..].iter().position(|&b| b == b'\n').map_or(gcode.len(), |pos| end + pos + 1);
+
+    let encoded: String = String::from_utf8_lossy(&gcode[start + ARCHIVE_START.len()..end])
+        .lines()
+        .map(|line| line.trim_start_matches(';').trim())
+        .collect();
+    let compressed = BASE64_STANDARD
+        .decode(encoded)
+        .map_err(|e| log::error!("Cannot base64 decode the archived original: {}", e))?;
+    let mut original = vec![];
+    flate2::read::DeflateDecoder::new(compressed.as_slice())
+        .read_to_end(&mut original)
+        .map_err(|e| log::error!("Cannot decompress the archived original: {}", e))?;
+    original.extend_from_slice(&gcode[remainder_start..]);
+
+    let tmp_path = crate::temporary_path(path);
+    std::fs::write(&tmp_path, &original)
+        .map_err(|e| log::error!("Failed to write `{}`: {}", tmp_path.display(), e))?;
+    std::fs::rename(&tmp_path, path).map_err(|e| {
+        log::error!("Failed to replace `{}`: {}", path.display(), e);
+        let _ = std::fs::remove_file(&tmp_path);
+    })?;
+    log::info!("Restored {} bytes of the original G-code", original.len());
+    Ok(())
+}
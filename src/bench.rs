@@ -0,0 +1,125 @@
+use crate::generate::synthetic_gcode;
+use mks_tft_img::footer::FooterInfo;
+use mks_tft_img::meta::PrintMeta;
+use mks_tft_img::options::ProcessOptions;
+use mks_tft_img::slicer::Generator;
+use mks_tft_img::{line_ending, mmap, post_process_info, source, write_gcode, LINE_ENDING_SAMPLE};
+use std::time::{Duration, Instant};
+
+/// Phases of a conversion, in the order they are run
+const PHASES: [&str; 5] = ["parse", "decode", "resize", "encode", "write"];
+
+/// Convert a G-code file once, measuring each phase
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the gcode file
+/// * `options` - How to process the thumbnail
+///
+/// # Returns
+///
+/// The durations of the phases, in the order of `PHASES`
+fn convert_timed(gcode: &[u8], options: &ProcessOptions) -> Result<[Duration; 5], ()> {
+    let mut timings = [Duration::ZERO; 5];
+
+    let started = Instant::now();
+    let (header, image_lines, mut remainder) = mmap::split_gcode(gcode)?;
+    timings[0] = started.elapsed();
+
+    let started = Instant::now();
+    let embedded = source::Embedded {
+        image_lines: &image_lines,
+        strict: options.strict,
+        limits: options.image_limits(),
+    };
+    let Some((thumbnail, source_info)) = source::first_thumbnail(&[&embedded])? else {
+        log::error!("The synthetic G-code has no thumbnail");
+        return Err(());
+    };
+    timings[1] = started.elapsed();
+
+    let started = Instant::now();
+    let (simage, gimages) = options.resize(&thumbnail.image);
+    timings[2] = started.elapsed();
+
+    let started = Instant::now();
+    let line_ending = line_ending(&gcode[..gcode.len().min(LINE_ENDING_SAMPLE)]);
+    let preview = options.target.encoder(options.row_end, options.trailing_semicolon).encode(
+        &thumbnail.image,
+        &simage,
+        &gimages,
+        line_ending,
+    )?;
+    let info = FooterInfo {
+        source_info: &source_info,
+        source_format: thumbnail.format,
+        source_size: (thumbnail.image.width(), thumbnail.image.height()),
+        preview_info: &preview.info,
+        meta: &PrintMeta::from_gcode(gcode),
+        file_name: "",
+    };
+    let footer = post_process_info(options, &info, line_ending);
+    timings[3] = started.elapsed();
+
+    let started = Instant::now();
+    let mut output = Vec::with_capacity(preview.data.len() + gcode.len());
+    write_gcode(
+        &mut output,
+        &preview.data,
+        options.position(),
+        header,
+        line_ending,
+        &footer,
+        &mut remainder,
+    )
+    .map_err(|e| log::error!("Failed to write gcode: {}", e))?;
+    timings[4] = started.elapsed();
+    Ok(timings)
+}
+
+/// Measure the conversion speed on synthetic G-code and print it as a table
+///
+/// # Arguments
+///
+/// * `options` - How to process the thumbnails
+/// * `sizes` - Sizes of the embedded thumbnails, one file is generated for each
+/// * `iterations` - How many times to convert each file
+/// * `lines` - How many G-code lines follow the thumbnail
+pub fn run(options: &ProcessOptions, sizes: &[u32], iterations: u32, lines: u32) -> Result<(), ()> {
+    let iterations = iterations.max(1);
+    println!(
+        "Converting for {} with {} iterations, average time of each phase in ms",
+        options.target, iterations
+    );
+    let mut header = format!("{:>9} {:>10}", "thumbnail", "gcode, kB");
+    for phase in PHASES {
+        header.push_str(&format!(" {:>8}", phase));
+    }
+    header.push_str(&format!(" {:>8} {:>8} {:>8}", "total", "files/s", "MB/s"));
+    println!("{}", header);
+
+    for &size in sizes {
+        let gcode = synthetic_gcode(Generator::PrusaSlicer, size, lines)?;
+        let mut totals = [Duration::ZERO; 5];
+        for _ in 0..iterations {
+            let timings = convert_timed(&gcode, options)?;
+            totals.iter_mut().zip(timings).for_each(|(total, timing)| *total += timing);
+        }
+        let average = |duration: Duration| duration.as_secs_f64() * 1000.0 / iterations as f64;
+        let total = average(totals.iter().sum());
+
+        let mut row = format!("{:>9} {:>10}", format!("{}x{}", size, size), gcode.len() / 1024);
+        for duration in totals {
+            row.push_str(&format!(" {:>8.3}", average(duration)));
+        }
+        let seconds = (total / 1000.0).max(f64::EPSILON);
+        row.push_str(&format!(
+            " {:>8.3} {:>8.1} {:>8.1}",
+            total,
+            1.0 / seconds,
+            gcode.len() as f64 / 1024.0 / 1024.0 / seconds
+        ));
+        println!("{}", row);
+    }
+    Ok(())
+}
@@ -0,0 +1,136 @@
+use serde::{Deserialize, Serialize};
+use std::collections::BTreeMap;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+use std::sync::Mutex;
+
+/// What is known about a G-code file that was processed before
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct Entry {
+    /// Checksum of the options it was processed with
+    options: u32,
+    /// Size of the file after processing
+    size: u64,
+    /// CRC32 of the content of the file after processing
+    checksum: u32,
+    /// Whether it was converted, or left unchanged because there is no thumbnail
+    converted: bool,
+}
+
+/// Files converted before, so unchanged ones are not converted and uploaded again
+///
+/// It is kept as JSON, with an entry for each file, keyed by its absolute path.
+/// A file is unchanged if its content is still the one written by the
+/// conversion and the options are the same. Whatever decides the preview,
+/// e.g. the embedded thumbnail, is part of the content.
+pub struct Cache {
+    /// The JSON file the cache is kept in
+    path: path::PathBuf,
+    /// Checksum of the options of this run
+    options: u32,
+    entries: Mutex<BTreeMap<String, Entry>>,
+}
+
+impl Cache {
+    /// Load the cache, or start an empty one if it doesn't exist yet
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The JSON file the cache is kept in
+    /// * `options` - Everything the output depends on besides the G-code, e.g. the
+    ///   processing options as JSON
+    pub fn load(path: &path::Path, options: &str) -> Self {
+        let entries = match std::fs::read_to_string(path) {
+            Ok(content) => serde_json::from_str(&content).unwrap_or_else(|e| {
+                log::warn!("Ignoring the invalid cache `{}`: {}", path.display(), e);
+                BTreeMap::new()
+            }),
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => BTreeMap::new(),
+            Err(e) => {
+                log::warn!("Cannot read the cache `{}`: {}", path.display(), e);
+                BTreeMap::new()
+            }
+        };
+        log::debug!("Loaded {} entries from the cache `{}`", entries.len(), path.display());
+        Cache {
+            path: path.to_path_buf(),
+            options: crc32fast::hash(options.as_bytes()),
+            entries: Mutex::new(entries),
+        }
+    }
+
+    /// Check whether a G-code file is unchanged since it was processed with the same options
+    ///
+    /// # Returns
+    ///
+    /// Whether the file was converted then, or `None` if it has to be processed
+    pub fn lookup(&self, path: &path::Path) -> Option<bool> {
+        let entry = *self.entries.lock().expect("Cache is never poisoned").get(&key(path)?)?;
+        if entry.options != self.options {
+            log::debug!("`{}` was processed with other options", path.display());
+            return None;
+        }
+        let size = std::fs::metadata(path).ok()?.len();
+        if size != entry.size || checksum(path).ok()? != entry.checksum {
+            log::debug!("`{}` changed since it was processed", path.display());
+            return None;
+        }
+        Some(entry.converted)
+    }
+
+    /// Remember the content of a processed G-code file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The G-code file, after processing
+    /// * `converted` - Whether it was converted, or left unchanged
+    pub fn record(&self, path: &path::Path, converted: bool) {
+        let (Some(key), Ok(size), Ok(checksum)) =
+            (key(path), std::fs::metadata(path).map(|m| m.len()), checksum(path))
+        else {
+            log::warn!("Cannot add `{}` to the cache", path.display());
+            return;
+        };
+        let entry = Entry {
+            options: self.options,
+            size,
+            checksum,
+            converted,
+        };
+        self.entries.lock().expect("Cache is never poisoned").insert(key, entry);
+    }
+
+    /// Write the cache back to its file
+    pub fn save(&self) -> Result<(), ()> {
+        let entries = self.entries.lock().expect("Cache is never poisoned");
+        let json = serde_json::to_string_pretty(&*entries).expect("Cache is always serializable");
+        let tmp_path = crate::temporary_path(&self.path);
+        std::fs::write(&tmp_path, json)
+            .and_then(|_| std::fs::rename(&tmp_path, &self.path))
+            .map_err(|e| {
+                log::error!("Failed to write the cache `{}`: {}", self.path.display(), e);
+                let _ = std::fs::remove_file(&tmp_path);
+            })?;
+        log::debug!("Saved {} entries to the cache `{}`", entries.len(), self.path.display());
+        Ok(())
+    }
+}
+
+/// Get the key of a G-code file in the cache, its absolute path
+fn key(path: &path::Path) -> Option<String> {
+    Some(std::fs::canonicalize(path).ok()?.to_string_lossy().into_owned())
+}
+
+/// Compute the CRC32 of the content of a file
+pub fn checksum(path: &path::Path) -> std::io::Result<u32> {
+    let mut file = File::open(path)?;
+    let mut hasher = crc32fast::Hasher::new();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        match file.read(&mut buffer)? {
+            0 => return Ok(hasher.finalize()),
+            read => hasher.update(&buffer[..read]),
+        }
+    }
+}
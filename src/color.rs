@@ -0,0 +1,424 @@
+//! Color management of thumbnails: ICC profiles and resizing in linear light
+
+use image::imageops::FilterType;
+use image::io::Reader as ImageReader;
+use image::{DynamicImage, ImageDecoder, ImageResult, Rgba32FImage, RgbaImage};
+use std::io::{BufRead, Seek};
+
+/// Colorants of the sRGB profile, adapted to the D50 white of ICC profiles,
+/// as the columns of the matrix converting linear sRGB to XYZ
+const SRGB_TO_XYZ: [[f32; 3]; 3] = [
+    [0.436_074_7, 0.385_064_9, 0.143_080_4],
+    [0.222_504_5, 0.716_878_6, 0.060_616_9],
+    [0.013_932_2, 0.097_104_5, 0.714_173_3],
+];
+
+/// Decode an image, with the ICC profile it is tagged with
+///
+/// # Returns
+///
+/// The image and its ICC profile, if it has one
+pub fn decode<R: BufRead + Seek>(
+    reader: ImageReader<R>,
+) -> ImageResult<(DynamicImage, Option<Vec<u8>>)> {
+    let mut decoder = reader.into_decoder()?;
+    let profile = decoder.icc_profile().unwrap_or_else(|e| {
+        log::warn!("Cannot read the ICC profile of the image: {}", e);
+        None
+    });
+    Ok((DynamicImage::from_decoder(decoder)?, profile))
+}
+
+/// Convert an sRGB value from 0 to 1 to linear light
+pub fn to_linear(value: f32) -> f32 {
+    if value <= 0.040_45 {
+        value / 12.92
+    } else {
+        ((value + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+/// Convert a linear light value from 0 to 1 to sRGB
+pub fn from_linear(value: f32) -> f32 {
+    let value = value.clamp(0.0, 1.0);
+    if value <= 0.003_130_8 {
+        value * 12.92
+    } else {
+        1.055 * value.powf(1.0 / 2.4) - 0.055
+    }
+}
+
+/// Convert an image to 8-bit RGBA, applying a function to the RGB channels
+/// in linear light
+fn map_linear(image: Rgba32FImage, map: impl Fn([f32; 3]) -> [f32; 3]) -> RgbaImage {
+    let (width, height) = image.dimensions();
+    let mut output = RgbaImage::new(width, height);
+    for (pixel, out) in image.pixels().zip(output.pixels_mut()) {
+        let [r, g, b, a] = pixel.0;
+        let [r, g, b] = map([r, g, b]);
+        let quantize = |value: f32| (value.clamp(0.0, 1.0) * 255.0).round() as u8;
+        out.0 = [
+            quantize(from_linear(r)),
+            quantize(from_linear(g)),
+            quantize(from_linear(b)),
+            quantize(a),
+        ];
+    }
+    output
+}
+
+/// Get an 8-bit image in the color type of the original one, with or without alpha
+pub(crate) fn with_alpha_of(original: &DynamicImage, image: RgbaImage) -> DynamicImage {
+    match original.color().has_alpha() {
+        true => DynamicImage::ImageRgba8(image),
+        false => DynamicImage::ImageRgb8(DynamicImage::ImageRgba8(image).to_rgb8()),
+    }
+}
+
+/// Resize an image in linear light, so fine details are not darkened
+///
+/// The pixels are converted from sRGB to linear light, resized to fit in the
+/// size keeping the aspect ratio, like [`DynamicImage::resize`], and converted
+/// back to 8-bit sRGB.
+pub fn resize_linear(
+    image: &DynamicImage,
+    width: u32,
+    height: u32,
+    filter: FilterType,
+) -> DynamicImage {
+    let mut linear = image.to_rgba32f();
+    for pixel in linear.pixels_mut() {
+        for value in &mut pixel.0[..3] {
+            *value = to_linear(*value);
+        }
+    }
+    let resized = DynamicImage::ImageRgba32F(linear).resize(width, height, filter).into_rgba32f();
+    with_alpha_of(image, map_linear(resized, |rgb| rgb))
+}
+
+/// Downscale an image by averaging blocks of pixels
+///
+/// Colors are weighted by their alpha, so transparent pixels don't darken
+/// the edges. Blocks cut off at the right and bottom edges are averaged over
+/// the pixels they have.
+///
+/// # Arguments
+///
+/// * `image` - The image
+/// * `factor` - Width and height of the blocks
+/// * `linear_light` - Whether to average in linear light instead of sRGB
+pub fn downscale_area(image: &DynamicImage, factor: u32, linear_light: bool) -> DynamicImage {
+    let mut source = image.to_rgba32f();
+    if linear_light {
+        for pixel in source.pixels_mut() {
+            for value in &mut pixel.0[..3] {
+                *value = to_linear(*value);
+            }
+        }
+    }
+    let (width, height) = source.dimensions();
+    let mut output = Rgba32FImage::new(width.div_ceil(factor), height.div_ceil(factor));
+    for (x, y, out) in output.enumerate_pixels_mut() {
+        let mut sum = [0.0f32; 4];
+        let mut count = 0.0;
+        for sy in y * factor..((y + 1) * factor).min(height) {
+            for sx in x * factor..((x + 1) * factor).min(width) {
+                let [r, g, b, a] = source.get_pixel(sx, sy).0;
+                sum = [sum[0] + r * a, sum[1] + g * a, sum[2] + b * a, sum[3] + a];
+                count += 1.0;
+            }
+        }
+        out.0 = match sum[3] > 0.0 {
+            true => [sum[0] / sum[3], sum[1] / sum[3], sum[2] / sum[3], sum[3] / count],
+            false => [0.0; 4],
+        };
+    }
+    let output = match linear_light {
+        true => map_linear(output, |rgb| rgb),
+        false => DynamicImage::ImageRgba32F(output).into_rgba8(),
+    };
+    with_alpha_of(image, output)
+}
+
+/// Dither an image to the colors of an RGB565 display with Floyd-Steinberg
+/// error diffusion, so gradients don't band into flat steps
+///
+/// Each channel is set to the nearest value the display shows, and the error
+/// is spread to the pixels right of and below it. The values are ones the
+/// RGB565 encoders keep as they are.
+pub fn dither_rgb565(image: &DynamicImage) -> DynamicImage {
+    let mut output = image.to_rgba8();
+    let (width, height) = output.dimensions();
+    let (width, height) = (width as usize, height as usize);
+    // Errors of the current and the next row, with a pixel of margin on each side
+    let mut errors = [vec![[0.0f32; 3]; width + 2], vec![[0.0f32; 3]; width + 2]];
+    for y in 0..height {
+        errors[1].fill([0.0; 3]);
+        for x in 0..width {
+            let pixel = output.get_pixel_mut(x as u32, y as u32);
+            for (channel, bits) in [5, 6, 5].into_iter().enumerate() {
+                let wanted =
+                    (pixel.0[channel] as f32 + errors[0][x + 1][channel]).clamp(0.0, 255.0);
+                let value = nearest_level(wanted, bits);
+                pixel.0[channel] = value;
+                let error = wanted - value as f32;
+                errors[0][x + 2][channel] += error * 7.0 / 16.0;
+                errors[1][x][channel] += error * 3.0 / 16.0;
+                errors[1][x + 1][channel] += error * 5.0 / 16.0;
+                errors[1][x + 2][channel] += error / 16.0;
+            }
+        }
+        errors.swap(0, 1);
+    }
+    with_alpha_of(image, output)
+}
+
+/// Get the 8-bit value of the level of a channel with `bits` bits nearest to a value
+fn nearest_level(value: f32, bits: u32) -> u8 {
+    let expand = |level: u32| (level << (8 - bits) | level >> (2 * bits - 8)) as u8;
+    // Expanded levels are up to a few values larger than the truncated ones
+    let level = value as u32 >> (8 - bits);
+    (level.saturating_sub(1)..=(level + 1).min((1 << bits) - 1))
+        .map(expand)
+        .min_by(|a, b| (*a as f32 - value).abs().total_cmp(&(*b as f32 - value).abs()))
+        .expect("There is always a level")
+}
+
+/// Tone response curve of a channel of an ICC profile
+#[derive(Debug, Clone, PartialEq)]
+enum Curve {
+    /// The value raised to a power
+    Gamma(f32),
+    /// Points evenly spaced from 0 to 1, interpolated linearly
+    Table(Vec<f32>),
+    /// Parametric curve `(a * x + b) ^ g + e` from `d` on, `c * x + f` below it
+    Parametric {
+        g: f32,
+        a: f32,
+        b: f32,
+        c: f32,
+        d: f32,
+        e: f32,
+        f: f32,
+    },
+}
+
+impl Curve {
+    /// Convert a value from 0 to 1 to linear light
+    fn apply(&self, x: f32) -> f32 {
+        match self {
+            Curve::Gamma(gamma) => x.powf(*gamma),
+            Curve::Table(points) => {
+                let position = x.clamp(0.0, 1.0) * (points.len() - 1) as f32;
+                let index = (position as usize).min(points.len() - 2);
+                let fraction = position - index as f32;
+                points[index] * (1.0 - fraction) + points[index + 1] * fraction
+            }
+            &Curve::Parametric {
+                g,
+                a,
+                b,
+                c,
+                d,
+                e,
+                f,
+            } => match x >= d {
+                true => (a * x + b).max(0.0).powf(g) + e,
+                false => c * x + f,
+            },
+        }
+    }
+}
+
+/// A matrix/TRC ICC profile of an RGB image, the kind cameras, screenshots
+/// and image editors tag images with, e.g. Display P3 or Adobe RGB
+#[derive(Debug, Clone, PartialEq)]
+pub struct IccProfile {
+    /// Tone response curves of the red, green and blue channels
+    curves: [Curve; 3],
+    /// Matrix converting linear RGB to XYZ
+    to_xyz: [[f32; 3]; 3],
+}
+
+impl IccProfile {
+    /// Parse an ICC profile
+    ///
+    /// # Returns
+    ///
+    /// The profile, or why it is not supported
+    pub fn parse(data: &[u8]) -> Result<Self, String> {
+        let bytes = |offset: usize, len: usize| {
+            data.get(offset..offset + len).ok_or_else(|| "The profile is truncated".to_string())
+        };
+        let u32_at = |offset| {
+            bytes(offset, 4).map(|b| u32::from_be_bytes(b.try_into().expect("Slices are 4 bytes")))
+        };
+        let s15_16_at = |offset| u32_at(offset).map(|value| value as i32 as f32 / 65536.0);
+        if bytes(16, 4)? != b"RGB " {
+            return Err("Only RGB profiles are supported".to_string());
+        }
+        let mut tags = vec![];
+        for i in 0..u32_at(128)? as usize {
+            let entry = 132 + i * 12;
+            tags.push((bytes(entry, 4)?, u32_at(entry + 4)? as usize, u32_at(entry + 8)? as usize));
+        }
+        let tag = |signature: &[u8; 4]| {
+            tags.iter()
+                .find(|(tag, _, _)| tag == signature)
+                .map(|&(_, offset, size)| (offset, size))
+                .ok_or_else(|| {
+                    format!(
+                        "Only matrix/TRC profiles are supported, there is no `{}` tag",
+                        String::from_utf8_lossy(signature)
+                    )
+                })
+        };
+
+        let mut to_xyz = [[0.0; 3]; 3];
+        for (column, signature) in [b"rXYZ", b"gXYZ", b"bXYZ"].into_iter().enumerate() {
+            let (offset, _) = tag(signature)?;
+            if bytes(offset, 4)? != b"XYZ " {
+                return Err("Invalid colorant tag".to_string());
+            }
+            for (row, values) in to_xyz.iter_mut().enumerate() {
+                values[column] = s15_16_at(offset + 8 + row * 4)?;
+            }
+        }
+
+        let curve = |signature| -> Result<Curve, String> {
+            let (offset, _) = tag(signature)?;
+            match bytes(offset, 4)? {
+                b"curv" => {
+                    let count = u32_at(offset + 8)? as usize;
+                    let value = |i: usize| {
+                        bytes(offset + 12 + i * 2, 2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+                    };
+                    match count {
+                        0 => Ok(Curve::Gamma(1.0)),
+                        1 => Ok(Curve::Gamma(value(0)? as f32 / 256.0)),
+                        _ => (0..count)
+                            .map(|i| value(i).map(|v| v as f32 / 65535.0))
+                            .collect::<Result<_, _>>()
+                            .map(Curve::Table),
+                    }
+                }
+                b"para" => {
+                    let function =
+                        bytes(offset + 8, 2).map(|b| u16::from_be_bytes([b[0], b[1]]))?;
+                    let count = match function {
+                        0 => 1,
+                        1 => 3,
+                        2 => 4,
+                        3 => 5,
+                        4 => 7,
+                        _ => return Err(format!("Unknown parametric curve {}", function)),
+                    };
+                    let mut p = [1.0, 1.0, 0.0, 0.0, 0.0, 0.0, 0.0];
+                    for (i, value) in p.iter_mut().take(count).enumerate() {
+                        *value = s15_16_at(offset + 12 + i * 4)?;
+                    }
+                    let [g, a, b, c, d, e, f] = p;
+                    Ok(match function {
+                        0 => Curve::Gamma(g),
+                        // Below `-b / a` the value is 0, or `c`
+                        1 | 2 => Curve::Parametric {
+                            g,
+                            a,
+                            b,
+                            c: 0.0,
+                            d: -b / a,
+                            e: c * (function - 1) as f32,
+                            f: c * (function - 1) as f32,
+                        },
+                        3 => Curve::Parametric {
+                            g,
+                            a,
+                            b,
+                            c,
+                            d,
+                            e: 0.0,
+                            f: 0.0,
+                        },
+                        _ => Curve::Parametric {
+                            g,
+                            a,
+                            b,
+                            c,
+                            d,
+                            e,
+                            f,
+                        },
+                    })
+                }
+                other => Err(format!("Unknown curve type `{}`", String::from_utf8_lossy(other))),
+            }
+        };
+        Ok(IccProfile {
+            curves: [curve(b"rTRC")?, curve(b"gTRC")?, curve(b"bTRC")?],
+            to_xyz,
+        })
+    }
+
+    /// Convert an image in the color space of the profile to sRGB
+    pub fn to_srgb(&self, image: &DynamicImage) -> DynamicImage {
+        let to_srgb = multiply(&invert(&SRGB_TO_XYZ), &self.to_xyz);
+        // 8-bit images only need the curves for each of their 256 values
+        let lut: Vec<[f32; 3]> = (0..256)
+            .map(|i| {
+                let value = i as f32 / 255.0;
+                [0, 1, 2].map(|channel| self.curves[channel].apply(value))
+            })
+            .collect();
+        let mut linear = image.to_rgba32f();
+        for pixel in linear.pixels_mut() {
+            for (channel, value) in pixel.0[..3].iter_mut().enumerate() {
+                *value = lut[(value.clamp(0.0, 1.0) * 255.0).round() as usize][channel];
+            }
+        }
+        let converted = map_linear(linear, |rgb| {
+            [0, 1, 2].map(|row| (0..3).map(|column| to_srgb[row][column] * rgb[column]).sum())
+        });
+        with_alpha_of(image, converted)
+    }
+}
+
+/// Convert an image tagged with an ICC profile to sRGB
+///
+/// Images without a profile are taken as sRGB already. Profiles other than
+/// matrix/TRC RGB ones are not supported, the image is used as is then.
+///
+/// # Arguments
+///
+/// * `image` - The image
+/// * `profile` - The ICC profile the image is tagged with
+pub fn to_srgb(image: DynamicImage, profile: Option<&[u8]>) -> DynamicImage {
+    let Some(profile) = profile else {
+        return image;
+    };
+    match IccProfile::parse(profile) {
+        Ok(profile) => {
+            log::debug!("Converting the thumbnail from its ICC profile to sRGB");
+            profile.to_srgb(&image)
+        }
+        Err(e) => {
+            log::warn!("Cannot use the ICC profile of the thumbnail, it is taken as sRGB: {}", e);
+            image
+        }
+    }
+}
+
+/// Multiply two 3x3 matrices
+fn multiply(a: &[[f32; 3]; 3], b: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| (0..3).map(|i| a[row][i] * b[i][column]).sum()))
+}
+
+/// Invert a 3x3 matrix
+fn invert(m: &[[f32; 3]; 3]) -> [[f32; 3]; 3] {
+    let cofactor = |row: usize, column: usize| {
+        let (r1, r2) = ((row + 1) % 3, (row + 2) % 3);
+        let (c1, c2) = ((column + 1) % 3, (column + 2) % 3);
+        m[r1][c1] * m[r2][c2] - m[r1][c2] * m[r2][c1]
+    };
+    let determinant: f32 = (0..3).map(|column| m[0][column] * cofactor(0, column)).sum();
+    [0, 1, 2].map(|row| [0, 1, 2].map(|column| cofactor(column, row) / determinant))
+}
@@ -0,0 +1,144 @@
+use crate::presets::Printer;
+use clap::ValueEnum;
+use std::path;
+
+/// File name of the generated post-processing script
+const SCRIPT_NAME: &str = "MksTftImg.py";
+
+/// Template of the Cura post-processing script
+///
+/// `{exe}`, `{args}` and `{printers}` are replaced when generating it.
+const SCRIPT_TEMPLATE: &str = r#"# Generated by `mks_tft_img cura-plugin`. Run it again to update the path
+# of the executable or the default arguments.
+import json
+import os
+import subprocess
+import tempfile
+
+from UM.Logger import Logger
+
+from ..Script import Script
+
+EXECUTABLE = {exe}
+ARGUMENTS = {args}
+
+
+class MksTftImg(Script):
+    """Converts the preview of the G-code for MKS TFT displays with mks_tft_img"""
+
+    def getSettingDataString(self):
+        return json.dumps({
+            "name": "MKS TFT preview (mks_tft_img)",
+            "key": "MksTftImg",
+            "metadata": {},
+            "version": 2,
+            "settings": {
+                "printer": {
+                    "label": "Printer",
+                    "description": "Preview settings of a known printer",
+                    "type": "enum",
+                    "options": {printers},
+                    "default_value": "default"
+                },
+                "arguments": {
+                    "label": "Additional arguments",
+                    "description": "Additional command line arguments of mks_tft_img",
+                    "type": "str",
+                    "default_value": ""
+                }
+            }
+        })
+
+    def execute(self, data):
+        fd, path = tempfile.mkstemp(suffix=".gcode")
+        try:
+            with os.fdopen(fd, "w", newline="") as file:
+                file.write("".join(data))
+            command = [EXECUTABLE] + ARGUMENTS
+            printer = self.getSettingValueByKey("printer")
+            if printer != "default":
+                command += ["--printer", printer]
+            command += self.getSettingValueByKey("arguments").split()
+            command += ["--missing-thumbnail", "render", path]
+            result = subprocess.run(command, capture_output=True, text=True)
+            if result.returncode != 0:
+                Logger.log("e", "mks_tft_img failed: %s", result.stderr)
+                return data
+            with open(path, "r", newline="") as file:
+                return [file.read()]
+        finally:
+            os.remove(path)
+"#;
+
+/// Generate the Cura post-processing script that runs this tool
+///
+/// # Arguments
+///
+/// * `args` - Additional arguments to run the tool with
+pub fn script(args: &[String]) -> Result<String, ()> {
+    let exe = std::env::current_exe()
+        .map_err(|e| log::error!("Cannot find the path of the executable: {}", e))?;
+    // JSON strings and arrays are valid Python literals
+    let printers: serde_json::Map<_, _> =
+        std::iter::once(("default".to_string(), "Default".into()))
+            .chain(Printer::value_variants().iter().filter_map(|printer| {
+                let value = printer.to_possible_value()?;
+                let label = value.get_help().map(|help| help.to_string()).unwrap_or_default();
+                Some((value.get_name().to_string(), label.into()))
+            }))
+            .collect();
+    Ok(SCRIPT_TEMPLATE
+        .replace("{exe}", &serde_json::json!(exe.to_string_lossy()).to_string())
+        .replace("{args}", &serde_json::json!(args).to_string())
+        .replace("{printers}", &serde_json::Value::from(printers).to_string()))
+}
+
+/// Find the scripts folder of the latest Cura version
+fn scripts_dir() -> Option<path::PathBuf> {
+    let mut candidates = vec![];
+    if let Some(app_data) = std::env::var_os("APPDATA") {
+        candidates.push(path::PathBuf::from(app_data).join("cura"));
+    }
+    if let Some(home) = std::env::var_os("HOME").map(path::PathBuf::from) {
+        candidates.push(home.join("Library").join("Application Support").join("cura"));
+        let data = std::env::var_os("XDG_DATA_HOME")
+            .map(path::PathBuf::from)
+            .unwrap_or_else(|| home.join(".local").join("share"));
+        candidates.push(data.join("cura"));
+    }
+    // Cura keeps its data in a folder per version, e.g. `5.7`
+    let version = |dir: &path::Path| -> Option<Vec<u32>> {
+        let name = dir.file_name()?.to_str()?;
+        name.split('.').map(|part| part.parse().ok()).collect()
+    };
+    candidates
+        .iter()
+        .filter_map(|dir| std::fs::read_dir(dir).ok())
+        .flat_map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()))
+        .filter(|dir| dir.is_dir())
+        .filter_map(|dir| Some((version(&dir)?, dir)))
+        .max()
+        .map(|(_, dir)| dir.join("scripts"))
+}
+
+/// Install the post-processing script into the scripts folder of Cura
+///
+/// # Arguments
+///
+/// * `args` - Additional arguments to run the tool with
+pub fn install(args: &[String]) -> Result<(), ()> {
+    let script = script(args)?;
+    let dir = scripts_dir().ok_or_else(|| {
+        log::error!("Cannot find the Cura configuration folder. Run Cura at least once")
+    })?;
+    std::fs::create_dir_all(&dir)
+        .map_err(|e| log::error!("Cannot create folder `{}`: {}", dir.display(), e))?;
+    let path = dir.join(SCRIPT_NAME);
+    std::fs::write(&path, script)
+        .map_err(|e| log::error!("Cannot write `{}`: {}", path.display(), e))?;
+    log::info!("Installed `{}`", path.display());
+    log::warn!(
+        "Restart Cura and add \"MKS TFT preview\" in Extensions > Post Processing > Modify G-Code"
+    );
+    Ok(())
+}
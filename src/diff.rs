@@ -0,0 +1,186 @@
+use mks_tft_img::slicer;
+
+/// Lines of context around the changes
+const CONTEXT: usize = 3;
+
+/// Above this many line pairs, the header is shown as replaced as a whole
+/// instead of searching for the common lines
+const MAX_PAIRS: usize = 4_000_000;
+
+/// ANSI colors of the diff parts
+const RED: &str = "\x1b[31m";
+const GREEN: &str = "\x1b[32m";
+const CYAN: &str = "\x1b[36m";
+const BOLD: &str = "\x1b[1m";
+const RESET: &str = "\x1b[0m";
+
+/// An edit turning the old lines into the new ones
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum Edit {
+    /// The line is in both, at these indices
+    Equal(usize, usize),
+    /// The old line at this index is removed
+    Delete(usize),
+    /// The new line at this index is added
+    Insert(usize),
+}
+
+/// Replace the image data of the thumbnails with a line telling its length
+///
+/// The `thumbnail begin` and `thumbnail end` lines are kept, so it's still
+/// visible which thumbnails are removed.
+///
+/// # Arguments
+///
+/// * `lines` - The lines of the original G-code header
+pub fn collapse_thumbnails(lines: &[&str]) -> Vec<String> {
+    let mut collapsed = vec![];
+    let mut data_lines = None;
+    for line in lines {
+        match data_lines {
+            None if slicer::is_thumbnail_begin(line) => data_lines = Some(0),
+            Some(count) if slicer::is_thumbnail_end(line) => {
+                collapsed.push(format!("; [{} lines of image data]", count));
+                data_lines = None;
+            }
+            Some(count) => {
+                data_lines = Some(count + 1);
+                continue;
+            }
+            None => (),
+        }
+        collapsed.push(line.to_string());
+    }
+    if let Some(count) = data_lines {
+        collapsed.push(format!("; [{} lines of image data]", count));
+    }
+    collapsed
+}
+
+/// Describe the encoded preview blocks with a line each, instead of their data
+///
+/// # Arguments
+///
+/// * `blocks` - Names and sizes of the encoded images
+pub fn preview_lines(blocks: &[(&str, usize)]) -> Vec<String> {
+    blocks.iter().map(|(name, size)| format!("; [{} preview, {} bytes]", name, size)).collect()
+}
+
+/// Find the edits turning the old lines into the new ones, keeping the
+/// longest common subsequence of lines
+fn edits(old: &[String], new: &[String]) -> Vec<Edit> {
+    if old.len().saturating_mul(new.len()) > MAX_PAIRS {
+        return (0..old.len()).map(Edit::Delete).chain((0..new.len()).map(Edit::Insert)).collect();
+    }
+    // common[i][j] is the length of the common subsequence of old[i..] and new[j..]
+    let width = new.len() + 1;
+    let mut common = vec![0u32; (old.len() + 1) * width];
+    for i in (0..old.len()).rev() {
+        for j in (0..new.len()).rev() {
+            common[i * width + j] = match old[i] == new[j] {
+                true => common[(i + 1) * width + j + 1] + 1,
+                false => common[(i + 1) * width + j].max(common[i * width + j + 1]),
+            };
+        }
+    }
+    let (mut i, mut j) = (0, 0);
+    let mut edits = vec![];
+    while i < old.len() || j < new.len() {
+        if i < old.len() && j < new.len() && old[i] == new[j] {
+            edits.push(Edit::Equal(i, j));
+            (i, j) = (i + 1, j + 1);
+        } else if i < old.len()
+            && (j == new.len() || common[(i + 1) * width + j] >= common[i * width + j + 1])
+        {
+            edits.push(Edit::Delete(i));
+            i += 1;
+        } else {
+            edits.push(Edit::Insert(j));
+            j += 1;
+        }
+    }
+    edits
+}
+
+/// Format a unified diff
+///
+/// # Arguments
+///
+/// * `old` - The original lines
+/// * `new` - The converted lines
+/// * `old_name` - Name of the original in the diff header
+/// * `new_name` - Name of the converted lines in the diff header
+/// * `color` - Whether to color the diff with ANSI escape codes
+///
+/// # Returns
+///
+/// The diff, empty if the lines are the same
+pub fn unified(
+    old: &[String],
+    new: &[String],
+    old_name: &str,
+    new_name: &str,
+    color: bool,
+) -> String {
+    let paint = |code: &str, text: String| match color {
+        true => format!("{}{}{}\n", code, text, RESET),
+        false => format!("{}\n", text),
+    };
+    let edits = edits(old, new);
+    let changes: Vec<usize> = edits
+        .iter()
+        .enumerate()
+        .filter(|(_, edit)| !matches!(edit, Edit::Equal(..)))
+        .map(|(index, _)| index)
+        .collect();
+    let Some(&first) = changes.first() else {
+        return String::new();
+    };
+
+    // Group the changes into hunks, merging the ones whose context overlaps
+    let mut hunks = vec![];
+    let mut start = first.saturating_sub(CONTEXT);
+    let mut last = first;
+    for &change in &changes[1..] {
+        if change - last - 1 > 2 * CONTEXT {
+            hunks.push((start, (last + CONTEXT + 1).min(edits.len())));
+            start = change - CONTEXT;
+        }
+        last = change;
+    }
+    hunks.push((start, (last + CONTEXT + 1).min(edits.len())));
+
+    let mut diff =
+        paint(BOLD, format!("--- {}", old_name)) + &paint(BOLD, format!("+++ {}", new_name));
+    for (start, end) in hunks {
+        let hunk = &edits[start..end];
+        // Where the hunk starts in the old and the new lines
+        let (mut old_start, mut new_start) = (0, 0);
+        for edit in edits[..start].iter() {
+            match edit {
+                Edit::Equal(..) => (old_start, new_start) = (old_start + 1, new_start + 1),
+                Edit::Delete(_) => old_start += 1,
+                Edit::Insert(_) => new_start += 1,
+            }
+        }
+        let old_count = hunk.iter().filter(|edit| !matches!(edit, Edit::Insert(_))).count();
+        let new_count = hunk.iter().filter(|edit| !matches!(edit, Edit::Delete(_))).count();
+        // Empty ranges point at the line before them
+        let range = |start: usize, count: usize| match count {
+            0 => format!("{},0", start),
+            _ => format!("{},{}", start + 1, count),
+        };
+        diff += &paint(
+            CYAN,
+            format!("@@ -{} +{} @@", range(old_start, old_count), range(new_start, new_count)),
+        );
+        for edit in hunk {
+            match *edit {
+                Edit::Equal(i, _) => diff += &format!(" {}\n", old[i]),
+                Edit::Delete(i) => diff += &paint(RED, format!("-{}", old[i])),
+                Edit::Insert(j) => diff += &paint(GREEN, format!("+{}", new[j])),
+            }
+        }
+    }
+    diff
+}
@@ -0,0 +1,148 @@
+use crate::Args;
+use clap::CommandFactory;
+use mks_tft_img::meta::META_SAMPLE;
+use std::fs::File;
+use std::io::Read;
+use std::path;
+
+/// Start of the comments with directives, e.g. in the custom start G-code of
+/// the slicer: `; mks_tft_img: gimage=160 filter=lanczos3`
+const PREFIX: &str = "mks_tft_img:";
+
+/// Options that can be set by directives, as on the command line, and their
+/// short aliases
+///
+/// Only the options of how the preview is made are allowed, so a G-code file
+/// cannot redirect where it is written or uploaded to.
+const OPTIONS: [(&str, &str); 35] = [
+    ("printer", "printer"),
+    ("target", "target"),
+    ("simage", "simage-size"),
+    ("simage-size", "simage-size"),
+    ("gimage", "gimage-size"),
+    ("gimage-size", "gimage-size"),
+    ("row-end", "row-end"),
+    ("trailing-semicolon", "trailing-semicolon"),
+    ("filter", "filter"),
+    ("integer-scale", "integer-scale"),
+    ("simage-quality", "simage-quality"),
+    ("linear-light", "linear-light"),
+    ("icc", "icc"),
+    ("autocrop", "autocrop"),
+    ("auto-contrast", "auto-contrast"),
+    ("auto-contrast-clip", "auto-contrast-clip"),
+    ("saturation", "saturation"),
+    ("replace-background", "replace-background"),
+    ("dither", "dither"),
+    ("lut", "lut"),
+    ("footer-template", "footer-template"),
+    ("no-footer", "no-footer"),
+    ("inject-metadata", "inject-metadata"),
+    ("m117", "m117"),
+    ("crc-trailer", "crc-trailer"),
+    ("qr", "qr"),
+    ("qr-corner", "qr-corner"),
+    ("preview-position", "preview-position"),
+    ("missing-thumbnail", "missing-thumbnail"),
+    ("preview-source", "preview-source"),
+    ("render-view", "render-view"),
+    ("render-azimuth", "render-azimuth"),
+    ("render-elevation", "render-elevation"),
+    ("render-zoom", "render-zoom"),
+    ("sidecar-png", "sidecar-png"),
+];
+
+/// Read the directives in the comments of a G-code file
+///
+/// Directives are `key=value` pairs, or only `key` for switches, after
+/// `; mks_tft_img:`. Values with spaces are written in double quotes. Only the
+/// beginning of the file is searched, where the slicer puts the start G-code.
+/// Unknown keys are warned about and ignored, so profiles written for other
+/// versions of the tool still work. A value given to a switch, or a missing
+/// value, is an error.
+///
+/// # Arguments
+///
+/// * `path` - Path to the G-code file
+///
+/// # Returns
+///
+/// The directives as command line arguments, e.g. `["--gimage-size=160"]`,
+/// in the order they were found
+pub fn read(path: &path::Path) -> Result<Vec<String>, ()> {
+    let mut head = vec![];
+    File::open(path)
+        .and_then(|file| file.take(META_SAMPLE as u64).read_to_end(&mut head))
+        .map_err(|e| log::error!("Cannot read directives from `{}`: {}", path.display(), e))?;
+    let command = Args::command();
+    let mut args = vec![];
+    for line in String::from_utf8_lossy(&head).lines() {
+        let Some(comment) = line.trim_start().strip_prefix(';') else {
+            continue;
+        };
+        let Some(directives) = comment.trim_start().strip_prefix(PREFIX) else {
+            continue;
+        };
+        for directive in split(directives) {
+            let (key, value) = match directive.split_once('=') {
+                Some((key, value)) => (key, Some(value)),
+                None => (directive.as_str(), None),
+            };
+            let Some((_, option)) = OPTIONS.iter().find(|(name, _)| *name == key) else {
+                log::warn!("Unknown directive `{}` in the G-code is ignored", key);
+                continue;
+            };
+            let takes_value = command
+                .get_arguments()
+                .find(|arg| arg.get_long() == Some(option))
+                .is_some_and(|arg| arg.get_action().takes_values());
+            // The value is joined to the option, so it can never be taken as
+            // another option or a path
+            match (value, takes_value) {
+                (Some(value), true) => args.push(format!("--{}={}", option, value)),
+                (None, false) => args.push(format!("--{}", option)),
+                (Some(_), false) => {
+                    log::error!(
+                        "The directive `{}` in the G-code is a switch, write it without `=`",
+                        key
+                    );
+                    return Err(());
+                }
+                (None, true) => {
+                    log::error!(
+                        "The directive `{}` in the G-code needs a value, e.g. `{}=...`",
+                        key,
+                        key
+                    );
+                    return Err(());
+                }
+            }
+        }
+    }
+    if !args.is_empty() {
+        log::info!("Using directives from the G-code: {}", args.join(" "));
+    }
+    Ok(args)
+}
+
+/// Split directives at whitespace outside of double quotes, removing the quotes
+fn split(directives: &str) -> Vec<String> {
+    let mut parts = vec![];
+    let mut part = String::new();
+    let mut quoted = false;
+    for c in directives.chars() {
+        match c {
+            '"' => quoted = !quoted,
+            c if c.is_whitespace() && !quoted => {
+                if !part.is_empty() {
+                    parts.push(std::mem::take(&mut part));
+                }
+            }
+            c => part.push(c),
+        }
+    }
+    if !part.is_empty() {
+        parts.push(part);
+    }
+    parts
+}
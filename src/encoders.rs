@@ -0,0 +1,540 @@
+use crate::meta::PrintMeta;
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::codecs::jpeg::JpegEncoder;
+use image::imageops::FilterType;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use rayon::prelude::*;
+use std::io::Cursor;
+
+/// Maximum length of a base64 line in the thumbnail comment block. This is the
+/// same value PrusaSlicer uses, so firmware that parses slicer thumbnails can
+/// handle it.
+pub const THUMBNAIL_LINE_LENGTH: usize = 78;
+
+/// Lookup table for hex encoding of pixels
+const HEX_DIGITS: &[u8; 16] = b"0123456789abcdef";
+
+/// Size of the BMP preview that Flashforge printers show in the file list
+pub const FLASHFORGE_BITMAP_SIZE: (u32, u32) = (80, 60);
+
+/// Size of the binary xgcode header that precedes the BMP preview
+const FLASHFORGE_HEADER_SIZE: u32 = 58;
+
+/// Convert an RGB pixel to the RGB565 format
+///
+/// # Arguments
+///
+/// * `pixel` - A reference to an Rgb pixel
+///
+/// # Returns
+///
+/// A tuple containing the higher and lower bytes of the RGB565 color
+pub fn rgb565(pixel: &Rgb<u8>) -> (u8, u8) {
+    let r = (pixel.0[0] as u16) >> 3;
+    let g = (pixel.0[1] as u16) >> 2;
+    let b = (pixel.0[2] as u16) >> 3;
+    let color = r << 11 | g << 5 | b;
+    ((color >> 8) as u8, (color & 0xFF) as u8)
+}
+
+/// Create G-code representation of a TFT image
+///
+/// # Arguments
+///
+/// * `prefix` - A string prefix for the G-code
+/// * `image` - The image to be converted
+/// * `row_end` - What goes after each image row, before the next marker
+/// * `marker` - The command after each image row, `M10086 ;` or `M10086 `
+///
+/// # Returns
+///
+/// A string containing the G-code for the image
+pub fn create_tft_image_gcode(
+    prefix: &str,
+    image: &DynamicImage,
+    row_end: &str,
+    marker: &str,
+) -> String {
+    log::debug!(
+        "Creating tft image gcode with prefix `{}` and size {}x{}",
+        prefix,
+        image.width(),
+        image.height()
+    );
+    let tft_image = rgb565_hex_rows(image);
+    join_rows(prefix, &tft_image, &format!("{}{}", row_end, marker), &format!("\n{}\n", marker))
+}
+
+/// Convert an RGB565 color back to an RGB pixel
+///
+/// The lower bits of each channel are filled with its higher bits, so that
+/// black and white stay exactly black and white.
+///
+/// # Arguments
+///
+/// * `higher` - The higher byte of the RGB565 color
+/// * `lower` - The lower byte of the RGB565 color
+///
+/// # Returns
+///
+/// An Rgb pixel as shown by the display
+pub fn rgb565_to_rgb(higher: u8, lower: u8) -> Rgb<u8> {
+    let color = (higher as u16) << 8 | lower as u16;
+    let r = (color >> 11) as u8;
+    let g = (color >> 5 & 0x3F) as u8;
+    let b = (color & 0x1F) as u8;
+    Rgb([r << 3 | r >> 2, g << 2 | g >> 4, b << 3 | b >> 2])
+}
+
+/// Reduce colors of an image to the ones an RGB565 display can show
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// The image as it is shown by the display
+pub fn quantize_rgb565(image: &DynamicImage) -> RgbImage {
+    let mut quantized = image.to_rgb8();
+    for pixel in quantized.pixels_mut() {
+        let (higher, lower) = rgb565(pixel);
+        *pixel = rgb565_to_rgb(higher, lower);
+    }
+    quantized
+}
+
+/// Create a raw RGB565 representation of an image
+///
+/// Pixels are stored row by row, lower byte first, the same way they are
+/// sent to the TFT display.
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// A vector with two bytes for every pixel of the image
+pub fn create_rgb565_raw(image: &DynamicImage) -> Vec<u8> {
+    log::debug!("Creating raw rgb565 image with size {}x{}", image.width(), image.height());
+    image
+        .to_rgb8()
+        .pixels()
+        .flat_map(|pixel| {
+            let (higher, lower) = rgb565(pixel);
+            [lower, higher]
+        })
+        .collect()
+}
+
+/// Create a C source representation of a raw RGB565 image
+///
+/// # Arguments
+///
+/// * `name` - Name of the array, also used as a prefix for size defines
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// A string with `<NAME>_WIDTH` and `<NAME>_HEIGHT` defines and a `uint8_t`
+/// array containing the output of [`create_rgb565_raw`]
+pub fn create_rgb565_c_array(name: &str, image: &DynamicImage) -> String {
+    let raw = create_rgb565_raw(image);
+    let mut source = format!(
+        "#include <stdint.h>\n\n\
+        #define {upper}_WIDTH {}\n\
+        #define {upper}_HEIGHT {}\n\n\
+        const uint8_t {}[{}] = {{\n",
+        image.width(),
+        image.height(),
+        name,
+        raw.len(),
+        upper = name.to_uppercase(),
+    );
+    for chunk in raw.chunks(16) {
+        let line: Vec<String> = chunk.iter().map(|byte| format!("0x{:02x},", byte)).collect();
+        source.push_str("    ");
+        source.push_str(&line.join(" "));
+        source.push('\n');
+    }
+    source.push_str("};\n");
+    source
+}
+
+/// Encode an image as rows of hex encoded RGB565 pixels, lower byte first
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// A vector with a hex string for every row of the image
+fn rgb565_hex_rows(image: &DynamicImage) -> Vec<String> {
+    // Rows are independent of each other, so they are encoded in parallel
+    let width = image.width() as usize;
+    image
+        .to_rgb8()
+        .as_raw()
+        .par_chunks(width * 3)
+        .map(|row| {
+            let mut colors = Vec::with_capacity(width);
+            rgb565_row(row, &mut colors);
+            let mut hex_row = String::with_capacity(width * 4);
+            for color in colors {
+                let [first, second] = color.to_le_bytes();
+                push_hex(&mut hex_row, first);
+                push_hex(&mut hex_row, second);
+            }
+            hex_row
+        })
+        .collect()
+}
+
+/// Convert a row of packed RGB8 pixels to RGB565 colors
+///
+/// With the `simd` feature on x86_64, several pixels are converted at once.
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+fn rgb565_row(row: &[u8], colors: &mut Vec<u16>) {
+    crate::simd::rgb565_row(row, colors)
+}
+
+/// Convert a row of packed RGB8 pixels to RGB565 colors
+#[cfg(not(all(feature = "simd", target_arch = "x86_64")))]
+fn rgb565_row(row: &[u8], colors: &mut Vec<u16>) {
+    colors.extend(row.chunks_exact(3).map(|pixel| {
+        let (higher, lower) = rgb565(&Rgb([pixel[0], pixel[1], pixel[2]]));
+        u16::from_be_bytes([higher, lower])
+    }));
+}
+
+/// Join encoded image rows into a single G-code string
+///
+/// The string is allocated once with the exact size it needs.
+///
+/// # Arguments
+///
+/// * `prefix` - A string prefix for the G-code
+/// * `rows` - Encoded image rows
+/// * `separator` - What goes between rows
+/// * `terminator` - What goes after the last row
+fn join_rows(prefix: &str, rows: &[String], separator: &str, terminator: &str) -> String {
+    let rows_len: usize = rows.iter().map(String::len).sum();
+    let separators_len = separator.len() * rows.len().saturating_sub(1);
+    let mut gcode =
+        String::with_capacity(prefix.len() + 1 + rows_len + separators_len + terminator.len());
+    gcode.push_str(prefix);
+    gcode.push(':');
+    for (i, row) in rows.iter().enumerate() {
+        if i > 0 {
+            gcode.push_str(separator);
+        }
+        gcode.push_str(row);
+    }
+    gcode.push_str(terminator);
+    gcode
+}
+
+/// Append a byte as two lowercase hex digits
+fn push_hex(string: &mut String, byte: u8) {
+    string.push(HEX_DIGITS[(byte >> 4) as usize] as char);
+    string.push(HEX_DIGITS[(byte & 0x0F) as usize] as char);
+}
+
+/// Create G-code representation of an Anycubic preview image
+///
+/// Anycubic Kobra/Vyper firmware reads a PNG preview from a PrusaSlicer style
+/// `; thumbnail begin <width>x<height> <size>` comment block. The image is
+/// re-encoded as PNG, base64 encoded and split into lines of
+/// [`THUMBNAIL_LINE_LENGTH`] characters.
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// A string containing the G-code for the image
+pub fn create_anycubic_image_gcode(image: &DynamicImage) -> Result<String, ()> {
+    log::debug!("Creating anycubic image gcode with size {}x{}", image.width(), image.height());
+    let mut png = Vec::new();
+    image
+        .to_rgb8()
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| log::error!("Cannot encode anycubic preview as PNG: {}", e))?;
+    let encoded = BASE64_STANDARD.encode(png);
+
+    let mut gcode =
+        format!(";\n; thumbnail begin {}x{} {}\n", image.width(), image.height(), encoded.len());
+    for chunk in encoded.as_bytes().chunks(THUMBNAIL_LINE_LENGTH) {
+        gcode.push_str("; ");
+        gcode.push_str(std::str::from_utf8(chunk).expect("base64 output is always ASCII"));
+        gcode.push('\n');
+    }
+    gcode.push_str("; thumbnail end\n;\n");
+    Ok(gcode)
+}
+
+/// Quality of the JPEG thumbnails of Creality printers
+const CREALITY_JPEG_QUALITY: u8 = 90;
+
+/// Encode a Creality preview image as JPEG
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// The JPEG file
+pub fn create_creality_jpeg(image: &DynamicImage) -> Result<Vec<u8>, ()> {
+    let mut jpeg = Vec::new();
+    let encoder = JpegEncoder::new_with_quality(&mut jpeg, CREALITY_JPEG_QUALITY);
+    image
+        .to_rgb8()
+        .write_with_encoder(encoder)
+        .map_err(|e| log::error!("Cannot encode creality preview as JPEG: {}", e))?;
+    Ok(jpeg)
+}
+
+/// Create G-code representation of a Creality preview image
+///
+/// The stock screens of the Creality Ender-3 V3 and K1 series read a JPEG
+/// preview from a `; jpg begin <width>x<height> <size>` comment block, as
+/// Creality Print writes it. The image is base64 encoded and split into lines
+/// of [`THUMBNAIL_LINE_LENGTH`] characters.
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// A string containing the G-code for the image
+pub fn create_creality_image_gcode(image: &DynamicImage) -> Result<String, ()> {
+    log::debug!("Creating creality image gcode with size {}x{}", image.width(), image.height());
+    let encoded = BASE64_STANDARD.encode(create_creality_jpeg(image)?);
+
+    let mut gcode =
+        format!(";\n; jpg begin {}x{} {}\n", image.width(), image.height(), encoded.len());
+    for chunk in encoded.as_bytes().chunks(THUMBNAIL_LINE_LENGTH) {
+        gcode.push_str("; ");
+        gcode.push_str(std::str::from_utf8(chunk).expect("base64 output is always ASCII"));
+        gcode.push('\n');
+    }
+    gcode.push_str("; jpg end\n;\n");
+    Ok(gcode)
+}
+
+/// Create the raw preview of custom firmware
+///
+/// The preview is the width and the height of the image as 16-bit little
+/// endian numbers, followed by its pixels as [`create_rgb565_raw`] writes
+/// them, so firmware can read it from the beginning of the file without
+/// parsing G-code.
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// The bytes that have to be written before the G-code
+pub fn create_raw_preview(image: &DynamicImage) -> Result<Vec<u8>, ()> {
+    let (Ok(width), Ok(height)) = (u16::try_from(image.width()), u16::try_from(image.height()))
+    else {
+        log::error!("The raw preview {}x{} is too large", image.width(), image.height());
+        return Err(());
+    };
+    let mut preview = Vec::with_capacity(4 + 2 * width as usize * height as usize);
+    preview.extend_from_slice(&width.to_le_bytes());
+    preview.extend_from_slice(&height.to_le_bytes());
+    preview.extend_from_slice(&create_rgb565_raw(image));
+    Ok(preview)
+}
+
+/// Create the Flashforge BMP preview image
+///
+/// The image is fitted into [`FLASHFORGE_BITMAP_SIZE`] and centered on a
+/// white background.
+///
+/// # Arguments
+///
+/// * `image` - The image to be converted
+///
+/// # Returns
+///
+/// The image that is shown on the printer screen
+pub fn create_flashforge_bitmap(image: &DynamicImage) -> RgbImage {
+    let (width, height) = FLASHFORGE_BITMAP_SIZE;
+    let fitted = image.resize(width, height, FilterType::CatmullRom).to_rgb8();
+    let mut canvas = RgbImage::from_pixel(width, height, Rgb([255, 255, 255]));
+    image::imageops::overlay(
+        &mut canvas,
+        &fitted,
+        ((width - fitted.width()) / 2).into(),
+        ((height - fitted.height()) / 2).into(),
+    );
+    canvas
+}
+
+/// Create the Flashforge xgcode header with an embedded BMP preview
+///
+/// FlashPrint `.gx` files start with a fixed size binary header, followed by
+/// a 24-bit BMP preview and the plain G-code. Print statistics in the header
+/// are left zeroed, they are filled in by [`fill_flashforge_stats`].
+///
+/// # Arguments
+///
+/// * `bitmap` - The preview created by [`create_flashforge_bitmap`]
+///
+/// # Returns
+///
+/// The bytes that have to be written before the G-code
+pub fn create_flashforge_header(bitmap: &RgbImage) -> Result<Vec<u8>, ()> {
+    log::debug!(
+        "Creating flashforge header with bitmap size {}x{}",
+        bitmap.width(),
+        bitmap.height()
+    );
+    let mut bmp = Vec::new();
+    bitmap
+        .write_to(&mut Cursor::new(&mut bmp), ImageFormat::Bmp)
+        .map_err(|e| log::error!("Cannot encode flashforge preview as BMP: {}", e))?;
+
+    let gcode_offset = FLASHFORGE_HEADER_SIZE + bmp.len() as u32;
+    let mut header = Vec::with_capacity(gcode_offset as usize);
+    header.extend_from_slice(b"xgcode 1.0\n\0\0\0\0\0");
+    // Unknown, always zero
+    header.extend_from_slice(&0u32.to_le_bytes());
+    header.extend_from_slice(&FLASHFORGE_HEADER_SIZE.to_le_bytes());
+    // The G-code offset is stored twice
+    header.extend_from_slice(&gcode_offset.to_le_bytes());
+    header.extend_from_slice(&gcode_offset.to_le_bytes());
+    // Print time in seconds, right and left filament usage in mm
+    header.extend_from_slice(&[0u8; 12]);
+    // Extruder type, layer height, unknown, perimeter shells, print speed,
+    // platform temperature and extruder temperature
+    header.extend_from_slice(&[0u8; 14]);
+    debug_assert_eq!(header.len(), FLASHFORGE_HEADER_SIZE as usize);
+
+    header.extend_from_slice(&bmp);
+    Ok(header)
+}
+
+/// Fill the print statistics of a Flashforge xgcode header from the slicer
+/// metadata
+///
+/// The printer shows them before a print starts. Values the slicer didn't
+/// write stay zero, as do the extruder type, the perimeter shells and the
+/// print speed, which are not in the metadata.
+///
+/// # Arguments
+///
+/// * `header` - The header created by [`create_flashforge_header`]
+/// * `meta` - The print statistics of the G-code
+pub fn fill_flashforge_stats(header: &mut [u8], meta: &PrintMeta) {
+    let mut put = |offset: usize, bytes: &[u8]| {
+        header[offset..offset + bytes.len()].copy_from_slice(bytes);
+    };
+    let short = |value: f64| (value.round().clamp(0.0, u16::MAX.into()) as u16).to_le_bytes();
+    if let Some(seconds) = meta.estimated_time {
+        put(32, &u32::try_from(seconds).unwrap_or(u32::MAX).to_le_bytes());
+    }
+    // All the filament is counted for the right extruder
+    if let Some(mm) = meta.filament_length {
+        put(36, &(mm.round().clamp(0.0, u32::MAX.into()) as u32).to_le_bytes());
+    }
+    if let Some(mm) = meta.layer_height {
+        put(46, &short(mm * 1000.0));
+    }
+    if let Some(celsius) = meta.bed_temperature {
+        put(54, &short(celsius.into()));
+    }
+    if let Some(celsius) = meta.nozzle_temperature {
+        put(56, &short(celsius.into()));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A test image with a different color in every pixel
+    fn gradient(width: u32, height: u32) -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(width, height, |x, y| {
+            Rgb([(x * 7) as u8, (y * 5) as u8, ((x + y) * 3) as u8])
+        }))
+    }
+
+    #[test]
+    fn writes_anycubic_thumbnails() {
+        let image = gradient(200, 150);
+        let gcode = create_anycubic_image_gcode(&image).unwrap();
+        let lines: Vec<&str> = gcode.lines().collect();
+        assert_eq!(lines[0], ";");
+        assert_eq!(lines[lines.len() - 2..], ["; thumbnail end", ";"]);
+
+        let header: Vec<&str> = lines[1].split(' ').collect();
+        assert_eq!(header[..4], [";", "thumbnail", "begin", "200x150"]);
+        let body = &lines[2..lines.len() - 2];
+        // Every line is full, except the last one
+        let (last, full) = body.split_last().unwrap();
+        assert!(full
+            .iter()
+            .all(|line| line.starts_with("; ") && line.len() == THUMBNAIL_LINE_LENGTH + 2));
+        assert!(last.starts_with("; ") && last.len() <= THUMBNAIL_LINE_LENGTH + 2);
+        let encoded: String = body.iter().map(|line| &line[2..]).collect();
+        assert_eq!(header[4], encoded.len().to_string());
+
+        let png = BASE64_STANDARD.decode(encoded).unwrap();
+        let decoded = image::load_from_memory_with_format(&png, ImageFormat::Png).unwrap();
+        assert_eq!(decoded.to_rgb8(), image.to_rgb8());
+    }
+
+    #[test]
+    fn writes_flashforge_headers() {
+        let bitmap = create_flashforge_bitmap(&gradient(200, 150));
+        assert_eq!(bitmap.dimensions(), FLASHFORGE_BITMAP_SIZE);
+        let mut header = create_flashforge_header(&bitmap).unwrap();
+        let untouched = header.clone();
+        fill_flashforge_stats(&mut header, &PrintMeta::default());
+        assert_eq!(header, untouched);
+        let meta = PrintMeta {
+            estimated_time: Some(3725),
+            filament_length: Some(1234.6),
+            layer_height: Some(0.2),
+            nozzle_temperature: Some(210),
+            bed_temperature: Some(60),
+            ..Default::default()
+        };
+        fill_flashforge_stats(&mut header, &meta);
+
+        let u16_at = |pos: usize| u16::from_le_bytes(header[pos..pos + 2].try_into().unwrap());
+        let u32_at = |pos: usize| u32::from_le_bytes(header[pos..pos + 4].try_into().unwrap());
+        assert_eq!(&header[..16], b"xgcode 1.0\n\0\0\0\0\0");
+        assert_eq!(u32_at(16), 0);
+        assert_eq!(u32_at(20), FLASHFORGE_HEADER_SIZE);
+        // Both G-code offsets point right after the BMP
+        assert_eq!(u32_at(24), header.len() as u32);
+        assert_eq!(u32_at(28), header.len() as u32);
+        assert_eq!(u32_at(32), 3725);
+        assert_eq!(u32_at(36), 1235);
+        assert_eq!(u32_at(40), 0);
+        assert_eq!(u16_at(44), 0);
+        assert_eq!(u16_at(46), 200);
+        assert_eq!([u16_at(48), u16_at(50), u16_at(52)], [0; 3]);
+        assert_eq!(u16_at(54), 60);
+        assert_eq!(u16_at(56), 210);
+
+        let bmp = &header[FLASHFORGE_HEADER_SIZE as usize..];
+        assert_eq!(&bmp[..2], b"BM");
+        // The BMP size, and the 80x60 size in its info header
+        let bmp_u32_at = |pos: usize| u32::from_le_bytes(bmp[pos..pos + 4].try_into().unwrap());
+        assert_eq!(bmp_u32_at(2), bmp.len() as u32);
+        assert_eq!((bmp_u32_at(18), bmp_u32_at(22)), FLASHFORGE_BITMAP_SIZE);
+        let decoded = image::load_from_memory_with_format(bmp, ImageFormat::Bmp).unwrap();
+        assert_eq!(decoded.to_rgb8(), bitmap);
+    }
+}
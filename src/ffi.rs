@@ -0,0 +1,220 @@
+//! C interface of the converter
+//!
+//! The functions are exported from the `cdylib` build of the crate, so slicer
+//! plugins written in C++ or Python (via `ctypes`) can convert G-code in
+//! process instead of running the executable. The C header is generated with
+//! `cbindgen --config cbindgen.toml --output include/mks_tft_img.h`.
+
+use crate::options::{PreviewSize, ProcessOptions, RowEnd};
+use crate::{convert, Target};
+use std::ffi::c_int;
+
+/// MKS TFT displays
+pub const MKS_TFT_IMG_TARGET_MKS: u32 = 0;
+/// Anycubic Kobra/Vyper
+pub const MKS_TFT_IMG_TARGET_ANYCUBIC: u32 = 1;
+/// Flashforge
+pub const MKS_TFT_IMG_TARGET_FLASHFORGE: u32 = 3;
+/// Creality Ender-3 V3 and K1
+pub const MKS_TFT_IMG_TARGET_CREALITY: u32 = 4;
+/// Raw RGB565 pixels for custom firmware
+pub const MKS_TFT_IMG_TARGET_RAW: u32 = 5;
+
+/// The conversion succeeded
+pub const MKS_TFT_IMG_OK: c_int = 0;
+/// An argument is a null pointer or out of range
+pub const MKS_TFT_IMG_INVALID_ARGUMENT: c_int = 1;
+/// There is no thumbnail in the G-code, or it cannot be converted, or the
+/// conversion crashed
+pub const MKS_TFT_IMG_FAILED: c_int = 2;
+
+/// Options of the conversion
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct MksTftImgOptions {
+    /// The display the preview is generated for, one of `MKS_TFT_IMG_TARGET_*`
+    pub target: u32,
+    /// The size of the simage
+    pub simage_size: u16,
+    /// The size of the gimage
+    pub gimage_size: u16,
+    /// Terminate MKS image rows with `\r\n` instead of `\r`
+    pub crlf_row_end: bool,
+    /// Fail if the thumbnail doesn't match its `thumbnail begin` line
+    pub strict: bool,
+}
+
+impl MksTftImgOptions {
+    /// Convert to the options of [`convert`]
+    fn to_options(self) -> Option<ProcessOptions> {
+        let target = match self.target {
+            MKS_TFT_IMG_TARGET_MKS => Target::Mks,
+            MKS_TFT_IMG_TARGET_ANYCUBIC => Target::Anycubic,
+            MKS_TFT_IMG_TARGET_FLASHFORGE => Target::Flashforge,
+            MKS_TFT_IMG_TARGET_CREALITY => Target::Creality,
+            MKS_TFT_IMG_TARGET_RAW => Target::Raw,
+            _ => return None,
+        };
+        let row_end = match self.crlf_row_end {
+            true => RowEnd::Crlf,
+            false => RowEnd::Cr,
+        };
+        Some(
+            ProcessOptions::new()
+                .target(target)
+                .simage_size(PreviewSize::new(self.simage_size)?)
+                .gimage_size(PreviewSize::new(self.gimage_size)?)
+                .row_end(row_end)
+                .strict(self.strict),
+        )
+    }
+}
+
+/// Get the default options, the same as the ones of the command line tool
+#[no_mangle]
+pub extern "C" fn mks_tft_img_default_options() -> MksTftImgOptions {
+    let options = ProcessOptions::default();
+    MksTftImgOptions {
+        target: MKS_TFT_IMG_TARGET_MKS,
+        simage_size: options.simage_size.get(),
+        gimage_size: options.gimage_size.get(),
+        crlf_row_end: options.row_end == RowEnd::Crlf,
+        strict: options.strict,
+    }
+}
+
+/// Convert the thumbnail of G-code in memory
+///
+/// On success, the converted G-code is stored in `output` and `output_len`,
+/// and has to be released with [`mks_tft_img_free`].
+///
+/// # Safety
+///
+/// `gcode` must point to `gcode_len` readable bytes. `options`, `output` and
+/// `output_len` must be valid pointers.
+#[no_mangle]
+pub unsafe extern "C" fn mks_tft_img_convert(
+    gcode: *const u8,
+    gcode_len: usize,
+    options: *const MksTftImgOptions,
+    output: *mut *mut u8,
+    output_len: *mut usize,
+) -> c_int {
+    if gcode.is_null() || options.is_null() || output.is_null() || output_len.is_null() {
+        return MKS_TFT_IMG_INVALID_ARGUMENT;
+    }
+    // SAFETY: The pointers are not null, and the caller guarantees they are valid
+    let (gcode, options) = unsafe { (std::slice::from_raw_parts(gcode, gcode_len), *options) };
+    let Some(options) = options.to_options() else {
+        return MKS_TFT_IMG_INVALID_ARGUMENT;
+    };
+    // A panic must not unwind into the caller, which would abort its process
+    let converted = std::panic::catch_unwind(|| convert(gcode, &options)).unwrap_or_else(|_| {
+        log::error!("The conversion crashed");
+        Err(())
+    });
+    match converted {
+        Ok(converted) => {
+            let converted = converted.into_boxed_slice();
+            // SAFETY: The pointers are not null, and the caller guarantees they are valid
+            unsafe {
+                *output_len = converted.len();
+                *output = Box::into_raw(converted).cast();
+            }
+            MKS_TFT_IMG_OK
+        }
+        Err(_) => MKS_TFT_IMG_FAILED,
+    }
+}
+
+/// Release G-code returned by [`mks_tft_img_convert`]
+///
+/// # Safety
+///
+/// `data` and `len` must be the ones returned by [`mks_tft_img_convert`], and
+/// must not be used after this call. Null pointers are ignored.
+#[no_mangle]
+pub unsafe extern "C" fn mks_tft_img_free(data: *mut u8, len: usize) {
+    if data.is_null() {
+        return;
+    }
+    // SAFETY: The caller guarantees the data was allocated by `mks_tft_img_convert`
+    drop(unsafe { Box::from_raw(std::ptr::slice_from_raw_parts_mut(data, len)) });
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::prelude::*;
+    use std::io::Cursor;
+
+    /// Convert G-code through the C interface, the way a plugin does
+    fn convert_gcode(gcode: &str) -> (c_int, Vec<u8>) {
+        let options = mks_tft_img_default_options();
+        let mut output = std::ptr::null_mut();
+        let mut output_len = 0;
+        // SAFETY: All pointers are valid for the duration of the call
+        let result = unsafe {
+            mks_tft_img_convert(gcode.as_ptr(), gcode.len(), &options, &mut output, &mut output_len)
+        };
+        if result != MKS_TFT_IMG_OK {
+            return (result, vec![]);
+        }
+        // SAFETY: The output was just returned by `mks_tft_img_convert`
+        let converted = unsafe { std::slice::from_raw_parts(output, output_len) }.to_vec();
+        // SAFETY: The output is released once, and not used afterwards
+        unsafe { mks_tft_img_free(output, output_len) };
+        (result, converted)
+    }
+
+    #[test]
+    fn converts_a_thumbnail() {
+        let mut png = Cursor::new(vec![]);
+        image::RgbImage::from_pixel(16, 16, image::Rgb([255, 0, 0]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let encoded = BASE64_STANDARD.encode(png.into_inner());
+        let gcode = format!(
+            "; THUMBNAIL_BLOCK_START\n; thumbnail begin 16x16 {}\n; {}\n; thumbnail end\n\
+             ; THUMBNAIL_BLOCK_END\nG28\n",
+            encoded.len(),
+            encoded
+        );
+        let (result, converted) = convert_gcode(&gcode);
+        assert_eq!(result, MKS_TFT_IMG_OK);
+        let converted = String::from_utf8(converted).unwrap();
+        assert!(converted.starts_with(";simage:"));
+        assert!(converted.contains(";;gimage:"));
+        assert!(converted.ends_with("G28\n"));
+    }
+
+    #[test]
+    fn reports_missing_thumbnails() {
+        let (result, _) = convert_gcode("G28\nG1 X10\n");
+        assert_eq!(result, MKS_TFT_IMG_FAILED);
+    }
+
+    #[test]
+    fn reports_damaged_thumbnails_without_crashing() {
+        let gcode = "; THUMBNAIL_BLOCK_START\n; iVBORw0KGgo\n; THUMBNAIL_BLOCK_END\nG28\n";
+        let (result, _) = convert_gcode(gcode);
+        assert_eq!(result, MKS_TFT_IMG_FAILED);
+    }
+
+    #[test]
+    fn rejects_invalid_arguments() {
+        let mut options = mks_tft_img_default_options();
+        options.target = 100;
+        let (mut output, mut output_len) = (std::ptr::null_mut(), 0);
+        // SAFETY: All pointers are valid for the duration of the call
+        let result = unsafe {
+            mks_tft_img_convert(b"G28".as_ptr(), 3, &options, &mut output, &mut output_len)
+        };
+        assert_eq!(result, MKS_TFT_IMG_INVALID_ARGUMENT);
+        // SAFETY: A null gcode pointer is checked before it is read
+        let result = unsafe {
+            mks_tft_img_convert(std::ptr::null(), 0, &options, &mut output, &mut output_len)
+        };
+        assert_eq!(result, MKS_TFT_IMG_INVALID_ARGUMENT);
+    }
+}
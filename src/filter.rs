@@ -0,0 +1,120 @@
+use mks_tft_img::slicer;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path;
+
+/// Which of the files found in directories are converted
+#[derive(Clone, Debug, Default)]
+pub struct FileFilter {
+    /// Glob patterns of the files to convert, all files if there are none
+    pub include: Vec<String>,
+    /// Glob patterns of the files to skip
+    pub exclude: Vec<String>,
+    /// Size of the largest file to convert, in bytes
+    pub max_size: Option<u64>,
+    /// Whether to skip G-code files without a thumbnail
+    pub skip_without_thumbnail: bool,
+}
+
+impl FileFilter {
+    /// Check whether a file found in a directory is converted
+    ///
+    /// Patterns with a `/` are matched against the path relative to the
+    /// directory, the others against the file name.
+    ///
+    /// # Arguments
+    ///
+    /// * `directory` - The directory that was searched
+    /// * `path` - The file found in it
+    pub fn accepts(&self, directory: &path::Path, path: &path::Path) -> bool {
+        let relative = path.strip_prefix(directory).unwrap_or(path);
+        let relative: Vec<_> =
+            relative.components().map(|part| part.as_os_str().to_string_lossy()).collect();
+        let relative = relative.join("/");
+        let name = path.file_name().unwrap_or_default().to_string_lossy();
+        let matches = |pattern: &String| match pattern.contains('/') {
+            true => glob_match(pattern, &relative),
+            false => glob_match(pattern, &name),
+        };
+        if !self.include.is_empty() && !self.include.iter().any(matches) {
+            log::debug!("Skipping `{}`, it is not included", path.display());
+            return false;
+        }
+        if let Some(pattern) = self.exclude.iter().find(|pattern| matches(pattern)) {
+            log::info!("Skipping `{}`, it is excluded by `{}`", path.display(), pattern);
+            return false;
+        }
+        if let Some(max_size) = self.max_size {
+            let size = std::fs::metadata(path).map(|metadata| metadata.len()).unwrap_or_default();
+            if size > max_size {
+                log::info!(
+                    "Skipping `{}`, it is larger than {} MB",
+                    path.display(),
+                    max_size / 1024 / 1024
+                );
+                return false;
+            }
+        }
+        let is_gcode =
+            path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("gcode"));
+        if self.skip_without_thumbnail && is_gcode && !has_thumbnail(path) {
+            log::info!("Skipping `{}`, it has no thumbnail", path.display());
+            return false;
+        }
+        true
+    }
+}
+
+/// Check whether a G-code file has a thumbnail, or a preview converted already
+fn has_thumbnail(path: &path::Path) -> bool {
+    let Ok(file) = File::open(path) else {
+        // Let the conversion report why it cannot be read
+        return true;
+    };
+    let mut reader = BufReader::new(file);
+    let mut line = vec![];
+    let mut first = true;
+    while reader.read_until(b'\n', &mut line).is_ok_and(|read| read > 0) {
+        let text = String::from_utf8_lossy(&line);
+        if slicer::is_thumbnail_begin(&text) || (first && mks_tft_img::is_converted(&line)) {
+            return true;
+        }
+        first = false;
+        line.clear();
+    }
+    false
+}
+
+/// Match a path against a glob pattern
+///
+/// `*` matches any characters but `/`, `**` any characters including `/`,
+/// and `?` any one character but `/`. Letters match in any case, the same
+/// way file extensions are compared.
+///
+/// # Arguments
+///
+/// * `pattern` - The pattern, e.g. `**/old/*.gcode`
+/// * `path` - The path, with `/` between its parts
+pub fn glob_match(pattern: &str, path: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let path: Vec<char> = path.chars().collect();
+    matches(&pattern, &path)
+}
+
+fn matches(pattern: &[char], path: &[char]) -> bool {
+    match pattern {
+        [] => path.is_empty(),
+        ['*', '*', rest @ ..] => {
+            // `**/` matches no directory as well
+            rest.strip_prefix(&['/']).is_some_and(|rest| matches(rest, path))
+                || (0..=path.len()).any(|skip| matches(rest, &path[skip..]))
+        }
+        ['*', rest @ ..] => (0..=path.len())
+            .take_while(|&skip| skip == 0 || path[skip - 1] != '/')
+            .any(|skip| matches(rest, &path[skip..])),
+        ['?', rest @ ..] => path.first().is_some_and(|&c| c != '/') && matches(rest, &path[1..]),
+        [c, rest @ ..] => {
+            path.first().is_some_and(|p| p.eq_ignore_ascii_case(c)) && matches(rest, &path[1..])
+        }
+    }
+}
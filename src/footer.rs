@@ -0,0 +1,186 @@
+use crate::meta::{self, PrintMeta};
+use crate::options::ProcessOptions;
+use crate::slicer;
+
+/// The post-processing info comment used without a custom template
+pub const DEFAULT_TEMPLATE: &str = "; MKS_TFT_PREVIEW_POSTPROCESS
+; Post processed by {tool} ({repository})
+{source}
+;  target = {target}
+{preview}
+{slicer}";
+
+/// What the post-processing info comment describes
+pub struct FooterInfo<'a> {
+    /// Lines describing where the preview was made from
+    pub source_info: &'a str,
+    /// Format of the original thumbnail, e.g. `png`, or what it was generated as
+    pub source_format: &'a str,
+    /// Width and height of the original thumbnail
+    pub source_size: (u32, u32),
+    /// Lines describing the preview
+    pub preview_info: &'a str,
+    /// Print statistics written by the slicer
+    pub meta: &'a PrintMeta,
+    /// Name of the G-code file, as the slicer saves it if known, empty for G-code in memory
+    pub file_name: &'a str,
+}
+
+/// Get the value of a placeholder
+///
+/// # Returns
+///
+/// The value, or `None` if there is no such placeholder
+fn placeholder(
+    name: &str,
+    options: &ProcessOptions,
+    info: &FooterInfo<'_>,
+    settings: &std::collections::BTreeMap<String, String>,
+) -> Option<String> {
+    let version = match options.reproducible {
+        true => "",
+        false => env!("CARGO_PKG_VERSION"),
+    };
+    let value = match name {
+        "tool" if version.is_empty() => "mks_tft_img".to_string(),
+        "tool" => format!("mks_tft_img v{}", version),
+        "version" => version.to_string(),
+        "repository" => env!("CARGO_PKG_REPOSITORY").to_string(),
+        "target" => options.target.to_string(),
+        "simage_size" => options.simage_size.to_string(),
+        "gimage_size" => {
+            options.gimage_sizes().iter().map(ToString::to_string).collect::<Vec<_>>().join(",")
+        }
+        "source_format" => info.source_format.to_string(),
+        "source_width" => info.source_size.0.to_string(),
+        "source_height" => info.source_size.1.to_string(),
+        "source" => info.source_info.trim_end().to_string(),
+        "preview" => info.preview_info.trim_end().to_string(),
+        "file" => info.file_name.to_string(),
+        "estimated_time" => info.meta.estimated_time.map(meta::format_duration).unwrap_or_default(),
+        "filament_length" => optional(info.meta.filament_length.map(|mm| format!("{:.2}", mm))),
+        "filament_weight" => optional(info.meta.filament_weight.map(|g| format!("{:.2}", g))),
+        "layer_height" => optional(info.meta.layer_height),
+        "layer_count" => optional(info.meta.layer_count),
+        "printer_model" => optional(info.meta.printer_model.as_ref()),
+        "slicer" => slicer::FOOTER_SETTINGS
+            .iter()
+            .filter_map(|name| settings.get(*name).map(|value| format!(";  {} = {}", name, value)))
+            .collect::<Vec<_>>()
+            .join("\n"),
+        _ => settings.get(name.strip_prefix("slicer.")?).cloned().unwrap_or_default(),
+    };
+    Some(value)
+}
+
+/// Format a value that may be missing, as empty if it is
+fn optional(value: Option<impl ToString>) -> String {
+    value.map(|value| value.to_string()).unwrap_or_default()
+}
+
+/// Render the post-processing info comment from a template
+///
+/// Placeholders in braces are replaced with their values, unknown ones are
+/// left as they are. A line holding only placeholders that are empty is
+/// dropped, and lines that don't start with `;` are made comments, so the
+/// template cannot add G-code commands.
+///
+/// # Arguments
+///
+/// * `template` - The template, e.g. [`DEFAULT_TEMPLATE`]
+/// * `options` - The options the preview was generated with
+/// * `info` - What the comment describes
+///
+/// # Returns
+///
+/// The lines of the comment, each ending with `\n`
+pub fn render(template: &str, options: &ProcessOptions, info: &FooterInfo<'_>) -> String {
+    let settings = slicer::settings();
+    log::debug!("{} slicer settings found in the environment", settings.len());
+    let mut footer = String::new();
+    for line in template.lines() {
+        let (rendered, only_placeholders) =
+            substitute(line, options, info, &settings, str::to_string);
+        if only_placeholders && rendered.trim().is_empty() && !line.trim().is_empty() {
+            continue;
+        }
+        if rendered.is_empty() {
+            footer.push('\n');
+        }
+        for line in rendered.lines() {
+            if !line.is_empty() && !line.starts_with(';') {
+                footer.push_str("; ");
+            }
+            footer.push_str(line);
+            footer.push('\n');
+        }
+    }
+    footer
+}
+
+/// Replace the placeholders of the footer template in a text, e.g. a URL
+///
+/// # Arguments
+///
+/// * `text` - The text with placeholders in braces, see [`render`]
+/// * `options` - The options the preview was generated with
+/// * `info` - What the placeholders describe
+/// * `url` - Whether to percent-encode the values, so they can go into a URL
+pub fn expand(text: &str, options: &ProcessOptions, info: &FooterInfo<'_>, url: bool) -> String {
+    let settings = slicer::settings();
+    let encode = match url {
+        true => percent_encode,
+        false => str::to_string,
+    };
+    substitute(text, options, info, &settings, encode).0
+}
+
+/// Replace the placeholders in a line
+///
+/// # Returns
+///
+/// The line, and whether it held nothing but known placeholders
+fn substitute(
+    line: &str,
+    options: &ProcessOptions,
+    info: &FooterInfo<'_>,
+    settings: &std::collections::BTreeMap<String, String>,
+    encode: fn(&str) -> String,
+) -> (String, bool) {
+    let mut rendered = String::new();
+    let mut only_placeholders = true;
+    let mut rest = line;
+    while let Some(start) = rest.find('{') {
+        let Some(length) = rest[start..].find('}') else {
+            break;
+        };
+        let name = &rest[start + 1..start + length];
+        rendered.push_str(&rest[..start]);
+        only_placeholders &= rest[..start].trim().is_empty();
+        match placeholder(name, options, info, settings) {
+            Some(value) => rendered.push_str(&encode(&value)),
+            None => {
+                log::warn!("Unknown placeholder `{{{}}}` in the template", name);
+                rendered.push_str(&rest[start..=start + length]);
+                only_placeholders = false;
+            }
+        }
+        rest = &rest[start + length + 1..];
+    }
+    rendered.push_str(rest);
+    only_placeholders &= rest.trim().is_empty();
+    (rendered, only_placeholders)
+}
+
+/// Percent-encode everything but the unreserved characters of URLs
+fn percent_encode(value: &str) -> String {
+    value
+        .bytes()
+        .map(|byte| match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                (byte as char).to_string()
+            }
+            _ => format!("%{:02X}", byte),
+        })
+        .collect()
+}
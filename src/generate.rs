@@ -0,0 +1,118 @@
+use base64::Engine;
+use image::{DynamicImage, ImageFormat, Rgb, RgbImage};
+use mks_tft_img::slicer::{Generator, ThumbnailStyle};
+use std::io::Cursor;
+use std::path;
+
+/// Version of the slicers written in the header of generated G-code
+fn version(slicer: Generator) -> &'static str {
+    match slicer {
+        Generator::PrusaSlicer => "2.7.1",
+        Generator::SuperSlicer => "2.5.59.8",
+        Generator::OrcaSlicer => "2.1.1",
+        Generator::BambuStudio => "01.09.00.70",
+        Generator::Cura => "5.7.1",
+        Generator::IdeaMaker => "4.4.1",
+    }
+}
+
+/// Generate a G-code file with an embedded PNG thumbnail, the way a slicer does
+///
+/// The thumbnail is a gradient with a grid, so its PNG compresses about as
+/// well as a rendered model. The output only depends on the arguments, so
+/// the same file is generated every time.
+///
+/// # Arguments
+///
+/// * `slicer` - The slicer whose header and thumbnail comments are written
+/// * `size` - Width and height of the thumbnail
+/// * `lines` - How many G-code lines follow the thumbnail
+pub fn synthetic_gcode(slicer: Generator, size: u32, lines: u32) -> Result<Vec<u8>, ()> {
+    let image = RgbImage::from_fn(size, size, |x, y| {
+        let grid = if x % 16 == 0 || y % 16 == 0 {
+            64
+        } else {
+            0
+        };
+        Rgb([(x * 255 / size) as u8, (y * 255 / size) as u8, 128 + grid])
+    });
+    let mut png = vec![];
+    DynamicImage::ImageRgb8(image)
+        .write_to(&mut Cursor::new(&mut png), ImageFormat::Png)
+        .map_err(|e| log::error!("Failed to encode the synthetic thumbnail: {}", e))?;
+    let data = base64::engine::general_purpose::STANDARD.encode(png);
+    // The moves extrude 0.01 mm each, one per 0.1 s
+    let filament = lines as f64 * 0.01;
+    let seconds = lines / 10;
+
+    let mut gcode = match slicer {
+        Generator::Cura => format!(
+            ";FLAVOR:Marlin\n;TIME:{}\n;Filament used: {:.5}m\n;Generated with {} {}\n",
+            seconds,
+            filament / 1000.0,
+            slicer.name(),
+            version(slicer)
+        ),
+        Generator::IdeaMaker => format!(";Sliced by {} {}\n", slicer.name(), version(slicer)),
+        _ => format!("; generated by {} {}\n", slicer.name(), version(slicer)),
+    };
+    let block = slicer.thumbnail_style() == ThumbnailStyle::Block;
+    if block {
+        gcode.push_str("\n;\n; THUMBNAIL_BLOCK_START\n");
+    }
+    gcode.push_str(";\n");
+    gcode.push_str(&format!("; thumbnail begin {}x{} {}\n", size, size, data.len()));
+    for chunk in data.as_bytes().chunks(78) {
+        gcode.push_str("; ");
+        gcode.push_str(std::str::from_utf8(chunk).expect("base64 is ASCII"));
+        gcode.push('\n');
+    }
+    gcode.push_str("; thumbnail end\n;\n");
+    if block {
+        gcode.push_str("; THUMBNAIL_BLOCK_END\n\n");
+    }
+    gcode.push_str("G21\nG90\nM82\nG28\nG92 E0\n");
+    for i in 0..lines {
+        gcode.push_str(&format!(
+            "G1 X{:.3} Y{:.3} E{:.5}\n",
+            (i % 200) as f64 + 0.5,
+            (i / 200 % 200) as f64 + 0.25,
+            i as f64 * 0.01
+        ));
+    }
+    if slicer != Generator::Cura {
+        gcode.push_str(&format!(
+            "\n; filament used [mm] = {:.2}\n; estimated printing time (normal mode) = {}m {}s\n",
+            filament,
+            seconds / 60,
+            seconds % 60
+        ));
+    }
+    Ok(gcode.into_bytes())
+}
+
+/// Write a synthetic G-code file, to test the tool or firmware with
+///
+/// # Arguments
+///
+/// * `path` - The G-code file to write
+/// * `slicer` - The slicer whose dialect is written
+/// * `size` - Width and height of the thumbnail
+/// * `lines` - How many G-code lines follow the thumbnail
+pub fn run(path: &path::Path, slicer: Generator, size: u32, lines: u32) -> Result<(), ()> {
+    if size == 0 {
+        log::error!("The thumbnail size must be at least 1 pixel");
+        return Err(());
+    }
+    let gcode = synthetic_gcode(slicer, size, lines)?;
+    std::fs::write(path, gcode)
+        .map_err(|e| log::error!("Failed to write `{}`: {}", path.display(), e))?;
+    log::info!(
+        "Generated `{}` with a {}x{} thumbnail like {} does",
+        path.display(),
+        size,
+        size,
+        slicer
+    );
+    Ok(())
+}
@@ -0,0 +1,72 @@
+use crate::report::FileReport;
+use std::path;
+use std::process::{Command, Stdio};
+
+/// Environment variable with the G-code file a hook is run for
+const FILE_VAR: &str = "MKS_TFT_IMG_FILE";
+/// Environment variable with the file the converted G-code was written to
+const OUTPUT_VAR: &str = "MKS_TFT_IMG_OUTPUT";
+/// Environment variable with the status of the file, e.g. `converted`
+const STATUS_VAR: &str = "MKS_TFT_IMG_STATUS";
+/// Environment variable with what happened to the file as JSON, the same
+/// as in the `--report`
+const SUMMARY_VAR: &str = "MKS_TFT_IMG_SUMMARY";
+
+/// Run a hook command with the shell
+///
+/// Its output goes to stderr, so it doesn't mix with G-code written to stdout.
+///
+/// # Arguments
+///
+/// * `name` - Name of the hook, for the log
+/// * `command` - The command line
+/// * `env` - Environment variables to run it with
+fn run(name: &str, command: &str, env: &[(&str, String)]) -> Result<(), ()> {
+    log::debug!("Running the {} `{}`", name, command);
+    let (shell, flag) = match cfg!(windows) {
+        true => ("cmd", "/C"),
+        false => ("sh", "-c"),
+    };
+    let status = Command::new(shell)
+        .arg(flag)
+        .arg(command)
+        .envs(env.iter().map(|(key, value)| (key, value)))
+        .stdin(Stdio::null())
+        .stdout(Stdio::from(std::io::stderr()))
+        .status()
+        .map_err(|e| log::error!("Cannot run the {} `{}`: {}", name, command, e))?;
+    if !status.success() {
+        log::error!("The {} `{}` failed with {}", name, command, status);
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Run the `--pre-hook` before a G-code file is converted
+///
+/// # Arguments
+///
+/// * `command` - The command line
+/// * `path` - The G-code file
+pub fn pre(command: &str, path: &path::Path) -> Result<(), ()> {
+    run("pre-hook", command, &[(FILE_VAR, path.display().to_string())])
+}
+
+/// Run the `--post-hook` after a G-code file is processed, whether it was
+/// converted or not
+///
+/// # Arguments
+///
+/// * `command` - The command line
+/// * `output` - The file the converted G-code was written to
+/// * `file` - What happened to the file
+pub fn post(command: &str, output: &path::Path, file: &FileReport) -> Result<(), ()> {
+    let status = serde_json::to_value(file.status).expect("Statuses are always serializable");
+    let env = [
+        (FILE_VAR, file.path.display().to_string()),
+        (OUTPUT_VAR, output.display().to_string()),
+        (STATUS_VAR, status.as_str().unwrap_or_default().to_string()),
+        (SUMMARY_VAR, serde_json::to_string(file).expect("Reports are always serializable")),
+    ];
+    run("post-hook", command, &env)
+}
@@ -0,0 +1,117 @@
+use mks_tft_img::meta::{self, PrintMeta};
+use mks_tft_img::slicer;
+use mks_tft_img::verify::{self, CrcCheck};
+use serde::Serialize;
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path;
+
+/// How many lines at the beginning of the G-code are searched for the slicer name
+const HEADER_LINES: usize = 64;
+
+/// What is known about a G-code file
+#[derive(Serialize)]
+pub struct Inspection<'a> {
+    /// The G-code file, `None` for G-code in memory
+    #[serde(skip_serializing_if = "Option::is_none")]
+    path: Option<&'a path::Path>,
+    /// The slicer the G-code was generated by
+    slicer: Option<String>,
+    /// Print statistics written by the slicer
+    #[serde(flatten)]
+    meta: PrintMeta,
+    /// The CRC32 trailer of the preview, if it has one
+    #[serde(skip_serializing_if = "Option::is_none")]
+    preview_crc: Option<CrcCheck>,
+}
+
+/// Print what the slicer wrote about the print into the G-code
+///
+/// # Arguments
+///
+/// * `path` - The G-code file
+/// * `json` - Whether to print it as JSON, for scripts
+pub fn run(path: &path::Path, json: bool) -> Result<(), ()> {
+    let file = File::open(path)
+        .map_err(|e| log::error!("Cannot open file `{}` for reading: {}", path.display(), e))?;
+    let header: Vec<String> =
+        BufReader::new(file).lines().take(HEADER_LINES).map_while(Result::ok).collect();
+    let inspection = Inspection {
+        path: Some(path),
+        slicer: slicer::detect_generator(header.iter().map(String::as_str))
+            .map(|generator| generator.to_string()),
+        meta: PrintMeta::from_file(path)?,
+        preview_crc: File::open(path)
+            .and_then(|file| verify::check_crc_trailer(BufReader::new(file)))
+            .map_err(|e| log::error!("Cannot check the CRC32 trailer of the preview: {}", e))?,
+    };
+    if json {
+        println!(
+            "{}",
+            serde_json::to_string(&inspection).expect("Inspection is always serializable")
+        );
+        return Ok(());
+    }
+
+    let unknown = || "unknown".to_string();
+    let meta = &inspection.meta;
+    println!("file: {}", path.display());
+    println!("slicer: {}", inspection.slicer.clone().unwrap_or_else(unknown));
+    println!(
+        "estimated time: {}",
+        meta.estimated_time.map(meta::format_duration).unwrap_or_else(unknown)
+    );
+    println!(
+        "filament length: {}",
+        meta.filament_length.map(|mm| format!("{:.2} mm", mm)).unwrap_or_else(unknown)
+    );
+    println!(
+        "filament weight: {}",
+        meta.filament_weight.map(|g| format!("{:.2} g", g)).unwrap_or_else(unknown)
+    );
+    println!(
+        "layer height: {}",
+        meta.layer_height.map(|mm| format!("{} mm", mm)).unwrap_or_else(unknown)
+    );
+    println!(
+        "layer count: {}",
+        meta.layer_count.map(|count| count.to_string()).unwrap_or_else(unknown)
+    );
+    println!(
+        "nozzle temperature: {}",
+        meta.nozzle_temperature.map(|celsius| format!("{} °C", celsius)).unwrap_or_else(unknown)
+    );
+    println!(
+        "bed temperature: {}",
+        meta.bed_temperature.map(|celsius| format!("{} °C", celsius)).unwrap_or_else(unknown)
+    );
+    println!("printer model: {}", meta.printer_model.clone().unwrap_or_else(unknown));
+    let crc = match inspection.preview_crc {
+        Some(check) if check.is_valid() => format!("{:08x}, ok", check.actual),
+        Some(check) => {
+            format!("{:08x}, CORRUPTED (the trailer says {:08x})", check.actual, check.expected)
+        }
+        None => "no trailer".to_string(),
+    };
+    println!("preview CRC32: {}", crc);
+    Ok(())
+}
+
+/// Inspect G-code in memory, e.g. uploaded to the `serve` command
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the gcode file
+pub fn inspect_gcode(gcode: &[u8]) -> Inspection<'static> {
+    let header = String::from_utf8_lossy(&gcode[..gcode.len().min(meta::META_SAMPLE)]);
+    Inspection {
+        path: None,
+        slicer: slicer::detect_generator(header.lines().take(HEADER_LINES))
+            .map(|generator| generator.to_string()),
+        meta: PrintMeta::from_gcode(gcode),
+        preview_crc: verify::check_crc_trailer(gcode).unwrap_or_else(|e| {
+            log::warn!("Cannot check the CRC32 trailer of the preview: {}", e);
+            None
+        }),
+    }
+}
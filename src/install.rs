@@ -0,0 +1,375 @@
+use clap::ValueEnum;
+use std::path;
+
+/// Slicers the tool can be registered in as a post-processing script
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Slicer {
+    /// PrusaSlicer
+    Prusaslicer,
+    /// OrcaSlicer
+    Orcaslicer,
+    /// SuperSlicer
+    Superslicer,
+}
+
+impl std::fmt::Display for Slicer {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str(self.dir_name())
+    }
+}
+
+impl Slicer {
+    /// Name of the config directory of the slicer
+    fn dir_name(self) -> &'static str {
+        match self {
+            Slicer::Prusaslicer => "PrusaSlicer",
+            Slicer::Orcaslicer => "OrcaSlicer",
+            Slicer::Superslicer => "SuperSlicer",
+        }
+    }
+
+    /// Flatpak application ID of the slicer
+    fn flatpak_id(self) -> &'static str {
+        match self {
+            Slicer::Prusaslicer => "com.prusa3d.PrusaSlicer",
+            Slicer::Orcaslicer => "io.github.softfever.OrcaSlicer",
+            Slicer::Superslicer => "com.superslicer.SuperSlicer",
+        }
+    }
+
+    /// Find the config directory of the slicer
+    fn config_dir(self) -> Option<path::PathBuf> {
+        let name = self.dir_name();
+        let mut candidates = vec![];
+        if let Some(app_data) = std::env::var_os("APPDATA") {
+            candidates.push(path::PathBuf::from(app_data).join(name));
+        }
+        if let Some(home) = std::env::var_os("HOME").map(path::PathBuf::from) {
+            candidates.push(home.join("Library").join("Application Support").join(name));
+            let config = std::env::var_os("XDG_CONFIG_HOME")
+                .map(path::PathBuf::from)
+                .unwrap_or_else(|| home.join(".config"));
+            candidates.push(config.join(name));
+            candidates.push(
+                home.join(".var").join("app").join(self.flatpak_id()).join("config").join(name),
+            );
+        }
+        candidates.into_iter().find(|dir| dir.is_dir())
+    }
+
+    /// Find the user print presets, where the post-processing scripts are set
+    fn print_presets(self, config_dir: &path::Path) -> Vec<path::PathBuf> {
+        let (dirs, extension) = match self {
+            Slicer::Prusaslicer | Slicer::Superslicer => (vec![config_dir.join("print")], "ini"),
+            // OrcaSlicer keeps presets per user account, `default` without one
+            Slicer::Orcaslicer => (
+                list_dir(&config_dir.join("user"))
+                    .into_iter()
+                    .map(|dir| dir.join("process"))
+                    .collect(),
+                "json",
+            ),
+        };
+        let mut presets: Vec<_> = dirs
+            .iter()
+            .flat_map(|dir| list_dir(dir))
+            .filter(|path| path.extension().is_some_and(|e| e == extension))
+            .collect();
+        presets.sort();
+        presets
+    }
+}
+
+/// List the entries of a directory, or nothing if it cannot be read
+fn list_dir(dir: &path::Path) -> Vec<path::PathBuf> {
+    std::fs::read_dir(dir)
+        .map(|entries| entries.filter_map(|entry| entry.ok()).map(|entry| entry.path()).collect())
+        .unwrap_or_default()
+}
+
+/// Register the tool as a post-processing script in all user print presets
+///
+/// An existing entry of the tool is replaced, so installing again only
+/// updates the arguments.
+///
+/// # Arguments
+///
+/// * `slicer` - The slicer to register the tool in
+/// * `args` - Additional arguments to run the tool with, e.g. `--printer`
+pub fn install(slicer: Slicer, args: &[String]) -> Result<(), ()> {
+    let exe = std::env::current_exe()
+        .map_err(|e| log::error!("Cannot find the path of the executable: {}", e))?;
+    let command = std::iter::once(exe.to_string_lossy().into_owned())
+        .chain(args.iter().cloned())
+        .map(|arg| quote_arg(&arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    log::info!("Registering `{}` in {}", command, slicer);
+    update_presets(slicer, |scripts| {
+        scripts.retain(|script| !is_own_script(script));
+        scripts.push(command.clone());
+    })
+}
+
+/// Remove the tool from the post-processing scripts of all user print presets
+///
+/// # Arguments
+///
+/// * `slicer` - The slicer to remove the tool from
+pub fn uninstall(slicer: Slicer) -> Result<(), ()> {
+    log::info!("Removing the post-processing script from {}", slicer);
+    update_presets(slicer, |scripts| scripts.retain(|script| !is_own_script(script)))
+}
+
+/// Registry key of the context menu action for G-code files
+const SHELL_KEY: &str = r"HKCU\Software\Classes\SystemFileAssociations\.gcode\shell\mks_tft_img";
+
+/// Add an action converting the preview to the Windows Explorer context menu
+/// of `.gcode` files
+///
+/// The action is registered for the current user only, so no administrator
+/// rights are needed.
+///
+/// # Arguments
+///
+/// * `args` - Additional arguments to run the tool with, e.g. `--printer`
+pub fn install_shell_extension(args: &[String]) -> Result<(), ()> {
+    if !cfg!(windows) {
+        log::error!("The shell extension is only supported on Windows");
+        return Err(());
+    }
+    let exe = std::env::current_exe()
+        .map_err(|e| log::error!("Cannot find the path of the executable: {}", e))?;
+    let exe = exe.to_string_lossy();
+    let command = std::iter::once(exe.to_string())
+        .chain(args.iter().cloned())
+        .chain(std::iter::once("%1".to_string()))
+        .map(|arg| format!("\"{}\"", arg))
+        .collect::<Vec<_>>()
+        .join(" ");
+    log::info!("Registering `{}` in the Explorer context menu", command);
+    reg(&["add", SHELL_KEY, "/ve", "/d", "Convert preview for MKS TFT", "/f"])?;
+    reg(&["add", SHELL_KEY, "/v", "Icon", "/d", &exe, "/f"])?;
+    reg(&["add", &format!(r"{}\command", SHELL_KEY), "/ve", "/d", &command, "/f"])
+}
+
+/// Remove the action converting the preview from the Windows Explorer context
+/// menu
+pub fn uninstall_shell_extension() -> Result<(), ()> {
+    if !cfg!(windows) {
+        log::error!("The shell extension is only supported on Windows");
+        return Err(());
+    }
+    log::info!("Removing the action from the Explorer context menu");
+    reg(&["delete", SHELL_KEY, "/f"])
+}
+
+/// Run `reg.exe` to change the Windows registry
+fn reg(args: &[&str]) -> Result<(), ()> {
+    let output = std::process::Command::new("reg")
+        .args(args)
+        .output()
+        .map_err(|e| log::error!("Cannot run `reg`: {}", e))?;
+    if !output.status.success() {
+        log::error!("`reg {}` failed: {}", args[0], String::from_utf8_lossy(&output.stderr).trim());
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Update post-processing scripts of all user print presets of a slicer
+fn update_presets(slicer: Slicer, update: impl Fn(&mut Vec<String>)) -> Result<(), ()> {
+    let config_dir = slicer.config_dir().ok_or_else(|| {
+        log::error!("Cannot find the config directory of {}. Run it at least once", slicer)
+    })?;
+    log::debug!("Found {} config in `{}`", slicer, config_dir.display());
+    let presets = slicer.print_presets(&config_dir);
+    if presets.is_empty() {
+        log::error!(
+            "There are no user print presets in `{}`. Save your print settings as a preset first",
+            config_dir.display()
+        );
+        return Err(());
+    }
+    let mut result = Ok(());
+    for preset in presets {
+        let updated = match slicer {
+            Slicer::Prusaslicer | Slicer::Superslicer => update_ini_preset(&preset, &update),
+            Slicer::Orcaslicer => update_json_preset(&preset, &update),
+        };
+        match updated {
+            Ok(true) => log::info!("Updated `{}`", preset.display()),
+            Ok(false) => log::debug!("`{}` is up to date", preset.display()),
+            Err(_) => result = Err(()),
+        }
+    }
+    log::warn!("Restart {} for the changes to take effect", slicer);
+    result
+}
+
+/// Name of the post-processing option in slicer presets
+const POST_PROCESS: &str = "post_process";
+
+/// Update post-processing scripts of an INI preset of PrusaSlicer or SuperSlicer
+///
+/// # Returns
+///
+/// Whether the preset was changed
+fn update_ini_preset(preset: &path::Path, update: &impl Fn(&mut Vec<String>)) -> Result<bool, ()> {
+    let content = std::fs::read_to_string(preset)
+        .map_err(|e| log::error!("Cannot read preset `{}`: {}", preset.display(), e))?;
+    let mut lines: Vec<String> = content.lines().map(String::from).collect();
+    let index = lines
+        .iter()
+        .position(|line| line.split_once('=').is_some_and(|(name, _)| name.trim() == POST_PROCESS));
+    let value = index.and_then(|index| lines[index].split_once('=')).map(|(_, value)| value.trim());
+    let mut scripts = value.map(unescape_strings).unwrap_or_default();
+    let original = scripts.clone();
+    update(&mut scripts);
+    if scripts == original {
+        return Ok(false);
+    }
+    let line = format!("{} = {}", POST_PROCESS, escape_strings(&scripts));
+    match index {
+        Some(index) => lines[index] = line,
+        None => lines.push(line),
+    }
+    let mut content = lines.join("\n");
+    content.push('\n');
+    std::fs::write(preset, content)
+        .map_err(|e| log::error!("Cannot write preset `{}`: {}", preset.display(), e))?;
+    Ok(true)
+}
+
+/// Update post-processing scripts of a JSON preset of OrcaSlicer
+///
+/// # Returns
+///
+/// Whether the preset was changed
+fn update_json_preset(preset: &path::Path, update: &impl Fn(&mut Vec<String>)) -> Result<bool, ()> {
+    let content = std::fs::read_to_string(preset)
+        .map_err(|e| log::error!("Cannot read preset `{}`: {}", preset.display(), e))?;
+    let mut json: serde_json::Value = serde_json::from_str(&content)
+        .map_err(|e| log::error!("Cannot parse preset `{}`: {}", preset.display(), e))?;
+    let Some(object) = json.as_object_mut() else {
+        log::error!("Unexpected content of preset `{}`", preset.display());
+        return Err(());
+    };
+    let mut scripts: Vec<String> = object
+        .get(POST_PROCESS)
+        .and_then(|value| value.as_array())
+        .map(|values| {
+            values
+                .iter()
+                .filter_map(|value| value.as_str())
+                .filter(|script| !script.is_empty())
+                .map(String::from)
+                .collect()
+        })
+        .unwrap_or_default();
+    let original = scripts.clone();
+    update(&mut scripts);
+    if scripts == original {
+        return Ok(false);
+    }
+    object.insert(POST_PROCESS.to_string(), scripts.into());
+    let content = serde_json::to_string_pretty(&json).unwrap_or_default();
+    std::fs::write(preset, content)
+        .map_err(|e| log::error!("Cannot write preset `{}`: {}", preset.display(), e))?;
+    Ok(true)
+}
+
+/// Check whether a post-processing script runs this tool
+fn is_own_script(script: &str) -> bool {
+    let exe = match script.strip_prefix('"') {
+        Some(rest) => rest.split('"').next().unwrap_or_default(),
+        None => script.split(' ').next().unwrap_or_default(),
+    };
+    path::Path::new(exe).file_stem().is_some_and(|stem| stem == env!("CARGO_PKG_NAME"))
+}
+
+/// Quote a command line argument for the slicer
+///
+/// The slicers split the command line the way the shell of the platform does,
+/// so arguments with spaces have to be quoted, e.g. `C:\Program Files\...`.
+fn quote_arg(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || c == '"' || c == '\'') {
+        return arg.to_string();
+    }
+    if cfg!(windows) {
+        format!("\"{}\"", arg.replace('"', "\\\""))
+    } else {
+        format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+    }
+}
+
+/// Serialize a list of strings the way PrusaSlicer does in INI files
+///
+/// Strings are separated by `;` and quoted with C-style escapes if needed.
+fn escape_strings(strings: &[String]) -> String {
+    strings
+        .iter()
+        .map(|string| {
+            if !string.is_empty() && !string.contains([';', '"', '\\', '\n', '\r', ' ']) {
+                return string.clone();
+            }
+            let mut escaped = String::from("\"");
+            for c in string.chars() {
+                match c {
+                    '\\' | '"' => {
+                        escaped.push('\\');
+                        escaped.push(c);
+                    }
+                    '\n' => escaped.push_str("\\n"),
+                    '\r' => escaped.push_str("\\r"),
+                    c => escaped.push(c),
+                }
+            }
+            escaped.push('"');
+            escaped
+        })
+        .collect::<Vec<_>>()
+        .join(";")
+}
+
+/// Parse a list of strings serialized by PrusaSlicer in INI files
+fn unescape_strings(value: &str) -> Vec<String> {
+    let mut strings = vec![];
+    let mut chars = value.chars().peekable();
+    while chars.peek().is_some() {
+        let mut string = String::new();
+        if chars.peek() == Some(&'"') {
+            chars.next();
+            while let Some(c) = chars.next() {
+                match c {
+                    '"' => break,
+                    '\\' => match chars.next() {
+                        Some('n') => string.push('\n'),
+                        Some('r') => string.push('\r'),
+                        Some(c) => string.push(c),
+                        None => (),
+                    },
+                    c => string.push(c),
+                }
+            }
+            // Keep anything between the closing quote and the separator
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+                string.push(c);
+            }
+        } else {
+            for c in chars.by_ref() {
+                if c == ';' {
+                    break;
+                }
+                string.push(c);
+            }
+        }
+        if !string.is_empty() {
+            strings.push(string);
+        }
+    }
+    strings
+}
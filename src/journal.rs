@@ -0,0 +1,182 @@
+use crate::{archive, cache, storage};
+use serde::{Deserialize, Serialize};
+use std::io;
+use std::path;
+
+/// Size and CRC32 of a file, to tell which state of a rewrite it is in
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct Fingerprint {
+    size: u64,
+    checksum: u32,
+}
+
+impl Fingerprint {
+    /// Get the fingerprint of a file
+    fn of(path: &path::Path) -> io::Result<Self> {
+        Ok(Fingerprint {
+            size: std::fs::metadata(path)?.len(),
+            checksum: cache::checksum(path)?,
+        })
+    }
+}
+
+/// What the journal of a rewrite records
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+struct Record {
+    /// The original G-code file
+    original: Fingerprint,
+    /// The converted G-code in the temporary file, once it is written completely
+    converted: Option<Fingerprint>,
+}
+
+/// Journal of an in-place rewrite of a G-code file
+///
+/// It is written next to the G-code file before the rewrite starts, and
+/// removed when it is dropped at the end of the rewrite, whether it succeeded
+/// or not. A journal that is left over means the tool was interrupted, e.g.
+/// by power loss, and the file may be half written, see [`repair`].
+pub struct Journal {
+    /// The journal file
+    path: path::PathBuf,
+    record: Record,
+    /// Whether to flush the journal to the storage device
+    fsync: bool,
+    /// How many times a failed write is retried
+    retries: u32,
+}
+
+impl Journal {
+    /// Start the journal of a rewrite
+    ///
+    /// # Arguments
+    ///
+    /// * `gcode_path` - The G-code file that is rewritten
+    /// * `fsync` - Whether to flush the journal to the storage device
+    /// * `retries` - How many times a failed write is retried
+    pub fn start(gcode_path: &path::Path, fsync: bool, retries: u32) -> Result<Self, ()> {
+        let original = Fingerprint::of(gcode_path)
+            .map_err(|e| log::error!("Cannot read `{}`: {}", gcode_path.display(), e))?;
+        let journal = Journal {
+            path: journal_path(gcode_path),
+            record: Record {
+                original,
+                converted: None,
+            },
+            fsync,
+            retries,
+        };
+        journal.save()?;
+        Ok(journal)
+    }
+
+    /// Record that the converted G-code has been written completely
+    ///
+    /// # Arguments
+    ///
+    /// * `converted` - The temporary file with the converted G-code
+    pub fn written(&mut self, converted: &path::Path) -> Result<(), ()> {
+        let fingerprint = Fingerprint::of(converted)
+            .map_err(|e| log::error!("Cannot read `{}`: {}", converted.display(), e))?;
+        self.record.converted = Some(fingerprint);
+        self.save()
+    }
+
+    /// Write the journal file
+    fn save(&self) -> Result<(), ()> {
+        log::debug!("Writing the journal `{}`", self.path.display());
+        let content = serde_json::to_vec(&self.record).expect("Records are always serializable");
+        storage::retry(self.retries, "write the journal", || std::fs::write(&self.path, &content))
+            .and_then(|_| match self.fsync {
+                true => storage::sync_file(&self.path, self.retries)
+                    .and_then(|_| storage::sync_folder(&self.path, self.retries)),
+                false => Ok(()),
+            })
+            .map_err(|e| {
+                log::error!("Failed to write the journal `{}`: {}", self.path.display(), e)
+            })
+    }
+}
+
+impl Drop for Journal {
+    fn drop(&mut self) {
+        log::debug!("Removing the journal `{}`", self.path.display());
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Failed to remove the journal `{}`: {}", self.path.display(), e);
+        }
+    }
+}
+
+/// Get the path of the journal of a G-code file
+fn journal_path(gcode_path: &path::Path) -> path::PathBuf {
+    let mut file_name = gcode_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".mks_tft_img.journal");
+    gcode_path.with_file_name(file_name)
+}
+
+/// Repair a G-code file whose rewrite was interrupted, e.g. by power loss
+///
+/// The journal left over tells whether the file is still the original one or
+/// the converted one. If it is neither, it is replaced by the converted
+/// G-code in the temporary file, if that was written completely, or
+/// restored from the original archived in it with `--archive-original`.
+///
+/// # Arguments
+///
+/// * `path` - Path to the G-code file
+pub fn repair(path: &path::Path) -> Result<(), ()> {
+    let journal = journal_path(path);
+    let tmp_path = crate::temporary_path(path);
+    let record: Record = match std::fs::read(&journal) {
+        Ok(content) => serde_json::from_slice(&content).map_err(|e| {
+            log::error!("The journal `{}` is damaged: {}", journal.display(), e);
+        })?,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => {
+            log::info!(
+                "The conversion of `{}` was not interrupted, nothing to repair",
+                path.display()
+            );
+            return Ok(());
+        }
+        Err(e) => {
+            log::error!("Cannot read the journal `{}`: {}", journal.display(), e);
+            return Err(());
+        }
+    };
+    let current = Fingerprint::of(path).ok();
+    let converted = Fingerprint::of(&tmp_path).ok().filter(|_| record.converted.is_some());
+
+    if current == Some(record.original) {
+        log::info!("`{}` is still the original file, it is left as it is", path.display());
+    } else if current.is_some() && current == record.converted {
+        log::info!("`{}` was converted completely, it is left as it is", path.display());
+    } else if converted.is_some() && converted == record.converted {
+        log::info!("Replacing `{}` with the converted G-code", path.display());
+        std::fs::rename(&tmp_path, path).map_err(|e| {
+            log::error!("Failed to replace `{}`: {}", path.display(), e);
+        })?;
+    } else {
+        log::warn!("`{}` is half written, restoring the original archived in it", path.display());
+        archive::restore(path).map_err(|_| {
+            log::error!(
+                "Cannot repair `{}`: neither the file nor the temporary file is complete",
+                path.display()
+            )
+        })?;
+        // The rest of the G-code after the archive may be cut off too
+        if Fingerprint::of(path).ok() != Some(record.original) {
+            log::error!(
+                "Cannot repair `{}`: the restored original is incomplete, the file is truncated",
+                path.display()
+            );
+            return Err(());
+        }
+    }
+    if tmp_path.exists() {
+        log::debug!("Removing the temporary file `{}`", tmp_path.display());
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    std::fs::remove_file(&journal)
+        .map_err(|e| log::error!("Failed to remove the journal `{}`: {}", journal.display(), e))?;
+    log::info!("Repaired `{}`", path.display());
+    Ok(())
+}
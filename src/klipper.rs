@@ -0,0 +1,208 @@
+use crate::upload;
+use std::path;
+
+/// Name of the generated Klipper config file, included from `printer.cfg`
+const CONFIG_NAME: &str = "mks_tft_img.cfg";
+
+/// Name of the generated systemd units
+const UNIT_NAME: &str = "mks_tft_img";
+
+/// Template of the Klipper config
+///
+/// `{command}` and `{gcodes}`, the folder of uploaded G-code, are replaced
+/// when generating it.
+const CONFIG_TEMPLATE: &str = r#"# Generated by `mks_tft_img klipper-setup`. Run it again to update the path
+# of the executable or the default arguments.
+# Needs the gcode_shell_command extension of Klipper, e.g. installed by KIAUH.
+
+[gcode_shell_command mks_tft_img]
+command: {command}
+timeout: 300.
+verbose: False
+
+[gcode_macro MKS_TFT_CONVERT]
+description: Convert the preview of a G-code file, or of all, for the MKS TFT display
+gcode:
+    {% if params.FILE %}
+        RUN_SHELL_COMMAND CMD=mks_tft_img PARAMS="{gcodes}/{params.FILE}"
+    {% else %}
+        RUN_SHELL_COMMAND CMD=mks_tft_img PARAMS="{gcodes}"
+    {% endif %}
+"#;
+
+/// Template of the systemd path unit, watching the `gcodes` folder
+const PATH_UNIT_TEMPLATE: &str = "# Generated by `mks_tft_img klipper-setup`
+[Unit]
+Description=Watch G-code uploaded to Moonraker to convert its preview for MKS TFT
+
+[Path]
+PathChanged={gcodes}
+
+[Install]
+WantedBy=default.target
+";
+
+/// Template of the systemd service unit, converting the `gcodes` folder
+const SERVICE_UNIT_TEMPLATE: &str = r#"# Generated by `mks_tft_img klipper-setup`
+[Unit]
+Description=Convert the preview of G-code uploaded to Moonraker for MKS TFT
+
+[Service]
+Type=oneshot
+ExecStart={command} "{gcodes}"
+"#;
+
+/// Where Klipper and Moonraker keep their files
+pub struct Setup {
+    /// The `printer_data` folder, with `config` and `gcodes` in it
+    pub printer_data: path::PathBuf,
+    /// Base URL of Moonraker
+    pub moonraker_url: String,
+    /// Additional arguments to run the tool with, e.g. `--printer`
+    pub args: Vec<String>,
+}
+
+impl Setup {
+    fn gcodes(&self) -> path::PathBuf {
+        self.printer_data.join("gcodes")
+    }
+
+    /// Get the command converting G-code, with the cache in the config folder,
+    /// so unchanged files are skipped, and refreshing the metadata in Moonraker
+    fn command(&self) -> Result<String, ()> {
+        let exe = std::env::current_exe()
+            .map_err(|e| log::error!("Cannot find the path of the executable: {}", e))?;
+        let cache = self.printer_data.join("config").join(".mks_tft_img-cache.json");
+        let gcodes = self.gcodes();
+        let args = [
+            exe.to_string_lossy().into_owned(),
+            "--cache".to_string(),
+            cache.to_string_lossy().into_owned(),
+            "--moonraker-url".to_string(),
+            self.moonraker_url.clone(),
+            "--moonraker-gcodes".to_string(),
+            gcodes.to_string_lossy().into_owned(),
+        ];
+        Ok(args.iter().chain(&self.args).map(|arg| quote(arg)).collect::<Vec<_>>().join(" "))
+    }
+
+    /// Generate the Klipper config and the systemd units
+    ///
+    /// # Returns
+    ///
+    /// The file names and contents
+    pub fn files(&self) -> Result<[(&'static str, String); 3], ()> {
+        let command = self.command()?;
+        let gcodes = self.gcodes().to_string_lossy().into_owned();
+        let fill =
+            |template: &str| template.replace("{command}", &command).replace("{gcodes}", &gcodes);
+        Ok([
+            (CONFIG_NAME, fill(CONFIG_TEMPLATE)),
+            (".path", fill(PATH_UNIT_TEMPLATE)),
+            (".service", fill(SERVICE_UNIT_TEMPLATE)),
+        ])
+    }
+
+    /// Print the Klipper config and the systemd units
+    pub fn print(&self) -> Result<(), ()> {
+        for (name, content) in self.files()? {
+            let name = match name.starts_with('.') {
+                true => format!("{}{}", UNIT_NAME, name),
+                false => name.to_string(),
+            };
+            println!("# ---- {} ----\n{}", name, content);
+        }
+        Ok(())
+    }
+
+    /// Install the Klipper config, include it from `printer.cfg`, and install
+    /// the systemd user units converting uploaded G-code
+    pub fn install(&self) -> Result<(), ()> {
+        let config_dir = self.printer_data.join("config");
+        if !config_dir.is_dir() {
+            log::error!(
+                "There is no Klipper config folder `{}`. Give the `printer_data` folder with \
+                `--printer-data`",
+                config_dir.display()
+            );
+            return Err(());
+        }
+        let units_dir = std::env::var_os("XDG_CONFIG_HOME")
+            .map(path::PathBuf::from)
+            .or_else(|| std::env::var_os("HOME").map(|home| path::Path::new(&home).join(".config")))
+            .map(|config| config.join("systemd").join("user"))
+            .ok_or_else(|| log::error!("Cannot find the home folder"))?;
+        std::fs::create_dir_all(&units_dir)
+            .map_err(|e| log::error!("Cannot create folder `{}`: {}", units_dir.display(), e))?;
+        for (name, content) in self.files()? {
+            let path = match name.starts_with('.') {
+                true => units_dir.join(format!("{}{}", UNIT_NAME, name)),
+                false => config_dir.join(name),
+            };
+            std::fs::write(&path, content)
+                .map_err(|e| log::error!("Cannot write `{}`: {}", path.display(), e))?;
+            log::info!("Installed `{}`", path.display());
+        }
+
+        let printer_cfg = config_dir.join("printer.cfg");
+        let include = format!("[include {}]", CONFIG_NAME);
+        let content = std::fs::read_to_string(&printer_cfg)
+            .map_err(|e| log::error!("Cannot read `{}`: {}", printer_cfg.display(), e))?;
+        if !content.lines().any(|line| line.trim() == include) {
+            std::fs::write(&printer_cfg, format!("{}\n{}", include, content))
+                .map_err(|e| log::error!("Cannot write `{}`: {}", printer_cfg.display(), e))?;
+            log::info!("Added `{}` to `{}`", include, printer_cfg.display());
+        }
+        log::warn!(
+            "Restart Klipper, and run `systemctl --user enable --now {}.path` to convert every \
+            uploaded file automatically",
+            UNIT_NAME
+        );
+        Ok(())
+    }
+}
+
+/// Ask Moonraker to scan the metadata of a converted file, so Fluidd and
+/// Mainsail show the new preview
+///
+/// # Arguments
+///
+/// * `url` - Base URL of Moonraker
+/// * `api_key` - API key of Moonraker, if it requires one
+/// * `gcodes` - The `gcodes` root folder of Moonraker
+/// * `path` - The converted G-code file, in the `gcodes` folder
+pub fn refresh_metadata(
+    url: &str,
+    api_key: Option<&str>,
+    gcodes: &path::Path,
+    path: &path::Path,
+) -> Result<(), ()> {
+    let relative = || -> Option<path::PathBuf> {
+        let path = std::fs::canonicalize(path).ok()?;
+        Some(path.strip_prefix(std::fs::canonicalize(gcodes).ok()?).ok()?.to_path_buf())
+    };
+    let Some(relative) = relative() else {
+        log::warn!(
+            "`{}` is not in the Moonraker folder `{}`, its metadata is not refreshed",
+            path.display(),
+            gcodes.display()
+        );
+        return Err(());
+    };
+    // Moonraker takes `/` separators, whatever the platform is
+    let name = relative.iter().map(|part| part.to_string_lossy()).collect::<Vec<_>>().join("/");
+    upload::moonraker_metascan(url, api_key, &name)
+}
+
+/// Get the default `printer_data` folder of Klipper installations, `~/printer_data`
+pub fn default_printer_data() -> path::PathBuf {
+    std::env::var_os("HOME").map(path::PathBuf::from).unwrap_or_default().join("printer_data")
+}
+
+/// Quote an argument for the shell, as Klipper and systemd split commands
+fn quote(arg: &str) -> String {
+    if !arg.is_empty() && !arg.contains(|c: char| c.is_whitespace() || "\"'\\$`".contains(c)) {
+        return arg.to_string();
+    }
+    format!("\"{}\"", arg.replace('\\', "\\\\").replace('"', "\\\""))
+}
@@ -0,0 +1,565 @@
+//! Conversion of G-code thumbnails to the previews of printer displays
+//!
+//! This is the core of the `mks_tft_img` tool, which can also be used from
+//! other programs, e.g. slicer plugins, via the C interface in [`ffi`], or
+//! from JavaScript via the WebAssembly build in `wasm`.
+
+// Errors are logged where they happen, so the functions just report failures
+#![allow(clippy::result_unit_err)]
+
+pub mod adjust;
+pub mod color;
+pub mod encoders;
+pub mod ffi;
+pub mod footer;
+pub mod lut;
+pub mod meta;
+pub mod mmap;
+#[cfg(feature = "async")]
+pub mod nonblocking;
+pub mod options;
+pub mod parser;
+pub mod presets;
+pub mod preview;
+pub mod qr;
+pub mod render;
+#[cfg(all(feature = "simd", target_arch = "x86_64"))]
+mod simd;
+pub mod slicer;
+pub mod source;
+pub mod verify;
+#[cfg(target_arch = "wasm32")]
+pub mod wasm;
+
+use base64::prelude::{Engine, BASE64_STANDARD};
+use clap::ValueEnum;
+use footer::FooterInfo;
+use image::io::Reader as ImageReader;
+use image::DynamicImage;
+use options::{PreviewPosition, ProcessOptions};
+use preview::Preview;
+use serde::{Deserialize, Serialize};
+use std::io::{Cursor, Read, Write};
+
+/// Supported preview image formats
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Target {
+    /// MKS TFT displays (simage and gimage in RGB565)
+    Mks,
+    /// Anycubic Kobra/Vyper (PNG thumbnail comment of the gimage size)
+    Anycubic,
+    /// Flashforge (xgcode header with an 80x60 BMP preview)
+    Flashforge,
+    /// Creality Ender-3 V3 and K1 with the stock screen (JPEG thumbnail comment of the
+    /// gimage size)
+    Creality,
+    /// Custom firmware (the gimage as raw RGB565 pixels after its width and height)
+    Raw,
+}
+
+impl Target {
+    /// Whether the preview is binary data instead of G-code comments, which
+    /// only firmware reading the beginning of the file finds
+    pub fn is_binary(self) -> bool {
+        matches!(self, Target::Flashforge | Target::Raw)
+    }
+}
+
+impl std::fmt::Display for Target {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Decode the thumbnail found in the G-code
+///
+/// # Arguments
+///
+/// * `image_lines` - The image lines returned by `read_gcode`
+/// * `strict` - Whether a mismatch with the `thumbnail begin` line is an error
+/// * `limits` - How much memory the decoder may use
+///
+/// # Returns
+///
+/// A tuple containing the decoded image, its format and the ICC profile it
+/// is tagged with
+pub fn decode_thumbnail(
+    image_lines: &[String],
+    strict: bool,
+    limits: image::io::Limits,
+) -> Result<(DynamicImage, &'static str, Option<Vec<u8>>), ()> {
+    log::debug!("Decoding base64 image from gcode");
+    // `image` reader is good in guessing the image format, so we can just skip
+    // `thumbnail_* begin <width>x<height> <size>` and `thumbnail_* end` lines
+    // here and process everything that is in between.
+    let [begin, data @ .., _] = image_lines else {
+        log::error!("The thumbnail has no data");
+        return Err(());
+    };
+    let encoded_len = data.iter().map(String::len).sum::<usize>();
+    let declared = parse_thumbnail_begin(begin);
+    let mismatch = |what: &str, declared: String, actual: String| {
+        let message = format!(
+            "Thumbnail {} is {}, but {} is declared. The thumbnail block may be damaged",
+            what, actual, declared
+        );
+        match strict {
+            true => {
+                log::error!("{}", message);
+                Err(())
+            }
+            false => {
+                log::warn!("{}", message);
+                Ok(())
+            }
+        }
+    };
+    if let Some((_, _, size)) = declared.filter(|&(_, _, size)| size != encoded_len) {
+        mismatch("data length", size.to_string(), encoded_len.to_string())?;
+    }
+    let decoded = decode_base64_lines(data, encoded_len)
+        .map_err(|e| log::error!("Cannot base64 decode image from gcode: {}", e))?;
+
+    log::debug!("Guessing image format");
+    let mut img = ImageReader::new(Cursor::new(decoded))
+        .with_guessed_format()
+        .expect("We are running on in-memory data for image. This should not fail");
+    img.limits(limits);
+
+    let img_format = match img.format().map(|format| format.extensions_str()) {
+        Some([ext, ..]) => ext,
+        _ => "UNKNOWN",
+    };
+
+    log::debug!("Decoding image as {}", img_format);
+    let (img, profile) = color::decode(img).map_err(|e| {
+        log::error!("Cannot decode image. Guessed format: {}. Error: {}", img_format, e)
+    })?;
+    log::debug!("{}x{} {} image has been decoded", img.width(), img.height(), img_format);
+    if let Some((width, height, _)) =
+        declared.filter(|&(width, height, _)| (width, height) != (img.width(), img.height()))
+    {
+        mismatch(
+            "size",
+            format!("{}x{}", width, height),
+            format!("{}x{}", img.width(), img.height()),
+        )?;
+    }
+    Ok((img, img_format, profile))
+}
+
+/// Decode base64 data split into lines, a few lines at a time, so the lines
+/// are not joined into another copy of the data
+///
+/// # Arguments
+///
+/// * `lines` - The lines of base64 data
+/// * `encoded_len` - The length of all the lines together
+fn decode_base64_lines(
+    lines: &[String],
+    encoded_len: usize,
+) -> Result<Vec<u8>, base64::DecodeError> {
+    /// Characters decoded at once, a whole number of 4 character groups
+    const CHUNK: usize = 4 * 1024;
+    let mut decoded = Vec::with_capacity(encoded_len / 4 * 3);
+    let mut chunk = Vec::with_capacity(CHUNK + 4);
+    for line in lines {
+        chunk.extend_from_slice(line.as_bytes());
+        if chunk.len() >= CHUNK {
+            // Groups split between lines wait for the rest of their characters
+            let whole = chunk.len() / 4 * 4;
+            BASE64_STANDARD.decode_vec(&chunk[..whole], &mut decoded)?;
+            chunk.drain(..whole);
+        }
+    }
+    BASE64_STANDARD.decode_vec(&chunk, &mut decoded)?;
+    Ok(decoded)
+}
+
+/// Parse a `thumbnail_* begin <width>x<height> <size>` line
+///
+/// # Returns
+///
+/// The declared width, height and length of the base64 data, or `None` if
+/// the line doesn't have them
+fn parse_thumbnail_begin(line: &str) -> Option<(u32, u32, usize)> {
+    let mut words = line.split_whitespace().skip(2);
+    let (width, height) = words.next()?.split_once('x')?;
+    let size = words.next()?.parse().ok()?;
+    Some((width.parse().ok()?, height.parse().ok()?, size))
+}
+
+/// How much of the G-code is checked to detect its line ending
+pub const LINE_ENDING_SAMPLE: usize = 64 * 1024;
+
+/// Detect the dominant line ending of the G-code
+///
+/// # Arguments
+///
+/// * `sample` - The beginning of the G-code
+///
+/// # Returns
+///
+/// `\r\n` if most lines end with it, otherwise `\n`
+pub fn line_ending(sample: &[u8]) -> &'static str {
+    let lines = sample.iter().filter(|&&b| b == b'\n').count();
+    let crlf = sample.windows(2).filter(|pair| pair == b"\r\n").count();
+    if crlf * 2 > lines {
+        log::debug!("Using CRLF line endings of the G-code");
+        "\r\n"
+    } else {
+        "\n"
+    }
+}
+
+/// Write the converted G-code
+///
+/// # Arguments
+///
+/// * `writer` - Where to write the G-code to
+/// * `preview` - The preview image for the display
+/// * `position` - Where the preview goes, see [`ProcessOptions::position`]
+/// * `header` - The G-code before the image, without the last line ending
+/// * `line_ending` - The line ending of the G-code
+/// * `footer` - The post processing info comment
+/// * `remainder` - The rest of the original G-code after the image
+pub fn write_gcode(
+    writer: &mut impl Write,
+    preview: &[u8],
+    position: PreviewPosition,
+    mut header: &[u8],
+    line_ending: &str,
+    footer: &str,
+    remainder: &mut impl Read,
+) -> std::io::Result<()> {
+    write_gcode_streamed(writer, preview, position, &mut header, line_ending, footer, remainder)
+}
+
+/// Write the converted G-code, copying the G-code before the image as is
+///
+/// This is the same as [`write_gcode`], but the G-code before the image is
+/// not held in memory.
+///
+/// # Arguments
+///
+/// * `writer` - Where to write the G-code to
+/// * `preview` - The preview image for the display
+/// * `position` - Where the preview goes, see [`ProcessOptions::position`]
+/// * `header` - The G-code before the image, without the last line ending
+/// * `line_ending` - The line ending of the G-code
+/// * `footer` - The post processing info comment
+/// * `remainder` - The rest of the original G-code after the image
+pub fn write_gcode_streamed(
+    writer: &mut impl Write,
+    preview: &[u8],
+    position: PreviewPosition,
+    header: &mut impl Read,
+    line_ending: &str,
+    footer: &str,
+    remainder: &mut impl Read,
+) -> std::io::Result<()> {
+    if position == PreviewPosition::Top {
+        writer.write_all(preview)?;
+    }
+    let header_len = std::io::copy(header, writer)?;
+    let footer = match position {
+        PreviewPosition::Top => footer,
+        PreviewPosition::Thumbnail => {
+            write_preview_after_header(writer, preview, header_len > 0, line_ending, footer)?
+        }
+    };
+    writer.write_all(footer.as_bytes())?;
+    std::io::copy(remainder, writer)?;
+    Ok(())
+}
+
+/// Write the preview after the G-code before the thumbnail, ending its last line
+///
+/// # Returns
+///
+/// The footer without the line ending it starts with, which was written
+/// before the preview instead
+fn write_preview_after_header<'a>(
+    writer: &mut impl Write,
+    preview: &[u8],
+    has_header: bool,
+    line_ending: &str,
+    footer: &'a str,
+) -> std::io::Result<&'a str> {
+    if has_header {
+        writer.write_all(line_ending.as_bytes())?;
+    }
+    writer.write_all(preview)?;
+    Ok(footer.strip_prefix(line_ending).unwrap_or(footer))
+}
+
+/// Create the post-processing info comment, which replaces the thumbnail
+///
+/// The comment is rendered from `options.footer_template`, or the default
+/// one, and left out with `options.no_footer`. The version of the tool is left
+/// out with `options.reproducible`, and the rest only depends on the input and
+/// the options. With `options.inject_metadata`, Cura style metadata comments
+/// follow it, and with `options.m117`, an `M117` message for the display.
+///
+/// # Arguments
+///
+/// * `options` - The options the preview was generated with
+/// * `info` - What the comment describes
+/// * `line_ending` - The line ending of the G-code
+///
+/// # Returns
+///
+/// The comment, starting with the line ending of the G-code line before it
+pub fn post_process_info(
+    options: &ProcessOptions,
+    info: &FooterInfo<'_>,
+    line_ending: &str,
+) -> String {
+    let mut comment = match (options.no_footer, &options.footer_template) {
+        (true, _) => String::new(),
+        (false, Some(template)) => footer::render(template, options, info),
+        (false, None) => footer::render(footer::DEFAULT_TEMPLATE, options, info),
+    };
+    if options.inject_metadata {
+        comment.push_str(&info.meta.cura_comments());
+    }
+    if let Some(template) = &options.m117 {
+        // A semicolon would start a comment, cutting the message short
+        let message =
+            footer::expand(template, options, info, false).replace(['\r', '\n', ';'], " ");
+        log::debug!("Adding M117 message `{}`", message.trim());
+        comment.push_str(&format!("M117 {}\n", message.trim()));
+    }
+    format!("\n{}", comment).replace('\n', line_ending)
+}
+
+/// Draw the QR code of `options.qr` into the gimages, if it is set
+///
+/// The placeholders of the text are replaced like the ones of the footer
+/// template, percent-encoded if the text is a URL.
+///
+/// # Arguments
+///
+/// * `options` - The options the preview is generated with
+/// * `info` - What the placeholders describe
+/// * `gimages` - The gimages to draw the QR code into
+pub fn add_qr_code(
+    options: &ProcessOptions,
+    info: &FooterInfo<'_>,
+    gimages: &mut [DynamicImage],
+) -> Result<(), ()> {
+    let Some(template) = &options.qr else {
+        return Ok(());
+    };
+    if options.target == Target::Flashforge {
+        log::warn!("The {} preview has no gimage to draw the QR code into", options.target);
+        return Ok(());
+    }
+    let text = footer::expand(template, options, info, template.contains("://"));
+    log::debug!("Drawing QR code of `{}`", text);
+    let code = qr::QrCode::encode(&text)?;
+    for gimage in gimages {
+        code.draw(gimage, options.qr_corner)?;
+    }
+    Ok(())
+}
+
+/// Fill the print statistics of the Flashforge header from the slicer
+/// metadata, see [`encoders::fill_flashforge_stats`]
+///
+/// # Arguments
+///
+/// * `options` - The options the preview is generated with
+/// * `meta` - The print statistics of the G-code
+/// * `preview` - The encoded preview
+pub fn add_print_stats(options: &ProcessOptions, meta: &meta::PrintMeta, preview: &mut Preview) {
+    if options.target == Target::Flashforge {
+        encoders::fill_flashforge_stats(&mut preview.data, meta);
+    }
+}
+
+/// Append the comment with the CRC32 of the preview to it, if set in the
+/// options, see [`verify::crc_trailer`]
+///
+/// # Arguments
+///
+/// * `options` - The options the preview is generated with
+/// * `preview` - The encoded preview
+/// * `line_ending` - The line ending of the G-code
+pub fn add_crc_trailer(options: &ProcessOptions, preview: &mut Preview, line_ending: &str) {
+    if !options.crc_trailer {
+        return;
+    }
+    if options.target.is_binary() {
+        log::warn!("The {} preview is binary, it gets no CRC32 trailer", options.target);
+        return;
+    }
+    // The trailer covers the file from its beginning
+    if options.position() != PreviewPosition::Top {
+        log::warn!("The preview is not at the top of the G-code, it gets no CRC32 trailer");
+        return;
+    }
+    let trailer = verify::crc_trailer(&preview.data, line_ending);
+    preview.data.extend_from_slice(trailer.as_bytes());
+}
+
+/// Check whether G-code was already converted by the tool
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+///
+/// # Returns
+///
+/// Whether it has a preview or the post-processing info comment
+pub fn is_converted(gcode: &[u8]) -> bool {
+    const FOOTER: &[u8] = b"; MKS_TFT_PREVIEW_POSTPROCESS";
+    const SIMAGE: &[u8] = b"\n;simage:";
+    gcode.starts_with(b";simage")
+        || gcode.starts_with(b"xgcode")
+        || gcode.windows(FOOTER.len()).any(|window| window == FOOTER)
+        || gcode.windows(SIMAGE.len()).any(|window| window == SIMAGE)
+}
+
+/// Convert the thumbnail of G-code in memory
+///
+/// This is what the tool does to a file, without the file handling and the
+/// additional outputs.
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - How to convert the thumbnail
+///
+/// # Returns
+///
+/// The converted G-code, or an error if there is no thumbnail or it cannot
+/// be converted
+pub fn convert(gcode: &[u8], options: &ProcessOptions) -> Result<Vec<u8>, ()> {
+    convert_with(gcode, options, None)
+}
+
+/// Convert the thumbnail of G-code in memory, with another thumbnail to use
+/// if the G-code has none
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - How to convert the thumbnail
+/// * `fallback` - Where the thumbnail comes from if there is none in the G-code
+///
+/// # Returns
+///
+/// The converted G-code, or an error if there is no thumbnail or it cannot
+/// be converted
+pub fn convert_with(
+    gcode: &[u8],
+    options: &ProcessOptions,
+    fallback: Option<&dyn source::ThumbnailSource>,
+) -> Result<Vec<u8>, ()> {
+    let converted = prepare(gcode, options, fallback)?;
+    let mut output = Vec::with_capacity(converted.preview.len() + gcode.len());
+    converted.write_to(&mut output).map_err(|e| log::error!("Failed to write gcode: {}", e))?;
+    Ok(output)
+}
+
+/// G-code with its converted thumbnail, not written out yet
+///
+/// Holds the encoded preview and the footer, but only borrows the G-code
+/// before and after the thumbnails, so it can be written to a file or a socket without
+/// building the whole output in memory.
+pub struct Converted<'a> {
+    preview: Vec<u8>,
+    position: PreviewPosition,
+    header: &'a [u8],
+    line_ending: &'static str,
+    footer: String,
+    remainder: &'a [u8],
+}
+
+impl Converted<'_> {
+    /// Write the converted G-code
+    ///
+    /// # Arguments
+    ///
+    /// * `writer` - Where the G-code is written to
+    pub fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        let mut remainder = self.remainder;
+        write_gcode(
+            writer,
+            &self.preview,
+            self.position,
+            self.header,
+            self.line_ending,
+            &self.footer,
+            &mut remainder,
+        )
+    }
+}
+
+/// Convert the thumbnail of G-code in memory, leaving the writing to the
+/// caller
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - How to convert the thumbnail
+/// * `fallback` - Where the thumbnail comes from if there is none in the G-code
+///
+/// # Returns
+///
+/// The G-code with the converted thumbnail, or an error if there is no
+/// thumbnail or it cannot be converted
+pub fn prepare<'a>(
+    gcode: &'a [u8],
+    options: &ProcessOptions,
+    fallback: Option<&dyn source::ThumbnailSource>,
+) -> Result<Converted<'a>, ()> {
+    let (header, image_lines, remainder) = mmap::split_gcode(gcode)?;
+    let embedded = source::Embedded {
+        image_lines: &image_lines,
+        strict: options.strict,
+        limits: options.image_limits(),
+    };
+    let mut sources: Vec<&dyn source::ThumbnailSource> = vec![&embedded];
+    sources.extend(fallback);
+    let Some((thumbnail, source_info)) = source::first_thumbnail(&sources)? else {
+        log::warn!("There is no image in gcode");
+        return Err(());
+    };
+    let line_ending = line_ending(&gcode[..gcode.len().min(LINE_ENDING_SAMPLE)]);
+    let format = thumbnail.format;
+    let img = thumbnail.into_image(options.icc);
+    let meta = meta::PrintMeta::from_gcode(gcode);
+    let mut info = FooterInfo {
+        source_info: &source_info,
+        source_format: format,
+        source_size: (img.width(), img.height()),
+        preview_info: "",
+        meta: &meta,
+        file_name: "",
+    };
+    let (simage, mut gimages) = options.resize(&img);
+    add_qr_code(options, &info, &mut gimages)?;
+    let mut preview = options.target.encoder(options.row_end, options.trailing_semicolon).encode(
+        &img,
+        &simage,
+        &gimages,
+        line_ending,
+    )?;
+    add_print_stats(options, &meta, &mut preview);
+    add_crc_trailer(options, &mut preview, line_ending);
+    info.preview_info = &preview.info;
+    let footer = post_process_info(options, &info, line_ending);
+
+    Ok(Converted {
+        preview: preview.data,
+        position: options.position(),
+        header,
+        line_ending,
+        footer,
+        remainder,
+    })
+}
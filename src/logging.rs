@@ -0,0 +1,499 @@
+use clap::ValueEnum;
+use simplelog::*;
+use std::cell::{Cell, RefCell};
+use std::fs::File;
+use std::io::Write;
+use std::path;
+use std::sync::Mutex;
+
+/// Formats of the log output
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogFormat {
+    /// Human readable lines
+    Text,
+    /// One JSON object per line
+    Json,
+}
+
+/// Where the log output goes, besides the log file
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum LogTarget {
+    /// Standard error output
+    Stderr,
+    /// Local syslog daemon (Unix only)
+    Syslog,
+    /// systemd journal (Linux only)
+    Journald,
+}
+
+/// When to color the output on the terminal
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ColorMode {
+    /// If stderr is a terminal and `NO_COLOR` is not set
+    Auto,
+    /// Always
+    Always,
+    /// Never
+    Never,
+}
+
+impl ColorMode {
+    /// Get the color choice of the terminal logger
+    fn color_choice(self) -> ColorChoice {
+        match self {
+            ColorMode::Auto if no_color() => ColorChoice::Never,
+            ColorMode::Auto => ColorChoice::Auto,
+            ColorMode::Always => ColorChoice::Always,
+            ColorMode::Never => ColorChoice::Never,
+        }
+    }
+
+    /// Check whether to color output to a stream
+    ///
+    /// # Arguments
+    ///
+    /// * `is_terminal` - Whether the stream is a terminal
+    pub fn enabled(self, is_terminal: bool) -> bool {
+        match self {
+            ColorMode::Auto => is_terminal && !no_color(),
+            ColorMode::Always => true,
+            ColorMode::Never => false,
+        }
+    }
+}
+
+/// Check whether colors are turned off by a non-empty `NO_COLOR` environment
+/// variable, see <https://no-color.org>
+fn no_color() -> bool {
+    std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty())
+}
+
+/// Rotation settings of the log file
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Rotation {
+    /// The size in bytes after which the log file is rotated
+    pub max_size: u64,
+    /// How many rotated log files are kept
+    pub max_files: u32,
+}
+
+thread_local! {
+    /// The G-code file that is being processed on this thread, included in JSON logs
+    static FILE: RefCell<Option<String>> = const { RefCell::new(None) };
+
+    /// The current processing phase on this thread, included in JSON logs
+    static PHASE: Cell<&'static str> = const { Cell::new("init") };
+
+    /// Records held back until the file processed on this thread is done
+    static HELD: RefCell<Option<Vec<HeldRecord>>> = const { RefCell::new(None) };
+
+    /// Warnings and errors of the file processed on this thread, for the report
+    static PROBLEMS: RefCell<Option<Vec<(log::Level, String)>>> = const { RefCell::new(None) };
+}
+
+/// Keeps the held records of one file together when they are released
+static RELEASE: Mutex<()> = Mutex::new(());
+
+/// Set the G-code file that is being processed
+pub fn set_file(path: &path::Path) {
+    FILE.set(Some(path.display().to_string()));
+}
+
+/// Set the current processing phase
+pub fn set_phase(phase: &'static str) {
+    PHASE.set(phase);
+}
+
+/// Get the G-code file that is being processed
+fn file() -> Option<String> {
+    FILE.with_borrow(Clone::clone)
+}
+
+/// A log record held back until the file it belongs to is done
+struct HeldRecord {
+    level: log::Level,
+    target: String,
+    message: String,
+    phase: &'static str,
+}
+
+/// Hold back the log records of this thread until `release_records`
+///
+/// When several files are processed in parallel, this keeps the records of
+/// each file together instead of interleaving them.
+pub fn hold_records() {
+    HELD.set(Some(vec![]));
+}
+
+/// Log the records held back on this thread, without interleaving them with
+/// the ones of other threads
+pub fn release_records() {
+    let Some(records) = HELD.take() else {
+        return;
+    };
+    let _release = RELEASE.lock().unwrap_or_else(|e| e.into_inner());
+    let phase = PHASE.get();
+    for record in records {
+        PHASE.set(record.phase);
+        log::logger().log(
+            &log::Record::builder()
+                .level(record.level)
+                .target(&record.target)
+                .args(format_args!("{}", record.message))
+                .build(),
+        );
+    }
+    PHASE.set(phase);
+    log::logger().flush();
+}
+
+/// Collect the warnings and errors of this thread until `take_problems`,
+/// whatever the log level is
+pub fn collect_problems() {
+    PROBLEMS.set(Some(vec![]));
+}
+
+/// Get the warnings and errors collected on this thread since `collect_problems`
+///
+/// # Returns
+///
+/// The levels and messages of the records, in the order they were logged
+pub fn take_problems() -> Vec<(log::Level, String)> {
+    PROBLEMS.take().unwrap_or_default()
+}
+
+/// Logger that holds back records of threads processing a file in a batch
+struct HoldingLogger {
+    inner: Box<dyn log::Log>,
+}
+
+impl log::Log for HoldingLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        self.inner.enabled(metadata)
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if record.level() <= log::Level::Warn {
+            PROBLEMS.with_borrow_mut(|problems| {
+                if let Some(problems) = problems {
+                    problems.push((record.level(), record.args().to_string()));
+                }
+            });
+        }
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let held = HELD.with_borrow_mut(|held| match held {
+            Some(held) => {
+                held.push(HeldRecord {
+                    level: record.level(),
+                    target: record.target().to_string(),
+                    message: record.args().to_string(),
+                    phase: PHASE.get(),
+                });
+                true
+            }
+            None => false,
+        });
+        if !held {
+            self.inner.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.inner.flush();
+    }
+}
+
+/// Initialize logging
+///
+/// Without rotation, the log file is overwritten on every run. With rotation,
+/// new records are appended to it, and once it's bigger than the maximum
+/// size, it's renamed to `<log file>.1` (shifting older files to `.2`, `.3`
+/// and so on) and a new file is started.
+///
+/// If syslog or journald cannot be reached, records go to stderr instead, and
+/// if the log file cannot be opened, only to the other target. This is warned
+/// about once logging is set up.
+pub fn init_logging(
+    log_file: &Option<path::PathBuf>,
+    rotation: Option<Rotation>,
+    level: log::LevelFilter,
+    format: LogFormat,
+    target: LogTarget,
+    color: ColorMode,
+) -> Result<(), ()> {
+    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
+    let stderr_logger = || -> Box<dyn SharedLogger> {
+        match format {
+            LogFormat::Text => TermLogger::new(
+                level,
+                Config::default(),
+                TerminalMode::Stderr,
+                color.color_choice(),
+            ),
+            LogFormat::Json => JsonLogger::new(level, Box::new(std::io::stderr())),
+        }
+    };
+    // Logged once the loggers are set, so a broken target doesn't lose all messages
+    let mut failures = vec![];
+    match target {
+        LogTarget::Stderr => loggers.push(stderr_logger()),
+        #[cfg(unix)]
+        LogTarget::Syslog | LogTarget::Journald => match system::SystemLogger::new(level, target) {
+            Ok(logger) => loggers.push(logger),
+            Err(e) => {
+                failures.push(format!(
+                    "Cannot connect to {:?}, logging to stderr instead: {}",
+                    target, e
+                ));
+                loggers.push(stderr_logger());
+            }
+        },
+        #[cfg(not(unix))]
+        LogTarget::Syslog | LogTarget::Journald => {
+            failures.push(format!(
+                "Logging to {:?} is not supported on this platform, logging to stderr instead",
+                target
+            ));
+            loggers.push(stderr_logger());
+        }
+    }
+    if let Some(path) = log_file {
+        let file = match rotation {
+            Some(rotation) => rotate(path, rotation)
+                .map_err(|e| format!("Cannot rotate log file `{}`: {}", path.display(), e))
+                .and_then(|_| {
+                    File::options().create(true).append(true).open(path).map_err(|e| {
+                        format!("Cannot open log file `{}` for writing: {}", path.display(), e)
+                    })
+                }),
+            None => File::create(path).map_err(|e| {
+                format!("Cannot open log file `{}` for writing: {}", path.display(), e)
+            }),
+        };
+        match (file, format) {
+            (Ok(file), LogFormat::Text) => {
+                loggers.push(WriteLogger::new(level, Config::default(), file))
+            }
+            (Ok(file), LogFormat::Json) => loggers.push(JsonLogger::new(level, Box::new(file))),
+            (Err(failure), _) => failures.push(failure),
+        }
+    }
+    // Warnings and errors always reach the logger, to be collected for the report
+    log::set_max_level(level.max(log::LevelFilter::Warn));
+    log::set_boxed_logger(Box::new(HoldingLogger {
+        inner: CombinedLogger::new(loggers),
+    }))
+    .expect("We don't expect any other loggers to be set");
+    log::debug!("Logging initialized");
+    for failure in &failures {
+        log::warn!("{}", failure);
+    }
+    match failures.is_empty() {
+        true => Ok(()),
+        false => Err(()),
+    }
+}
+
+/// Rotate the log file if it exceeds the maximum size
+fn rotate(path: &path::Path, rotation: Rotation) -> std::io::Result<()> {
+    match std::fs::metadata(path) {
+        Ok(metadata) if metadata.len() >= rotation.max_size => (),
+        _ => return Ok(()),
+    }
+    let rotated = |index: u32| {
+        let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+        file_name.push(format!(".{}", index));
+        path.with_file_name(file_name)
+    };
+    if rotation.max_files == 0 {
+        return std::fs::remove_file(path);
+    }
+    let _ = std::fs::remove_file(rotated(rotation.max_files));
+    for index in (1..rotation.max_files).rev() {
+        let from = rotated(index);
+        if from.exists() {
+            std::fs::rename(from, rotated(index + 1))?;
+        }
+    }
+    std::fs::rename(path, rotated(1))
+}
+
+/// Logger that writes every record as a single line JSON object
+struct JsonLogger {
+    level: log::LevelFilter,
+    config: Config,
+    writer: Mutex<Box<dyn Write + Send>>,
+}
+
+impl JsonLogger {
+    fn new(level: log::LevelFilter, writer: Box<dyn Write + Send>) -> Box<Self> {
+        Box::new(JsonLogger {
+            level,
+            config: Config::default(),
+            writer: Mutex::new(writer),
+        })
+    }
+}
+
+impl log::Log for JsonLogger {
+    fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+        metadata.level() <= self.level
+    }
+
+    fn log(&self, record: &log::Record<'_>) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        let timestamp = time::OffsetDateTime::now_utc()
+            .format(&time::format_description::well_known::Rfc3339)
+            .unwrap_or_default();
+        let event = serde_json::json!({
+            "timestamp": timestamp,
+            "level": record.level().as_str(),
+            "message": record.args().to_string(),
+            "file": file(),
+            "phase": PHASE.get(),
+            "target": record.target(),
+        });
+        let mut writer = self.writer.lock().unwrap_or_else(|e| e.into_inner());
+        let _ = writeln!(writer, "{}", event);
+    }
+
+    fn flush(&self) {
+        let _ = self.writer.lock().unwrap_or_else(|e| e.into_inner()).flush();
+    }
+}
+
+impl SharedLogger for JsonLogger {
+    fn level(&self) -> log::LevelFilter {
+        self.level
+    }
+
+    fn config(&self) -> Option<&Config> {
+        Some(&self.config)
+    }
+
+    fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+        Box::new(*self)
+    }
+}
+
+#[cfg(unix)]
+mod system {
+    use super::{file, LogTarget, PHASE};
+    use simplelog::{Config, SharedLogger};
+    use std::io::Write;
+    use std::os::unix::net::UnixDatagram;
+
+    /// Socket of the local syslog daemon
+    const SYSLOG_SOCKET: &str = "/dev/log";
+
+    /// Socket of the systemd journal native protocol
+    const JOURNALD_SOCKET: &str = "/run/systemd/journal/socket";
+
+    /// Name the messages are logged under
+    const IDENTIFIER: &str = "mks_tft_img";
+
+    /// Syslog facility of user-level messages
+    const FACILITY_USER: u8 = 1;
+
+    /// Logger that sends records to syslog or journald over a Unix socket
+    pub struct SystemLogger {
+        level: log::LevelFilter,
+        config: Config,
+        target: LogTarget,
+        socket: UnixDatagram,
+    }
+
+    impl SystemLogger {
+        pub fn new(level: log::LevelFilter, target: LogTarget) -> std::io::Result<Box<Self>> {
+            let socket = UnixDatagram::unbound()?;
+            match target {
+                LogTarget::Journald => socket.connect(JOURNALD_SOCKET)?,
+                _ => socket.connect(SYSLOG_SOCKET)?,
+            }
+            Ok(Box::new(SystemLogger {
+                level,
+                config: Config::default(),
+                target,
+                socket,
+            }))
+        }
+
+        /// Syslog severity of a log level
+        fn severity(level: log::Level) -> u8 {
+            match level {
+                log::Level::Error => 3,
+                log::Level::Warn => 4,
+                log::Level::Info => 6,
+                log::Level::Debug | log::Level::Trace => 7,
+            }
+        }
+
+        /// Format a record according to RFC 3164, as expected by `/dev/log`
+        fn syslog_message(record: &log::Record<'_>) -> Vec<u8> {
+            let priority = FACILITY_USER * 8 + Self::severity(record.level());
+            format!("<{}>{}[{}]: {}", priority, IDENTIFIER, std::process::id(), record.args())
+                .into_bytes()
+        }
+
+        /// Format a record according to the journald native protocol
+        fn journald_message(record: &log::Record<'_>) -> Vec<u8> {
+            let mut message = Vec::new();
+            let mut field = |name: &str, value: &str| {
+                if value.contains('\n') {
+                    // Values with new lines are prefixed with their length
+                    let _ = writeln!(message, "{}", name);
+                    message.extend_from_slice(&(value.len() as u64).to_le_bytes());
+                    message.extend_from_slice(value.as_bytes());
+                    message.push(b'\n');
+                } else {
+                    let _ = writeln!(message, "{}={}", name, value);
+                }
+            };
+            field("MESSAGE", &record.args().to_string());
+            field("PRIORITY", &Self::severity(record.level()).to_string());
+            field("SYSLOG_IDENTIFIER", IDENTIFIER);
+            if let Some(file) = file() {
+                field("GCODE_FILE", &file);
+            }
+            field("PHASE", PHASE.get());
+            message
+        }
+    }
+
+    impl log::Log for SystemLogger {
+        fn enabled(&self, metadata: &log::Metadata<'_>) -> bool {
+            metadata.level() <= self.level
+        }
+
+        fn log(&self, record: &log::Record<'_>) {
+            if !self.enabled(record.metadata()) {
+                return;
+            }
+            let message = match self.target {
+                LogTarget::Journald => Self::journald_message(record),
+                _ => Self::syslog_message(record),
+            };
+            let _ = self.socket.send(&message);
+        }
+
+        fn flush(&self) {}
+    }
+
+    impl SharedLogger for SystemLogger {
+        fn level(&self) -> log::LevelFilter {
+            self.level
+        }
+
+        fn config(&self) -> Option<&Config> {
+            Some(&self.config)
+        }
+
+        fn as_log(self: Box<Self>) -> Box<dyn log::Log> {
+            Box::new(*self)
+        }
+    }
+}
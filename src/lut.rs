@@ -0,0 +1,272 @@
+//! Color calibration of previews for a particular display panel
+//!
+//! Cheap TFT panels often have a strong color cast. A lookup table (LUT)
+//! maps the colors of the preview to the ones that look right on the panel,
+//! before they are reduced to the colors the display can show.
+
+use crate::color::with_alpha_of;
+use crate::presets::Printer;
+use clap::ValueEnum;
+use image::DynamicImage;
+use serde::{Serialize, Serializer};
+use std::path;
+
+/// Largest size of a `.cube` LUT, the same limit most editors have
+const MAX_CUBE_SIZE: usize = 256;
+
+/// A color lookup table loaded from a file
+#[derive(Clone, Debug, PartialEq)]
+pub struct Lut {
+    /// The file the LUT was loaded from, for the log
+    file: String,
+    /// CRC32 of the file, so the cache notices when the LUT changes
+    checksum: u32,
+    table: Table,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Table {
+    /// A curve for each of the red, green and blue channels
+    Curves(Box<[[u8; 256]; 3]>),
+    /// A 3D table of `size` values on each side, red changing fastest
+    Cube {
+        size: usize,
+        values: Vec<[f32; 3]>,
+        min: [f32; 3],
+        max: [f32; 3],
+    },
+}
+
+/// LUTs are described by their file in the options, not by all their values
+impl Serialize for Lut {
+    fn serialize<S: Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        format!("{} ({:08x})", self.file, self.checksum).serialize(serializer)
+    }
+}
+
+impl Lut {
+    /// Load a LUT from a file
+    ///
+    /// Files with the `.cube` extension are 1D or 3D LUTs in the format of
+    /// Adobe and DaVinci Resolve. Any other file has a curve for each channel:
+    /// lines of an input value and the red, green and blue values it maps to,
+    /// all from 0 to 255, e.g. `128 120 128 140`. Values in between are
+    /// interpolated. Lines starting with `#` are comments.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The LUT file
+    pub fn load(path: &path::Path) -> Result<Self, String> {
+        let content =
+            std::fs::read(path).map_err(|e| format!("Cannot read `{}`: {}", path.display(), e))?;
+        let text = String::from_utf8_lossy(&content);
+        let is_cube =
+            path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("cube"));
+        let table = match is_cube {
+            true => parse_cube(&text),
+            false => parse_curves(&text),
+        }
+        .map_err(|e| format!("Invalid LUT `{}`: {}", path.display(), e))?;
+        Ok(Lut {
+            file: path.display().to_string(),
+            checksum: crc32fast::hash(&content),
+            table,
+        })
+    }
+
+    /// Map the colors of an image through the LUT, keeping its alpha
+    pub fn apply(&self, image: &DynamicImage) -> DynamicImage {
+        let mut rgba = image.to_rgba8();
+        for pixel in rgba.pixels_mut() {
+            let rgb = [pixel[0], pixel[1], pixel[2]];
+            pixel.0[..3].copy_from_slice(&self.map(rgb));
+        }
+        with_alpha_of(image, rgba)
+    }
+
+    /// Map a color through the LUT
+    fn map(&self, rgb: [u8; 3]) -> [u8; 3] {
+        match &self.table {
+            Table::Curves(curves) => {
+                [0, 1, 2].map(|channel| curves[channel][rgb[channel] as usize])
+            }
+            Table::Cube {
+                size,
+                values,
+                min,
+                max,
+            } => {
+                // Position in the table and the weight of the next value on each axis
+                let position = [0, 1, 2].map(|channel| {
+                    let value = f32::from(rgb[channel]) / 255.0;
+                    let scaled = (value - min[channel]) / (max[channel] - min[channel]);
+                    let scaled = scaled.clamp(0.0, 1.0) * (size - 1) as f32;
+                    let index = (scaled.floor() as usize).min(size - 2);
+                    (index, scaled - index as f32)
+                });
+                let mut color = [0.0; 3];
+                for corner in 0..8 {
+                    let mut weight = 1.0;
+                    let mut index = 0;
+                    for (axis, &(start, fraction)) in position.iter().enumerate() {
+                        let next = corner >> axis & 1;
+                        weight *= match next {
+                            1 => fraction,
+                            _ => 1.0 - fraction,
+                        };
+                        index += (start + next) * size.pow(axis as u32);
+                    }
+                    for (value, corner) in color.iter_mut().zip(values[index]) {
+                        *value += weight * corner;
+                    }
+                }
+                color.map(|value| (value * 255.0).round().clamp(0.0, 255.0) as u8)
+            }
+        }
+    }
+}
+
+/// Parse a LUT for a printer, like `jgaurora=panel.cube`, or for all
+/// printers, like `panel.cube`
+///
+/// # Returns
+///
+/// The printer, if one is given, and the loaded LUT
+pub fn parse_printer_lut(value: &str) -> Result<(Option<Printer>, Lut), String> {
+    let printer = value
+        .split_once('=')
+        .and_then(|(printer, file)| Some((Printer::from_str(printer, true).ok()?, file)));
+    match printer {
+        Some((printer, file)) => Ok((Some(printer), Lut::load(path::Path::new(file))?)),
+        None => Ok((None, Lut::load(path::Path::new(value))?)),
+    }
+}
+
+/// Parse the lines of numbers of a LUT file, skipping comments and empty lines
+///
+/// # Returns
+///
+/// The number of each line, with the line number for errors
+fn numbers(text: &str) -> impl Iterator<Item = (usize, Result<Vec<f32>, String>)> + '_ {
+    text.lines()
+        .enumerate()
+        .map(|(index, line)| (index + 1, line.trim()))
+        .filter(|(_, line)| !line.is_empty() && !line.starts_with('#'))
+        .map(|(number, line)| {
+            let values = line.split_whitespace().map(str::parse).collect::<Result<Vec<f32>, _>>();
+            (number, values.map_err(|_| format!("line {} is not numbers: `{}`", number, line)))
+        })
+}
+
+/// Parse a `.cube` LUT
+fn parse_cube(text: &str) -> Result<Table, String> {
+    let (mut size_1d, mut size_3d) = (None, None);
+    let (mut min, mut max) = ([0.0; 3], [1.0; 3]);
+    let mut values: Vec<[f32; 3]> = vec![];
+    for (index, line) in text.lines().map(str::trim).enumerate() {
+        let mut words = line.split_whitespace();
+        let keyword = words.next().unwrap_or_default();
+        let arguments: Vec<f32> = words.filter_map(|word| word.parse().ok()).collect();
+        let triple = || -> Result<[f32; 3], String> {
+            arguments[..].try_into().map_err(|_| format!("`{}` needs 3 numbers", line))
+        };
+        let size = || -> Result<usize, String> {
+            arguments
+                .first()
+                .map(|&size| size as usize)
+                .filter(|size| (2..=MAX_CUBE_SIZE).contains(size))
+                .ok_or_else(|| format!("`{}` needs a size from 2 to {}", line, MAX_CUBE_SIZE))
+        };
+        match keyword {
+            "LUT_1D_SIZE" => size_1d = Some(size()?),
+            "LUT_3D_SIZE" => size_3d = Some(size()?),
+            "DOMAIN_MIN" => min = triple()?,
+            "DOMAIN_MAX" => max = triple()?,
+            "LUT_1D_INPUT_RANGE" | "LUT_3D_INPUT_RANGE" => match arguments[..] {
+                [low, high] => (min, max) = ([low; 3], [high; 3]),
+                _ => return Err(format!("`{}` needs 2 numbers", line)),
+            },
+            "" => (),
+            // Other keywords, like `TITLE`, don't change the colors
+            keyword if keyword.starts_with(|c: char| c.is_ascii_alphabetic() || c == '#') => (),
+            _ => {
+                let parsed: Vec<f32> = line
+                    .split_whitespace()
+                    .map(str::parse)
+                    .collect::<Result<_, _>>()
+                    .map_err(|_| format!("line {} is not numbers: `{}`", index + 1, line))?;
+                let value =
+                    parsed.try_into().map_err(|_| format!("line {} needs 3 numbers", index + 1))?;
+                values.push(value);
+            }
+        }
+    }
+    if (0..3).any(|channel| max[channel] <= min[channel]) {
+        return Err("the domain is empty".to_string());
+    }
+    let expected = match (size_1d, size_3d) {
+        (Some(size), None) => size,
+        (None, Some(size)) => size * size * size,
+        (None, None) => return Err("`LUT_1D_SIZE` or `LUT_3D_SIZE` is missing".to_string()),
+        (Some(_), Some(_)) => return Err("it has both a 1D and a 3D size".to_string()),
+    };
+    if values.len() != expected {
+        return Err(format!("it has {} values instead of {}", values.len(), expected));
+    }
+    match size_1d {
+        Some(size) => {
+            let mut curves = Box::new([[0; 256]; 3]);
+            for (channel, curve) in curves.iter_mut().enumerate() {
+                for (input, output) in curve.iter_mut().enumerate() {
+                    let value = input as f32 / 255.0;
+                    let scaled = (value - min[channel]) / (max[channel] - min[channel]);
+                    let scaled = scaled.clamp(0.0, 1.0) * (size - 1) as f32;
+                    let index = (scaled.floor() as usize).min(size - 2);
+                    let fraction = scaled - index as f32;
+                    let mapped = values[index][channel] * (1.0 - fraction)
+                        + values[index + 1][channel] * fraction;
+                    *output = (mapped * 255.0).round().clamp(0.0, 255.0) as u8;
+                }
+            }
+            Ok(Table::Curves(curves))
+        }
+        None => Ok(Table::Cube {
+            size: size_3d.expect("Either size is given"),
+            values,
+            min,
+            max,
+        }),
+    }
+}
+
+/// Parse a file with a curve for each channel, see [`Lut::load`]
+fn parse_curves(text: &str) -> Result<Table, String> {
+    let mut points: Vec<[f32; 4]> = vec![];
+    for (number, line) in numbers(text) {
+        let point: [f32; 4] =
+            line?.try_into().map_err(|_| format!("line {} needs 4 numbers", number))?;
+        if point.iter().any(|value| !(0.0..=255.0).contains(value)) {
+            return Err(format!("line {} has values outside of 0 to 255", number));
+        }
+        if points.last().is_some_and(|last| last[0] >= point[0]) {
+            return Err(format!("line {} is not after the previous input value", number));
+        }
+        points.push(point);
+    }
+    if points.len() < 2 {
+        return Err("it needs at least 2 points".to_string());
+    }
+    let mut curves = Box::new([[0; 256]; 3]);
+    for input in 0..256 {
+        let value = input as f32;
+        // The segment the input is in, inputs outside of all are clamped to the ends
+        let next = points.iter().position(|point| point[0] >= value).unwrap_or(points.len() - 1);
+        let (from, to) = (points[next.max(1) - 1], points[next.max(1)]);
+        let fraction = ((value - from[0]) / (to[0] - from[0])).clamp(0.0, 1.0);
+        for (channel, curve) in curves.iter_mut().enumerate() {
+            let mapped = from[channel + 1] * (1.0 - fraction) + to[channel + 1] * fraction;
+            curve[input] = mapped.round().clamp(0.0, 255.0) as u8;
+        }
+    }
+    Ok(Table::Curves(curves))
+}
@@ -1,250 +1,2506 @@
-use base64::prelude::{Engine, BASE64_STANDARD};
-use clap::Parser;
-use image::imageops::FilterType;
-use image::io::Reader as ImageReader;
-use image::{DynamicImage, Rgb};
+mod archive;
+mod bench;
+mod cache;
+mod cura;
+mod diff;
+mod directives;
+mod filter;
+mod generate;
+mod hooks;
+mod inspect;
+mod install;
+mod journal;
+mod klipper;
+mod logging;
+mod progress;
+mod report;
+mod serve;
+mod simulate;
+mod sink;
+mod storage;
+mod summary;
+mod terminal;
+mod threemf;
+mod upload;
+mod version;
+mod zip;
+
+use clap::{CommandFactory, FromArgMatches, Parser, Subcommand, ValueEnum};
+use image::DynamicImage;
+use serde::Serialize;
+use std::borrow::Cow;
+use std::cell::RefCell;
 use std::fs::File;
-use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::io::{BufRead, BufReader, BufWriter, Cursor, IsTerminal, Read, Seek, Write};
 use std::path;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use mks_tft_img::footer::FooterInfo;
+use mks_tft_img::lut::Lut;
+use mks_tft_img::meta::PrintMeta;
+use mks_tft_img::options::{
+    Dither, Filter, PreviewPosition, PreviewSize, ProcessOptions, RowEnd, SimageQuality,
+};
+use mks_tft_img::parser::{Step, ThumbnailParser};
+use mks_tft_img::preview::{Preview, PreviewEncoder};
+use mks_tft_img::{
+    adjust, encoders, line_ending, lut, mmap, presets, qr, render, slicer, source, verify,
+    write_gcode, write_gcode_streamed, Target, LINE_ENDING_SAMPLE,
+};
 
 /// Replace preview image in the G-code with a one that is suitable for for MKS TFT35 display
-#[derive(Parser, Debug)]
+#[derive(Parser, Clone, Debug)]
 #[command(version, about, long_about = None)]
+#[command(args_conflicts_with_subcommands = true, subcommand_negates_reqs = true)]
 struct Args {
-    /// Path to the G-code file.
-    path: path::PathBuf,
+    #[command(subcommand)]
+    command: Option<Command>,
+
+    /// Paths to the G-code files, or directories to convert all `.gcode` files in.
+    #[arg(required = true)]
+    paths: Vec<path::PathBuf>,
+
+    /// How many files to convert in parallel [default: the number of CPUs]
+    #[arg(short, long)]
+    jobs: Option<usize>,
+
+    /// Write the converted G-code to this file instead of replacing the original one.
+    /// Use `-` to write it to stdout
+    #[arg(short, long)]
+    output: Option<path::PathBuf>,
+
+    /// The size of the simage [default: 50, or the one of the printer]
+    #[arg(short, long)]
+    simage_size: Option<PreviewSize>,
+
+    /// The size of the gimage [default: 200, or the one of the printer].
+    /// Give several sizes, e.g. `-g 200,300` or `-g 200 -g 300`, to add a gimage of each size
+    #[arg(short, long, value_delimiter = ',')]
+    gimage_size: Option<Vec<PreviewSize>>,
+
+    /// The display the preview image is generated for [default: mks, or the one of the printer]
+    #[arg(short, long, value_enum)]
+    target: Option<Target>,
+
+    /// The printer to take default simage/gimage sizes and display settings from
+    #[arg(short, long, value_enum)]
+    printer: Option<presets::Printer>,
+
+    /// JSON file mapping printer model names to printers, e.g. `{"My Ghost": "flyingbear-ghost5"}`,
+    /// to pick the printer from `; printer_model = ...` of the G-code if `--printer` isn't given.
+    /// These are looked at before the known models
+    #[arg(long, value_name = "FILE")]
+    printer_models: Option<path::PathBuf>,
+
+    /// JSON file of processing options, e.g. `{"filter": "lanczos3", "dither": "floyd-steinberg"}`,
+    /// with the fields of `ProcessOptions` of the library. Options given on the command line
+    /// or by directives take precedence
+    #[arg(long, value_name = "FILE", value_parser = parse_config)]
+    config: Option<serde_json::Map<String, serde_json::Value>>,
+
+    /// Ids of the arguments given on the command line or by directives, which take
+    /// precedence over `--config`
+    #[arg(skip)]
+    given: Vec<String>,
+
+    /// What goes after each MKS image row [default: cr, or the one of the printer].
+    /// Try another one if the preview on the printer is blank or color shifted
+    #[arg(long, value_enum)]
+    row_end: Option<RowEnd>,
+
+    /// Whether the `M10086` command after each MKS image row ends with ` ;`
+    /// [default: true, or the one of the printer]
+    #[arg(long, value_name = "BOOL")]
+    trailing_semicolon: Option<bool>,
+
+    /// The filter to resize the thumbnail with
+    #[arg(long, value_enum, default_value_t = Filter::CatmullRom)]
+    filter: Filter,
+
+    /// Scale the thumbnail by whole factors only and pad it to the size, so pixel art and icon
+    /// style thumbnails keep crisp edges with `--filter nearest`
+    #[arg(long)]
+    integer_scale: bool,
+
+    /// How carefully the simage is downscaled. `high` resizes to 4 times the size first and
+    /// averages that down, which reduces aliasing on thin features of the model
+    #[arg(long, value_enum, default_value_t = SimageQuality::Normal)]
+    simage_quality: SimageQuality,
+
+    /// Resize in linear light instead of gamma-encoded sRGB, which keeps fine details from
+    /// getting darker
+    #[arg(long)]
+    linear_light: bool,
+
+    /// Convert thumbnails tagged with an ICC profile, e.g. Display P3, to sRGB
+    #[arg(long)]
+    icc: bool,
+
+    /// Trim the empty borders of the thumbnail before resizing, so the model fills the
+    /// preview instead of being tiny in the middle of it
+    #[arg(long)]
+    autocrop: bool,
+
+    /// Stretch the histogram of the thumbnail, so thumbnails rendered on dark backgrounds
+    /// don't look muddy on the display
+    #[arg(long)]
+    auto_contrast: bool,
+
+    /// Percentage of the darkest and of the lightest values clipped by `--auto-contrast`
+    #[arg(long, value_name = "PERCENT", default_value_t = 0.5, requires = "auto_contrast")]
+    auto_contrast_clip: f32,
+
+    /// Multiply the saturation of the thumbnail by this factor, e.g. 1.3, so filament colors
+    /// stay recognizable on the display. 0 makes it gray
+    #[arg(long, value_name = "FACTOR")]
+    saturation: Option<f32>,
+
+    /// Replace the flat background of the thumbnail, detected from its corners, with this
+    /// color, e.g. `#1e90ff`, to color-code previews by printer or material
+    #[arg(long, value_name = "HEX", value_parser = adjust::parse_color)]
+    replace_background: Option<[u8; 3]>,
+
+    /// Dither the preview to the colors of RGB565 displays, so gradients of the model don't
+    /// band into flat steps
+    #[arg(long, value_enum, default_value_t = Dither::None)]
+    dither: Dither,
+
+    /// Calibrate the preview colors for the display panel with a `.cube` LUT or a file of
+    /// channel curves. Prefix it with a printer, e.g. `jgaurora=panel.cube`, to use it only for
+    /// that printer; can be given for several printers
+    #[arg(long, value_name = "[PRINTER=]FILE", value_parser = lut::parse_printer_lut)]
+    lut: Vec<(Option<presets::Printer>, Lut)>,
+
+    /// Make the preview from this image file instead of the thumbnail in the G-code
+    #[arg(long)]
+    image: Option<path::PathBuf>,
+
+    /// Also write the gimage as raw RGB565 pixels (low byte first) to this file
+    #[arg(long)]
+    emit_raw: Option<path::PathBuf>,
+
+    /// Format of the `--emit-raw` output
+    #[arg(long, value_enum, default_value_t = RawFormat::Bin, requires = "emit_raw")]
+    raw_format: RawFormat,
+
+    /// Re-read the written file and check that the preview decodes to the expected image
+    #[arg(long)]
+    verify: bool,
+
+    /// Save the image shown on the printer display (gimage for MKS) as PNG to this file
+    #[arg(long)]
+    preview_out: Option<path::PathBuf>,
+
+    /// Also write the resized preview as PNG next to the output file, with the same name and
+    /// the `.png` extension, for screens and file managers that read it from there
+    #[arg(long)]
+    sidecar_png: bool,
+
+    /// Only write the `--sidecar-png` file, leaving the G-code unchanged
+    #[arg(long, requires = "sidecar_png")]
+    no_embed: bool,
+
+    /// Show the image shown on the printer display in the terminal
+    #[arg(long)]
+    show: bool,
+
+    /// Show progress of reading and writing the G-code on stderr.
+    /// It is never shown if stderr is not a terminal or when run by a slicer
+    #[arg(long)]
+    progress: bool,
+
+    /// Leave the tool version out of the output, so the same input and options give the same
+    /// output with any release, e.g. for content-addressed caches
+    #[arg(long)]
+    reproducible: bool,
+
+    /// Template of the comment added after the preview, with placeholders like `{version}`,
+    /// `{target}`, `{source_format}` or `{slicer.layer_height}`. `\n` starts a new line
+    #[arg(long, value_name = "TEMPLATE")]
+    footer_template: Option<String>,
+
+    /// Leave out the comment added after the preview
+    #[arg(long, conflicts_with = "footer_template")]
+    no_footer: bool,
+
+    /// Add `;TIME:`, `;Filament used:` and `;Layer height:` comments made from the
+    /// metadata of the slicer, for MKS firmware showing them on its print info screen
+    #[arg(long)]
+    inject_metadata: bool,
+
+    /// Add an `M117` message, shown on the display when the print starts, e.g.
+    /// "Preview by mks_tft_img" or "{estimated_time}, {filament_weight}g". Takes the
+    /// placeholders of `--footer-template`
+    #[arg(long, value_name = "TEMPLATE")]
+    m117: Option<String>,
+
+    /// Add a `; MKS_TFT_IMG_CRC32=<hex>` comment with the CRC32 of the preview after it, so
+    /// `inspect` and `--verify` can detect a corrupted copy, e.g. after a flaky upload
+    #[arg(long)]
+    crc_trailer: bool,
+
+    /// Draw a QR code of this text into a corner of the gimage, e.g. a URL to open the
+    /// print job. It takes the placeholders of `--footer-template`, e.g. `{file}`
+    #[arg(long, value_name = "TEXT")]
+    qr: Option<String>,
 
-    /// The size of the simage
-    #[arg(short, long, default_value_t = 50)]
-    simage_size: u8,
+    /// The corner of the gimage to draw the QR code in
+    #[arg(long, value_enum, default_value_t = qr::Corner::BottomRight, requires = "qr")]
+    qr_corner: qr::Corner,
 
-    /// The size of the gimage
-    #[arg(short, long, default_value_t = 200)]
-    gimage_size: u16,
+    /// Where the preview is written. MKS firmware only finds it at the top; `thumbnail` puts
+    /// it in place of the slicer thumbnail, after the slicer header
+    #[arg(long, value_enum, default_value_t = PreviewPosition::Top)]
+    preview_position: PreviewPosition,
+
+    /// Convert without writing or uploading anything, only print what would change
+    #[arg(long)]
+    dry_run: bool,
+
+    /// With `--dry-run`, print a unified diff of the G-code header, with the image data
+    /// summarized
+    #[arg(long, requires = "dry_run")]
+    diff: bool,
+
+    /// Memory-map the G-code file instead of reading it line by line.
+    /// Faster for very large files
+    #[arg(long)]
+    mmap: bool,
+
+    /// Limit decoding the thumbnail to this many megabytes of memory, e.g. on a Raspberry Pi
+    /// Zero. The G-code before the thumbnail is then copied from the file instead of held in
+    /// memory
+    #[arg(long)]
+    max_memory: Option<u64>,
+
+    /// Keep the original thumbnail compressed in the output, so the file can be restored
+    /// with the `restore` command
+    #[arg(long)]
+    archive_original: bool,
+
+    /// Write a JSON report of what happened to each file to this file: its status, sizes,
+    /// duration, errors and warnings
+    #[arg(long, value_name = "FILE")]
+    report: Option<path::PathBuf>,
+
+    /// Run this command with the shell before converting each file, e.g. a virus scan. The file
+    /// is in `MKS_TFT_IMG_FILE`, and it is skipped if the command fails
+    #[arg(long, value_name = "COMMAND")]
+    pre_hook: Option<String>,
+
+    /// Run this command with the shell after processing each file, e.g. to rename or upload
+    /// it. `MKS_TFT_IMG_FILE` and `MKS_TFT_IMG_OUTPUT` are the G-code and output files,
+    /// `MKS_TFT_IMG_STATUS` the status and `MKS_TFT_IMG_SUMMARY` the `--report` entry as JSON
+    #[arg(long, value_name = "COMMAND")]
+    post_hook: Option<String>,
+
+    /// POST what happened to each file as JSON to this URL when it is done: its status,
+    /// sizes, duration, errors and warnings
+    #[arg(long, value_name = "URL")]
+    notify_url: Option<String>,
+
+    /// Write the result line printed on stdout for each file as JSON, instead of
+    /// `RESULT key=value ...`
+    #[arg(long)]
+    porcelain: bool,
+
+    /// Convert the `.gcode` files inside `.zip` archives, and look for them in directories
+    #[arg(long)]
+    zip: bool,
+
+    /// Only convert the files found in directories that match this glob pattern, e.g.
+    /// `*_PLA_*.gcode`. Patterns with a `/` match the path in the directory, e.g.
+    /// `printer1/**`. Can be given several times
+    #[arg(long, value_name = "GLOB")]
+    include: Vec<String>,
+
+    /// Skip the files found in directories that match this glob pattern, e.g. `**/old/**`.
+    /// Can be given several times
+    #[arg(long, value_name = "GLOB")]
+    exclude: Vec<String>,
+
+    /// Skip the files found in directories that are larger than this many megabytes
+    #[arg(long, value_name = "MB")]
+    max_file_size: Option<u64>,
+
+    /// Skip the G-code files found in directories that have no thumbnail, instead of
+    /// reporting them
+    #[arg(long)]
+    skip_without_thumbnail: bool,
+
+    /// Remember the converted files in this JSON file, and skip the ones unchanged since,
+    /// e.g. when converting a directory again
+    #[arg(long, value_name = "FILE", conflicts_with = "dry_run")]
+    cache: Option<path::PathBuf>,
+
+    /// How many seconds to wait if the G-code file is locked by another process
+    #[arg(long, default_value_t = 0)]
+    lock_timeout: u64,
+
+    /// Flush the converted G-code to the storage device before it replaces the
+    /// original file, e.g. on SD cards that may be removed right after
+    #[arg(long)]
+    fsync: bool,
+
+    /// How many times a failed write is retried, with increasing delays, e.g. on
+    /// network shares
+    #[arg(long, default_value_t = 3)]
+    write_retries: u32,
+
+    /// Keep the modification time, permissions and, where possible, the owner of the
+    /// original file when it is replaced, so sync tools and sorting by date keep working
+    #[arg(long)]
+    preserve_metadata: bool,
+
+    /// Print statistics of the conversion to stderr when done
+    #[arg(long)]
+    summary: bool,
+
+    /// Print how long each processing phase took to stderr when done
+    #[arg(long)]
+    timings: bool,
+
+    /// Append this to the name of the output file when run by PrusaSlicer, e.g. `_MKS`
+    #[arg(long)]
+    output_suffix: Option<String>,
+
+    /// Upload the converted G-code to this service
+    #[arg(long, value_enum, requires = "destination")]
+    upload: Option<upload::Uploader>,
+
+    /// Base URL of the upload service, e.g. `http://octopi.local`
+    #[arg(long, group = "destination")]
+    url: Option<String>,
+
+    /// Host name or IP address of the upload service, same as `--url http://<host>`
+    #[arg(long, group = "destination")]
+    host: Option<String>,
+
+    /// API key of the upload service
+    #[arg(long)]
+    api_key: Option<String>,
+
+    /// Select the uploaded file for printing
+    #[arg(long)]
+    select: bool,
+
+    /// Start printing the uploaded file
+    #[arg(long)]
+    start_print: bool,
+
+    /// After converting, ask Moonraker at this URL to refresh the metadata of the file, so
+    /// Fluidd and Mainsail show the new preview. The file must be in `--moonraker-gcodes`
+    #[arg(long, value_name = "URL")]
+    moonraker_url: Option<String>,
+
+    /// The `gcodes` folder of Moonraker [default: ~/printer_data/gcodes]
+    #[arg(long, value_name = "DIR", requires = "moonraker_url")]
+    moonraker_gcodes: Option<path::PathBuf>,
+
+    /// The printer on the upload service, e.g. the slug of a Repetier-Server printer
+    #[arg(long, value_name = "SLUG")]
+    remote_printer: Option<String>,
+
+    /// How many times a failed upload is retried, with increasing delays
+    #[arg(long, default_value_t = 3)]
+    upload_retries: u32,
+
+    /// What to do if there is no thumbnail in the G-code
+    #[arg(long, value_enum, default_value_t = MissingThumbnail::Warn)]
+    missing_thumbnail: MissingThumbnail,
+
+    /// What to make the preview from
+    #[arg(long, value_enum, default_value_t = PreviewSource::Thumbnail, conflicts_with = "image")]
+    preview_source: PreviewSource,
+
+    /// Where rendered previews are looked at from, see `--preview-source` and
+    /// `--missing-thumbnail render`
+    #[arg(long, value_enum, default_value_t = render::View::Top)]
+    render_view: render::View,
+
+    /// Rotation of the camera of rendered previews around the Z axis in degrees,
+    /// 0 looks from the front [default: 0 from the top, 45 otherwise]
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    render_azimuth: Option<f32>,
+
+    /// Angle of the camera of rendered previews above the bed in degrees,
+    /// 90 looks from the top [default: 90 from the top, 35.264 isometric, 30 perspective]
+    #[arg(long, value_name = "DEGREES", allow_negative_numbers = true)]
+    render_elevation: Option<f32>,
+
+    /// Magnification of rendered previews, 1 fits the whole toolpath into the preview
+    #[arg(long, default_value_t = 1.0)]
+    render_zoom: f32,
+
+    /// Exit with a non-zero code if the conversion fails or there is no thumbnail, and fail on
+    /// sizes larger than the printer supports instead of reducing them.
+    /// Codes: 3 - file not found, 4 - read error, 5 - no thumbnail, 6 - thumbnail decode error,
+    /// 7 - preview encode error or size too large, 8 - write error, 9 - verification error, 10 - upload error,
+    /// 11 - file locked, 12 - crash, 13 - hook failed, 1 - other errors
+    #[arg(long)]
+    strict: bool,
 
     /// Log file
     #[arg(long)]
     log_file: Option<path::PathBuf>,
 
-    ///Log level. Possible levels are OFF, DEBUG, INFO, WARN, ERROR
-    #[arg(long, default_value_t = log::LevelFilter::Warn)]
-    log_level: log::LevelFilter,
+    /// Append to the log file and rotate it when it grows above this size in KB
+    #[arg(long, requires = "log_file")]
+    log_max_size: Option<u64>,
+
+    /// How many rotated log files to keep
+    #[arg(long, default_value_t = 3, requires = "log_max_size")]
+    log_max_files: u32,
+
+    /// Log more details, repeat for more: `-v` info, `-vv` debug, `-vvv` trace
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "quiet")]
+    verbose: u8,
+
+    /// Log less, repeat for less: `-q` errors only, `-qq` nothing
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    quiet: u8,
+
+    /// Log level, overrides `-v` and `-q`. Possible levels are OFF, ERROR, WARN, INFO, DEBUG,
+    /// TRACE [default: WARN]
+    #[arg(long)]
+    log_level: Option<log::LevelFilter>,
+
+    /// Log format
+    #[arg(long, value_enum, default_value_t = logging::LogFormat::Text)]
+    log_format: logging::LogFormat,
+
+    /// Where to send logs to, in addition to the log file
+    #[arg(long, value_enum, default_value_t = logging::LogTarget::Stderr)]
+    log_target: logging::LogTarget,
+
+    /// When to color the log output on the terminal. `NO_COLOR` in the environment turns
+    /// `auto` off
+    #[arg(long, value_enum, default_value_t = logging::ColorMode::Auto, global = true)]
+    color: logging::ColorMode,
+}
+
+/// Commands besides converting a G-code file
+#[derive(Subcommand, Clone, Debug)]
+enum Command {
+    /// Register the tool as a post-processing script in the user print presets of a slicer
+    #[command(group(clap::ArgGroup::new("what").required(true).multiple(true)))]
+    Install {
+        /// The slicer to register the tool in
+        #[arg(long, value_enum, group = "what")]
+        slicer: Option<install::Slicer>,
+
+        /// Add "Convert preview for MKS TFT" to the context menu of `.gcode` files in Windows Explorer
+        #[arg(long, group = "what")]
+        shell_extension: bool,
+
+        /// Additional arguments to run the tool with, e.g. `-- --printer jgaurora`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Remove the tool from the post-processing scripts of a slicer
+    #[command(group(clap::ArgGroup::new("what").required(true).multiple(true)))]
+    Uninstall {
+        /// The slicer to remove the tool from
+        #[arg(long, value_enum, group = "what")]
+        slicer: Option<install::Slicer>,
+
+        /// Remove the action from the context menu of `.gcode` files in Windows Explorer
+        #[arg(long, group = "what")]
+        shell_extension: bool,
+    },
+    /// Generate a Cura post-processing script that runs the tool
+    CuraPlugin {
+        /// Install the script into the Cura scripts folder instead of printing it
+        #[arg(long)]
+        install: bool,
+
+        /// Additional arguments to run the tool with, e.g. `-- --printer jgaurora`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Generate the Klipper config running the tool from a macro, and systemd units converting
+    /// every file uploaded to Moonraker automatically
+    KlipperSetup {
+        /// Install them, and include the config from `printer.cfg`, instead of printing them
+        #[arg(long)]
+        install: bool,
+
+        /// The `printer_data` folder of Klipper and Moonraker [default: ~/printer_data]
+        #[arg(long)]
+        printer_data: Option<path::PathBuf>,
+
+        /// Base URL of Moonraker, to refresh the metadata of converted files
+        #[arg(long, default_value = "http://localhost:7125")]
+        moonraker_url: String,
+
+        /// Additional arguments to run the tool with, e.g. `-- --printer jgaurora`
+        #[arg(last = true)]
+        args: Vec<String>,
+    },
+    /// Restore the original G-code of a file converted with `--archive-original`
+    Restore {
+        /// Path to the converted G-code file
+        path: path::PathBuf,
+    },
+    /// Repair a G-code file left half written when a conversion was interrupted, e.g. by
+    /// power loss
+    Repair {
+        /// Path to the G-code file
+        path: path::PathBuf,
+    },
+    /// Measure the conversion speed on synthetic G-code
+    Bench {
+        /// The printer to take the preview settings from
+        #[arg(short, long, value_enum)]
+        printer: Option<presets::Printer>,
+
+        /// The display the preview image is generated for [default: the one of the printer]
+        #[arg(short, long, value_enum)]
+        target: Option<Target>,
+
+        /// Sizes of the embedded thumbnails, a file is generated for each
+        #[arg(long, value_delimiter = ',', default_values_t = [100, 300, 600])]
+        sizes: Vec<u32>,
+
+        /// How many times to convert each file
+        #[arg(long, default_value_t = 10)]
+        iterations: u32,
+
+        /// How many G-code lines follow the thumbnail
+        #[arg(long, default_value_t = 100_000)]
+        lines: u32,
+    },
+    /// Write a G-code file with an embedded thumbnail, to test the tool or printer firmware with
+    Generate {
+        /// Width and height of the thumbnail
+        #[arg(long, default_value_t = 300)]
+        size: u32,
+
+        /// The slicer whose header and thumbnail comments are written
+        #[arg(long, value_enum, default_value_t = slicer::Generator::PrusaSlicer)]
+        slicer: slicer::Generator,
+
+        /// How many G-code lines follow the thumbnail
+        #[arg(long, default_value_t = 1000)]
+        lines: u32,
+
+        /// The G-code file to write
+        #[arg(short, long)]
+        output: path::PathBuf,
+    },
+    /// Save a mock screenshot of the MKS TFT file browser showing the simages of G-code files
+    Simulate {
+        /// The G-code files, up to 6 fit on the page
+        #[arg(required = true)]
+        paths: Vec<path::PathBuf>,
+
+        /// The PNG file to save
+        #[arg(short, long)]
+        output: path::PathBuf,
+
+        /// The printer to take the preview settings from
+        #[arg(short, long, value_enum)]
+        printer: Option<presets::Printer>,
+
+        /// Size of the simage [default: the one of the printer]
+        #[arg(short, long)]
+        simage_size: Option<PreviewSize>,
+
+        /// Color of the screen
+        #[arg(long, value_name = "HEX", default_value = "#000000", value_parser = adjust::parse_color)]
+        background: [u8; 3],
+    },
+    /// Print a shell completion script for the flags and subcommands
+    Completions {
+        /// The shell to generate the script for
+        #[arg(value_enum)]
+        shell: clap_complete::Shell,
+    },
+    /// Print what the slicer wrote about the print: estimated time, filament and layers
+    Inspect {
+        /// The G-code file
+        path: path::PathBuf,
+        /// Print it as JSON
+        #[arg(long)]
+        json: bool,
+    },
+    /// Convert G-code uploaded over HTTP: `POST /convert` responds with the converted G-code,
+    /// `POST /inspect` with the print metadata as JSON
+    Serve {
+        /// The address and port to listen on, e.g. `0.0.0.0:8080` for other machines
+        #[arg(long, default_value = "127.0.0.1:8080")]
+        listen: String,
+
+        /// The printer to take the preview settings from
+        #[arg(short, long, value_enum)]
+        printer: Option<presets::Printer>,
+
+        /// The display the preview image is generated for [default: the one of the printer]
+        #[arg(short, long, value_enum)]
+        target: Option<Target>,
+
+        /// Largest G-code accepted, in megabytes
+        #[arg(long, default_value_t = 256)]
+        max_size: u64,
+
+        /// How many requests are handled at the same time [default: the number of CPUs]
+        #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+        workers: Option<u16>,
+
+        /// Seconds a client may stall sending the request or receiving the response
+        #[arg(long, default_value_t = 30, value_parser = clap::value_parser!(u64).range(1..))]
+        timeout: u64,
+    },
+    /// Print a man page in roff format, e.g. for `man -l -` or packaging into `man1`
+    Man,
+    /// Print the version, build and supported targets
+    Version {
+        /// Print it as JSON
+        #[arg(long)]
+        json: bool,
+    },
+}
+
+/// What to do if there is no thumbnail in the G-code
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum MissingThumbnail {
+    /// Leave the file unchanged silently
+    Ignore,
+    /// Leave the file unchanged with a warning
+    Warn,
+    /// Leave the file unchanged and exit with code 5
+    Error,
+    /// Use a crossed out frame as the preview
+    Placeholder,
+    /// Render extrusion moves of the G-code as the preview
+    Render,
+}
+
+/// What to make the preview from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum PreviewSource {
+    /// The thumbnail embedded by the slicer, or the `--image` file
+    Thumbnail,
+    /// Extrusion moves of the first layer, rendered from the top
+    FirstLayer,
+}
+
+/// Formats of the raw RGB565 dump
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+enum RawFormat {
+    /// Plain binary
+    Bin,
+    /// C source with a `uint8_t` array
+    C,
 }
 
 fn main() {
-    let args = Args::parse();
+    let matches = Args::command().get_matches();
+    let mut args = Args::from_arg_matches(&matches).unwrap_or_else(|e| e.exit());
+    args.given = given_ids(&matches);
 
-    let _ = init_logging(&args.log_file, args.log_level);
-    match do_main(&args) {
-        Ok(_) => log::debug!("Finished successfully"),
-        Err(_) => log::debug!("Finished with errors. Do not fail, to let the slicer continue"),
+    let rotation = args.log_max_size.map(|max_size| logging::Rotation {
+        max_size: max_size * 1024,
+        max_files: args.log_max_files,
+    });
+    let _ = logging::init_logging(
+        &args.log_file,
+        rotation,
+        args.log_level(),
+        args.log_format,
+        args.log_target,
+        args.color,
+    );
+    if let Some(command) = &args.command {
+        let result = match command {
+            Command::Install {
+                slicer,
+                shell_extension,
+                args,
+            } => {
+                let slicer = slicer.map_or(Ok(()), |slicer| install::install(slicer, args));
+                let shell = match shell_extension {
+                    true => install::install_shell_extension(args),
+                    false => Ok(()),
+                };
+                slicer.and(shell)
+            }
+            Command::Uninstall {
+                slicer,
+                shell_extension,
+            } => {
+                let slicer = slicer.map_or(Ok(()), install::uninstall);
+                let shell = match shell_extension {
+                    true => install::uninstall_shell_extension(),
+                    false => Ok(()),
+                };
+                slicer.and(shell)
+            }
+            Command::CuraPlugin {
+                install: true,
+                args,
+            } => cura::install(args),
+            Command::CuraPlugin {
+                install: false,
+                args,
+            } => cura::script(args).map(|script| print!("{}", script)),
+            Command::KlipperSetup {
+                install,
+                printer_data,
+                moonraker_url,
+                args,
+            } => {
+                let setup = klipper::Setup {
+                    printer_data: printer_data
+                        .clone()
+                        .unwrap_or_else(klipper::default_printer_data),
+                    moonraker_url: moonraker_url.clone(),
+                    args: args.clone(),
+                };
+                match install {
+                    true => setup.install(),
+                    false => setup.print(),
+                }
+            }
+            Command::Restore {
+                path,
+            } => archive::restore(path),
+            Command::Repair {
+                path,
+            } => journal::repair(path),
+            Command::Bench {
+                printer,
+                target,
+                sizes,
+                iterations,
+                lines,
+            } => {
+                let preset = printer.map(presets::Printer::preset).unwrap_or_default();
+                let mut options = ProcessOptions::from_preset(preset);
+                if let Some(target) = target {
+                    options = options.target(*target);
+                }
+                bench::run(&options, sizes, *iterations, *lines)
+            }
+            Command::Generate {
+                size,
+                slicer,
+                lines,
+                output,
+            } => generate::run(output, *slicer, *size, *lines),
+            Command::Simulate {
+                paths,
+                output,
+                printer,
+                simage_size,
+                background,
+            } => {
+                let preset = printer.map(presets::Printer::preset).unwrap_or_default();
+                let mut options = ProcessOptions::from_preset(preset);
+                if let Some(size) = simage_size {
+                    options = options.simage_size(*size);
+                }
+                simulate::run(paths, output, &options, *background)
+            }
+            Command::Completions {
+                shell,
+            } => {
+                // Generated into memory, the generator panics if writing fails, e.g. to `head`
+                let mut script = vec![];
+                let name = env!("CARGO_PKG_NAME");
+                clap_complete::generate(*shell, &mut Args::command(), name, &mut script);
+                let _ = std::io::stdout().write_all(&script);
+                Ok(())
+            }
+            Command::Inspect {
+                path,
+                json,
+            } => inspect::run(path, *json),
+            Command::Serve {
+                listen,
+                printer,
+                target,
+                max_size,
+                workers,
+                timeout,
+            } => {
+                let preset = printer.map(presets::Printer::preset).unwrap_or_default();
+                let mut options = ProcessOptions::from_preset(preset);
+                if let Some(target) = target {
+                    options = options.target(*target);
+                }
+                let workers = workers.map_or_else(
+                    || std::thread::available_parallelism().map_or(1, usize::from),
+                    usize::from,
+                );
+                let limits = serve::Limits {
+                    max_body: max_size * 1024 * 1024,
+                    workers,
+                    timeout: std::time::Duration::from_secs(*timeout),
+                };
+                serve::run(listen, options, limits)
+            }
+            Command::Man => {
+                let mut page = vec![];
+                clap_mangen::Man::new(Args::command())
+                    .render(&mut page)
+                    .expect("Rendering into memory doesn't fail");
+                let _ = std::io::stdout().write_all(&page);
+                Ok(())
+            }
+            Command::Version {
+                json,
+            } => {
+                version::print(*json);
+                Ok(())
+            }
+        };
+        log::logger().flush();
+        std::process::exit(if result.is_ok() {
+            0
+        } else {
+            1
+        });
     }
-}
 
-fn do_main(args: &Args) -> Result<(), ()> {
-    let (gcode_lines, image_lines) = read_gcode(&args.path)?;
+    let filter = filter::FileFilter {
+        include: args.include.clone(),
+        exclude: args.exclude.clone(),
+        max_size: args.max_file_size.map(|megabytes| megabytes * 1024 * 1024),
+        skip_without_thumbnail: args.skip_without_thumbnail,
+    };
+    let paths = gcode_paths(&slicer::rejoin_paths(&args.paths), args.zip, &filter);
+    let cache = args.cache.as_deref().map(|path| cache::Cache::load(path, &args.cache_key()));
+    let report = args.report.as_ref().map(|_| report::Report::new());
+    let results = match paths.as_slice() {
+        [path] => vec![convert(&args, path, args.progress, cache.as_ref(), report.as_ref())],
+        _ if args.output.is_some() => {
+            log::error!("`--output` can only be used with a single G-code file");
+            vec![Err(Failure::Export)]
+        }
+        _ => convert_batch(&args, &paths, cache.as_ref(), report.as_ref()),
+    };
+    if let Some(cache) = &cache {
+        let _ = cache.save();
+    }
+    if let (Some(report), Some(path)) = (&report, &args.report) {
+        let _ = report.save(path);
+    }
+    if paths.len() > 1 {
+        let converted = results.iter().filter(|result| result.is_ok()).count();
+        log::info!("{} of {} files converted", converted, paths.len());
+        let failed: Vec<String> = paths
+            .iter()
+            .zip(&results)
+            .filter_map(|(path, result)| match result {
+                Err(Failure::NoThumbnail) | Ok(()) => None,
+                Err(failure) => Some(format!("  {} ({:?})", path.display(), failure)),
+            })
+            .collect();
+        if !failed.is_empty() {
+            log::error!("{} files failed:\n{}", failed.len(), failed.join("\n"));
+        }
+    }
+    // Files without the thumbnail `--missing-thumbnail error` requires fail the run even
+    // without `--strict`, once all the files are done
+    let required = results.contains(&Err(Failure::ThumbnailRequired));
+    match results.into_iter().find_map(Result::err) {
+        None => log::debug!("Finished successfully"),
+        Some(failure) if args.strict || required => {
+            let failure = match args.strict {
+                true => failure,
+                false => Failure::ThumbnailRequired,
+            };
+            log::debug!("Finished with errors. Exiting with code {}", failure.exit_code());
+            log::logger().flush();
+            std::process::exit(failure.exit_code());
+        }
+        Some(Failure::NoThumbnail) => log::debug!("Finished without changes"),
+        Some(_) => log::debug!("Finished with errors. Do not fail, to let the slicer continue"),
+    }
+}
 
-    if image_lines.is_empty() {
-        log::warn!("There is no image in gcode file. Leaving the original file unchanged");
-        return Ok(());
+/// Get the G-code files to convert
+///
+/// Directories are searched recursively for `.gcode` files, the
+/// `.gcode.3mf` archives of sliced plates and, if asked to, zip archives,
+/// which are converted in the order of their paths, unless the filter skips
+/// them. Other paths are taken as they are.
+///
+/// # Arguments
+///
+/// * `paths` - The paths given on the command line
+/// * `zip` - Whether to look for zip archives too
+/// * `filter` - Which of the files found in directories are converted
+fn gcode_paths(
+    paths: &[path::PathBuf],
+    zip: bool,
+    filter: &filter::FileFilter,
+) -> Vec<path::PathBuf> {
+    let mut gcode_paths = vec![];
+    for path in paths {
+        if !path.is_dir() {
+            gcode_paths.push(path.clone());
+            continue;
+        }
+        let root = path;
+        let mut found = vec![];
+        let mut directories = vec![path.clone()];
+        while let Some(directory) = directories.pop() {
+            let entries = match std::fs::read_dir(&directory) {
+                Ok(entries) => entries,
+                Err(e) => {
+                    log::warn!("Cannot read directory `{}`: {}", directory.display(), e);
+                    continue;
+                }
+            };
+            for entry in entries.flatten() {
+                let path = entry.path();
+                if path.is_dir() {
+                    directories.push(path);
+                } else if (path
+                    .extension()
+                    .is_some_and(|extension| extension.eq_ignore_ascii_case("gcode"))
+                    || path.to_string_lossy().to_lowercase().ends_with(".gcode.3mf")
+                    || (zip && zip::is_zip(&path)))
+                    && filter.accepts(root, &path)
+                {
+                    found.push(path);
+                }
+            }
+        }
+        if found.is_empty() {
+            log::warn!("There are no G-code files in `{}`", path.display());
+        }
+        found.sort();
+        gcode_paths.extend(found);
     }
+    gcode_paths
+}
 
-    log::debug!("Decoding base64 image from gcode");
-    // `image` reader is good in guessing the image format, so we can just skip
-    // `thumbnail_* begin <width>x<height> <size>` and `thumbnail_* end` lines
-    // here and process everything that is in between.
-    let decoded = BASE64_STANDARD
-        .decode(image_lines[1..image_lines.len() - 1].join(""))
-        .map_err(|e| log::error!("Cannot base64 decode image from gcode: {}", e))?;
+/// Convert several G-code files in parallel
+///
+/// The log records of each file are held back until it's done, so the ones
+/// of different files don't interleave. Progress bars are not shown.
+///
+/// Files are converted by plain threads, not by rayon tasks. The state of a
+/// file, like its held log records, is kept per thread, and a rayon worker
+/// waiting for the parallel encoding of one file could pick up another file
+/// in the meantime.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `paths` - The G-code files
+/// * `cache` - The files converted before, see [`convert`]
+/// * `report` - The report to add the files to
+///
+/// # Returns
+///
+/// The results of the files, in the same order
+fn convert_batch(
+    args: &Args,
+    paths: &[path::PathBuf],
+    cache: Option<&cache::Cache>,
+    report: Option<&report::Report>,
+) -> Vec<Result<(), Failure>> {
+    let jobs = args
+        .jobs
+        .filter(|&jobs| jobs > 0)
+        .unwrap_or_else(|| std::thread::available_parallelism().map_or(1, usize::from))
+        .min(paths.len());
+    log::debug!("Converting {} files with {} jobs", paths.len(), jobs);
+    let next = AtomicUsize::new(0);
+    let results = Mutex::new(vec![None; paths.len()]);
+    let work = || loop {
+        let index = next.fetch_add(1, Ordering::Relaxed);
+        let Some(path) = paths.get(index) else {
+            break;
+        };
+        logging::hold_records();
+        let result = convert(args, path, false, cache, report);
+        if let Err(failure) = result {
+            log::debug!("Failed to convert `{}`: {:?}", path.display(), failure);
+        }
+        logging::release_records();
+        results.lock().expect("Workers don't panic")[index] = Some(result);
+    };
+    std::thread::scope(|scope| {
+        for job in 0..jobs {
+            let spawned = std::thread::Builder::new()
+                .name(format!("job {}", job + 1))
+                .spawn_scoped(scope, work);
+            if let Err(e) = spawned {
+                log::error!("Cannot start job {}: {}", job + 1, e);
+                break;
+            }
+        }
+    });
+    // Files are only left without a result if no job could be started
+    let results = results.into_inner().expect("Workers don't panic");
+    results.into_iter().map(|result| result.unwrap_or(Err(Failure::Export))).collect()
+}
 
-    log::debug!("Guessing image format");
-    let img = ImageReader::new(Cursor::new(decoded))
-        .with_guessed_format()
-        .expect("We are running on in-memory data for image. This should not fail");
+/// Convert a G-code file, running the hooks, adding what happened to the report and
+/// notifying the webhook
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The G-code file
+/// * `progress` - Whether to show progress bars, if requested
+/// * `cache` - The files converted before, if `--cache` is given
+/// * `report` - The report, if `--report` is given
+fn convert(
+    args: &Args,
+    path: &path::Path,
+    progress: bool,
+    cache: Option<&cache::Cache>,
+    report: Option<&report::Report>,
+) -> Result<(), Failure> {
+    let output = args.output.as_deref().filter(|output| output.as_os_str() != "-");
+    // The result line is left out when the G-code itself goes to stdout
+    let result_line = args.output.is_none() || output.is_some();
+    if report.is_none() && args.notify_url.is_none() && args.post_hook.is_none() && !result_line {
+        return pre_hook(args, path)
+            .and_then(|_| convert_cached(args, path, progress, cache))
+            .map(|_| ());
+    }
+    logging::collect_problems();
+    let started = std::time::Instant::now();
+    let size = |path: &path::Path| std::fs::metadata(path).ok().map(|metadata| metadata.len());
+    let input_size = size(path);
+    report::set_preview_sizes(None, None);
+    let mut result = pre_hook(args, path).and_then(|_| convert_cached(args, path, progress, cache));
+    let (simage_size, gimage_size) = report::take_preview_sizes();
+    let messages = |problems: &[(log::Level, String)], level| {
+        problems.iter().filter(|(l, _)| *l == level).map(|(_, message)| message.clone()).collect()
+    };
+    let problems = logging::take_problems();
+    let mut file = report::FileReport {
+        path: path.to_path_buf(),
+        status: match result {
+            Ok(status) => status,
+            Err(Failure::NoThumbnail) => report::Status::NoThumbnail,
+            Err(_) => report::Status::Failed,
+        },
+        failure: result.err(),
+        exit_code: result.err().map(Failure::exit_code),
+        input_size,
+        output_size: size(output.unwrap_or(path)),
+        simage_size,
+        gimage_size,
+        duration_ms: report::milliseconds(started.elapsed()),
+        errors: messages(&problems, log::Level::Error),
+        warnings: messages(&problems, log::Level::Warn),
+    };
+    if let Some(command) = &args.post_hook {
+        logging::collect_problems();
+        let hook = hooks::post(command, output.unwrap_or(path), &file);
+        let problems = logging::take_problems();
+        file.errors.extend(messages(&problems, log::Level::Error));
+        file.warnings.extend(messages(&problems, log::Level::Warn));
+        if hook.is_err() && result.is_ok() {
+            result = Err(Failure::Hook);
+            file.status = report::Status::Failed;
+            file.failure = Some(Failure::Hook);
+            file.exit_code = Some(Failure::Hook.exit_code());
+        }
+    }
+    if let Some(url) = &args.notify_url {
+        let _ = upload::notify(url, &file);
+    }
+    if result_line {
+        // A closed stdout, e.g. of `| head`, must not fail the conversion
+        let _ = writeln!(std::io::stdout(), "{}", file.result_line(args.porcelain));
+    }
+    if let Some(report) = report {
+        report.add(file);
+    }
+    result.map(|_| ())
+}
 
-    let img_format = match img.format().map(|format| format.extensions_str()) {
-        Some([ext, ..]) => ext,
-        _ => "UNKNOWN",
+/// Run the `--pre-hook` of a G-code file, if it is given
+fn pre_hook(args: &Args, path: &path::Path) -> Result<(), Failure> {
+    match &args.pre_hook {
+        Some(command) => hooks::pre(command, path).map_err(|_| {
+            log::error!("`{}` is skipped, because its pre-hook failed", path.display());
+            Failure::Hook
+        }),
+        None => Ok(()),
+    }
+}
+
+/// Convert a G-code file, unless the cache tells it is unchanged since the last time
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The G-code file
+/// * `progress` - Whether to show progress bars, if requested
+/// * `cache` - The files converted before, if `--cache` is given
+///
+/// # Returns
+///
+/// Whether it was converted, or skipped as unchanged
+fn convert_cached(
+    args: &Args,
+    path: &path::Path,
+    progress: bool,
+    cache: Option<&cache::Cache>,
+) -> Result<report::Status, Failure> {
+    // What was written to stdout, or to a deleted output file, cannot be skipped
+    let cache = cache.filter(|_| args.output.as_ref().is_none_or(|output| output.exists()));
+    let Some(cache) = cache else {
+        return do_main_isolated(args, path, progress).map(|_| report::Status::Converted);
     };
+    match cache.lookup(path) {
+        Some(true) => {
+            log::info!("Skipping `{}`, it is unchanged since it was converted", path.display());
+            return Ok(report::Status::Unchanged);
+        }
+        Some(false) => {
+            log::info!("Skipping `{}`, it had no thumbnail when last seen", path.display());
+            return Err(Failure::NoThumbnail);
+        }
+        None => (),
+    }
+    let result = do_main_isolated(args, path, progress);
+    match result {
+        Ok(()) => cache.record(path, true),
+        Err(Failure::NoThumbnail) => cache.record(path, false),
+        Err(_) => (),
+    }
+    result.map(|_| report::Status::Converted)
+}
 
-    log::debug!("Decoding image as {}", img_format);
-    let img = img.decode().map_err(|e| {
-        log::error!("Cannot decode image. Guessed format: {}. Error: {}", img_format, e)
-    })?;
-    log::debug!("{}x{} {} image has been decoded", img.width(), img.height(), img_format);
+/// Convert a G-code file, turning a crash into a failure of the file
+///
+/// A bug, e.g. in the image decoder on a corrupt thumbnail, then doesn't stop
+/// the conversion of the other files in batch mode. The original file is
+/// left unchanged and the temporary file is removed.
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The G-code file
+/// * `progress` - Whether to show progress bars, if requested
+fn do_main_isolated(args: &Args, path: &path::Path, progress: bool) -> Result<(), Failure> {
+    let result =
+        std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| do_main(args, path, progress)));
+    result.unwrap_or_else(|panic| {
+        log::error!("The conversion of `{}` crashed: {}", path.display(), panic_message(&*panic));
+        let tmp_path = temporary_path(path);
+        if tmp_path.exists() {
+            let _ = std::fs::remove_file(&tmp_path);
+        }
+        Err(Failure::Crash)
+    })
+}
 
-    let simage = create_tft_image_gcode(
-        ";simage",
-        img.resize(args.simage_size.into(), args.simage_size.into(), FilterType::CatmullRom),
-    );
-    let gimage = create_tft_image_gcode(
-        ";;gimage",
-        img.resize(args.gimage_size.into(), args.gimage_size.into(), FilterType::CatmullRom),
-    );
+/// Get the message a panic was raised with, if it has one
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> String {
+    panic
+        .downcast_ref::<&str>()
+        .map(|message| message.to_string())
+        .or_else(|| panic.downcast_ref::<String>().cloned())
+        .unwrap_or_default()
+}
+
+/// Reasons of an unsuccessful run, reported as exit codes in strict mode
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+enum Failure {
+    /// The G-code file doesn't exist
+    NotFound,
+    /// The G-code file cannot be read
+    Read,
+    /// The thumbnail block is malformed, e.g. its end is missing
+    Parse,
+    /// There is no thumbnail in the G-code
+    NoThumbnail,
+    /// There is no thumbnail in the G-code, and `--missing-thumbnail error`
+    /// requires one
+    ThumbnailRequired,
+    /// The thumbnail cannot be decoded
+    Decode,
+    /// The preview cannot be encoded
+    Encode,
+    /// The converted G-code cannot be written
+    Write,
+    /// The written preview doesn't match the converted one
+    Verify,
+    /// Additional outputs, like the preview PNG, cannot be written
+    Export,
+    /// The converted G-code cannot be uploaded
+    Upload,
+    /// The G-code file is locked by another process
+    Locked,
+    /// The conversion crashed, which is a bug of the tool
+    Crash,
+    /// The `--pre-hook` or `--post-hook` failed
+    Hook,
+}
 
-    // There is a possibility that we can corrupt the gcode file here if writing
-    // fails mid process. I guess we could write to a temporary file first and
-    // then, overwrite the original file. But I'll take the risk of leaving it
-    // as it is for now.
-    log::debug!("Writing gcode with converted image back to {}", args.path.display());
-    let mut file = File::create(&args.path)
-        .map_err(|e| log::error!("Failed to open original gcode file for writing: {}", e))?;
-
-    file.write_all(simage.as_bytes()).map_err(|e| log::error!("Failed to write simage: {}", e))?;
-    file.write_all(gimage.as_bytes()).map_err(|e| log::error!("Failed to write gimage: {}", e))?;
-    file.write_all(gcode_lines[..gcode_lines.len() - 1].join("\n").as_bytes())
-        .map_err(|e| log::error!("Failed to write original gcode header: {}", e))?;
-    file.write_all(
+impl Failure {
+    /// Exit code of the failure
+    fn exit_code(self) -> i32 {
+        match self {
+            Failure::Export => 1,
+            Failure::NotFound => 3,
+            Failure::Read => 4,
+            Failure::NoThumbnail | Failure::ThumbnailRequired => 5,
+            Failure::Decode => 6,
+            Failure::Encode => 7,
+            Failure::Write => 8,
+            Failure::Verify => 9,
+            Failure::Upload => 10,
+            Failure::Locked => 11,
+            Failure::Crash => 12,
+            Failure::Hook => 13,
+            Failure::Parse => 14,
+        }
+    }
+}
+
+impl Args {
+    /// Get the log level from `--log-level`, or from the `-v` and `-q` counts
+    fn log_level(&self) -> log::LevelFilter {
+        self.log_level.unwrap_or(match (self.verbose, self.quiet) {
+            (0, 0) => log::LevelFilter::Warn,
+            (0, 1) => log::LevelFilter::Error,
+            (0, _) => log::LevelFilter::Off,
+            (1, _) => log::LevelFilter::Info,
+            (2, _) => log::LevelFilter::Debug,
+            _ => log::LevelFilter::Trace,
+        })
+    }
+
+    /// Get everything the converted G-code depends on besides the input file, for the cache
+    fn cache_key(&self) -> String {
+        let options = serde_json::to_string(&self.process_options())
+            .expect("Options are always serializable");
+        let outputs = (
+            &self.output,
+            &self.output_suffix,
+            self.archive_original,
+            self.sidecar_png,
+            self.no_embed,
+        );
+        let upload = (
+            self.upload,
+            &self.url,
+            &self.host,
+            self.select,
+            self.start_print,
+            &self.remote_printer,
+        );
         format!(
-            "\n; MKS_TFT_PREVIEW_POSTPROCESS\n\
-            ; Post processed by mks_tft_img v{} ({})\n\
-            ;  The original {} image was removed from here. Its size was {}x{}\n\
-            ;  simage = {}\n\
-            ;  gimage = {}\n",
-            env!("CARGO_PKG_VERSION"),
-            env!("CARGO_PKG_REPOSITORY"),
-            img_format,
-            img.width(),
-            img.height(),
-            args.simage_size,
-            args.gimage_size
+            "{} {:?} {:?} {:?} {:?} {:?} {:?} {:?}",
+            options,
+            self.printer_models,
+            self.image,
+            self.preview_source,
+            self.missing_thumbnail,
+            self.camera(),
+            outputs,
+            upload
         )
-        .as_bytes(),
-    )
-    .map_err(|e| log::error!("Failed to write postprocessing info: {}", e))?;
-    file.write_all(gcode_lines[gcode_lines.len() - 1].as_bytes())
-        .map_err(|e| log::error!("Failed to write original gcode: {}", e))?;
+    }
+
+    /// Get the arguments overridden by the directives of a G-code file, see [`directives::read`]
+    fn with_directives(&self, directives: &[String]) -> Result<Self, ()> {
+        let invalid = |e: clap::Error| {
+            let message = e.to_string();
+            let message = message.lines().next().unwrap_or_default();
+            log::error!(
+                "Invalid directive in the G-code: {}",
+                message.trim_start_matches("error: ")
+            );
+        };
+        // The paths are given on the command line, not by the directives
+        let program = std::iter::once(env!("CARGO_PKG_NAME").to_string());
+        let matches = Args::command()
+            .mut_arg("paths", |arg| arg.required(false))
+            .try_get_matches_from(program.chain(directives.iter().cloned()))
+            .map_err(invalid)?;
+        let given = Args::from_arg_matches(&matches).map_err(invalid)?;
+        let mut args = self.clone();
+        for id in given_ids(&matches) {
+            match id.as_str() {
+                "printer" => args.printer = given.printer,
+                "target" => args.target = given.target,
+                "simage_size" => args.simage_size = given.simage_size,
+                "gimage_size" => args.gimage_size = given.gimage_size.clone(),
+                "row_end" => args.row_end = given.row_end,
+                "trailing_semicolon" => args.trailing_semicolon = given.trailing_semicolon,
+                "filter" => args.filter = given.filter,
+                "integer_scale" => args.integer_scale = given.integer_scale,
+                "simage_quality" => args.simage_quality = given.simage_quality,
+                "linear_light" => args.linear_light = given.linear_light,
+                "icc" => args.icc = given.icc,
+                "autocrop" => args.autocrop = given.autocrop,
+                "auto_contrast" => args.auto_contrast = given.auto_contrast,
+                "auto_contrast_clip" => args.auto_contrast_clip = given.auto_contrast_clip,
+                "saturation" => args.saturation = given.saturation,
+                "replace_background" => args.replace_background = given.replace_background,
+                "dither" => args.dither = given.dither,
+                "lut" => args.lut = given.lut.clone(),
+                "footer_template" => args.footer_template = given.footer_template.clone(),
+                "no_footer" => args.no_footer = given.no_footer,
+                "inject_metadata" => args.inject_metadata = given.inject_metadata,
+                "m117" => args.m117 = given.m117.clone(),
+                "crc_trailer" => args.crc_trailer = given.crc_trailer,
+                "qr" => args.qr = given.qr.clone(),
+                "qr_corner" => args.qr_corner = given.qr_corner,
+                "preview_position" => args.preview_position = given.preview_position,
+                "missing_thumbnail" => args.missing_thumbnail = given.missing_thumbnail,
+                "preview_source" => args.preview_source = given.preview_source,
+                "render_view" => args.render_view = given.render_view,
+                "render_azimuth" => args.render_azimuth = given.render_azimuth,
+                "render_elevation" => args.render_elevation = given.render_elevation,
+                "render_zoom" => args.render_zoom = given.render_zoom,
+                "sidecar_png" => args.sidecar_png = given.sidecar_png,
+                // The group of all the arguments
+                "Args" => (),
+                id => {
+                    log::error!("`{}` cannot be set by a directive in the G-code", id);
+                    return Err(());
+                }
+            }
+            args.given.push(id);
+        }
+        Ok(args)
+    }
+
+    /// Get the printer of the printer model in the G-code metadata, from `--printer-models`
+    /// or the known models
+    fn model_printer(&self, meta: &PrintMeta) -> Result<Option<presets::Printer>, ()> {
+        let Some(model) = &meta.printer_model else {
+            return Ok(None);
+        };
+        let mut models = vec![];
+        if let Some(path) = &self.printer_models {
+            let read = std::fs::read_to_string(path)
+                .map_err(|e| log::error!("Cannot read `{}`: {}", path.display(), e))?;
+            let names: std::collections::BTreeMap<String, String> = serde_json::from_str(&read)
+                .map_err(|e| log::error!("Invalid printer models `{}`: {}", path.display(), e))?;
+            for (name, printer) in names {
+                let printer = presets::Printer::from_str(&printer, true).map_err(|_| {
+                    log::error!("Unknown printer `{}` in `{}`", printer, path.display());
+                })?;
+                models.push((name, printer));
+            }
+        }
+        let user = models.iter().map(|(name, printer)| (name.as_str(), *printer));
+        let printer = presets::Printer::from_model(model, user.chain(presets::MODELS));
+        match printer {
+            Some(printer) => {
+                let name = printer.to_possible_value().expect("There are no skipped values");
+                log::info!("Using printer `{}` for printer model `{}`", name.get_name(), model);
+            }
+            None => log::debug!("Printer model `{}` is not known", model),
+        }
+        Ok(printer)
+    }
+
+    /// Get the camera of rendered previews
+    fn camera(&self) -> render::Camera {
+        let mut camera = render::Camera::new(self.render_view).zoom(self.render_zoom);
+        if let Some(azimuth) = self.render_azimuth {
+            camera = camera.azimuth(azimuth);
+        }
+        if let Some(elevation) = self.render_elevation {
+            camera = camera.elevation(elevation);
+        }
+        camera
+    }
+
+    /// Get the LUT from `--lut` for the printer, or the one for all printers
+    fn printer_lut(&self) -> Option<Lut> {
+        let for_printer =
+            |printer| self.lut.iter().rev().find(|(lut_printer, _)| *lut_printer == printer);
+        let printer = self.printer.and_then(|printer| for_printer(Some(printer)));
+        printer.or_else(|| for_printer(None)).map(|(_, lut)| lut.clone())
+    }
+
+    /// Get the processing options, taking the ones not given from the printer preset
+    fn process_options(&self) -> ProcessOptions {
+        let preset = self.printer.map(presets::Printer::preset).unwrap_or_default();
+        let mut options = ProcessOptions::from_preset(preset)
+            .filter(self.filter)
+            .integer_scale(self.integer_scale)
+            .simage_quality(self.simage_quality)
+            .linear_light(self.linear_light)
+            .icc(self.icc)
+            .autocrop(self.autocrop)
+            .auto_contrast(self.auto_contrast.then_some(self.auto_contrast_clip))
+            .saturation(self.saturation)
+            .replace_background(self.replace_background)
+            .dither(self.dither)
+            .lut(self.printer_lut())
+            .strict(self.strict)
+            .reproducible(self.reproducible)
+            .footer_template(
+                self.footer_template.as_ref().map(|template| template.replace("\\n", "\n")),
+            )
+            .no_footer(self.no_footer)
+            .inject_metadata(self.inject_metadata)
+            .m117(self.m117.clone())
+            .crc_trailer(self.crc_trailer)
+            .qr(self.qr.clone(), self.qr_corner)
+            .preview_position(self.preview_position)
+            .max_memory(self.max_memory.map(|megabytes| megabytes * 1024 * 1024));
+        // Without a printer, the display the sizes are for is not known
+        if self.printer.is_some() {
+            options = options.max_sizes(&preset);
+        }
+        if let Some(target) = self.target {
+            options = options.target(target);
+        }
+        if let Some(size) = self.simage_size {
+            options = options.simage_size(size);
+        }
+        if let Some(row_end) = self.row_end {
+            options = options.row_end(row_end);
+        }
+        if let Some(trailing_semicolon) = self.trailing_semicolon {
+            options = options.trailing_semicolon(trailing_semicolon);
+        }
+        if let Some(sizes) = &self.gimage_size {
+            options = options.gimage_size(sizes[0]).extra_gimage_sizes(sizes[1..].to_vec());
+        }
+        match &self.config {
+            Some(config) => self.with_config(options, config),
+            None => options,
+        }
+    }
+
+    /// Get the processing options with the ones of `--config` that are not given as arguments
+    fn with_config(
+        &self,
+        options: ProcessOptions,
+        config: &serde_json::Map<String, serde_json::Value>,
+    ) -> ProcessOptions {
+        let given = |id: &str| self.given.iter().any(|given| given == id);
+        let mut merged = serde_json::to_value(&options).expect("Options are always serializable");
+        for (key, value) in config {
+            let overridden = match key.as_str() {
+                "target" | "simage_size" | "gimage_size" | "row_end" | "trailing_semicolon" => {
+                    given(key) || given("printer")
+                }
+                "extra_gimage_sizes" => given("gimage_size"),
+                "max_simage_size" | "max_gimage_size" => given("printer"),
+                "auto_contrast" => given("auto_contrast") || given("auto_contrast_clip"),
+                key => given(key),
+            };
+            if !overridden {
+                merged[key] = value.clone();
+            }
+        }
+        // Not part of the file, it is taken from `--lut`
+        merged.as_object_mut().expect("Options are an object").remove("lut");
+        let merged: ProcessOptions =
+            serde_json::from_value(merged).expect("The config is checked when it is read");
+        merged.lut(options.lut)
+    }
+}
+
+/// Get the ids of the arguments given explicitly, not by their defaults
+fn given_ids(matches: &clap::ArgMatches) -> Vec<String> {
+    matches
+        .ids()
+        .filter(|id| {
+            matches.value_source(id.as_str()) == Some(clap::parser::ValueSource::CommandLine)
+        })
+        .map(|id| id.as_str().to_string())
+        .collect()
+}
+
+/// Parse the `--config` file, see [`ProcessOptions::load`]
+///
+/// # Returns
+///
+/// The options set in the file
+fn parse_config(path: &str) -> Result<serde_json::Map<String, serde_json::Value>, String> {
+    let path = path::Path::new(path);
+    ProcessOptions::load(path)?;
+    let content = std::fs::read_to_string(path)
+        .map_err(|e| format!("Cannot read `{}`: {}", path.display(), e))?;
+    serde_json::from_str(&content).map_err(|e| format!("Invalid `{}`: {}", path.display(), e))
+}
+
+/// Convert a G-code file
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The G-code file
+/// * `progress` - Whether to show progress bars, if requested
+fn do_main(args: &Args, path: &path::Path, progress: bool) -> Result<(), Failure> {
+    logging::set_file(path);
+    if !path.exists() {
+        log::error!("File `{}` does not exist", path.display());
+        return Err(Failure::NotFound);
+    }
+    let meta = PrintMeta::from_file(path).unwrap_or_default();
+    let mut file_args = match directives::read(path).map_err(|_| Failure::Read)? {
+        directives if directives.is_empty() => None,
+        directives => Some(args.with_directives(&directives).map_err(|_| Failure::Read)?),
+    };
+    if file_args.as_ref().unwrap_or(args).printer.is_none() {
+        if let Some(printer) = args.model_printer(&meta).map_err(|_| Failure::Read)? {
+            file_args.get_or_insert_with(|| args.clone()).printer = Some(printer);
+        }
+    }
+    let args = file_args.as_ref().unwrap_or(args);
+    let mut options = args.process_options();
+    options.check_sizes().map_err(|_| Failure::Encode)?;
+    log::debug!(
+        "Using options {}",
+        serde_json::to_string(&options).expect("Options are always serializable")
+    );
+    let target = options.target;
+    match target {
+        Target::Mks => report::set_preview_sizes(
+            Some(options.simage_size.get()),
+            Some(options.gimage_size.get()),
+        ),
+        Target::Anycubic | Target::Creality | Target::Raw => {
+            report::set_preview_sizes(None, Some(options.gimage_size.get()))
+        }
+        Target::Flashforge => report::set_preview_sizes(None, None),
+    }
+    // Generated thumbnails are made for the largest gimage, so none is upscaled
+    let gimage_size = options.gimage_sizes().into_iter().max().unwrap_or(options.gimage_size);
+
+    let lock = lock_gcode(path, args.lock_timeout)?;
+    if threemf::is_3mf(path) || (args.zip && zip::is_zip(path)) {
+        return convert_archive(args, path, &options);
+    }
+    let mut summary = summary::Summary::new();
+    summary.phase("read");
+    let show_progress = progress && progress::is_supported();
+    let low_memory = options.max_memory.is_some();
+    if low_memory && args.mmap {
+        log::warn!("`--mmap` is ignored with `--max-memory`");
+    }
+    let mapped = if args.mmap && !low_memory {
+        Some(mmap::map_gcode(path).map_err(|_| Failure::Read)?)
+    } else {
+        None
+    };
+    // How much of the original G-code is replaced, the rest is copied as is
+    let replaced_len;
+    // How much of the G-code before the thumbnail is copied from the file,
+    // instead of being held in memory
+    let mut header_len = None;
+    let (header, image_lines, mut remainder, size): (Cow<[u8]>, _, Box<dyn Read>, _) = match &mapped
+    {
+        Some(mapped) => {
+            let (header, image_lines, remainder) =
+                mmap::split_gcode(mapped).map_err(|_| Failure::Parse)?;
+            replaced_len = (mapped.len() - remainder.len()) as u64;
+            (Cow::Borrowed(header), image_lines, Box::new(remainder), mapped.len() as u64)
+        }
+        None => {
+            let mut read = read_gcode(path, show_progress, !low_memory)?;
+            read.reader.get_mut().finish();
+            replaced_len = read.reader.get_ref().done()
+                - read.reader.buffer().len() as u64
+                - read.held.len() as u64;
+            if low_memory {
+                header_len = Some(read.header_len);
+            }
+            let size = read.reader.get_ref().total();
+            let remainder = Box::new(Cursor::new(read.held).chain(read.reader));
+            (Cow::Owned(read.header), read.image_lines, remainder, size)
+        }
+    };
+
+    let header_lines = || header.split(|&b| b == b'\n').map(String::from_utf8_lossy);
+    if header_lines().any(|line| !line.trim().is_empty() && !line.trim_start().starts_with(';')) {
+        log::info!(
+            "The thumbnail was found after {} lines of G-code. It is removed from there, \
+            and the preview is put at the top",
+            header_lines().count()
+        );
+    }
+
+    let line_ending = match &mapped {
+        Some(mapped) => line_ending(&mapped[..mapped.len().min(LINE_ENDING_SAMPLE)]),
+        None => {
+            let mut sample = vec![];
+            let _ = File::open(path)
+                .and_then(|file| file.take(LINE_ENDING_SAMPLE as u64).read_to_end(&mut sample));
+            line_ending(&sample)
+        }
+    };
+
+    summary.phase("decode");
+    let embedded = source::Embedded {
+        image_lines: &image_lines,
+        strict: options.strict,
+        limits: options.image_limits(),
+    };
+    let image_file = args.image.as_deref().map(|path| source::ImageFile {
+        path,
+        limits: options.image_limits(),
+    });
+    let placeholder = source::Placeholder {
+        size: gimage_size.into(),
+    };
+    let toolpath = source::Toolpath {
+        path,
+        size: gimage_size.into(),
+        camera: args.camera(),
+    };
+    let first_layer = source::FirstLayer {
+        path,
+        size: gimage_size.into(),
+        camera: args.camera(),
+    };
+    let mut sources: Vec<&dyn source::ThumbnailSource> = vec![];
+    match args.preview_source {
+        PreviewSource::Thumbnail => {
+            if let Some(image_file) = &image_file {
+                sources.push(image_file);
+            }
+            sources.push(&embedded);
+            match args.missing_thumbnail {
+                MissingThumbnail::Placeholder => sources.push(&placeholder),
+                MissingThumbnail::Render => sources.push(&toolpath),
+                MissingThumbnail::Ignore | MissingThumbnail::Warn | MissingThumbnail::Error => {}
+            }
+        }
+        PreviewSource::FirstLayer => sources.push(&first_layer),
+    }
+    let Some((thumbnail, source_info)) =
+        source::first_thumbnail(&sources).map_err(|_| Failure::Decode)?
+    else {
+        match args.missing_thumbnail {
+            MissingThumbnail::Ignore => {
+                log::info!("There is no image in gcode file. Leaving the original file unchanged");
+                return Err(Failure::NoThumbnail);
+            }
+            MissingThumbnail::Error => {
+                log::error!("There is no image in gcode file");
+                return Err(Failure::ThumbnailRequired);
+            }
+            _ => {
+                log::warn!("There is no image in gcode file. Leaving the original file unchanged");
+                return Err(Failure::NoThumbnail);
+            }
+        }
+    };
+    let img_format = thumbnail.format;
+    let img = thumbnail.into_image(options.icc);
+    summary.source_format = img_format.to_string();
+    summary.source_dimensions = (img.width(), img.height());
+
+    let file_name = slicer::output_name()
+        .as_deref()
+        .unwrap_or(path)
+        .file_name()
+        .unwrap_or_default()
+        .to_string_lossy()
+        .into_owned();
+    let mut info = FooterInfo {
+        source_info: &source_info,
+        source_format: img_format,
+        source_size: (img.width(), img.height()),
+        preview_info: "",
+        meta: &meta,
+        file_name: &file_name,
+    };
+
+    summary.phase("resize");
+    let (simage_img, mut gimage_imgs) = options.resize(&img);
+    mks_tft_img::add_qr_code(&options, &info, &mut gimage_imgs).map_err(|_| Failure::Encode)?;
+    let gimage_img = &gimage_imgs[0];
+    summary.phase("encode");
+    let encoder = target.encoder(options.row_end, options.trailing_semicolon);
+    let mut preview = encoder
+        .encode(&img, &simage_img, &gimage_imgs, line_ending)
+        .map_err(|_| Failure::Encode)?;
+    mks_tft_img::add_print_stats(&options, &meta, &mut preview);
+    mks_tft_img::add_crc_trailer(&options, &mut preview, line_ending);
+    summary.previews = preview.blocks.clone();
+
+    info.preview_info = &preview.info;
+    let footer = mks_tft_img::post_process_info(&options, &info, line_ending);
+    let footer = if args.archive_original {
+        let mut original = vec![];
+        File::open(path)
+            .and_then(|file| file.take(replaced_len).read_to_end(&mut original))
+            .map_err(|e| {
+                log::error!("Cannot read the original G-code to archive: {}", e);
+                Failure::Read
+            })?;
+        footer + &archive::archive_section(&original, line_ending).map_err(|_| Failure::Encode)?
+    } else {
+        footer
+    };
+
+    if args.dry_run {
+        let header = (replaced_len, header_len.ok_or(&*header));
+        let preview = (&preview, options.position());
+        return print_dry_run(args, path, header, preview, line_ending, &footer);
+    }
+
+    if args.no_embed {
+        summary.phase("export");
+        let output = args.output.as_deref().filter(|output| output.as_os_str() != "-");
+        let output = slicer::output_name().or(output.map(path::Path::to_path_buf));
+        write_sidecar_png(output.as_deref().unwrap_or(path), gimage_img)?;
+        summary.finish_phase();
+        log::info!("Summary:\n{}", summary);
+        return Ok(());
+    }
+
+    // The remainder of the G-code is streamed from the original file, so the
+    // new content goes to a temporary file first, which then replaces the
+    // original one. This also keeps the original file intact if writing fails
+    // mid process.
+    summary.phase("write");
+    let tmp_path = temporary_path(path);
+    let to_stdout = args.output.as_ref().is_some_and(|output| output.as_os_str() == "-");
+    let total = preview.data.len() as u64 + size;
+    storage::check_free_space(&tmp_path, total).map_err(|_| Failure::Write)?;
+    if let Some(output) = args.output.as_deref().filter(|_| !to_stdout) {
+        storage::check_free_space(output, total).map_err(|_| Failure::Write)?;
+    }
+    // Only rewriting the original file in place can leave it half written
+    let mut journal = match args.output.is_none() {
+        true => Some(
+            journal::Journal::start(path, args.fsync, args.write_retries)
+                .map_err(|_| Failure::Write)?,
+        ),
+        false => None,
+    };
+    log::debug!("Writing gcode with converted image to {}", tmp_path.display());
+    let file =
+        storage::retry(args.write_retries, "create the temporary file", || File::create(&tmp_path))
+            .map_err(|e| {
+                log::error!("Failed to open temporary gcode file for writing: {}", e);
+                Failure::Write
+            })?;
+    summary.input_size = size;
+    let file = storage::RetryWriter::new(file, args.write_retries);
+    let mut file = BufWriter::new(progress::Progress::new(file, "Writing", total, show_progress));
+
+    let position = options.position();
+    let written = match header_len {
+        Some(header_len) => File::open(path).and_then(|header| {
+            write_gcode_streamed(
+                &mut file,
+                &preview.data,
+                position,
+                &mut header.take(header_len),
+                line_ending,
+                &footer,
+                &mut remainder,
+            )
+        }),
+        None => write_gcode(
+            &mut file,
+            &preview.data,
+            position,
+            &header,
+            line_ending,
+            &footer,
+            &mut remainder,
+        ),
+    }
+    .and_then(|_| file.flush());
+    drop(file);
+    let written = match args.fsync && !to_stdout {
+        true => written.and_then(|_| storage::sync_file(&tmp_path, args.write_retries)),
+        false => written,
+    };
+    if let Err(e) = written {
+        log::error!("Failed to write gcode: {}", e);
+        let _ = std::fs::remove_file(&tmp_path);
+        return Err(Failure::Write);
+    }
+    let header_len = header_len.unwrap_or(header.len() as u64);
+    drop(header);
+    drop(remainder);
+    drop(mapped);
+
+    summary.output_size = std::fs::metadata(&tmp_path).map(|m| m.len()).unwrap_or_default();
+
+    if args.verify {
+        summary.phase("verify");
+        let offset = match position {
+            PreviewPosition::Top => 0,
+            PreviewPosition::Thumbnail => match header_len {
+                0 => 0,
+                header_len => header_len + line_ending.len() as u64,
+            },
+        };
+        verify_preview(
+            &tmp_path,
+            target,
+            encoder.as_ref(),
+            (offset, preview.data.len()),
+            &simage_img,
+            &gimage_imgs,
+        )
+        .and_then(|_| verify_crc_trailer(&tmp_path))
+        .map_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+            Failure::Verify
+        })?;
+    }
+    if let Some(journal) = &mut journal {
+        journal.written(&tmp_path).map_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+            Failure::Write
+        })?;
+    }
+
+    let output_name = match &args.output_suffix {
+        Some(suffix) => slicer::rename_output(path, suffix).map_err(|_| {
+            let _ = std::fs::remove_file(&tmp_path);
+            Failure::Export
+        })?,
+        None => slicer::output_name(),
+    };
 
+    let outputs = Outputs::new(args, path, output_name.as_deref());
+    sink::deliver(&outputs.sinks(), &tmp_path, &mut summary)?;
+    drop(lock);
+
+    if let (Some(url), false) = (&args.moonraker_url, to_stdout) {
+        let gcodes = args
+            .moonraker_gcodes
+            .clone()
+            .unwrap_or_else(|| klipper::default_printer_data().join("gcodes"));
+        let converted = outputs.new_file.as_ref().map_or(path, |new_file| new_file.path);
+        let _ = klipper::refresh_metadata(url, args.api_key.as_deref(), &gcodes, converted);
+    }
+
+    summary.phase("export");
+    if args.sidecar_png {
+        let output =
+            output_name.as_deref().or(outputs.new_file.as_ref().map(|new_file| new_file.path));
+        write_sidecar_png(output.unwrap_or(path), gimage_img)?;
+    }
+    if args.preview_out.is_some() || args.show {
+        let display_img = encoder.display_image(&img, gimage_img);
+        if let Some(preview_path) = &args.preview_out {
+            log::debug!("Saving display preview to {}", preview_path.display());
+            display_img.save_with_format(preview_path, image::ImageFormat::Png).map_err(|e| {
+                log::error!("Failed to save display preview to {}: {}", preview_path.display(), e);
+                Failure::Export
+            })?;
+        }
+        if args.show {
+            print!("{}", terminal::render_half_blocks(&display_img, terminal::columns()));
+        }
+    }
+
+    if let Some(raw_path) = &args.emit_raw {
+        log::debug!("Writing raw gimage to {}", raw_path.display());
+        let raw = match args.raw_format {
+            RawFormat::Bin => encoders::create_rgb565_raw(gimage_img),
+            RawFormat::C => encoders::create_rgb565_c_array("gimage", gimage_img).into_bytes(),
+        };
+        std::fs::write(raw_path, raw).map_err(|e| {
+            log::error!("Failed to write raw gimage to {}: {}", raw_path.display(), e);
+            Failure::Export
+        })?;
+    }
+
+    summary.finish_phase();
+    log::info!("Summary:\n{}", summary);
+    if args.summary {
+        eprintln!("{}", summary);
+    }
+    if args.timings {
+        eprint!("{}", summary.timings());
+    }
     Ok(())
 }
 
-/// Convert an RGB pixel to the RGB565 format
+/// Write the resized preview as PNG next to the G-code file
 ///
 /// # Arguments
 ///
-/// * `pixel` - A reference to an Rgb pixel
+/// * `gcode` - The G-code file, whose extension is replaced with `.png`
+/// * `image` - The resized preview
+fn write_sidecar_png(gcode: &path::Path, image: &DynamicImage) -> Result<(), Failure> {
+    let sidecar = gcode.with_extension("png");
+    log::debug!("Saving sidecar preview to {}", sidecar.display());
+    image.save_with_format(&sidecar, image::ImageFormat::Png).map_err(|e| {
+        log::error!("Failed to save sidecar preview to {}: {}", sidecar.display(), e);
+        Failure::Export
+    })
+}
+
+/// Convert the G-code in a 3MF archive, see [`threemf::convert`], or in a
+/// zip archive, see [`zip::convert_gcode`]
 ///
-/// # Returns
+/// Only the processing options are used, the thumbnails come from the
+/// archive and nothing but the archive is written.
 ///
-/// A tuple containing the higher and lower bytes of the RGB565 color
-fn rgb565(pixel: &Rgb<u8>) -> (u8, u8) {
-    let r = (pixel.0[0] as u16) >> 3;
-    let g = (pixel.0[1] as u16) >> 2;
-    let b = (pixel.0[2] as u16) >> 3;
-    let color = r << 11 | g << 5 | b;
-    ((color >> 8) as u8, (color & 0xFF) as u8)
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The archive
+/// * `options` - How to convert the thumbnails
+fn convert_archive(
+    args: &Args,
+    path: &path::Path,
+    options: &ProcessOptions,
+) -> Result<(), Failure> {
+    if args.image.is_some() || args.preview_out.is_some() {
+        log::warn!("`--image` and `--preview-out` are not used for archives");
+    }
+    let archive = RefCell::new(zip::Archive::open(path).map_err(|_| Failure::Read)?);
+    let converted = match threemf::is_3mf(path) {
+        true => threemf::convert(&archive.borrow(), options),
+        false => zip::convert_gcode(&archive.borrow(), options),
+    }
+    .map_err(|_| Failure::Encode)?;
+    if converted.is_empty() {
+        log::warn!("There is no G-code to convert in `{}`", path.display());
+        return Err(Failure::NoThumbnail);
+    }
+    if args.dry_run {
+        println!("{}: {} G-code files would be converted", path.display(), converted.len());
+        return Ok(());
+    }
+
+    // Each converted G-code goes into its member first, then the archive to the outputs
+    let mut summary = summary::Summary::new();
+    let tmp_path = temporary_path(path);
+    for (name, gcode) in &converted {
+        summary.phase("write");
+        storage::retry(args.write_retries, "write the temporary file", || {
+            std::fs::write(&tmp_path, gcode)
+        })
+        .map_err(|e| {
+            log::error!("Failed to write `{}`: {}", tmp_path.display(), e);
+            Failure::Write
+        })?;
+        let member = sink::ArchiveMember {
+            archive: &archive,
+            name,
+        };
+        sink::deliver(&[&member], &tmp_path, &mut summary)?;
+    }
+    summary.phase("write");
+    archive.borrow().save(&tmp_path).map_err(|_| {
+        let _ = std::fs::remove_file(&tmp_path);
+        Failure::Write
+    })?;
+    let outputs = Outputs::new(args, path, None);
+    sink::deliver(&outputs.sinks(), &tmp_path, &mut summary)?;
+    summary.finish_phase();
+    log::info!("Converted {} G-code files in `{}`", converted.len(), path.display());
+    log::info!("Summary:\n{}", summary);
+    Ok(())
+}
+
+/// The sinks the converted file is delivered to, as set by the arguments
+struct Outputs<'a> {
+    /// Replaces the original file, unless there is another output
+    replace_original: sink::ReplaceOriginal<'a>,
+    /// Writes the `--output` file
+    new_file: Option<sink::NewFile<'a>>,
+    /// Whether the output is written to stdout
+    to_stdout: bool,
+    /// Uploads the file with `--upload`
+    upload: Option<sink::Upload>,
+}
+
+impl<'a> Outputs<'a> {
+    /// Get the sinks of the arguments
+    ///
+    /// # Arguments
+    ///
+    /// * `args` - The command line arguments
+    /// * `path` - The original file
+    /// * `output_name` - Where the slicer saves the output, if it is renamed
+    fn new(args: &'a Args, path: &'a path::Path, output_name: Option<&path::Path>) -> Self {
+        let to_stdout = args.output.as_ref().is_some_and(|output| output.as_os_str() == "-");
+        let new_file = args.output.as_deref().filter(|_| !to_stdout).map(|path| sink::NewFile {
+            path,
+            fsync: args.fsync,
+            retries: args.write_retries,
+        });
+        let upload_name = output_name
+            .or(new_file.as_ref().map(|new_file| new_file.path))
+            .unwrap_or(path)
+            .file_name()
+            .unwrap_or_default()
+            .to_string_lossy()
+            .into_owned();
+        let upload = args.upload.map(|uploader| {
+            let url = match (&args.url, &args.host) {
+                (Some(url), _) => url.clone(),
+                (None, Some(host)) => format!("http://{}", host),
+                (None, None) => unreachable!("Clap requires one of `--url` and `--host`"),
+            };
+            sink::Upload {
+                options: upload::UploadOptions {
+                    uploader,
+                    url,
+                    api_key: args.api_key.clone(),
+                    select: args.select,
+                    print: args.start_print,
+                    remote_printer: args.remote_printer.clone(),
+                    retries: args.upload_retries,
+                },
+                name: upload_name,
+            }
+        });
+        Outputs {
+            replace_original: sink::ReplaceOriginal {
+                path,
+                fsync: args.fsync,
+                retries: args.write_retries,
+                preserve_metadata: args.preserve_metadata,
+            },
+            new_file,
+            to_stdout,
+            upload,
+        }
+    }
+
+    /// Get the stack of sinks: the file or stdout output, then the upload
+    fn sinks(&self) -> Vec<&dyn sink::OutputSink> {
+        let mut sinks: Vec<&dyn sink::OutputSink> = match &self.new_file {
+            Some(new_file) => vec![new_file],
+            None if self.to_stdout => vec![&sink::Stdout],
+            None => vec![&self.replace_original],
+        };
+        if let Some(upload) = &self.upload {
+            sinks.push(upload);
+        }
+        sinks
+    }
 }
 
-/// Create G-code representation of a TFT image
+/// Verify the preview written to the G-code file
+///
+/// The preview is decoded back from the beginning of the file and compared
+/// with the in-memory images it was created from.
 ///
 /// # Arguments
 ///
-/// * `prefix` - A string prefix for the G-code
-/// * `image` - The image to be converted
+/// * `path` - Path to the written G-code file
+/// * `target` - The display the preview was generated for
+/// * `encoder` - The encoder of the preview
+/// * `(offset, preview_len)` - Where the preview starts in the file, and its length
+/// * `simage` - The simage that was encoded
+/// * `gimages` - The gimages that were encoded
+fn verify_preview(
+    path: &path::Path,
+    target: Target,
+    encoder: &dyn PreviewEncoder,
+    (offset, preview_len): (u64, usize),
+    simage: &DynamicImage,
+    gimages: &[DynamicImage],
+) -> Result<(), ()> {
+    log::debug!("Verifying preview written to {}", path.display());
+    let mut written = Vec::with_capacity(preview_len);
+    File::open(path)
+        .and_then(|mut file| {
+            file.seek(std::io::SeekFrom::Start(offset))?;
+            file.take(preview_len as u64).read_to_end(&mut written)
+        })
+        .map_err(|e| log::error!("Cannot read written gcode for verification: {}", e))?;
+    let written = String::from_utf8_lossy(&written);
+
+    let Some(checks) = encoder.checks(&written, simage, gimages) else {
+        log::warn!("Verification is not supported for {} target", target);
+        return Ok(());
+    };
+
+    for (actual, expected) in checks {
+        let actual = actual.map_err(|e| log::error!("Verification failed: {}", e))?;
+        if actual != expected {
+            log::error!("Verification failed: expected {} image, found {}", expected, actual);
+            return Err(());
+        }
+        log::debug!("Verified {} image", actual);
+    }
+    Ok(())
+}
+
+/// Check the CRC32 trailer of the written preview, if it has one
 ///
-/// # Returns
+/// # Arguments
 ///
-/// A string containing the G-code for the image
-fn create_tft_image_gcode(prefix: &str, image: DynamicImage) -> String {
-    log::debug!(
-        "Creating tft image gcode with prefix `{}` and size {}x{}",
-        prefix,
-        image.width(),
-        image.height()
-    );
-    let mut tft_image = Vec::with_capacity(image.height() as usize);
-    let mut tft_line = Vec::with_capacity(image.width() as usize);
+/// * `path` - Path to the written G-code file
+fn verify_crc_trailer(path: &path::Path) -> Result<(), ()> {
+    let check = File::open(path)
+        .and_then(|file| verify::check_crc_trailer(BufReader::new(file)))
+        .map_err(|e| log::error!("Cannot check the CRC32 trailer: {}", e))?;
+    match check {
+        Some(check) if !check.is_valid() => {
+            log::error!(
+                "Verification failed: the CRC32 of the preview is {:08x}, the trailer says {:08x}",
+                check.actual,
+                check.expected
+            );
+            Err(())
+        }
+        Some(_) => {
+            log::debug!("Verified the CRC32 trailer");
+            Ok(())
+        }
+        None => Ok(()),
+    }
+}
 
-    for (i, pixel) in image.to_rgb8().pixels().enumerate() {
-        let (higher, lower) = rgb565(pixel);
-        tft_line.push(format!("{:02x}{:02x}", lower, higher));
+/// Advisory lock of a G-code file, released and removed when dropped
+struct GcodeLock {
+    file: File,
+    path: path::PathBuf,
+}
 
-        if (i + 1) % image.width() as usize == 0 {
-            tft_image.push(tft_line.join(""));
-            tft_line.clear();
+impl Drop for GcodeLock {
+    fn drop(&mut self) {
+        // Removed while still locked, so a process waiting for the lock notices that it
+        // got the lock of a removed file, and takes a new one
+        if let Err(e) = std::fs::remove_file(&self.path) {
+            log::warn!("Cannot remove the lock file `{}`: {}", self.path.display(), e);
         }
+        let _ = self.file.unlock();
     }
+}
 
-    format!("{}:{}\nM10086 ;\n", prefix, tft_image.join("\rM10086 ;"))
+/// Take an advisory lock on the G-code file for the duration of the rewrite
+///
+/// The lock is taken on a `<file>.mks_tft_img.lock` file next to the G-code,
+/// not on the G-code itself. The G-code is still read while the lock is held,
+/// which mandatory locks on Windows would not allow, and it is replaced by
+/// the converted file, so another process waiting for a lock on it would
+/// only get the lock of the replaced file. If the lock file cannot be
+/// created or locked, e.g. on a read-only file system, the file is processed
+/// without a lock.
+///
+/// # Arguments
+///
+/// * `path` - Path to the G-code file
+/// * `timeout` - How many seconds to wait for the lock
+///
+/// # Returns
+///
+/// The lock, which is released when dropped
+fn lock_gcode(path: &path::Path, timeout: u64) -> Result<Option<GcodeLock>, Failure> {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".mks_tft_img.lock");
+    let lock_path = path.with_file_name(file_name);
+    let deadline = std::time::Instant::now() + std::time::Duration::from_secs(timeout);
+    loop {
+        let file = match File::options().create(true).truncate(false).write(true).open(&lock_path) {
+            Ok(file) => file,
+            Err(e) => {
+                log::warn!(
+                    "Cannot create the lock file `{}`, continuing without a lock: {}",
+                    lock_path.display(),
+                    e
+                );
+                return Ok(None);
+            }
+        };
+        match file.try_lock() {
+            Ok(()) if is_same_file(&file, &lock_path) => {
+                log::debug!("Locked `{}`", path.display());
+                return Ok(Some(GcodeLock {
+                    file,
+                    path: lock_path,
+                }));
+            }
+            // The process that held the lock removed the file in the meantime
+            Ok(()) => continue,
+            Err(std::fs::TryLockError::WouldBlock) if std::time::Instant::now() < deadline => {
+                std::thread::sleep(std::time::Duration::from_millis(100));
+            }
+            Err(std::fs::TryLockError::WouldBlock) => {
+                log::error!(
+                    "File `{}` is locked by another process. Use `--lock-timeout` to wait for it",
+                    path.display()
+                );
+                return Err(Failure::Locked);
+            }
+            Err(std::fs::TryLockError::Error(e)) => {
+                log::warn!("Cannot lock `{}`, continuing without a lock: {}", path.display(), e);
+                return Ok(None);
+            }
+        }
+    }
+}
+
+/// Check whether an open file is still the one at a path, and not one that was
+/// removed, or replaced with another file
+fn is_same_file(file: &File, path: &path::Path) -> bool {
+    let (Ok(open), Ok(current)) = (file.metadata(), std::fs::metadata(path)) else {
+        return false;
+    };
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        open.dev() == current.dev() && open.ino() == current.ino()
+    }
+    #[cfg(not(unix))]
+    {
+        // Without inode numbers, a file that still exists is taken as the same file
+        let _ = (open, current);
+        true
+    }
+}
+
+/// Get a path of the temporary file to write the converted G-code to
+///
+/// The file is placed next to the original one, so it can be renamed over it.
+fn temporary_path(path: &path::Path) -> path::PathBuf {
+    let mut file_name = path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(".mks_tft_img.tmp");
+    path.with_file_name(file_name)
+}
+
+/// Print what a conversion would change, without writing anything
+///
+/// # Arguments
+///
+/// * `args` - The command line arguments
+/// * `path` - The G-code file
+/// * `(replaced_len, header)` - How much of the original G-code is replaced,
+///   and how much of it before the thumbnail is copied from the file, or the
+///   G-code before the thumbnail if it is kept
+/// * `(preview, position)` - The encoded preview and where it goes
+/// * `line_ending` - The line ending of the G-code
+/// * `footer` - The post processing info comment
+fn print_dry_run(
+    args: &Args,
+    path: &path::Path,
+    (replaced_len, header): (u64, Result<u64, &[u8]>),
+    (preview, position): (&Preview, PreviewPosition),
+    line_ending: &str,
+    footer: &str,
+) -> Result<(), Failure> {
+    let mut original = vec![];
+    File::open(path).and_then(|file| file.take(replaced_len).read_to_end(&mut original)).map_err(
+        |e| {
+            log::error!("Cannot read the original G-code header: {}", e);
+            Failure::Read
+        },
+    )?;
+    let header = match header {
+        Ok(header_len) => String::from_utf8_lossy(&original[..header_len as usize]),
+        Err(header) => String::from_utf8_lossy(header),
+    };
+    let new_len = (preview.data.len() + header.len() + footer.len()) as u64;
+    if !args.diff {
+        println!(
+            "{}: the first {} bytes would be replaced with {} bytes, the rest is unchanged",
+            path.display(),
+            replaced_len,
+            new_len
+        );
+        return Ok(());
+    }
+
+    let original = String::from_utf8_lossy(&original);
+    let old_lines = diff::collapse_thumbnails(&original.lines().collect::<Vec<_>>());
+    let header_lines = header.lines().map(str::to_string);
+    let preview_lines = diff::preview_lines(&preview.blocks).into_iter();
+    let mut new_lines: Vec<_> = match position {
+        PreviewPosition::Top => preview_lines.chain(header_lines).collect(),
+        PreviewPosition::Thumbnail => header_lines.chain(preview_lines).collect(),
+    };
+    // The footer starts with the line ending of the header
+    let footer = footer.strip_prefix(line_ending).filter(|_| !header.is_empty()).unwrap_or(footer);
+    new_lines.extend(footer.lines().map(str::to_string));
+    let color = args.color.enabled(std::io::stdout().is_terminal());
+    let name = path.display().to_string();
+    print!(
+        "{}",
+        diff::unified(&old_lines, &new_lines, &name, &format!("{} (converted)", name), color)
+    );
+    Ok(())
+}
+
+/// Reader of the G-code file
+type GcodeReader = BufReader<progress::Progress<File>>;
+
+/// G-code read by `read_gcode`
+struct ReadGcode {
+    /// The G-code before the thumbnail, without the line ending of its last
+    /// line, if it is kept
+    header: Vec<u8>,
+    /// The image lines of the thumbnail
+    image_lines: Vec<String>,
+    /// Length of the G-code before the thumbnail, without the line ending of
+    /// its last line
+    header_len: u64,
+    /// The lines read after the thumbnails, which go before the rest of the G-code
+    held: Vec<u8>,
+    /// Reader of the rest of the G-code
+    reader: GcodeReader,
 }
 
 /// Read G-code from a file and extract image data
 ///
-/// The image data is expected between `THUMBNAIL_BLOCK_START` and
-/// `THUMBNAIL_BLOCK_END` comments. Every string that is found before this
-/// block is added to the G-code lines vector unchanged, line by line (usually
-/// this is a header comment generated by the slicer). The comments are not
-/// added. Content between them is added to image lines vector. Each line is
-/// trimmed and the `;` symbol in the beginning is also removed. The rest of
-/// the G-code is added as a single unchanged string as the last element of the
-/// G-code lines vector.  
+/// The lines are fed to a [`ThumbnailParser`] up to the end of the
+/// thumbnails. The G-code found before them is kept byte for byte (usually this is a header comment generated by the
+/// slicer, but the thumbnails may also come after the start G-code or at the
+/// end of the file). The lines of the largest thumbnail are added to the image
+/// lines vector, trimmed and without the `;` symbol in the beginning. The rest
+/// of the G-code is not read, the returned reader is positioned at its
+/// beginning, so it can be streamed to the output. The blank lines that had to
+/// be read after the thumbnails to see whether more of them follow are
+/// returned separately, and go before the rest of the G-code.
+///
+/// If the end of a thumbnail is missing, reading stops with an error at the
+/// first line that doesn't look like image data, so the file is left intact
+/// instead of losing the G-code after the thumbnail.
 ///
 /// # Arguments
 ///
 /// * `path` - Path to the gcode file
+/// * `show_progress` - Whether to draw a progress bar on stderr
+/// * `keep_header` - Whether to keep the G-code before the thumbnail.
+///   Otherwise, only their length is returned, so they can be copied from the
+///   file instead of being held in memory
 ///
 /// # Returns
 ///
-/// A tuple containing a vector of G-code lines and a vector of image lines
-fn read_gcode(path: &path::PathBuf) -> Result<(Vec<String>, Vec<String>), ()> {
+/// The G-code before the thumbnail, the image lines, the length of the G-code before the
+/// thumbnail, the lines read after the thumbnails and the reader of the rest of
+/// the G-code, or [`Failure::Parse`] if the thumbnail block is malformed
+fn read_gcode(
+    path: &path::Path,
+    show_progress: bool,
+    keep_header: bool,
+) -> Result<ReadGcode, Failure> {
     log::info!("Reading gcode from `{}`", path.display());
-    let mut reader =
-        BufReader::new(File::open(path).map_err(|e| {
-            log::error!("Cannot open file `{}` for reading: {}", path.display(), e)
-        })?);
-
-    let mut gcode_lines = vec![];
-    let mut image_lines = vec![];
-    let mut reading_image = false;
-
-    for line_result in reader.by_ref().lines() {
-        let line = line_result.map_err(|e| log::error!("Failed to read from gcode file: {}", e))?;
-        if line.contains("THUMBNAIL_BLOCK_START") {
-            log::debug!("THUMBNAIL_BLOCK_START found");
-            reading_image = true;
-            continue;
-        }
-        if line.contains("THUMBNAIL_BLOCK_END") {
-            log::debug!("THUMBNAIL_BLOCK_END found");
+    let file = File::open(path).map_err(|e| {
+        log::error!("Cannot open file `{}` for reading: {}", path.display(), e);
+        Failure::Read
+    })?;
+    let size = file.metadata().map(|metadata| metadata.len()).unwrap_or_default();
+    let mut reader = BufReader::new(progress::Progress::new(file, "Reading", size, show_progress));
+
+    let mut parser = ThumbnailParser::new().without_gcode_lines();
+    let mut header = vec![];
+    let mut held = vec![];
+    let mut offset = 0;
+    let mut ending_len = 0;
+    let mut header_len = None;
+
+    // Lines are read as bytes, since some slicer plugins put stray non-UTF-8
+    // bytes in comments. They are only replaced for the parser, the G-code is
+    // written out as it was read.
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        let read = reader.read_until(b'\n', &mut buf).map_err(|e| {
+            log::error!("Failed to read from gcode file: {}", e);
+            Failure::Read
+        })?;
+        if read == 0 {
             break;
         }
-        if reading_image {
-            let clean_line = line.trim_start_matches(';').trim();
-            if !clean_line.is_empty() {
-                image_lines.push(clean_line.to_string());
+        let line = String::from_utf8_lossy(&buf);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        let step = parser.feed(line).map_err(|_| Failure::Parse)?;
+        if header_len.is_none() && parser.thumbnail_found() {
+            header_len = Some(offset - ending_len);
+        } else if header_len.is_none() && keep_header {
+            header.extend_from_slice(&buf);
+        }
+        offset += read as u64;
+        ending_len = match buf.as_slice() {
+            [.., b'\r', b'\n'] => 2,
+            [.., b'\n' | b'\r'] => 1,
+            _ => 0,
+        };
+        match step {
+            Step::Next => held.clear(),
+            Step::Hold => held.extend_from_slice(&buf),
+            Step::Stop => {
+                held.extend_from_slice(&buf);
+                break;
             }
-        } else {
-            gcode_lines.push(line);
-        }
-    }
-    let mut reminder = String::new();
-    reader
-        .read_to_string(&mut reminder)
-        .map_err(|e| log::error!("Failed to read from gcode file: {}", e))?;
-    gcode_lines.push(reminder);
-    Ok((gcode_lines, image_lines))
-}
-
-/// Initialize logging
-fn init_logging(log_file: &Option<path::PathBuf>, level: log::LevelFilter) -> Result<(), ()> {
-    use simplelog::*;
-    let mut loggers: Vec<Box<dyn SharedLogger>> = vec![];
-    loggers.push(TermLogger::new(
-        level,
-        Config::default(),
-        TerminalMode::Stderr,
-        ColorChoice::Auto,
-    ));
-    if let Some(path) = log_file {
-        loggers.push(WriteLogger::new(
-            level,
-            Config::default(),
-            File::create(path).map_err(|e| {
-                eprintln!("Failed to open log file {} for writing: {}", path.display(), e)
-            })?,
-        ))
-    }
-    CombinedLogger::init(loggers).expect("We don't expect any other loggers to be set");
-    log::debug!("Logging initialized");
-    Ok(())
+        }
+    }
+    let (_, image_lines) = parser.finish().map_err(|_| Failure::Parse)?;
+    header.truncate(mmap::strip_line_ending(&header).len());
+    Ok(ReadGcode {
+        header,
+        image_lines,
+        header_len: header_len.unwrap_or(offset - ending_len),
+        held,
+        reader,
+    })
+}
+
+#[cfg(test)]
+pub(crate) mod tests {
+    use super::*;
+    use base64::prelude::*;
+
+    /// Make G-code with a 16x16 PNG thumbnail block between two parts
+    pub(crate) fn thumbnail_gcode(before: &[u8], after: &[u8]) -> Vec<u8> {
+        let mut png = Cursor::new(vec![]);
+        image::RgbImage::from_pixel(16, 16, image::Rgb([0, 128, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let encoded = BASE64_STANDARD.encode(png.into_inner());
+        let block = format!(
+            "; THUMBNAIL_BLOCK_START\n; thumbnail begin 16x16 {}\n; {}\n; thumbnail end\n\
+             ; THUMBNAIL_BLOCK_END\n",
+            encoded.len(),
+            encoded
+        );
+        [before, block.as_bytes(), after].concat()
+    }
+
+    #[test]
+    fn keeps_all_gimage_sizes_without_a_printer() {
+        let args = Args::try_parse_from(["mks_tft_img", "-g", "200,300", "print.gcode"]).unwrap();
+        let options = args.process_options();
+        let converted = mks_tft_img::convert(&thumbnail_gcode(b"", b"G28\n"), &options).unwrap();
+        let converted = String::from_utf8_lossy(&converted);
+        assert!(converted.contains("\n;;gimage:"));
+        assert!(converted.contains("\n;;gimage300:"));
+    }
+
+    #[test]
+    fn keeps_invalid_utf8_before_the_thumbnail() {
+        let before = b"G28 ; caf\xe9\r\nG1 Z5\r\n";
+        let path =
+            std::env::temp_dir().join(format!("mks_tft_img_utf8_{}.gcode", std::process::id()));
+        std::fs::write(&path, thumbnail_gcode(before, b"G1 X10\n")).unwrap();
+        let read = read_gcode(&path, false, true);
+        let _ = std::fs::remove_file(&path);
+        let read = read.unwrap();
+        assert_eq!(read.header, b"G28 ; caf\xe9\r\nG1 Z5");
+        assert_eq!(read.header_len, read.header.len() as u64);
+        assert!(!read.image_lines.is_empty());
+    }
+
+    #[test]
+    fn reports_a_truncated_thumbnail_block_as_parse_failure() {
+        let gcode = thumbnail_gcode(b"G28\n", b"");
+        let marker = b"; THUMBNAIL_BLOCK_END";
+        let end = gcode.windows(marker.len()).position(|window| window == marker).unwrap();
+        let path = std::env::temp_dir()
+            .join(format!("mks_tft_img_truncated_{}.gcode", std::process::id()));
+        std::fs::write(&path, &gcode[..end]).unwrap();
+        let read = read_gcode(&path, false, true);
+        let _ = std::fs::remove_file(&path);
+        assert_eq!(read.err(), Some(Failure::Parse));
+        assert_eq!(read_gcode(&path, false, true).err(), Some(Failure::Read));
+        assert_eq!(serde_json::to_value(Failure::Parse).unwrap(), "parse");
+    }
 }
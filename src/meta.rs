@@ -0,0 +1,258 @@
+use serde::Serialize;
+use std::fs::File;
+use std::io::{Read, Seek, SeekFrom};
+use std::path;
+
+/// How many bytes at the beginning and at the end of the G-code are searched
+/// for metadata. Slicers write it in the header or in the config block at
+/// the end, so the moves in between are not read.
+pub const META_SAMPLE: usize = 256 * 1024;
+
+/// Print statistics the slicer wrote into the G-code comments
+///
+/// PrusaSlicer and its forks write them at the end of the file, OrcaSlicer
+/// and Bambu Studio also in the `HEADER_BLOCK` at the beginning, and Cura at
+/// the beginning. Values the slicer didn't write are `None`.
+#[derive(Serialize, Clone, Debug, Default, PartialEq)]
+pub struct PrintMeta {
+    /// Estimated printing time in seconds
+    pub estimated_time: Option<u64>,
+    /// Length of the filament used in mm, summed over the extruders
+    pub filament_length: Option<f64>,
+    /// Weight of the filament used in g, summed over the extruders
+    pub filament_weight: Option<f64>,
+    /// Layer height in mm
+    pub layer_height: Option<f64>,
+    /// Number of layers
+    pub layer_count: Option<u32>,
+    /// Nozzle temperature in °C, of the first extruder
+    pub nozzle_temperature: Option<u32>,
+    /// Bed temperature in °C
+    pub bed_temperature: Option<u32>,
+    /// The printer model of the slicer profile, e.g. `Ghost5`
+    pub printer_model: Option<String>,
+    /// Whether the G-code already has the Cura style comments, e.g. `;TIME:`
+    #[serde(skip)]
+    pub has_cura_comments: bool,
+}
+
+impl PrintMeta {
+    /// Extract the metadata from the G-code
+    ///
+    /// # Arguments
+    ///
+    /// * `gcode` - The content of the gcode file
+    pub fn from_gcode(gcode: &[u8]) -> Self {
+        if gcode.len() <= 2 * META_SAMPLE {
+            return Self::from_parts(gcode, &[]);
+        }
+        Self::from_parts(&gcode[..META_SAMPLE], &gcode[gcode.len() - META_SAMPLE..])
+    }
+
+    /// Extract the metadata from a G-code file, reading only its beginning and its end
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the gcode file
+    pub fn from_file(path: &path::Path) -> Result<Self, ()> {
+        let read = || -> std::io::Result<Self> {
+            let mut file = File::open(path)?;
+            let len = file.metadata()?.len();
+            let mut head = vec![];
+            let mut tail = vec![];
+            if len <= 2 * META_SAMPLE as u64 {
+                file.read_to_end(&mut head)?;
+            } else {
+                (&mut file).take(META_SAMPLE as u64).read_to_end(&mut head)?;
+                file.seek(SeekFrom::End(-(META_SAMPLE as i64)))?;
+                file.read_to_end(&mut tail)?;
+            }
+            Ok(Self::from_parts(&head, &tail))
+        };
+        read().map_err(|e| {
+            log::error!("Cannot read metadata from `{}`: {}", path.display(), e);
+        })
+    }
+
+    /// Extract the metadata from the beginning and the end of the G-code
+    fn from_parts(head: &[u8], tail: &[u8]) -> Self {
+        let mut meta = PrintMeta::default();
+        // The first layer may be thicker, it is needed to count PrusaSlicer layers
+        let mut first_layer_height = None;
+        let mut last_z = None;
+        for part in [head, tail] {
+            for line in String::from_utf8_lossy(part).lines() {
+                meta.parse_line(line.trim(), &mut first_layer_height, &mut last_z);
+            }
+        }
+        // PrusaSlicer doesn't write the layer count, but the height of each layer
+        if let (None, Some(layer_height), Some(last_z)) =
+            (meta.layer_count, meta.layer_height, last_z)
+        {
+            let first_layer_height = first_layer_height.unwrap_or(layer_height);
+            if layer_height > 0.0 && last_z >= first_layer_height {
+                meta.layer_count =
+                    Some(((last_z - first_layer_height) / layer_height).round() as u32 + 1);
+            }
+        }
+        log::debug!("Print metadata: {:?}", meta);
+        meta
+    }
+
+    /// Get the metadata as the header comments Cura writes
+    ///
+    /// Some MKS TFT firmware fills its print info screen from the `;TIME:`,
+    /// `;Filament used:` and `;Layer height:` comments, which only Cura writes.
+    ///
+    /// # Returns
+    ///
+    /// The comments of the known values, each ending with `\n`, or nothing
+    /// if the G-code already has them
+    pub fn cura_comments(&self) -> String {
+        if self.has_cura_comments {
+            log::debug!("The G-code already has Cura style metadata comments");
+            return String::new();
+        }
+        let mut comments = String::new();
+        if let Some(seconds) = self.estimated_time {
+            comments.push_str(&format!(";TIME:{}\n", seconds));
+        }
+        if let Some(mm) = self.filament_length {
+            comments.push_str(&format!(";Filament used: {:.5}m\n", mm / 1000.0));
+        }
+        if let Some(mm) = self.layer_height {
+            comments.push_str(&format!(";Layer height: {}\n", mm));
+        }
+        if comments.is_empty() {
+            log::warn!("There is no metadata in the G-code to inject");
+        }
+        comments
+    }
+
+    /// Take the metadata from a comment line, if it has any
+    fn parse_line(
+        &mut self,
+        line: &str,
+        first_layer_height: &mut Option<f64>,
+        last_z: &mut Option<f64>,
+    ) {
+        let Some(comment) = line.strip_prefix(';') else {
+            return;
+        };
+        // Bambu Studio writes `; model printing time: 1m 2s; total estimated time: 3m 4s`
+        if comment.trim_start().starts_with("model printing time") {
+            for pair in comment.split(';') {
+                self.parse_pair(pair, first_layer_height, last_z);
+            }
+        } else {
+            self.parse_pair(comment, first_layer_height, last_z);
+        }
+    }
+
+    /// Take the metadata from a key and a value in a comment
+    fn parse_pair(
+        &mut self,
+        comment: &str,
+        first_layer_height: &mut Option<f64>,
+        last_z: &mut Option<f64>,
+    ) {
+        // Cura writes `;KEY:value`, the others `; key = value` or `; key: value`
+        let Some((key, value)) = comment.split_once(" = ").or_else(|| comment.split_once(':'))
+        else {
+            return;
+        };
+        let (key, value) = (key.trim().to_lowercase(), value.trim());
+        match key.as_str() {
+            "time" => {
+                self.estimated_time = value.parse().ok().or(self.estimated_time);
+                self.has_cura_comments = true;
+            }
+            "estimated printing time (normal mode)" | "total estimated time" => {
+                self.estimated_time = parse_duration(value).or(self.estimated_time)
+            }
+            "filament used [mm]" | "total filament length [mm]" => {
+                self.filament_length = sum(value, "").or(self.filament_length)
+            }
+            "filament used" => {
+                self.filament_length =
+                    sum(value, "m").map(|meters| meters * 1000.0).or(self.filament_length)
+            }
+            "filament used [g]" | "total filament used [g]" | "total filament weight [g]" => {
+                self.filament_weight = sum(value, "").or(self.filament_weight)
+            }
+            "layer_height" | "layer height" => {
+                self.layer_height = value.parse().ok().or(self.layer_height)
+            }
+            "first_layer_height" => *first_layer_height = value.parse().ok(),
+            "layer_count" | "total layer number" | "total layers count" => {
+                self.layer_count = value.parse().ok().or(self.layer_count)
+            }
+            "temperature" | "nozzle_temperature" => {
+                self.nozzle_temperature = first_temperature(value).or(self.nozzle_temperature)
+            }
+            "bed_temperature" | "hot_plate_temp" => {
+                self.bed_temperature = first_temperature(value).or(self.bed_temperature)
+            }
+            "z" => *last_z = value.parse().ok().or(*last_z),
+            "printer_model" if !value.is_empty() => self.printer_model = Some(value.to_string()),
+            _ => (),
+        }
+    }
+}
+
+/// Parse a duration like `1d 2h 3m 4s`
+fn parse_duration(value: &str) -> Option<u64> {
+    let mut seconds = 0;
+    let mut found = false;
+    for part in value.split_whitespace() {
+        let (number, unit) = part.split_at(part.find(|c: char| !c.is_ascii_digit())?);
+        let number: u64 = number.parse().ok()?;
+        seconds += number
+            * match unit {
+                "d" => 86400,
+                "h" => 3600,
+                "m" => 60,
+                "s" => 1,
+                _ => return None,
+            };
+        found = true;
+    }
+    found.then_some(seconds)
+}
+
+/// Sum a comma separated list of numbers, one for each extruder
+///
+/// # Arguments
+///
+/// * `value` - The list, e.g. `1234.5, 0.0`
+/// * `unit` - The unit each number may end with, e.g. `m`
+fn sum(value: &str, unit: &str) -> Option<f64> {
+    value
+        .split(',')
+        .map(|number| number.trim().trim_end_matches(unit).trim().parse::<f64>().ok())
+        .sum()
+}
+
+/// Parse the temperature of the first extruder or plate from a list like `215,220`
+fn first_temperature(value: &str) -> Option<u32> {
+    let temperature: f64 = value.split(',').next()?.trim().parse().ok()?;
+    (temperature >= 0.0).then(|| temperature.round() as u32)
+}
+
+/// Format a duration like `1h 2m 3s`, the way slicers show it
+pub fn format_duration(seconds: u64) -> String {
+    let (days, hours, minutes, seconds) =
+        (seconds / 86400, seconds / 3600 % 24, seconds / 60 % 60, seconds % 60);
+    let mut parts = vec![];
+    if days > 0 {
+        parts.push(format!("{}d", days));
+    }
+    if days > 0 || hours > 0 {
+        parts.push(format!("{}h", hours));
+    }
+    if days > 0 || hours > 0 || minutes > 0 {
+        parts.push(format!("{}m", minutes));
+    }
+    parts.push(format!("{}s", seconds));
+    parts.join(" ")
+}
@@ -0,0 +1,121 @@
+use crate::parser::{Step, ThumbnailParser};
+use crate::slicer;
+
+/// The G-code before the thumbnail, image lines and the rest of the G-code
+type SplitGcode<'a> = (&'a [u8], Vec<String>, &'a [u8]);
+
+/// Memory-map the G-code file
+///
+/// # Arguments
+///
+/// * `path` - Path to the gcode file
+#[cfg(not(target_arch = "wasm32"))]
+pub fn map_gcode(path: &std::path::Path) -> Result<memmap2::Mmap, ()> {
+    log::info!("Memory-mapping gcode from `{}`", path.display());
+    let file = std::fs::File::open(path)
+        .map_err(|e| log::error!("Cannot open file `{}` for reading: {}", path.display(), e))?;
+    // SAFETY: The file is only read while it is mapped. If it is modified by
+    // another process in the meantime, the output may contain a mix of old and
+    // new content, which is the same as with regular reads.
+    unsafe { memmap2::Mmap::map(&file) }
+        .map_err(|e| log::error!("Cannot memory-map file `{}`: {}", path.display(), e))
+}
+
+/// Extract image data from memory-mapped G-code
+///
+/// This is the same as `read_gcode`, but the first thumbnail is located by
+/// searching bytes, and only the lines from there to the end of the
+/// thumbnails are fed to the parser. The G-code before the thumbnail and the
+/// rest of it are returned as slices of the input, so they are written out
+/// byte for byte.
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the gcode file
+///
+/// # Returns
+///
+/// A tuple containing the G-code before the thumbnail, without the line
+/// ending of its last line, a vector of image lines and the rest of the G-code
+pub fn split_gcode(gcode: &[u8]) -> Result<SplitGcode<'_>, ()> {
+    let header_lines =
+        gcode.split(|&b| b == b'\n').map(|line| std::str::from_utf8(line).unwrap_or_default());
+    let style = slicer::detect_generator(header_lines)
+        .map_or(slicer::ThumbnailStyle::Block, slicer::Generator::thumbnail_style);
+    let block = find(gcode, b"THUMBNAIL_BLOCK_START")
+        .map(|pos| gcode[..pos].iter().rposition(|&b| b == b'\n').map_or(0, |pos| pos + 1));
+    let comment = match style {
+        slicer::ThumbnailStyle::Comment => find_thumbnail_comment(gcode),
+        slicer::ThumbnailStyle::Block => None,
+    };
+    let Some(start) = block.into_iter().chain(comment).min() else {
+        return Ok((&[], vec![], gcode));
+    };
+    log::debug!("Thumbnail found at byte {}", start);
+
+    let mut parser = ThumbnailParser::with_style(style);
+    let mut pos = start;
+    let mut held = None;
+    while pos < gcode.len() {
+        let end = next_line(gcode, pos);
+        let line = String::from_utf8_lossy(&gcode[pos..end]);
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        let line = line.strip_suffix('\r').unwrap_or(line);
+        match parser.feed(line)? {
+            Step::Next => held = None,
+            Step::Hold => held = held.or(Some(pos)),
+            Step::Stop => {
+                held = held.or(Some(pos));
+                break;
+            }
+        }
+        pos = end;
+    }
+    let (_, image_lines) = parser.finish()?;
+
+    Ok((strip_line_ending(&gcode[..start]), image_lines, &gcode[held.unwrap_or(pos)..]))
+}
+
+/// Remove the line ending of the last line
+pub fn strip_line_ending(gcode: &[u8]) -> &[u8] {
+    let gcode = gcode.strip_suffix(b"\n").unwrap_or(gcode);
+    gcode.strip_suffix(b"\r").unwrap_or(gcode)
+}
+
+/// Find the beginning of the first `thumbnail begin` comment line
+fn find_thumbnail_comment(gcode: &[u8]) -> Option<usize> {
+    let mut pos = 0;
+    while pos < gcode.len() {
+        let end = next_line(gcode, pos);
+        if slicer::is_thumbnail_begin(&String::from_utf8_lossy(&gcode[pos..end])) {
+            return Some(pos);
+        }
+        pos = end;
+    }
+    None
+}
+
+/// Find the first occurrence of `needle` in `haystack`
+fn find(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    haystack.windows(needle.len()).position(|window| window == needle)
+}
+
+/// Find the beginning of the line that follows the one containing `pos`
+fn next_line(data: &[u8], pos: usize) -> usize {
+    data[pos..].iter().position(|&b| b == b'\n').map_or(data.len(), |p| pos + p + 1)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn borrows_the_gcode_around_the_thumbnail() {
+        let gcode = b"G28 ; caf\xe9\nG1 Z5\n; THUMBNAIL_BLOCK_START\n; thumbnail begin 1x1 4\n\
+                      ; AAAA\n; thumbnail end\n; THUMBNAIL_BLOCK_END\nG1 X10\n";
+        let (header, image_lines, remainder) = split_gcode(gcode).unwrap();
+        assert_eq!(header, b"G28 ; caf\xe9\nG1 Z5");
+        assert!(image_lines.iter().any(|line| line == "AAAA"));
+        assert_eq!(remainder, b"G1 X10\n");
+    }
+}
@@ -0,0 +1,94 @@
+//! Conversion for async services
+//!
+//! Converting a thumbnail keeps a CPU busy for a while, which would stall
+//! the other tasks of a tokio runtime. These functions run the conversion on
+//! the blocking thread pool of the runtime, so a service can convert several
+//! files at the same time while it keeps handling its connections.
+
+use crate::options::ProcessOptions;
+use crate::source::ThumbnailSource;
+
+/// Convert the thumbnail of G-code in memory, without blocking the runtime
+///
+/// This is [`crate::convert`] on the blocking thread pool of the tokio
+/// runtime it is awaited in.
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - How to convert the thumbnail
+///
+/// # Returns
+///
+/// The converted G-code, or an error if there is no thumbnail or it cannot
+/// be converted
+pub async fn convert(gcode: Vec<u8>, options: ProcessOptions) -> Result<Vec<u8>, ()> {
+    convert_with(gcode, options, None).await
+}
+
+/// Convert the thumbnail of G-code in memory, with another thumbnail to use
+/// if the G-code has none, without blocking the runtime
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - How to convert the thumbnail
+/// * `fallback` - Where the thumbnail comes from if there is none in the G-code
+///
+/// # Returns
+///
+/// The converted G-code, or an error if there is no thumbnail or it cannot
+/// be converted
+pub async fn convert_with(
+    gcode: Vec<u8>,
+    options: ProcessOptions,
+    fallback: Option<Box<dyn ThumbnailSource + Send>>,
+) -> Result<Vec<u8>, ()> {
+    tokio::task::spawn_blocking(move || {
+        let fallback = fallback.as_deref().map(|source| source as &dyn ThumbnailSource);
+        crate::convert_with(&gcode, &options, fallback)
+    })
+    .await
+    .map_err(|e| log::error!("The conversion did not finish: {}", e))?
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use base64::prelude::*;
+    use std::io::Cursor;
+
+    #[test]
+    fn converts_files_concurrently() {
+        let mut png = Cursor::new(vec![]);
+        image::RgbImage::from_pixel(16, 16, image::Rgb([0, 0, 255]))
+            .write_to(&mut png, image::ImageFormat::Png)
+            .unwrap();
+        let encoded = BASE64_STANDARD.encode(png.into_inner());
+        let gcode = format!(
+            "; THUMBNAIL_BLOCK_START\n; thumbnail begin 16x16 {}\n; {}\n; thumbnail end\n\
+             ; THUMBNAIL_BLOCK_END\nG28\n",
+            encoded.len(),
+            encoded
+        )
+        .into_bytes();
+        let expected = crate::convert(&gcode, &ProcessOptions::default()).unwrap();
+
+        let runtime = tokio::runtime::Builder::new_current_thread().build().unwrap();
+        let converted = runtime.block_on(async {
+            let tasks: Vec<_> = (0..4)
+                .map(|_| tokio::spawn(convert(gcode.clone(), ProcessOptions::default())))
+                .collect();
+            let mut converted = vec![];
+            for task in tasks {
+                converted.push(task.await.unwrap());
+            }
+            converted
+        });
+        assert!(converted.into_iter().all(|gcode| gcode == Ok(expected.clone())));
+        assert_eq!(
+            runtime.block_on(convert(b"G28\n".to_vec(), ProcessOptions::default())),
+            Err(())
+        );
+    }
+}
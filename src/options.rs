@@ -0,0 +1,723 @@
+use crate::lut::Lut;
+use crate::presets::Preset;
+use crate::qr::Corner;
+use crate::Target;
+use crate::{adjust, color};
+use clap::ValueEnum;
+use image::imageops::{self, FilterType};
+use image::{DynamicImage, GenericImageView, RgbaImage};
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+
+/// How many times the simage size the thumbnail is resized to first, with
+/// [`SimageQuality::High`]
+const SUPERSAMPLING: u32 = 4;
+
+/// Width and height of a square preview image, in pixels
+///
+/// Sizes are checked when they are made, so a size of 0 or one no display
+/// could show doesn't get to the resize.
+#[derive(Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Hash)]
+#[serde(try_from = "u16", into = "u16")]
+pub struct PreviewSize(u16);
+
+impl PreviewSize {
+    /// The largest size of a preview image
+    pub const MAX: u16 = 1024;
+
+    /// Get a size, if it is from 1 to [`PreviewSize::MAX`]
+    pub fn new(pixels: u16) -> Option<Self> {
+        (1..=Self::MAX).contains(&pixels).then_some(PreviewSize(pixels))
+    }
+
+    /// Get the size in pixels
+    pub fn get(self) -> u16 {
+        self.0
+    }
+}
+
+impl TryFrom<u16> for PreviewSize {
+    type Error = String;
+
+    fn try_from(pixels: u16) -> Result<Self, Self::Error> {
+        PreviewSize::new(pixels).ok_or_else(|| {
+            format!("{} is not a preview size from 1 to {}", pixels, PreviewSize::MAX)
+        })
+    }
+}
+
+impl From<PreviewSize> for u16 {
+    fn from(size: PreviewSize) -> Self {
+        size.0
+    }
+}
+
+impl From<PreviewSize> for u32 {
+    fn from(size: PreviewSize) -> Self {
+        size.0.into()
+    }
+}
+
+impl std::str::FromStr for PreviewSize {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        let pixels =
+            value.parse::<u16>().map_err(|_| format!("`{}` is not a size in pixels", value))?;
+        PreviewSize::try_from(pixels)
+    }
+}
+
+impl std::fmt::Display for PreviewSize {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        self.0.fmt(f)
+    }
+}
+
+/// Filters to resize the thumbnail with
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Filter {
+    /// Nearest neighbor, keeps pixel art sharp
+    Nearest,
+    /// Linear
+    Triangle,
+    /// Cubic
+    CatmullRom,
+    /// Gaussian, softer than cubic
+    Gaussian,
+    /// Lanczos with window 3, the sharpest and the slowest
+    Lanczos3,
+}
+
+impl Filter {
+    /// Get the filter of the `image` crate
+    pub fn filter_type(self) -> FilterType {
+        match self {
+            Filter::Nearest => FilterType::Nearest,
+            Filter::Triangle => FilterType::Triangle,
+            Filter::CatmullRom => FilterType::CatmullRom,
+            Filter::Gaussian => FilterType::Gaussian,
+            Filter::Lanczos3 => FilterType::Lanczos3,
+        }
+    }
+}
+
+impl std::fmt::Display for Filter {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// How carefully the simage is downscaled
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum SimageQuality {
+    /// A single pass of the resize filter
+    Normal,
+    /// Resized to 4 times the size first, then averaged down, which reduces
+    /// aliasing on thin features of the model
+    High,
+}
+
+impl std::fmt::Display for SimageQuality {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// What goes after each MKS image row, before the next `M10086 ;` command
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum RowEnd {
+    /// A carriage return, which most MKS firmwares expect
+    Cr,
+    /// A carriage return and a line feed
+    Crlf,
+    /// A line feed
+    Lf,
+}
+
+impl RowEnd {
+    /// Get the characters the row ends with
+    pub fn as_str(self) -> &'static str {
+        match self {
+            RowEnd::Cr => "\r",
+            RowEnd::Crlf => "\r\n",
+            RowEnd::Lf => "\n",
+        }
+    }
+}
+
+impl std::fmt::Display for RowEnd {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// How the colors of the preview are reduced to the ones of the display
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Dither {
+    /// Each color is rounded down to the nearest one the display shows
+    None,
+    /// Floyd-Steinberg error diffusion, smoother gradients at the cost of
+    /// some noise, see [`crate::color::dither_rgb565`]
+    #[value(alias = "fs")]
+    FloydSteinberg,
+}
+
+impl std::fmt::Display for Dither {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Where in the G-code the preview is written
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum PreviewPosition {
+    /// At the beginning of the file, where MKS firmware looks for it
+    Top,
+    /// In place of the slicer thumbnail, after the G-code before it, for
+    /// firmware that reads the slicer header first
+    Thumbnail,
+}
+
+impl std::fmt::Display for PreviewPosition {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Options of processing a thumbnail into a preview
+///
+/// The same options are used by the command line tool and the library
+/// interfaces. They start from the settings of a printer preset, which can
+/// then be overridden one by one, e.g.
+/// `ProcessOptions::from_preset(preset).gimage_size(size).filter(Filter::Lanczos3)`.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+#[serde(default, deny_unknown_fields)]
+pub struct ProcessOptions {
+    /// The display the preview is generated for
+    pub target: Target,
+    /// The size of the simage
+    pub simage_size: PreviewSize,
+    /// The size of the gimage
+    pub gimage_size: PreviewSize,
+    /// Sizes of additional gimages, for firmware looking for larger previews
+    pub extra_gimage_sizes: Vec<PreviewSize>,
+    /// What goes after each MKS image row
+    pub row_end: RowEnd,
+    /// Whether the `M10086` command after each MKS image row ends with ` ;`
+    pub trailing_semicolon: bool,
+    /// The filter to resize the thumbnail with
+    pub filter: Filter,
+    /// How carefully the simage is downscaled
+    pub simage_quality: SimageQuality,
+    /// Whether to scale the thumbnail by whole factors only and pad it,
+    /// instead of scaling it fractionally
+    pub integer_scale: bool,
+    /// Whether to resize in linear light instead of gamma-encoded sRGB
+    pub linear_light: bool,
+    /// Whether to convert thumbnails tagged with an ICC profile to sRGB
+    pub icc: bool,
+    /// Whether to trim the empty borders of the thumbnail before resizing
+    pub autocrop: bool,
+    /// Percentage of the darkest and lightest values clipped when stretching
+    /// the histogram of the thumbnail, if it is stretched
+    pub auto_contrast: Option<f32>,
+    /// What the saturation of the thumbnail is multiplied by, if it is changed
+    pub saturation: Option<f32>,
+    /// The color to replace the flat background of the thumbnail with, if it
+    /// is replaced
+    pub replace_background: Option<[u8; 3]>,
+    /// How the colors of the resized previews are reduced to the display ones
+    pub dither: Dither,
+    /// Where in the G-code the preview is written
+    pub preview_position: PreviewPosition,
+    /// Color calibration of the display panel, applied to the resized previews
+    #[serde(skip_deserializing)]
+    pub lut: Option<Lut>,
+    /// The largest simage the printer shows, if it is known
+    pub max_simage_size: Option<PreviewSize>,
+    /// The largest gimage the printer shows, if it is known
+    pub max_gimage_size: Option<PreviewSize>,
+    /// Whether a thumbnail not matching its `thumbnail begin` line, or a size
+    /// larger than the printer shows, is an error
+    pub strict: bool,
+    /// How many bytes of memory decoding the thumbnail may take, if limited
+    pub max_memory: Option<u64>,
+    /// Whether to leave out everything that depends on the tool version, so a
+    /// new release gives the same output
+    pub reproducible: bool,
+    /// Template of the post-processing info comment, the default one if not set
+    pub footer_template: Option<String>,
+    /// Whether to leave out the post-processing info comment
+    pub no_footer: bool,
+    /// Whether to add Cura style metadata comments, which some MKS firmware
+    /// shows on its print info screen
+    pub inject_metadata: bool,
+    /// Template of an `M117` message shown on the display when the print
+    /// starts, with the placeholders of the footer template
+    pub m117: Option<String>,
+    /// Whether to add a comment with the CRC32 of the preview after it, so
+    /// a corrupted copy of the G-code can be detected
+    pub crc_trailer: bool,
+    /// Text of a QR code to draw into the gimage, with the placeholders of
+    /// the footer template
+    pub qr: Option<String>,
+    /// The corner of the gimage to draw the QR code in
+    pub qr_corner: Corner,
+}
+
+impl Default for ProcessOptions {
+    fn default() -> Self {
+        ProcessOptions::from_preset(Preset::default())
+    }
+}
+
+impl ProcessOptions {
+    /// Get the default options
+    pub fn new() -> Self {
+        ProcessOptions::default()
+    }
+
+    /// Get the options with the settings of a printer preset
+    pub fn from_preset(preset: Preset) -> Self {
+        ProcessOptions {
+            target: preset.target,
+            simage_size: preset.simage_size,
+            gimage_size: preset.gimage_size,
+            extra_gimage_sizes: vec![],
+            row_end: preset.row_end,
+            trailing_semicolon: preset.trailing_semicolon,
+            filter: Filter::CatmullRom,
+            simage_quality: SimageQuality::Normal,
+            integer_scale: false,
+            linear_light: false,
+            icc: false,
+            autocrop: false,
+            auto_contrast: None,
+            saturation: None,
+            replace_background: None,
+            dither: Dither::None,
+            preview_position: PreviewPosition::Top,
+            lut: None,
+            max_simage_size: None,
+            max_gimage_size: None,
+            strict: false,
+            max_memory: None,
+            reproducible: false,
+            footer_template: None,
+            no_footer: false,
+            inject_metadata: false,
+            m117: None,
+            crc_trailer: false,
+            qr: None,
+            qr_corner: Corner::BottomRight,
+        }
+    }
+
+    /// Set the display the preview is generated for
+    pub fn target(mut self, target: Target) -> Self {
+        self.target = target;
+        self
+    }
+
+    /// Set the size of the simage
+    pub fn simage_size(mut self, size: PreviewSize) -> Self {
+        self.simage_size = size;
+        self
+    }
+
+    /// Set the size of the gimage
+    pub fn gimage_size(mut self, size: PreviewSize) -> Self {
+        self.gimage_size = size;
+        self
+    }
+
+    /// Set the sizes of additional gimages, which are written after the gimage
+    pub fn extra_gimage_sizes(mut self, sizes: Vec<PreviewSize>) -> Self {
+        self.extra_gimage_sizes = sizes;
+        self
+    }
+
+    /// Set what goes after each MKS image row
+    pub fn row_end(mut self, row_end: RowEnd) -> Self {
+        self.row_end = row_end;
+        self
+    }
+
+    /// Set whether the `M10086` command after each MKS image row ends with ` ;`
+    pub fn trailing_semicolon(mut self, trailing_semicolon: bool) -> Self {
+        self.trailing_semicolon = trailing_semicolon;
+        self
+    }
+
+    /// Set the filter to resize the thumbnail with
+    pub fn filter(mut self, filter: Filter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    /// Set how carefully the simage is downscaled
+    pub fn simage_quality(mut self, simage_quality: SimageQuality) -> Self {
+        self.simage_quality = simage_quality;
+        self
+    }
+
+    /// Set whether to scale the thumbnail by whole factors only, padding it
+    /// to the size, which keeps the edges of pixel art crisp with
+    /// [`Filter::Nearest`]
+    pub fn integer_scale(mut self, integer_scale: bool) -> Self {
+        self.integer_scale = integer_scale;
+        self
+    }
+
+    /// Set whether to resize in linear light, see [`crate::color::resize_linear`]
+    pub fn linear_light(mut self, linear_light: bool) -> Self {
+        self.linear_light = linear_light;
+        self
+    }
+
+    /// Set whether to convert thumbnails tagged with an ICC profile to sRGB,
+    /// see [`crate::color::to_srgb`]
+    pub fn icc(mut self, icc: bool) -> Self {
+        self.icc = icc;
+        self
+    }
+
+    /// Set whether to trim the empty borders of the thumbnail before
+    /// resizing, see [`crate::adjust::autocrop`]
+    pub fn autocrop(mut self, autocrop: bool) -> Self {
+        self.autocrop = autocrop;
+        self
+    }
+
+    /// Set whether to stretch the histogram of the thumbnail, and the
+    /// percentage of values clipped at each end, see [`crate::adjust::auto_contrast`]
+    pub fn auto_contrast(mut self, clip: Option<f32>) -> Self {
+        self.auto_contrast = clip;
+        self
+    }
+
+    /// Set what the saturation of the thumbnail is multiplied by, see
+    /// [`crate::adjust::saturate`]
+    pub fn saturation(mut self, factor: Option<f32>) -> Self {
+        self.saturation = factor;
+        self
+    }
+
+    /// Set the color to replace the flat background of the thumbnail with,
+    /// see [`crate::adjust::replace_background`]
+    pub fn replace_background(mut self, color: Option<[u8; 3]>) -> Self {
+        self.replace_background = color;
+        self
+    }
+
+    /// Set how the colors of the resized previews are reduced to the display
+    /// ones, see [`Dither`]
+    pub fn dither(mut self, dither: Dither) -> Self {
+        self.dither = dither;
+        self
+    }
+
+    /// Set where in the G-code the preview is written, see [`PreviewPosition`]
+    pub fn preview_position(mut self, position: PreviewPosition) -> Self {
+        self.preview_position = position;
+        self
+    }
+
+    /// Set the color calibration of the display panel, see [`Lut`]
+    pub fn lut(mut self, lut: Option<Lut>) -> Self {
+        self.lut = lut;
+        self
+    }
+
+    /// Set the largest simage and gimage the printer shows, see
+    /// [`ProcessOptions::check_sizes`]
+    pub fn max_sizes(mut self, preset: &Preset) -> Self {
+        self.max_simage_size = Some(preset.max_simage_size);
+        self.max_gimage_size = Some(preset.max_gimage_size);
+        self
+    }
+
+    /// Set whether a thumbnail not matching its `thumbnail begin` line, or a
+    /// size larger than the printer shows, is an error
+    pub fn strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
+    /// Limit how many bytes of memory decoding the thumbnail may take
+    pub fn max_memory(mut self, max_memory: Option<u64>) -> Self {
+        self.max_memory = max_memory;
+        self
+    }
+
+    /// Set whether to leave out everything that depends on the tool version
+    pub fn reproducible(mut self, reproducible: bool) -> Self {
+        self.reproducible = reproducible;
+        self
+    }
+
+    /// Set the template of the post-processing info comment, see [`crate::footer::render`]
+    pub fn footer_template(mut self, template: Option<String>) -> Self {
+        self.footer_template = template;
+        self
+    }
+
+    /// Set whether to leave out the post-processing info comment, e.g. for
+    /// firmware with tiny comment buffers
+    pub fn no_footer(mut self, no_footer: bool) -> Self {
+        self.no_footer = no_footer;
+        self
+    }
+
+    /// Set whether to add Cura style metadata comments, see
+    /// [`crate::meta::PrintMeta::cura_comments`]
+    pub fn inject_metadata(mut self, inject_metadata: bool) -> Self {
+        self.inject_metadata = inject_metadata;
+        self
+    }
+
+    /// Set the template of an `M117` message shown on the display when the
+    /// print starts, see [`crate::post_process_info`]
+    pub fn m117(mut self, template: Option<String>) -> Self {
+        self.m117 = template;
+        self
+    }
+
+    /// Set whether to add a comment with the CRC32 of the preview after it,
+    /// see [`crate::add_crc_trailer`]
+    pub fn crc_trailer(mut self, crc_trailer: bool) -> Self {
+        self.crc_trailer = crc_trailer;
+        self
+    }
+
+    /// Set the text of a QR code to draw into a corner of the gimage, see
+    /// [`crate::add_qr_code`]
+    pub fn qr(mut self, text: Option<String>, corner: Corner) -> Self {
+        self.qr = text;
+        self.qr_corner = corner;
+        self
+    }
+
+    /// Get where the preview is actually written
+    ///
+    /// Binary previews, like the Flashforge header, are only read at the
+    /// beginning of the file, so they always go there.
+    pub fn position(&self) -> PreviewPosition {
+        match self.target.is_binary() {
+            true => PreviewPosition::Top,
+            false => self.preview_position,
+        }
+    }
+
+    /// Load the options from a JSON file, with the fields that are not in
+    /// the file set to the defaults
+    ///
+    /// The LUT is not part of the file, it is set with [`ProcessOptions::lut`].
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the file
+    ///
+    /// # Returns
+    ///
+    /// The options, or an error message if the file cannot be read or has an
+    /// unknown or invalid field
+    pub fn load(path: &std::path::Path) -> Result<Self, String> {
+        let content = std::fs::read_to_string(path)
+            .map_err(|e| format!("Cannot read `{}`: {}", path.display(), e))?;
+        serde_json::from_str(&content).map_err(|e| format!("Invalid `{}`: {}", path.display(), e))
+    }
+
+    /// Get the memory limits of image decoders
+    pub fn image_limits(&self) -> image::io::Limits {
+        let mut limits = image::io::Limits::default();
+        if let Some(max_memory) = self.max_memory {
+            limits.max_alloc = Some(max_memory);
+        }
+        limits
+    }
+
+    /// Check the simage and gimage sizes against the largest ones the printer
+    /// shows, see [`ProcessOptions::max_sizes`]
+    ///
+    /// Sizes that are too large are reduced to the largest ones with a
+    /// warning, or are an error in strict mode.
+    ///
+    /// # Returns
+    ///
+    /// `Err` if a size is too large in strict mode
+    pub fn check_sizes(&mut self) -> Result<(), ()> {
+        let strict = self.strict;
+        let check = |name: &str, size: PreviewSize, max: Option<PreviewSize>| match max {
+            Some(max) if size > max && strict => {
+                log::error!(
+                    "The {} size {} is larger than the {} the printer supports",
+                    name,
+                    size,
+                    max
+                );
+                Err(())
+            }
+            Some(max) if size > max => {
+                log::warn!(
+                    "The {} size {} is larger than the {} the printer supports, {} is used instead",
+                    name,
+                    size,
+                    max,
+                    max
+                );
+                Ok(max)
+            }
+            _ => Ok(size),
+        };
+        self.simage_size = check("simage", self.simage_size, self.max_simage_size)?;
+        self.gimage_size = check("gimage", self.gimage_size, self.max_gimage_size)?;
+        for size in &mut self.extra_gimage_sizes {
+            *size = check("gimage", *size, self.max_gimage_size)?;
+        }
+        Ok(())
+    }
+
+    /// Get the sizes of all gimages, the gimage first and then the additional
+    /// ones, without repeating a size
+    pub fn gimage_sizes(&self) -> Vec<PreviewSize> {
+        let mut sizes = vec![self.gimage_size];
+        for &size in &self.extra_gimage_sizes {
+            if !sizes.contains(&size) {
+                sizes.push(size);
+            }
+        }
+        sizes
+    }
+
+    /// Crop the thumbnail and adjust its colors, as set in the options
+    pub fn adjust<'a>(&self, img: &'a DynamicImage) -> Cow<'a, DynamicImage> {
+        let mut img = Cow::Borrowed(img);
+        // First, so the borders don't count in the histogram
+        if self.autocrop {
+            img = Cow::Owned(adjust::autocrop(&img));
+        }
+        if let Some(clip) = self.auto_contrast {
+            img = Cow::Owned(adjust::auto_contrast(&img, clip));
+        }
+        if let Some(factor) = self.saturation {
+            img = Cow::Owned(adjust::saturate(&img, factor));
+        }
+        // Last, so the background gets exactly the color
+        if let Some(color) = self.replace_background {
+            img = Cow::Owned(adjust::replace_background(&img, color));
+        }
+        img
+    }
+
+    /// Adjust the colors of the thumbnail, see [`ProcessOptions::adjust`],
+    /// resize it to the simage and gimage sizes and calibrate them with the LUT
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing the simage and the gimages in the order of
+    /// [`ProcessOptions::gimage_sizes`], so the gimage is always the first one
+    pub fn resize(&self, img: &DynamicImage) -> (DynamicImage, Vec<DynamicImage>) {
+        let img = &*self.adjust(img);
+        let filter = self.filter.filter_type();
+        let resize = |size: u32| match self.linear_light {
+            true => color::resize_linear(img, size, size, filter),
+            false => img.resize(size, size, filter),
+        };
+        let resize = |size: u32| match self.integer_scale {
+            true => self.resize_integer(img, size),
+            false => resize(size),
+        };
+        // Last, so the colors don't change until they are reduced to the display ones
+        let calibrate = |image: DynamicImage| match &self.lut {
+            Some(lut) => lut.apply(&image),
+            None => image,
+        };
+        // Only the RGB565 displays have fewer colors than the thumbnail
+        let dithered = matches!(self.target, Target::Mks | Target::Raw)
+            && self.dither == Dither::FloydSteinberg;
+        let calibrate = |image: DynamicImage| match dithered {
+            true => color::dither_rgb565(&calibrate(image)),
+            false => calibrate(image),
+        };
+        let gimage_sizes = self.gimage_sizes();
+        let gimages: Vec<_> =
+            gimage_sizes.iter().map(|&size| calibrate(resize(size.into()))).collect();
+        // A gimage of the simage size is the simage already, unless that is supersampled
+        let same_size = gimage_sizes
+            .iter()
+            .position(|&size| size == self.simage_size)
+            .filter(|_| self.integer_scale || self.simage_quality == SimageQuality::Normal);
+        if let Some(index) = same_size {
+            log::debug!("Using gimage {} as the simage, it has the same size", gimage_sizes[index]);
+            return (gimages[index].clone(), gimages);
+        }
+        let simage = match self.simage_quality {
+            // Averaging would blur the edges integer scaling keeps crisp
+            _ if self.integer_scale => resize(self.simage_size.into()),
+            SimageQuality::Normal => resize(self.simage_size.into()),
+            SimageQuality::High => color::downscale_area(
+                &resize(u32::from(self.simage_size) * SUPERSAMPLING),
+                SUPERSAMPLING,
+                self.linear_light,
+            ),
+        };
+        (calibrate(simage), gimages)
+    }
+
+    /// Scale an image by a whole factor, up or down, to fit in a size, and pad
+    /// it to the size a fractional scale would give, centered
+    fn resize_integer(&self, img: &DynamicImage, size: u32) -> DynamicImage {
+        let (width, height) = img.dimensions();
+        let (scaled_width, scaled_height) = match width <= size && height <= size {
+            true => {
+                let factor = (size / width.max(1)).min(size / height.max(1));
+                (width * factor, height * factor)
+            }
+            false => {
+                let divisor = width.div_ceil(size).max(height.div_ceil(size));
+                ((width / divisor).max(1), (height / divisor).max(1))
+            }
+        };
+        let filter = self.filter.filter_type();
+        let scaled = match self.linear_light {
+            true => color::resize_linear(img, scaled_width, scaled_height, filter),
+            false => img.resize_exact(scaled_width, scaled_height, filter),
+        };
+        let (padded_width, padded_height) = fit(width, height, size);
+        log::debug!(
+            "Scaling the thumbnail to {}x{}, padded to {}x{}",
+            scaled_width,
+            scaled_height,
+            padded_width,
+            padded_height
+        );
+        let mut padded = RgbaImage::new(padded_width, padded_height);
+        imageops::overlay(
+            &mut padded,
+            &scaled.to_rgba8(),
+            ((padded_width - scaled_width) / 2).into(),
+            ((padded_height - scaled_height) / 2).into(),
+        );
+        color::with_alpha_of(img, padded)
+    }
+}
+
+/// Get the size an image is resized to, to fit in a square keeping its
+/// aspect ratio, the same way as [`DynamicImage::resize`]
+fn fit(width: u32, height: u32, size: u32) -> (u32, u32) {
+    let ratio = f64::min(size as f64 / width as f64, size as f64 / height as f64);
+    let fitted = |value: u32| ((value as f64 * ratio).round() as u32).max(1);
+    (fitted(width), fitted(height))
+}
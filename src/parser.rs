@@ -0,0 +1,567 @@
+use crate::{parse_thumbnail_begin, slicer};
+
+/// Where in the G-code the parser is
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum State {
+    /// Comments at the beginning of the file, where the slicer is recognized
+    Header,
+    /// G-code before the first thumbnail
+    Body,
+    /// Between `THUMBNAIL_BLOCK_START` and `THUMBNAIL_BLOCK_END`
+    Block,
+    /// Between `thumbnail begin` and `thumbnail end` outside a block
+    Comment,
+    /// Right after a thumbnail, where more thumbnails may follow
+    AfterThumbnail,
+}
+
+/// What the caller does with a line fed to the parser
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Step {
+    /// The line has been taken, feed the next one
+    Next,
+    /// The line is held back, since it belongs to the rest of the G-code
+    /// unless another thumbnail follows
+    Hold,
+    /// The line and the lines held back are the beginning of the rest of the
+    /// G-code, which is not parsed
+    Stop,
+}
+
+/// Parser of the G-code up to the end of its thumbnails
+///
+/// The G-code is fed line by line. Lines before the first thumbnail are kept
+/// as G-code lines. A thumbnail is either a `THUMBNAIL_BLOCK_START` to
+/// `THUMBNAIL_BLOCK_END` block, which may hold several `thumbnail begin` to
+/// `thumbnail end` comments, or just these comments for slicers that don't
+/// wrap them in a block, like Cura. Thumbnails that directly follow each other
+/// are all taken, and the largest one is used.
+pub struct ThumbnailParser {
+    state: State,
+    style: slicer::ThumbnailStyle,
+    keep_gcode_lines: bool,
+    gcode_lines: Vec<String>,
+    thumbnails: Vec<Vec<String>>,
+    current: Vec<String>,
+}
+
+impl Default for ThumbnailParser {
+    fn default() -> Self {
+        ThumbnailParser::new()
+    }
+}
+
+impl ThumbnailParser {
+    pub fn new() -> Self {
+        ThumbnailParser {
+            state: State::Header,
+            style: slicer::ThumbnailStyle::Block,
+            keep_gcode_lines: true,
+            gcode_lines: vec![],
+            thumbnails: vec![],
+            current: vec![],
+        }
+    }
+
+    /// Create a parser for G-code whose slicer is already known
+    ///
+    /// # Arguments
+    ///
+    /// * `style` - How the slicer embeds thumbnails
+    pub fn with_style(style: slicer::ThumbnailStyle) -> Self {
+        ThumbnailParser {
+            state: State::Body,
+            style,
+            ..ThumbnailParser::new()
+        }
+    }
+
+    /// Don't keep the G-code lines before the thumbnail, e.g. to copy them
+    /// from the file instead of holding them in memory
+    pub fn without_gcode_lines(mut self) -> Self {
+        self.keep_gcode_lines = false;
+        self
+    }
+
+    /// Whether the beginning of the first thumbnail has been fed
+    pub fn thumbnail_found(&self) -> bool {
+        !matches!(self.state, State::Header | State::Body)
+    }
+
+    /// Feed the next line of the G-code
+    ///
+    /// # Arguments
+    ///
+    /// * `line` - The line without its line ending
+    ///
+    /// # Returns
+    ///
+    /// What to do with the line, or an error if a thumbnail is broken, e.g.
+    /// its end is missing
+    pub fn feed(&mut self, line: &str) -> Result<Step, ()> {
+        match self.state {
+            State::Header | State::Body => {
+                if self.state == State::Header {
+                    self.detect_generator(line);
+                }
+                if line.contains("THUMBNAIL_BLOCK_START") {
+                    log::debug!("THUMBNAIL_BLOCK_START found");
+                    self.state = State::Block;
+                } else if self.style == slicer::ThumbnailStyle::Comment
+                    && slicer::is_thumbnail_begin(line)
+                {
+                    log::debug!("Thumbnail comment found");
+                    self.state = State::Comment;
+                    self.push_image_line(line);
+                } else {
+                    if line.contains("THUMBNAIL_BLOCK_END") {
+                        log::warn!(
+                            "THUMBNAIL_BLOCK_END without THUMBNAIL_BLOCK_START, ignoring it"
+                        );
+                    }
+                    if self.keep_gcode_lines {
+                        self.gcode_lines.push(line.to_string());
+                    }
+                }
+                Ok(Step::Next)
+            }
+            State::Block => {
+                if line.contains("THUMBNAIL_BLOCK_END") {
+                    log::debug!("THUMBNAIL_BLOCK_END found");
+                    self.finish_block()?;
+                    self.state = State::AfterThumbnail;
+                    return Ok(Step::Next);
+                }
+                if line.contains("THUMBNAIL_BLOCK_START") {
+                    log::error!(
+                        "THUMBNAIL_BLOCK_START inside the thumbnail block, its end is missing"
+                    );
+                    return Err(());
+                }
+                self.check_image_line(line)?;
+                self.push_image_line(line);
+                Ok(Step::Next)
+            }
+            State::Comment => {
+                if slicer::is_thumbnail_begin(line) {
+                    log::error!("`thumbnail begin` inside the thumbnail, its end is missing");
+                    return Err(());
+                }
+                self.check_image_line(line)?;
+                self.push_image_line(line);
+                if slicer::is_thumbnail_end(line) {
+                    self.thumbnails.push(std::mem::take(&mut self.current));
+                    self.state = State::AfterThumbnail;
+                }
+                Ok(Step::Next)
+            }
+            State::AfterThumbnail => {
+                if line.contains("THUMBNAIL_BLOCK_START") {
+                    log::debug!("Another THUMBNAIL_BLOCK_START found");
+                    self.state = State::Block;
+                    Ok(Step::Next)
+                } else if self.style == slicer::ThumbnailStyle::Comment
+                    && slicer::is_thumbnail_begin(line)
+                {
+                    log::debug!("Another thumbnail comment found");
+                    self.state = State::Comment;
+                    self.push_image_line(line);
+                    Ok(Step::Next)
+                } else if line.trim_start_matches(';').trim().is_empty() {
+                    Ok(Step::Hold)
+                } else {
+                    Ok(Step::Stop)
+                }
+            }
+        }
+    }
+
+    /// Finish parsing at the end of the G-code or where the parser stopped
+    ///
+    /// # Returns
+    ///
+    /// A tuple containing a vector of G-code lines before the thumbnail and
+    /// the image lines of the largest thumbnail, from `thumbnail begin` to
+    /// `thumbnail end` with the `;` and spaces around them removed
+    pub fn finish(self) -> Result<(Vec<String>, Vec<String>), ()> {
+        if matches!(self.state, State::Block | State::Comment) {
+            log::error!("The G-code ended inside the thumbnail block, the file may be truncated");
+            return Err(());
+        }
+        if self.thumbnails.len() > 1 {
+            log::info!("{} thumbnails found, using the largest one", self.thumbnails.len());
+        }
+        let image_lines = self
+            .thumbnails
+            .into_iter()
+            .max_by_key(|lines| {
+                let declared = lines.first().and_then(|line| parse_thumbnail_begin(line));
+                let area = declared.map_or(0, |(width, height, _)| width as u64 * height as u64);
+                (area, lines.iter().map(String::len).sum::<usize>())
+            })
+            .unwrap_or_default();
+        Ok((self.gcode_lines, image_lines))
+    }
+
+    /// Recognize the slicer from the header comments
+    fn detect_generator(&mut self, line: &str) {
+        if line.starts_with(';') {
+            if let Some(generator) = slicer::Generator::detect(line) {
+                log::info!("G-code generated by {}", generator);
+                self.style = generator.thumbnail_style();
+                self.state = State::Body;
+            }
+        } else if !line.trim().is_empty() {
+            self.state = State::Body;
+        }
+    }
+
+    /// Check that a line of a thumbnail looks like image data
+    ///
+    /// Otherwise, the end of the thumbnail is probably missing, and the G-code
+    /// would be lost with it.
+    fn check_image_line(&self, line: &str) -> Result<(), ()> {
+        if slicer::is_image_line(line) {
+            return Ok(());
+        }
+        log::error!(
+            "Unexpected line in the thumbnail block, its end is probably missing: `{}`",
+            line
+        );
+        Err(())
+    }
+
+    /// Add a line to the current thumbnail
+    fn push_image_line(&mut self, line: &str) {
+        let clean_line = line.trim_start_matches(';').trim();
+        if !clean_line.is_empty() {
+            self.current.push(clean_line.to_string());
+        }
+    }
+
+    /// Split the lines of a finished block into its thumbnails
+    ///
+    /// A block without `thumbnail begin` lines is taken as a single thumbnail,
+    /// whose first and last lines stand for the markers.
+    fn finish_block(&mut self) -> Result<(), ()> {
+        let lines = std::mem::take(&mut self.current);
+        if !lines.iter().any(|line| is_marker(line, slicer::is_thumbnail_begin)) {
+            match lines.len() {
+                0 => (),
+                1 => {
+                    log::error!("The thumbnail block has a single line, the thumbnail is damaged");
+                    return Err(());
+                }
+                _ => self.thumbnails.push(lines),
+            }
+            return Ok(());
+        }
+        let mut thumbnail: Option<Vec<String>> = None;
+        for line in lines {
+            if is_marker(&line, slicer::is_thumbnail_begin) {
+                if thumbnail.is_some() {
+                    log::error!("`thumbnail begin` inside the thumbnail, its end is missing");
+                    return Err(());
+                }
+                thumbnail = Some(vec![line]);
+            } else if let Some(lines) = thumbnail.as_mut() {
+                let is_end = is_marker(&line, slicer::is_thumbnail_end);
+                lines.push(line);
+                if is_end {
+                    self.thumbnails.extend(thumbnail.take());
+                }
+            }
+        }
+        if thumbnail.is_some() {
+            log::error!("`thumbnail end` is missing in the thumbnail block");
+            return Err(());
+        }
+        Ok(())
+    }
+}
+
+/// Check a cleaned image line, which has no `;`, with a marker check
+fn is_marker(line: &str, check: fn(&str) -> bool) -> bool {
+    check(&format!(";{}", line))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PRUSASLICER_HEADER: &str = "\
+; generated by PrusaSlicer 2.7.1+win64 on 2024-06-01 at 10:00:00 UTC
+
+; 
+";
+
+    const ORCASLICER_HEADER: &str = "\
+; HEADER_BLOCK_START
+; generated by OrcaSlicer 2.1.1 on 2024-06-01 at 10:00:00
+; total layer number: 120
+; estimated printing time (normal mode) = 1h 2m 3s
+; HEADER_BLOCK_END
+
+";
+
+    const CURA_HEADER: &str = "\
+;FLAVOR:Marlin
+;TIME:3723
+;Filament used: 1.2345m
+;Layer height: 0.2
+;MINX:90.2
+;MINY:90.2
+;MINZ:0.2
+;MAXX:129.8
+;MAXY:129.8
+;MAXZ:20
+;Generated with Cura_SteamEngine 5.7.1
+";
+
+    /// A 16x16 PNG, as the slicers write it
+    const SMALL: &str = "\
+; thumbnail begin 16x16 104
+; iVBORw0KGgoAAAANSUhEUgAAABAAAAAQCAIAAACQkWg2AAAAFUlEQVR4nGNg+M9AGhrVMKph+GoAAJ
+; Hq/wEkpOWMAAAAAElFTkSuQmCC
+; thumbnail end
+";
+
+    /// A larger thumbnail, the data doesn't matter to the parser
+    const LARGE: &str = "\
+; thumbnail begin 120x120 160
+; iVBORw0KGgoAAAANSUhEUgAAAHgAAAB4CAIAAAC2BqGFAAByoklEQVR4nAzQ8UcrAQDA8ZmZmRkzMz
+; Mz5syZc445M3POOGdmZo6ZM2cSSZIkkqRvEkmSJJIkSSRJkkiSJJEkSSJJkkSSPE/64b3Pn/BxuVy4
+; thumbnail end
+";
+
+    const BODY: &str = "\
+; external perimeters extrusion width = 0.45mm
+M73 P0 R10
+G28
+G1 X10 Y10 E1.0
+";
+
+    /// What the parser found in the G-code
+    #[derive(Debug)]
+    struct Parsed {
+        gcode_lines: Vec<String>,
+        image_lines: Vec<String>,
+        /// The rest of the G-code, from the line the parser stopped at
+        rest: Vec<String>,
+    }
+
+    /// Feed all lines of the G-code, the way `read_gcode` does
+    fn parse(gcode: &str) -> Result<Parsed, ()> {
+        let mut parser = ThumbnailParser::new();
+        let mut lines = gcode.lines();
+        let mut held = vec![];
+        for line in lines.by_ref() {
+            match parser.feed(line)? {
+                Step::Next => held.clear(),
+                Step::Hold => held.push(line),
+                Step::Stop => {
+                    held.push(line);
+                    break;
+                }
+            }
+        }
+        let (gcode_lines, image_lines) = parser.finish()?;
+        let rest = held.into_iter().chain(lines).map(str::to_string).collect();
+        Ok(Parsed {
+            gcode_lines,
+            image_lines,
+            rest,
+        })
+    }
+
+    fn block(thumbnails: &[&str]) -> String {
+        format!("; THUMBNAIL_BLOCK_START\n;\n{};\n; THUMBNAIL_BLOCK_END\n", thumbnails.join(";\n"))
+    }
+
+    fn lines(text: &str) -> Vec<String> {
+        text.lines().map(str::to_string).collect()
+    }
+
+    /// The image lines of a thumbnail, without `;` and spaces
+    fn image_lines(thumbnail: &str) -> Vec<String> {
+        thumbnail.lines().map(|line| line.trim_start_matches(';').trim().to_string()).collect()
+    }
+
+    #[test]
+    fn prusaslicer() {
+        let gcode = format!("{}{}\n{}", PRUSASLICER_HEADER, block(&[LARGE]), BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.gcode_lines, lines(PRUSASLICER_HEADER));
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert_eq!(parsed.rest, lines(&format!("\n{}", BODY)));
+    }
+
+    #[test]
+    fn orcaslicer() {
+        let gcode = format!("{}{}\n{}", ORCASLICER_HEADER, block(&[SMALL, LARGE]), BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.gcode_lines, lines(ORCASLICER_HEADER));
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert_eq!(parsed.rest, lines(&format!("\n{}", BODY)));
+    }
+
+    #[test]
+    fn cura() {
+        let gcode = format!("{}{}{};LAYER_COUNT:100\n{}", CURA_HEADER, SMALL, LARGE, BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.gcode_lines, lines(CURA_HEADER));
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert_eq!(parsed.rest, lines(&format!(";LAYER_COUNT:100\n{}", BODY)));
+    }
+
+    #[test]
+    fn cura_thumbnails_are_only_comments_for_cura() {
+        // Without a Cura header, thumbnails have to be in a block
+        let gcode = format!("{}{}{}", PRUSASLICER_HEADER, LARGE, BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert!(parsed.image_lines.is_empty());
+        assert_eq!(parsed.gcode_lines, lines(&gcode));
+    }
+
+    #[test]
+    fn largest_of_several_blocks() {
+        let gcode =
+            format!("{}{};\n{}\n{}", PRUSASLICER_HEADER, block(&[LARGE]), block(&[SMALL]), BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert_eq!(parsed.rest, lines(&format!("\n{}", BODY)));
+    }
+
+    #[test]
+    fn only_the_first_group_of_blocks() {
+        let gcode = format!("{}{}{}{}", PRUSASLICER_HEADER, block(&[SMALL]), BODY, block(&[LARGE]));
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.image_lines, image_lines(SMALL));
+        assert_eq!(parsed.rest, lines(&format!("{}{}", BODY, block(&[LARGE]))));
+    }
+
+    #[test]
+    fn thumbnail_after_gcode() {
+        let gcode = format!("{}{}{}\nG1 X20\n", PRUSASLICER_HEADER, BODY, block(&[LARGE]));
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.gcode_lines, lines(&format!("{}{}", PRUSASLICER_HEADER, BODY)));
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert_eq!(parsed.rest, lines("\nG1 X20"));
+    }
+
+    #[test]
+    fn thumbnail_at_the_end_of_the_file() {
+        let gcode = format!("{}{}{}", PRUSASLICER_HEADER, BODY, block(&[LARGE]));
+        let parsed = parse(gcode.trim_end()).unwrap();
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert!(parsed.rest.is_empty());
+
+        let gcode = format!("{}{}{}", CURA_HEADER, BODY, LARGE);
+        let parsed = parse(gcode.trim_end()).unwrap();
+        assert_eq!(parsed.image_lines, image_lines(LARGE));
+        assert!(parsed.rest.is_empty());
+    }
+
+    #[test]
+    fn no_thumbnail() {
+        let gcode = format!("{}{}", PRUSASLICER_HEADER, BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert!(parsed.image_lines.is_empty());
+        assert_eq!(parsed.gcode_lines, lines(&gcode));
+        assert!(parsed.rest.is_empty());
+    }
+
+    #[test]
+    fn block_without_markers() {
+        let data = "; iVBORw0KGgoAAAANSUhEUgAAABAAAAAQCAIAAACQkWg2\n; AAAAFUlEQVR4nGNg+M9AGhrVMKph+GoAAJ\n";
+        let gcode = format!(
+            "{}; THUMBNAIL_BLOCK_START\n{}; THUMBNAIL_BLOCK_END\n{}",
+            PRUSASLICER_HEADER, data, BODY
+        );
+        let parsed = parse(&gcode).unwrap();
+        assert_eq!(parsed.image_lines, image_lines(data));
+    }
+
+    #[test]
+    fn block_with_a_single_line() {
+        let gcode = "; THUMBNAIL_BLOCK_START\n; iVBORw0KGgo\n; THUMBNAIL_BLOCK_END\nG28\n";
+        assert!(parse(gcode).is_err());
+    }
+
+    #[test]
+    fn empty_block() {
+        let gcode =
+            format!("{}; THUMBNAIL_BLOCK_START\n; THUMBNAIL_BLOCK_END\n{}", CURA_HEADER, BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert!(parsed.image_lines.is_empty());
+    }
+
+    #[test]
+    fn missing_block_end() {
+        // The G-code after the thumbnail would be lost with it
+        let gcode = format!("{}; THUMBNAIL_BLOCK_START\n{}{}", PRUSASLICER_HEADER, LARGE, BODY);
+        assert!(parse(&gcode).is_err());
+        // Truncated file
+        let gcode = format!("{}; THUMBNAIL_BLOCK_START\n{}", PRUSASLICER_HEADER, LARGE);
+        assert!(parse(&gcode).is_err());
+    }
+
+    #[test]
+    fn missing_block_start() {
+        let gcode = format!("{}{}; THUMBNAIL_BLOCK_END\n{}", PRUSASLICER_HEADER, LARGE, BODY);
+        let parsed = parse(&gcode).unwrap();
+        assert!(parsed.image_lines.is_empty());
+        assert_eq!(parsed.gcode_lines, lines(&gcode));
+    }
+
+    #[test]
+    fn missing_thumbnail_end() {
+        let thumbnail = LARGE.replace("; thumbnail end\n", "");
+        let gcode = format!("{}{}{}", PRUSASLICER_HEADER, block(&[&thumbnail]), BODY);
+        assert!(parse(&gcode).is_err());
+        // In Cura G-code, the next G-code line is not image data
+        let gcode = format!("{}{}{}", CURA_HEADER, thumbnail, BODY);
+        assert!(parse(&gcode).is_err());
+        let gcode = format!("{}{}", CURA_HEADER, thumbnail);
+        assert!(parse(&gcode).is_err());
+    }
+
+    #[test]
+    fn nested_blocks() {
+        let gcode =
+            format!("{}; THUMBNAIL_BLOCK_START\n{}{}", PRUSASLICER_HEADER, block(&[LARGE]), BODY);
+        assert!(parse(&gcode).is_err());
+    }
+
+    #[test]
+    fn nested_thumbnails() {
+        let thumbnail = LARGE.replacen("; thumbnail end\n", SMALL, 1);
+        let gcode = format!("{}{}{}", PRUSASLICER_HEADER, block(&[&thumbnail]), BODY);
+        assert!(parse(&gcode).is_err());
+        let gcode = format!("{}{}{}", CURA_HEADER, thumbnail, BODY);
+        assert!(parse(&gcode).is_err());
+    }
+
+    #[test]
+    fn thumbnail_found() {
+        let mut parser = ThumbnailParser::new();
+        parser.feed("; generated by PrusaSlicer 2.7.1").unwrap();
+        assert!(!parser.thumbnail_found());
+        parser.feed("; THUMBNAIL_BLOCK_START").unwrap();
+        assert!(parser.thumbnail_found());
+    }
+
+    #[test]
+    fn without_gcode_lines() {
+        let gcode = format!("{}{}{}", PRUSASLICER_HEADER, block(&[LARGE]), BODY);
+        let mut parser = ThumbnailParser::new().without_gcode_lines();
+        for line in gcode.lines() {
+            if parser.feed(line).unwrap() == Step::Stop {
+                break;
+            }
+        }
+        let (gcode_lines, image_lines) = parser.finish().unwrap();
+        assert!(gcode_lines.is_empty());
+        assert_eq!(image_lines, self::image_lines(LARGE));
+    }
+}
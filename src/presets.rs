@@ -0,0 +1,217 @@
+use crate::options::{PreviewSize, RowEnd};
+use crate::Target;
+use clap::ValueEnum;
+
+/// Printers with known preview settings
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Printer {
+    /// FlyingBear Ghost 5 (and other printers with a stock MKS TFT35)
+    FlyingbearGhost5,
+    /// Two Trees Sapphire Pro/Plus
+    TwoTreesSapphire,
+    /// JGAurora A5S/A3S
+    Jgaurora,
+}
+
+/// Preview settings of a printer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Preset {
+    /// The display the preview image is generated for
+    pub target: Target,
+    /// The size of the simage
+    pub simage_size: PreviewSize,
+    /// The size of the gimage
+    pub gimage_size: PreviewSize,
+    /// What goes after each image row, before the next `M10086 ;` command
+    pub row_end: RowEnd,
+    /// Whether the `M10086` command after each image row ends with ` ;`
+    pub trailing_semicolon: bool,
+    /// The largest simage the firmware shows
+    pub max_simage_size: PreviewSize,
+    /// The largest gimage the firmware shows, larger ones break the display
+    pub max_gimage_size: PreviewSize,
+}
+
+/// Get a size of a preset
+fn size(pixels: u16) -> PreviewSize {
+    PreviewSize::new(pixels).expect("Preset sizes are valid")
+}
+
+/// Parts of printer model names, as slicers write them in `; printer_model = ...`,
+/// and the printers they are
+///
+/// The parts are lowercase letters and digits only, see [`Printer::from_model`].
+pub const MODELS: [(&str, Printer); 5] = [
+    ("ghost", Printer::FlyingbearGhost5),
+    ("sapphire", Printer::TwoTreesSapphire),
+    ("jgaurora", Printer::Jgaurora),
+    ("a5s", Printer::Jgaurora),
+    ("a3s", Printer::Jgaurora),
+];
+
+impl Default for Preset {
+    fn default() -> Self {
+        Printer::FlyingbearGhost5.preset()
+    }
+}
+
+impl Printer {
+    /// Find the printer of a printer model name, e.g. `FlyingBear Ghost 5`
+    ///
+    /// Case, spaces and punctuation are ignored, and the model name only has
+    /// to contain one of the parts.
+    ///
+    /// # Arguments
+    ///
+    /// * `model` - The printer model name
+    /// * `models` - Parts of model names and their printers, looked at in order
+    pub fn from_model<'a>(
+        model: &str,
+        models: impl IntoIterator<Item = (&'a str, Printer)>,
+    ) -> Option<Printer> {
+        let normalize = |name: &str| -> String {
+            name.chars()
+                .filter(char::is_ascii_alphanumeric)
+                .map(|c| c.to_ascii_lowercase())
+                .collect()
+        };
+        let model = normalize(model);
+        models
+            .into_iter()
+            .find(|(part, _)| {
+                let part = normalize(part);
+                !part.is_empty() && model.contains(&part)
+            })
+            .map(|(_, printer)| printer)
+    }
+
+    /// Get preview settings of the printer
+    pub fn preset(self) -> Preset {
+        match self {
+            Printer::FlyingbearGhost5 => Preset {
+                target: Target::Mks,
+                simage_size: size(50),
+                gimage_size: size(200),
+                row_end: RowEnd::Cr,
+                trailing_semicolon: true,
+                max_simage_size: size(100),
+                max_gimage_size: size(200),
+            },
+            Printer::TwoTreesSapphire => Preset {
+                target: Target::Mks,
+                simage_size: size(100),
+                gimage_size: size(200),
+                row_end: RowEnd::Cr,
+                trailing_semicolon: true,
+                max_simage_size: size(100),
+                max_gimage_size: size(200),
+            },
+            // JGAurora firmware reads the image line by line and ignores the
+            // row if it is terminated with a single carriage return
+            Printer::Jgaurora => Preset {
+                target: Target::Mks,
+                simage_size: size(100),
+                gimage_size: size(180),
+                row_end: RowEnd::Crlf,
+                trailing_semicolon: true,
+                max_simage_size: size(100),
+                max_gimage_size: size(180),
+            },
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use image::{DynamicImage, Rgb, RgbImage};
+
+    /// The printer, and its simage size, gimage size, row end, trailing
+    /// semicolon, largest simage size and largest gimage size, as documented
+    const DOCUMENTED: [(Printer, u16, u16, RowEnd, bool, u16, u16); 3] = [
+        (Printer::FlyingbearGhost5, 50, 200, RowEnd::Cr, true, 100, 200),
+        (Printer::TwoTreesSapphire, 100, 200, RowEnd::Cr, true, 100, 200),
+        (Printer::Jgaurora, 100, 180, RowEnd::Crlf, true, 100, 180),
+    ];
+
+    #[test]
+    fn documented_presets() {
+        for (printer, simage, gimage, row_end, trailing_semicolon, max_simage, max_gimage) in
+            DOCUMENTED
+        {
+            let preset = printer.preset();
+            assert_eq!(preset.target, Target::Mks, "{:?}", printer);
+            assert_eq!(preset.simage_size.get(), simage, "{:?}", printer);
+            assert_eq!(preset.gimage_size.get(), gimage, "{:?}", printer);
+            assert_eq!(preset.row_end, row_end, "{:?}", printer);
+            assert_eq!(preset.trailing_semicolon, trailing_semicolon, "{:?}", printer);
+            assert_eq!(preset.max_simage_size.get(), max_simage, "{:?}", printer);
+            assert_eq!(preset.max_gimage_size.get(), max_gimage, "{:?}", printer);
+        }
+        assert_eq!(Printer::value_variants().len(), DOCUMENTED.len());
+    }
+
+    #[test]
+    fn presets_fit_their_firmware() {
+        for printer in Printer::value_variants() {
+            let preset = printer.preset();
+            assert!(preset.simage_size <= preset.max_simage_size, "{:?}", printer);
+            assert!(preset.gimage_size <= preset.max_gimage_size, "{:?}", printer);
+        }
+    }
+
+    #[test]
+    fn default_is_the_stock_mks_tft35() {
+        assert_eq!(Preset::default(), Printer::FlyingbearGhost5.preset());
+    }
+
+    #[test]
+    fn names() {
+        let names: Vec<_> = Printer::value_variants()
+            .iter()
+            .map(|printer| printer.to_possible_value().unwrap().get_name().to_string())
+            .collect();
+        assert_eq!(names, ["flyingbear-ghost5", "two-trees-sapphire", "jgaurora"]);
+    }
+
+    #[test]
+    fn row_formatting() {
+        // A 2x2 image: red and green on the first row, blue and white on the second
+        let mut image = RgbImage::new(2, 2);
+        image.put_pixel(0, 0, Rgb([255, 0, 0]));
+        image.put_pixel(1, 0, Rgb([0, 255, 0]));
+        image.put_pixel(0, 1, Rgb([0, 0, 255]));
+        image.put_pixel(1, 1, Rgb([255, 255, 255]));
+        let image = DynamicImage::ImageRgb8(image);
+        // Rows are followed by the row end and `M10086 ;`, and the last one by a line end
+        for (printer, expected) in [
+            (Printer::FlyingbearGhost5, ";simage:00f8e007\rM10086 ;1f00ffff\nM10086 ;\n"),
+            (Printer::TwoTreesSapphire, ";simage:00f8e007\rM10086 ;1f00ffff\nM10086 ;\n"),
+            (Printer::Jgaurora, ";simage:00f8e007\r\nM10086 ;1f00ffff\nM10086 ;\n"),
+        ] {
+            let preset = printer.preset();
+            let encoder = preset.target.encoder(preset.row_end, preset.trailing_semicolon);
+            let preview =
+                encoder.encode(&image, &image, std::slice::from_ref(&image), "\n").unwrap();
+            let data = String::from_utf8(preview.data).unwrap();
+            assert!(data.starts_with(expected), "{:?}: {:?}", printer, data);
+            assert!(data[expected.len()..].starts_with(";;gimage:"), "{:?}: {:?}", printer, data);
+        }
+    }
+
+    #[test]
+    fn models() {
+        for (model, printer) in [
+            ("FlyingBear Ghost 5", Printer::FlyingbearGhost5),
+            ("Two Trees Sapphire Pro", Printer::TwoTreesSapphire),
+            ("TwoTrees SAPPHIRE-PLUS", Printer::TwoTreesSapphire),
+            ("JGAurora A5S", Printer::Jgaurora),
+            ("A3S", Printer::Jgaurora),
+        ] {
+            assert_eq!(Printer::from_model(model, MODELS), Some(printer), "{}", model);
+        }
+        assert_eq!(Printer::from_model("Prusa MK4", MODELS), None);
+        let custom = [("MK4", Printer::Jgaurora)];
+        assert_eq!(Printer::from_model("Original Prusa MK4", custom), Some(Printer::Jgaurora));
+    }
+}
@@ -0,0 +1,381 @@
+use crate::options::RowEnd;
+use crate::verify::{self, ImageSummary};
+use crate::{encoders, Target};
+use image::{DynamicImage, RgbImage};
+
+/// Encoded preview for a printer display
+pub struct Preview {
+    /// The preview, which goes at the top of the G-code
+    pub data: Vec<u8>,
+    /// Lines of the post-processing info describing the preview
+    pub info: String,
+    /// Names and sizes of the encoded images, for statistics
+    pub blocks: Vec<(&'static str, usize)>,
+}
+
+/// An image decoded from the written preview and the one it should match
+pub type Check = (Result<ImageSummary, String>, ImageSummary);
+
+/// Preview format of a display
+///
+/// Each display target implements this, so the parsing and resizing of the
+/// thumbnail doesn't depend on how the preview is written.
+pub trait PreviewEncoder {
+    /// Encode the preview
+    ///
+    /// # Arguments
+    ///
+    /// * `img` - The original thumbnail
+    /// * `simage` - The thumbnail resized to the simage size
+    /// * `gimages` - The thumbnail resized to the gimage sizes, the gimage
+    ///   first and then the additional ones
+    /// * `line_ending` - The line ending of the G-code, for comment based previews
+    fn encode(
+        &self,
+        img: &DynamicImage,
+        simage: &DynamicImage,
+        gimages: &[DynamicImage],
+        line_ending: &str,
+    ) -> Result<Preview, ()>;
+
+    /// Get the image as it is shown on the display
+    ///
+    /// # Arguments
+    ///
+    /// * `img` - The original thumbnail
+    /// * `gimage` - The thumbnail resized to the gimage size
+    fn display_image(&self, img: &DynamicImage, gimage: &DynamicImage) -> RgbImage;
+
+    /// Decode the images back from the written preview
+    ///
+    /// # Arguments
+    ///
+    /// * `written` - The written preview
+    /// * `simage` - The simage that was encoded
+    /// * `gimages` - The gimages that were encoded
+    ///
+    /// # Returns
+    ///
+    /// The decoded images with the ones they should match, or `None` if the
+    /// format cannot be verified
+    fn checks(
+        &self,
+        written: &str,
+        simage: &DynamicImage,
+        gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>>;
+}
+
+impl Target {
+    /// Get the preview encoder of the display
+    ///
+    /// # Arguments
+    ///
+    /// * `row_end` - What goes after each MKS image row
+    /// * `trailing_semicolon` - Whether the `M10086` command after each MKS
+    ///   image row ends with ` ;`
+    pub fn encoder(self, row_end: RowEnd, trailing_semicolon: bool) -> Box<dyn PreviewEncoder> {
+        match self {
+            Target::Mks => Box::new(Mks {
+                row_end,
+                trailing_semicolon,
+            }),
+            Target::Anycubic => Box::new(Anycubic),
+            Target::Flashforge => Box::new(Flashforge),
+            Target::Creality => Box::new(Creality),
+            Target::Raw => Box::new(Raw),
+        }
+    }
+}
+
+/// Get the size an image was resized to, which is its longer side
+fn size(image: &DynamicImage) -> u32 {
+    image.width().max(image.height())
+}
+
+/// Get the prefix of a gimage, `;;gimage` for the first one and e.g.
+/// `;;gimage300` for the additional ones, so firmware looking for `;;gimage:`
+/// only finds the first one
+fn gimage_prefix(index: usize, gimage: &DynamicImage) -> String {
+    match index {
+        0 => ";;gimage".to_string(),
+        _ => format!(";;gimage{}", size(gimage)),
+    }
+}
+
+/// Describe the simage and gimage sizes in the post-processing info
+fn rgb565_info(simage: &DynamicImage, gimages: &[DynamicImage]) -> String {
+    let gimage_sizes: Vec<String> = gimages.iter().map(|gimage| size(gimage).to_string()).collect();
+    format!(";  simage = {}\n;  gimage = {}\n", size(simage), gimage_sizes.join(", "))
+}
+
+/// Encode the simage and the gimages of an RGB565 display
+///
+/// A gimage that is the same image as the simage, e.g. when both sizes are
+/// the same, is not encoded again: the rows of the simage are reused after
+/// its own prefix.
+///
+/// # Arguments
+///
+/// * `simage` - The simage
+/// * `gimages` - The gimages, the first one is the gimage
+/// * `encode` - Encode an image with a prefix, which the rows follow
+fn encode_rgb565(
+    simage: &DynamicImage,
+    gimages: &[DynamicImage],
+    encode: impl Fn(&str, &DynamicImage) -> String,
+) -> Preview {
+    const SIMAGE_PREFIX: &str = ";simage";
+    let simage_data = encode(SIMAGE_PREFIX, simage);
+    let simage_len = simage_data.len();
+    let mut blocks = vec![("simage", simage_len)];
+    let mut data = simage_data.into_bytes();
+    for (index, gimage) in gimages.iter().enumerate() {
+        let prefix = gimage_prefix(index, gimage);
+        let gimage_data = match gimage == simage {
+            true => [prefix.as_bytes(), &data[SIMAGE_PREFIX.len()..simage_len]].concat(),
+            false => encode(&prefix, gimage).into_bytes(),
+        };
+        blocks.push(("gimage", gimage_data.len()));
+        data.extend_from_slice(&gimage_data);
+    }
+    Preview {
+        data,
+        info: rgb565_info(simage, gimages),
+        blocks,
+    }
+}
+
+/// Decode the simage and the gimages of an RGB565 display back from the
+/// written preview
+///
+/// # Arguments
+///
+/// * `written` - The written preview
+/// * `simage` - The simage that was encoded
+/// * `gimages` - The gimages that were encoded
+/// * `marker` - The command that separates rows
+fn check_rgb565(
+    written: &str,
+    simage: &DynamicImage,
+    gimages: &[DynamicImage],
+    marker: &str,
+) -> Vec<Check> {
+    let mut checks = vec![(
+        verify::read_rgb565_image(written, ";simage", marker, true),
+        verify::summarize_rgb565(simage),
+    )];
+    for (index, gimage) in gimages.iter().enumerate() {
+        checks.push((
+            verify::read_rgb565_image(written, &gimage_prefix(index, gimage), marker, true),
+            verify::summarize_rgb565(gimage),
+        ));
+    }
+    checks
+}
+
+/// MKS TFT displays: simage and gimage in hex encoded RGB565
+pub struct Mks {
+    /// What goes after each image row
+    pub row_end: RowEnd,
+    /// Whether the `M10086` command after each image row ends with ` ;`
+    pub trailing_semicolon: bool,
+}
+
+impl Mks {
+    /// Get the command after each image row. Without the semicolon, the space
+    /// is kept, so the pixels of the next row don't run into the command
+    fn marker(&self) -> &'static str {
+        match self.trailing_semicolon {
+            true => "M10086 ;",
+            false => "M10086 ",
+        }
+    }
+}
+
+impl PreviewEncoder for Mks {
+    fn encode(
+        &self,
+        _img: &DynamicImage,
+        simage: &DynamicImage,
+        gimages: &[DynamicImage],
+        _line_ending: &str,
+    ) -> Result<Preview, ()> {
+        let row_end = self.row_end.as_str();
+        Ok(encode_rgb565(simage, gimages, |prefix, image| {
+            encoders::create_tft_image_gcode(prefix, image, row_end, self.marker())
+        }))
+    }
+
+    fn display_image(&self, _img: &DynamicImage, gimage: &DynamicImage) -> RgbImage {
+        encoders::quantize_rgb565(gimage)
+    }
+
+    fn checks(
+        &self,
+        written: &str,
+        simage: &DynamicImage,
+        gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>> {
+        Some(check_rgb565(written, simage, gimages, self.marker()))
+    }
+}
+
+/// Anycubic Kobra/Vyper: PNG thumbnail comments of the gimage sizes
+pub struct Anycubic;
+
+impl PreviewEncoder for Anycubic {
+    fn encode(
+        &self,
+        _img: &DynamicImage,
+        _simage: &DynamicImage,
+        gimages: &[DynamicImage],
+        line_ending: &str,
+    ) -> Result<Preview, ()> {
+        let mut preview = Preview {
+            data: vec![],
+            info: String::new(),
+            blocks: vec![],
+        };
+        for gimage in gimages {
+            let thumbnail = encoders::create_anycubic_image_gcode(gimage)?;
+            let thumbnail = thumbnail.replace('\n', line_ending);
+            preview.blocks.push(("thumbnail", thumbnail.len()));
+            preview.data.extend_from_slice(thumbnail.as_bytes());
+            preview.info += &format!(";  thumbnail = {}x{}\n", gimage.width(), gimage.height());
+        }
+        Ok(preview)
+    }
+
+    fn display_image(&self, _img: &DynamicImage, gimage: &DynamicImage) -> RgbImage {
+        gimage.to_rgb8()
+    }
+
+    fn checks(
+        &self,
+        written: &str,
+        _simage: &DynamicImage,
+        gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>> {
+        // The first thumbnail is the one the display shows
+        let written = verify::read_thumbnail_image(written, "thumbnail");
+        Some(vec![(written, verify::summarize_rgb8(&gimages[0]))])
+    }
+}
+
+/// Flashforge: xgcode header with an 80x60 BMP preview
+pub struct Flashforge;
+
+impl PreviewEncoder for Flashforge {
+    fn encode(
+        &self,
+        img: &DynamicImage,
+        _simage: &DynamicImage,
+        _gimages: &[DynamicImage],
+        _line_ending: &str,
+    ) -> Result<Preview, ()> {
+        let (width, height) = encoders::FLASHFORGE_BITMAP_SIZE;
+        let header = encoders::create_flashforge_header(&encoders::create_flashforge_bitmap(img))?;
+        Ok(Preview {
+            blocks: vec![("bitmap", header.len())],
+            data: header,
+            info: format!(";  bitmap = {}x{}\n", width, height),
+        })
+    }
+
+    fn display_image(&self, img: &DynamicImage, _gimage: &DynamicImage) -> RgbImage {
+        encoders::create_flashforge_bitmap(img)
+    }
+
+    fn checks(
+        &self,
+        _written: &str,
+        _simage: &DynamicImage,
+        _gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>> {
+        None
+    }
+}
+
+/// Creality Ender-3 V3 and K1: JPEG thumbnail comments of the gimage sizes
+pub struct Creality;
+
+impl PreviewEncoder for Creality {
+    fn encode(
+        &self,
+        _img: &DynamicImage,
+        _simage: &DynamicImage,
+        gimages: &[DynamicImage],
+        line_ending: &str,
+    ) -> Result<Preview, ()> {
+        let mut preview = Preview {
+            data: vec![],
+            info: String::new(),
+            blocks: vec![],
+        };
+        for gimage in gimages {
+            let thumbnail = encoders::create_creality_image_gcode(gimage)?;
+            let thumbnail = thumbnail.replace('\n', line_ending);
+            preview.blocks.push(("jpg", thumbnail.len()));
+            preview.data.extend_from_slice(thumbnail.as_bytes());
+            preview.info += &format!(";  jpg = {}x{}\n", gimage.width(), gimage.height());
+        }
+        Ok(preview)
+    }
+
+    fn display_image(&self, _img: &DynamicImage, gimage: &DynamicImage) -> RgbImage {
+        encoders::create_creality_jpeg(gimage)
+            .ok()
+            .and_then(|jpeg| image::load_from_memory(&jpeg).ok())
+            .unwrap_or_else(|| gimage.clone())
+            .to_rgb8()
+    }
+
+    fn checks(
+        &self,
+        written: &str,
+        _simage: &DynamicImage,
+        gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>> {
+        // JPEG is lossy, so the decoded image is compared with the decoded encoding
+        let expected = encoders::create_creality_jpeg(&gimages[0]).ok()?;
+        let expected = image::load_from_memory(&expected).ok()?;
+        let written = verify::read_thumbnail_image(written, "jpg");
+        Some(vec![(written, verify::summarize_rgb8(&expected))])
+    }
+}
+
+/// Custom firmware: the gimage as raw RGB565 pixels, see
+/// [`encoders::create_raw_preview`]
+pub struct Raw;
+
+impl PreviewEncoder for Raw {
+    fn encode(
+        &self,
+        _img: &DynamicImage,
+        _simage: &DynamicImage,
+        gimages: &[DynamicImage],
+        _line_ending: &str,
+    ) -> Result<Preview, ()> {
+        let gimage = &gimages[0];
+        let data = encoders::create_raw_preview(gimage)?;
+        Ok(Preview {
+            blocks: vec![("raw", data.len())],
+            data,
+            info: format!(";  raw = {}x{}\n", gimage.width(), gimage.height()),
+        })
+    }
+
+    fn display_image(&self, _img: &DynamicImage, gimage: &DynamicImage) -> RgbImage {
+        encoders::quantize_rgb565(gimage)
+    }
+
+    fn checks(
+        &self,
+        _written: &str,
+        _simage: &DynamicImage,
+        _gimages: &[DynamicImage],
+    ) -> Option<Vec<Check>> {
+        None
+    }
+}
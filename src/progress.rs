@@ -0,0 +1,125 @@
+use std::io::{IsTerminal, Read, Write};
+use std::time::{Duration, Instant};
+
+/// How often the progress bar is redrawn
+const REDRAW_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Width of the bar itself, without the phase name and numbers
+const BAR_WIDTH: usize = 30;
+
+/// Check whether a progress bar can be shown
+///
+/// The progress bar is only shown when stderr is a terminal and the tool is
+/// not run by a slicer. PrusaSlicer and its forks pass `SLIC3R_*` environment
+/// variables to post-processing scripts.
+pub fn is_supported() -> bool {
+    std::io::stderr().is_terminal() && crate::slicer::output_name().is_none()
+}
+
+/// Reader or writer wrapper that draws a progress bar of a single phase
+pub struct Progress<T> {
+    inner: T,
+    phase: &'static str,
+    total: u64,
+    done: u64,
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl<T> Progress<T> {
+    /// Wrap a reader or writer
+    ///
+    /// # Arguments
+    ///
+    /// * `inner` - The reader or writer to count bytes of
+    /// * `phase` - The name of the phase shown before the bar
+    /// * `total` - The expected number of bytes
+    /// * `enabled` - Whether to draw the progress bar at all
+    pub fn new(inner: T, phase: &'static str, total: u64, enabled: bool) -> Self {
+        Progress {
+            inner,
+            phase,
+            total,
+            done: 0,
+            enabled,
+            last_draw: None,
+        }
+    }
+
+    /// Get the expected number of bytes
+    pub fn total(&self) -> u64 {
+        self.total
+    }
+
+    /// Get the number of bytes read or written so far
+    pub fn done(&self) -> u64 {
+        self.done
+    }
+
+    /// Stop drawing the progress bar, leaving its last state on screen
+    pub fn finish(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            self.draw();
+            eprintln!();
+        }
+        self.enabled = false;
+    }
+
+    fn advance(&mut self, bytes: usize) {
+        self.done += bytes as u64;
+        if !self.enabled {
+            return;
+        }
+        let now = Instant::now();
+        if self.last_draw.is_some_and(|last| now - last < REDRAW_INTERVAL) {
+            return;
+        }
+        self.last_draw = Some(now);
+        self.draw();
+    }
+
+    fn draw(&self) {
+        let ratio = if self.total == 0 {
+            1.0
+        } else {
+            self.done as f64 / self.total as f64
+        };
+        let ratio = ratio.min(1.0);
+        let filled = (ratio * BAR_WIDTH as f64) as usize;
+        eprint!(
+            "\r{:>8} [{}{}] {:>6.1}/{:.1} MB {:>3.0}%",
+            self.phase,
+            "#".repeat(filled),
+            " ".repeat(BAR_WIDTH - filled),
+            self.done as f64 / 1_000_000.0,
+            self.total as f64 / 1_000_000.0,
+            ratio * 100.0
+        );
+    }
+}
+
+impl<T> Drop for Progress<T> {
+    fn drop(&mut self) {
+        self.finish();
+    }
+}
+
+impl<R: Read> Read for Progress<R> {
+    fn read(&mut self, buf: &mut [u8]) -> std::io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.advance(read);
+        Ok(read)
+    }
+}
+
+impl<W: Write> Write for Progress<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        let written = self.inner.write(buf)?;
+        self.advance(written);
+        Ok(written)
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.inner.flush()
+    }
+}
@@ -0,0 +1,128 @@
+//! QR codes drawn into previews, e.g. to open the print job by scanning the
+//! printer display
+//!
+//! The codes use error correction level M and versions 1 to 10, which hold
+//! up to 213 bytes; larger ones have too small modules for the display.
+
+use clap::ValueEnum;
+use image::{DynamicImage, GenericImage, Rgba};
+use serde::{Deserialize, Serialize};
+
+/// The largest version readable on the display
+const MAX_VERSION: i16 = 10;
+
+/// Width of the light border around the code, in modules. The standard asks
+/// for 4, but 2 is enough on a display, which has no print bleeding.
+const QUIET_ZONE: u32 = 2;
+
+/// Corners of the image a QR code can be drawn in
+#[derive(ValueEnum, Serialize, Deserialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Corner {
+    /// The top left corner
+    TopLeft,
+    /// The top right corner
+    TopRight,
+    /// The bottom left corner
+    BottomLeft,
+    /// The bottom right corner
+    BottomRight,
+}
+
+impl std::fmt::Display for Corner {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// A QR code, as a square of dark and light modules
+pub struct QrCode {
+    /// Width and height in modules
+    size: usize,
+    /// Whether each module is dark, row by row
+    modules: Vec<bool>,
+}
+
+impl QrCode {
+    /// Encode a text in the smallest version it fits into
+    ///
+    /// # Arguments
+    ///
+    /// * `text` - The text, usually a URL
+    pub fn encode(text: &str) -> Result<Self, ()> {
+        let too_long = || {
+            log::error!(
+                "The QR code text is {} bytes long, which is more than a QR code on the display can hold",
+                text.len()
+            )
+        };
+        let code = qrcode::QrCode::with_error_correction_level(text, qrcode::EcLevel::M)
+            .map_err(|_| too_long())?;
+        let qrcode::Version::Normal(version @ 1..=MAX_VERSION) = code.version() else {
+            too_long();
+            return Err(());
+        };
+        log::debug!("Encoding {} bytes as a version {} QR code", text.len(), version);
+        Ok(QrCode {
+            size: code.width(),
+            modules: code
+                .into_colors()
+                .into_iter()
+                .map(|color| color == qrcode::Color::Dark)
+                .collect(),
+        })
+    }
+
+    /// Check whether a module is dark
+    fn get(&self, x: usize, y: usize) -> bool {
+        self.modules[y * self.size + x]
+    }
+
+    /// Draw the code with its quiet zone into a corner of an image
+    ///
+    /// The modules are as large as possible while the code takes at most
+    /// a third of the image width.
+    ///
+    /// # Arguments
+    ///
+    /// * `image` - The image to draw into
+    /// * `corner` - Where to draw the code
+    pub fn draw(&self, image: &mut DynamicImage, corner: Corner) -> Result<(), ()> {
+        let modules = self.size as u32 + 2 * QUIET_ZONE;
+        let scale = (image.width().min(image.height()) / 3 / modules).max(1);
+        let side = modules * scale;
+        if side > image.width() || side > image.height() {
+            log::error!(
+                "The {}x{} image is too small for a QR code of {} modules",
+                image.width(),
+                image.height(),
+                modules
+            );
+            return Err(());
+        }
+        let left = match corner {
+            Corner::TopLeft | Corner::BottomLeft => 0,
+            Corner::TopRight | Corner::BottomRight => image.width() - side,
+        };
+        let top = match corner {
+            Corner::TopLeft | Corner::TopRight => 0,
+            Corner::BottomLeft | Corner::BottomRight => image.height() - side,
+        };
+        for y in 0..side {
+            for x in 0..side {
+                let (module_x, module_y) = (x / scale, y / scale);
+                let dark = (QUIET_ZONE..QUIET_ZONE + self.size as u32).contains(&module_x)
+                    && (QUIET_ZONE..QUIET_ZONE + self.size as u32).contains(&module_y)
+                    && self.get((module_x - QUIET_ZONE) as usize, (module_y - QUIET_ZONE) as usize);
+                let value = if dark {
+                    0
+                } else {
+                    255
+                };
+                image.put_pixel(left + x, top + y, Rgba([value, value, value, 255]));
+            }
+        }
+        Ok(())
+    }
+}
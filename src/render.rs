@@ -0,0 +1,381 @@
+use clap::ValueEnum;
+use image::{DynamicImage, Rgb, RgbImage};
+use std::fs::File;
+use std::io::{BufRead, BufReader};
+use std::path;
+
+/// Background color of generated previews
+const BACKGROUND: Rgb<u8> = Rgb([32, 32, 32]);
+
+/// Color of the lines of the placeholder
+const PLACEHOLDER_COLOR: Rgb<u8> = Rgb([112, 112, 112]);
+
+/// Color of rendered extrusion moves of the top layer
+const TOOLPATH_COLOR: Rgb<u8> = Rgb([255, 140, 0]);
+
+/// Color of rendered extrusion moves of the bottom layer, layers in between
+/// get a color between this one and [`TOOLPATH_COLOR`]
+const BOTTOM_LAYER_COLOR: Rgb<u8> = Rgb([110, 50, 10]);
+
+/// Distance of the perspective camera from the center of the toolpath, in
+/// the radii of the toolpath
+const PERSPECTIVE_DISTANCE: f32 = 2.5;
+
+/// Where rendered previews are looked at from
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum View {
+    /// From the top, like a plan of the bed
+    Top,
+    /// From the front corner, without perspective, like slicer thumbnails
+    Isometric,
+    /// From the front corner, with perspective
+    Perspective,
+}
+
+impl std::fmt::Display for View {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let value = self.to_possible_value().expect("There are no skipped values");
+        f.write_str(value.get_name())
+    }
+}
+
+/// Camera of rendered previews
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct Camera {
+    /// Whether the view has perspective
+    pub view: View,
+    /// Rotation around the Z axis in degrees, 0 looks from the front
+    pub azimuth: f32,
+    /// Angle above the bed in degrees, 90 looks from the top
+    pub elevation: f32,
+    /// Magnification, 1 fits the whole toolpath into the image
+    pub zoom: f32,
+}
+
+impl Default for Camera {
+    fn default() -> Self {
+        Camera::new(View::Top)
+    }
+}
+
+impl Camera {
+    /// Get the camera of a view with its usual angles
+    pub fn new(view: View) -> Self {
+        let (azimuth, elevation) = match view {
+            View::Top => (0.0, 90.0),
+            // The angle at which the three axes look equally long
+            View::Isometric => (45.0, 35.264),
+            View::Perspective => (45.0, 30.0),
+        };
+        Camera {
+            view,
+            azimuth,
+            elevation,
+            zoom: 1.0,
+        }
+    }
+
+    /// Set the rotation around the Z axis in degrees
+    pub fn azimuth(mut self, azimuth: f32) -> Self {
+        self.azimuth = azimuth;
+        self
+    }
+
+    /// Set the angle above the bed in degrees
+    pub fn elevation(mut self, elevation: f32) -> Self {
+        self.elevation = elevation;
+        self
+    }
+
+    /// Set the magnification
+    pub fn zoom(mut self, zoom: f32) -> Self {
+        self.zoom = zoom;
+        self
+    }
+
+    /// Project points of the toolpath onto the image plane
+    ///
+    /// # Arguments
+    ///
+    /// * `points` - The points, centered around the origin
+    /// * `radius` - Distance of the farthest point from the origin
+    ///
+    /// # Returns
+    ///
+    /// The points on the image plane, with Y going up
+    fn project(&self, points: &[Point], radius: f32) -> Vec<(f32, f32)> {
+        let (sin_azimuth, cos_azimuth) = self.azimuth.to_radians().sin_cos();
+        let (sin_elevation, cos_elevation) = self.elevation.to_radians().sin_cos();
+        let distance = PERSPECTIVE_DISTANCE * radius.max(f32::EPSILON);
+        points
+            .iter()
+            .map(|&(x, y, z)| {
+                let (x, y) = (x * cos_azimuth - y * sin_azimuth, x * sin_azimuth + y * cos_azimuth);
+                let (up, towards_camera) =
+                    (y * sin_elevation + z * cos_elevation, z * sin_elevation - y * cos_elevation);
+                match self.view {
+                    View::Top | View::Isometric => (x, up),
+                    View::Perspective => {
+                        let factor = distance / (distance - towards_camera);
+                        (x * factor, up * factor)
+                    }
+                }
+            })
+            .collect()
+    }
+}
+
+/// Create a placeholder preview
+///
+/// The placeholder is a crossed out frame, which makes it obvious on the
+/// display that the G-code had no thumbnail.
+///
+/// # Arguments
+///
+/// * `size` - Width and height of the placeholder
+pub fn placeholder(size: u32) -> DynamicImage {
+    let mut image = RgbImage::from_pixel(size, size, BACKGROUND);
+    let margin = size as f32 / 8.0;
+    let (near, far) = (margin, size as f32 - 1.0 - margin);
+    for (from, to) in [
+        ((near, near), (far, near)),
+        ((far, near), (far, far)),
+        ((far, far), (near, far)),
+        ((near, far), (near, near)),
+        ((near, near), (far, far)),
+        ((near, far), (far, near)),
+    ] {
+        draw_line(&mut image, from, to, PLACEHOLDER_COLOR);
+    }
+    DynamicImage::ImageRgb8(image)
+}
+
+/// Render extrusion moves of the G-code
+///
+/// Only `G0`/`G1` moves are taken into account. A move is an extrusion if it
+/// changes X or Y and increases E, honoring `G90`/`G91` and `M82`/`M83`.
+/// Each layer gets a color of a gradient, from dark at the bottom to bright
+/// at the top.
+///
+/// # Arguments
+///
+/// * `path` - Path to the gcode file
+/// * `size` - Width and height of the rendered image
+/// * `camera` - Where the toolpath is looked at from
+pub fn render_toolpath(path: &path::Path, size: u32, camera: Camera) -> Result<DynamicImage, ()> {
+    render(path, size, camera, false)
+}
+
+/// Render the extrusion moves of the first layer
+///
+/// The first layer is made of the extrusions at the height of the first
+/// extrusion, so travel moves with Z hops in between don't end it. The rest
+/// of the G-code is not read.
+///
+/// # Arguments
+///
+/// * `path` - Path to the gcode file
+/// * `size` - Width and height of the rendered image
+/// * `camera` - Where the first layer is looked at from
+pub fn render_first_layer(
+    path: &path::Path,
+    size: u32,
+    camera: Camera,
+) -> Result<DynamicImage, ()> {
+    render(path, size, camera, true)
+}
+
+/// Render extrusion moves of the G-code, see [`render_toolpath`]
+fn render(
+    path: &path::Path,
+    size: u32,
+    camera: Camera,
+    first_layer: bool,
+) -> Result<DynamicImage, ()> {
+    log::debug!("Rendering toolpath of `{}` with {:?}", path.display(), camera);
+    let file = File::open(path)
+        .map_err(|e| log::error!("Cannot open file `{}` for reading: {}", path.display(), e))?;
+    let segments = extrusion_segments(BufReader::new(file), first_layer)
+        .map_err(|e| log::error!("Failed to read from gcode file: {}", e))?;
+    if segments.is_empty() {
+        log::error!("There are no extrusion moves to render in the gcode file");
+        return Err(());
+    }
+    log::debug!("{} extrusion moves found", segments.len());
+
+    // Center the toolpath around the origin, so the camera turns around its center
+    let (mut min, mut max) = ([f32::MAX; 3], [f32::MIN; 3]);
+    for &(x, y, z) in segments.iter().flat_map(|(from, to)| [from, to]) {
+        for (axis, value) in [x, y, z].into_iter().enumerate() {
+            min[axis] = min[axis].min(value);
+            max[axis] = max[axis].max(value);
+        }
+    }
+    let center: Vec<f32> = (0..3).map(|axis| (min[axis] + max[axis]) / 2.0).collect();
+    let points: Vec<Point> = segments
+        .iter()
+        .flat_map(|&(from, to)| [from, to])
+        .map(|(x, y, z)| (x - center[0], y - center[1], z - center[2]))
+        .collect();
+    let radius = points.iter().map(|&(x, y, z)| (x * x + y * y + z * z).sqrt()).fold(0.0, f32::max);
+    let projected = camera.project(&points, radius);
+
+    let (mut min_x, mut min_y) = (f32::MAX, f32::MAX);
+    let (mut max_x, mut max_y) = (f32::MIN, f32::MIN);
+    for &(x, y) in &projected {
+        min_x = min_x.min(x);
+        min_y = min_y.min(y);
+        max_x = max_x.max(x);
+        max_y = max_y.max(y);
+    }
+    let margin = size as f32 / 16.0;
+    let scale = (size as f32 - 1.0 - 2.0 * margin) / (max_x - min_x).max(max_y - min_y).max(1.0)
+        * camera.zoom.max(f32::EPSILON);
+    let (center_x, center_y) = ((min_x + max_x) / 2.0, (min_y + max_y) / 2.0);
+    // The Y axis of the printer goes up, and the one of the image goes down
+    let to_image = |(x, y): (f32, f32)| {
+        let middle = (size as f32 - 1.0) / 2.0;
+        (middle + (x - center_x) * scale, middle - (y - center_y) * scale)
+    };
+
+    let height = max[2] - min[2];
+    let mut image = RgbImage::from_pixel(size, size, BACKGROUND);
+    for (segment, ends) in segments.iter().zip(projected.chunks_exact(2)) {
+        // A single layer is shown in the color of the top one
+        let color = match height > 0.0 {
+            true => gradient((segment.0 .2 - min[2]) / height),
+            false => TOOLPATH_COLOR,
+        };
+        draw_line(&mut image, to_image(ends[0]), to_image(ends[1]), color);
+    }
+    Ok(DynamicImage::ImageRgb8(image))
+}
+
+/// Get the color of a layer
+///
+/// # Arguments
+///
+/// * `height` - Height of the layer, from 0 at the bottom to 1 at the top
+fn gradient(height: f32) -> Rgb<u8> {
+    let height = height.clamp(0.0, 1.0);
+    let channel =
+        |bottom: u8, top: u8| (bottom as f32 + (top as f32 - bottom as f32) * height).round() as u8;
+    Rgb([
+        channel(BOTTOM_LAYER_COLOR.0[0], TOOLPATH_COLOR.0[0]),
+        channel(BOTTOM_LAYER_COLOR.0[1], TOOLPATH_COLOR.0[1]),
+        channel(BOTTOM_LAYER_COLOR.0[2], TOOLPATH_COLOR.0[2]),
+    ])
+}
+
+/// A point of the toolpath
+type Point = (f32, f32, f32);
+
+/// A line between two points of the toolpath
+type Segment = (Point, Point);
+
+/// Collect segments of extrusion moves
+///
+/// # Arguments
+///
+/// * `reader` - The G-code
+/// * `first_layer` - Whether to stop at the first extrusion above the first one
+fn extrusion_segments(
+    mut reader: impl BufRead,
+    first_layer: bool,
+) -> std::io::Result<Vec<Segment>> {
+    let mut segments = vec![];
+    let (mut x, mut y, mut z, mut e) = (0.0f32, 0.0f32, 0.0f32, 0.0f32);
+    let mut relative_xy = false;
+    let mut relative_e = false;
+    // Moves from the unknown position the printer starts at are not rendered
+    let mut position_known = false;
+    // Height of the first extrusion, when only the first layer is collected
+    let mut first_layer_z = None;
+
+    let mut buf = vec![];
+    loop {
+        buf.clear();
+        if reader.read_until(b'\n', &mut buf)? == 0 {
+            break;
+        }
+        let line = String::from_utf8_lossy(&buf);
+        let code = line.split(';').next().unwrap_or_default().trim();
+        let mut words = code.split_whitespace();
+        match words.next() {
+            Some("G90") => (relative_xy, relative_e) = (false, false),
+            Some("G91") => (relative_xy, relative_e) = (true, true),
+            Some("M82") => relative_e = false,
+            Some("M83") => relative_e = true,
+            Some("G92") => {
+                for word in words {
+                    if let Some(value) = word.strip_prefix('E').and_then(|v| v.parse().ok()) {
+                        e = value;
+                    }
+                }
+            }
+            Some("G0" | "G1") => {
+                let (mut new_x, mut new_y, mut new_z, mut new_e) = (x, y, z, e);
+                for word in words {
+                    let (axis, value) = word.split_at(1);
+                    let Ok(value) = value.parse::<f32>() else {
+                        continue;
+                    };
+                    match axis {
+                        "X" => {
+                            new_x = if relative_xy {
+                                x + value
+                            } else {
+                                value
+                            }
+                        }
+                        "Y" => {
+                            new_y = if relative_xy {
+                                y + value
+                            } else {
+                                value
+                            }
+                        }
+                        "Z" => {
+                            new_z = if relative_xy {
+                                z + value
+                            } else {
+                                value
+                            }
+                        }
+                        "E" => {
+                            new_e = if relative_e {
+                                e + value
+                            } else {
+                                value
+                            }
+                        }
+                        _ => (),
+                    }
+                }
+                if position_known && new_e > e && (new_x != x || new_y != y) {
+                    if first_layer && *first_layer_z.get_or_insert(new_z) < new_z {
+                        break;
+                    }
+                    segments.push(((x, y, z), (new_x, new_y, new_z)));
+                }
+                position_known = position_known || code.contains('X') && code.contains('Y');
+                (x, y, z, e) = (new_x, new_y, new_z, new_e);
+            }
+            _ => (),
+        }
+    }
+    Ok(segments)
+}
+
+/// Draw a line, clipping the parts outside of the image
+fn draw_line(image: &mut RgbImage, from: (f32, f32), to: (f32, f32), color: Rgb<u8>) {
+    let steps = (to.0 - from.0).abs().max((to.1 - from.1).abs()).ceil().max(1.0) as u32;
+    for step in 0..=steps {
+        let t = step as f32 / steps as f32;
+        let x = (from.0 + (to.0 - from.0) * t).round();
+        let y = (from.1 + (to.1 - from.1) * t).round();
+        if x >= 0.0 && y >= 0.0 && (x as u32) < image.width() && (y as u32) < image.height() {
+            image.put_pixel(x as u32, y as u32, color);
+        }
+    }
+}
@@ -0,0 +1,218 @@
+use crate::Failure;
+use serde::Serialize;
+use std::cell::Cell;
+use std::path;
+use std::sync::Mutex;
+use std::time::{Duration, Instant, SystemTime};
+
+/// What happened to a file
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub enum Status {
+    /// The preview was converted
+    Converted,
+    /// It was skipped, because the cache tells it is unchanged since it was converted
+    Unchanged,
+    /// It was left unchanged, because there is no thumbnail
+    NoThumbnail,
+    /// The conversion failed
+    Failed,
+}
+
+thread_local! {
+    /// Sizes of the simage and the gimage of the file processed on this thread
+    static PREVIEW_SIZES: Cell<(Option<u16>, Option<u16>)> = const { Cell::new((None, None)) };
+}
+
+/// Remember the sizes of the simage and the gimage of the file processed on
+/// this thread, for its report
+pub fn set_preview_sizes(simage: Option<u16>, gimage: Option<u16>) {
+    PREVIEW_SIZES.set((simage, gimage));
+}
+
+/// Get the sizes set with [`set_preview_sizes`] since the last call
+pub fn take_preview_sizes() -> (Option<u16>, Option<u16>) {
+    PREVIEW_SIZES.take()
+}
+
+/// What happened to a single file of the run
+#[derive(Serialize, Debug)]
+pub struct FileReport {
+    /// The G-code file
+    pub path: path::PathBuf,
+    pub status: Status,
+    /// Why the conversion failed, or there was nothing to convert
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub failure: Option<Failure>,
+    /// Exit code of the failure in `--strict` mode
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub exit_code: Option<i32>,
+    /// Size of the file before and after, in bytes
+    pub input_size: Option<u64>,
+    pub output_size: Option<u64>,
+    /// Sizes of the simage and the gimage, for the displays that have them
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub simage_size: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub gimage_size: Option<u16>,
+    /// How long processing the file took, in milliseconds
+    pub duration_ms: f64,
+    /// The errors and warnings logged for the file
+    pub errors: Vec<String>,
+    pub warnings: Vec<String>,
+}
+
+/// The whole report, as it is written
+#[derive(Serialize)]
+struct Written<'a> {
+    /// When the run started, in seconds since the Unix epoch
+    started: u64,
+    /// How long the run took, in milliseconds
+    duration_ms: f64,
+    converted: usize,
+    unchanged: usize,
+    no_thumbnail: usize,
+    failed: usize,
+    files: &'a [FileReport],
+}
+
+/// Machine-readable report of a run over several files, for print farms
+pub struct Report {
+    started: SystemTime,
+    timer: Instant,
+    files: Mutex<Vec<FileReport>>,
+}
+
+impl Report {
+    pub fn new() -> Self {
+        Report {
+            started: SystemTime::now(),
+            timer: Instant::now(),
+            files: Mutex::new(vec![]),
+        }
+    }
+
+    /// Add what happened to a file
+    pub fn add(&self, file: FileReport) {
+        self.files.lock().expect("Report is never poisoned").push(file);
+    }
+
+    /// Write the report as JSON
+    ///
+    /// The files are listed in the order of their paths, whatever order they
+    /// were converted in.
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - The file to write the report to
+    pub fn save(&self, path: &path::Path) -> Result<(), ()> {
+        let mut files = self.files.lock().expect("Report is never poisoned");
+        files.sort_by(|a, b| a.path.cmp(&b.path));
+        let count = |status| files.iter().filter(|file| file.status == status).count();
+        let written = Written {
+            started: self
+                .started
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+            duration_ms: milliseconds(self.timer.elapsed()),
+            converted: count(Status::Converted),
+            unchanged: count(Status::Unchanged),
+            no_thumbnail: count(Status::NoThumbnail),
+            failed: count(Status::Failed),
+            files: &files,
+        };
+        let json = serde_json::to_string_pretty(&written).expect("Report is always serializable");
+        std::fs::write(path, json + "\n").map_err(|e| {
+            log::error!("Failed to write the report to `{}`: {}", path.display(), e);
+        })?;
+        log::debug!("Report of {} files written to `{}`", files.len(), path.display());
+        Ok(())
+    }
+}
+
+/// Convert a duration to milliseconds
+pub fn milliseconds(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+/// Outcome of a file, printed as the last line on stdout for wrapper scripts
+#[derive(Serialize)]
+struct ResultLine<'a> {
+    file: String,
+    /// `ok`, `unchanged`, `no-thumbnail` or `failed`
+    status: &'a str,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    failure: Option<Failure>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    simage: Option<u16>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    gimage: Option<u16>,
+    /// How many bytes the file grew by
+    #[serde(skip_serializing_if = "Option::is_none")]
+    bytes_added: Option<i64>,
+}
+
+impl FileReport {
+    /// Get the line telling the outcome of the file to wrapper scripts
+    ///
+    /// The line is `RESULT` followed by `key=value` pairs, e.g.
+    /// `RESULT file=print.gcode status=ok simage=50 gimage=200 bytes_added=123456`.
+    /// Values with spaces, quotes or backslashes are quoted, with `\` before
+    /// quotes and backslashes. Keys without a value are left out.
+    ///
+    /// # Arguments
+    ///
+    /// * `json` - Whether to write the same keys as a JSON object instead
+    pub fn result_line(&self, json: bool) -> String {
+        let line = ResultLine {
+            file: self.path.display().to_string(),
+            status: match self.status {
+                Status::Converted => "ok",
+                Status::Unchanged => "unchanged",
+                Status::NoThumbnail => "no-thumbnail",
+                Status::Failed => "failed",
+            },
+            failure: self.failure.filter(|_| self.status == Status::Failed),
+            // The sizes are only written into converted files
+            simage: self.simage_size.filter(|_| self.status == Status::Converted),
+            gimage: self.gimage_size.filter(|_| self.status == Status::Converted),
+            bytes_added: self
+                .input_size
+                .zip(self.output_size)
+                .map(|(input, output)| output as i64 - input as i64),
+        };
+        if json {
+            return serde_json::to_string(&line).expect("Results are always serializable");
+        }
+        let failure = line.failure.map(|failure| {
+            let value = serde_json::to_value(failure).expect("Failures are always serializable");
+            value.as_str().unwrap_or_default().to_string()
+        });
+        let values = [
+            ("file", Some(line.file)),
+            ("status", Some(line.status.to_string())),
+            ("failure", failure),
+            ("simage", line.simage.map(|size| size.to_string())),
+            ("gimage", line.gimage.map(|size| size.to_string())),
+            ("bytes_added", line.bytes_added.map(|bytes| bytes.to_string())),
+        ];
+        let mut text = String::from("RESULT");
+        for (key, value) in values {
+            let Some(value) = value else {
+                continue;
+            };
+            let quoted = value.is_empty()
+                || value.contains(|c: char| c.is_whitespace() || c == '"' || c == '\\');
+            match quoted {
+                true => text.push_str(&format!(
+                    " {}=\"{}\"",
+                    key,
+                    value.replace('\\', "\\\\").replace('"', "\\\"")
+                )),
+                false => text.push_str(&format!(" {}={}", key, value)),
+            }
+        }
+        text
+    }
+}
@@ -0,0 +1,436 @@
+use crate::{inspect, logging};
+use mks_tft_img::options::ProcessOptions;
+use std::io::{BufRead, BufReader, BufWriter, Read, Write};
+use std::net::{TcpListener, TcpStream};
+use std::sync::mpsc::{self, TrySendError};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+/// Longest request line or header accepted
+const MAX_HEADER_LINE: usize = 8 * 1024;
+
+/// Most headers accepted in a request
+const MAX_HEADERS: usize = 64;
+
+/// Size of the chunks the converted G-code is sent in
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// How the service runs
+pub struct Limits {
+    /// Largest request body accepted, in bytes
+    pub max_body: u64,
+    /// How many connections are handled at the same time
+    pub workers: usize,
+    /// How long reading or writing a connection may stall
+    pub timeout: Duration,
+}
+
+/// The body of a response
+enum Body<'a> {
+    /// A body that is sent as it is
+    Bytes(Vec<u8>),
+    /// Converted G-code, which is written to the connection as it is produced
+    Gcode(mks_tft_img::Converted<'a>),
+}
+
+/// A response to a request
+struct Response<'a> {
+    status: &'static str,
+    content_type: &'static str,
+    body: Body<'a>,
+}
+
+impl Response<'_> {
+    /// A plain text response
+    fn text(status: &'static str, text: impl Into<String>) -> Self {
+        let mut body = text.into().into_bytes();
+        body.push(b'\n');
+        Response {
+            status,
+            content_type: "text/plain; charset=utf-8",
+            body: Body::Bytes(body),
+        }
+    }
+
+    /// Write the response to a connection
+    fn write_to(&self, writer: &mut impl Write) -> std::io::Result<()> {
+        write!(
+            writer,
+            "HTTP/1.1 {}\r\nContent-Type: {}\r\nConnection: close\r\n",
+            self.status, self.content_type
+        )?;
+        match &self.body {
+            Body::Bytes(body) => {
+                write!(writer, "Content-Length: {}\r\n\r\n", body.len())?;
+                writer.write_all(body)?;
+            }
+            Body::Gcode(converted) => {
+                writer.write_all(b"Transfer-Encoding: chunked\r\n\r\n")?;
+                let mut chunked = BufWriter::with_capacity(CHUNK_SIZE, Chunked(&mut *writer));
+                converted.write_to(&mut chunked)?;
+                chunked.into_inner().map_err(|e| e.into_error())?;
+                writer.write_all(b"0\r\n\r\n")?;
+            }
+        }
+        writer.flush()
+    }
+}
+
+/// Writes each buffer as a chunk of chunked transfer encoding
+struct Chunked<W>(W);
+
+impl<W: Write> Write for Chunked<W> {
+    fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+        // An empty chunk would end the body
+        if !buf.is_empty() {
+            write!(self.0, "{:x}\r\n", buf.len())?;
+            self.0.write_all(buf)?;
+            self.0.write_all(b"\r\n")?;
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> std::io::Result<()> {
+        self.0.flush()
+    }
+}
+
+/// Serve conversions over HTTP, until the process is stopped
+///
+/// `POST /convert` takes G-code as the request body and responds with the
+/// converted G-code. `POST /inspect` responds with the print metadata as
+/// JSON, like the `inspect` command. A fixed number of worker threads handle
+/// the connections, each closed after the response. Connections beyond the
+/// ones waiting for a worker are refused with 503, and a request that crashes
+/// its worker gets a 500 instead of taking the worker down.
+///
+/// # Arguments
+///
+/// * `listen` - The address and port to listen on, e.g. `0.0.0.0:8080`
+/// * `options` - How to convert the thumbnails
+/// * `limits` - How much the service takes on
+pub fn run(listen: &str, options: ProcessOptions, limits: Limits) -> Result<(), ()> {
+    let listener = TcpListener::bind(listen)
+        .map_err(|e| log::error!("Cannot listen on `{}`: {}", listen, e))?;
+    println!("Listening on http://{}, for POST /convert and POST /inspect", listen);
+    serve(listener, options, limits)
+}
+
+/// Accept connections and hand them to the workers, see [`run`]
+fn serve(listener: TcpListener, options: ProcessOptions, limits: Limits) -> Result<(), ()> {
+    let options = Arc::new(options);
+    let workers = limits.workers.max(1);
+    let (sender, receiver) = mpsc::sync_channel::<TcpStream>(workers);
+    let receiver = Arc::new(Mutex::new(receiver));
+    for _ in 0..workers {
+        let options = Arc::clone(&options);
+        let receiver = Arc::clone(&receiver);
+        let max_body = limits.max_body;
+        std::thread::spawn(move || loop {
+            // The lock is released before handling, so the next worker can wait
+            let stream = match receiver.lock().expect("A worker panicked").recv() {
+                Ok(stream) => stream,
+                Err(_) => break,
+            };
+            handle_isolated(stream, |stream| handle(stream, &options, max_body));
+        });
+    }
+
+    for stream in listener.incoming() {
+        let stream = match stream {
+            Ok(stream) => stream,
+            Err(e) => {
+                log::warn!("Cannot accept a connection: {}", e);
+                continue;
+            }
+        };
+        if let Err(e) = stream
+            .set_read_timeout(Some(limits.timeout))
+            .and_then(|_| stream.set_write_timeout(Some(limits.timeout)))
+        {
+            log::warn!("Cannot set the timeouts of a connection: {}", e);
+            continue;
+        }
+        match sender.try_send(stream) {
+            Ok(()) => (),
+            Err(TrySendError::Full(mut stream)) => {
+                log::warn!("All {} workers are busy, refusing a connection", workers);
+                let busy =
+                    Response::text("503 Service Unavailable", "Too many conversions, retry later");
+                // The client may be gone already, and there is nobody to tell
+                let _ = busy.write_to(&mut stream);
+            }
+            Err(TrySendError::Disconnected(_)) => {
+                log::error!("The workers of the service stopped");
+                return Err(());
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Handle a connection, turning a crash into a 500 response
+///
+/// A bug, e.g. in the image decoder on a corrupt thumbnail, then only fails
+/// its request, and the worker goes on with the next one.
+///
+/// # Arguments
+///
+/// * `stream` - The connection
+/// * `handle` - What reads the request from the connection and responds to it
+fn handle_isolated(stream: TcpStream, handle: impl FnOnce(TcpStream) -> std::io::Result<()>) {
+    let peer = stream.peer_addr().map(|addr| addr.to_string()).unwrap_or_default();
+    let mut crashed = stream.try_clone();
+    let handled = std::panic::catch_unwind(std::panic::AssertUnwindSafe(|| handle(stream)));
+    match handled {
+        Ok(Ok(())) => (),
+        Ok(Err(e)) => log::warn!("Connection with {} failed: {}", peer, e),
+        Err(panic) => {
+            log::error!("The request of {} crashed: {}", peer, crate::panic_message(&*panic));
+            if let Ok(stream) = &mut crashed {
+                let response = Response::text("500 Internal Server Error", "The request crashed");
+                // The response may be half sent already, then the client sees it cut off
+                let _ = response.write_to(stream);
+            }
+        }
+    }
+}
+
+/// Read a request from a connection and respond to it
+fn handle(stream: TcpStream, options: &ProcessOptions, max_body: u64) -> std::io::Result<()> {
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut writer = stream;
+    let request_line = read_line(&mut reader)?;
+    let mut parts = request_line.split_whitespace();
+    let (method, target) = (parts.next().unwrap_or_default(), parts.next().unwrap_or_default());
+    let path = target.split('?').next().unwrap_or_default();
+
+    let mut content_length = None;
+    let mut expect_continue = false;
+    for _ in 0..MAX_HEADERS {
+        let line = read_line(&mut reader)?;
+        if line.is_empty() {
+            break;
+        }
+        let Some((name, value)) = line.split_once(':') else {
+            continue;
+        };
+        match name.trim().to_lowercase().as_str() {
+            "content-length" => content_length = value.trim().parse::<u64>().ok(),
+            "expect" => expect_continue = value.trim().eq_ignore_ascii_case("100-continue"),
+            _ => (),
+        }
+    }
+
+    // Declared here, as the converted G-code is sent from it
+    let mut body = vec![];
+    let response = match (method, path) {
+        ("POST", "/convert" | "/inspect") => match content_length {
+            None => Response::text("411 Length Required", "Content-Length is required"),
+            Some(length) if length > max_body => {
+                Response::text("413 Content Too Large", format!("Send at most {} bytes", max_body))
+            }
+            Some(length) => {
+                if expect_continue {
+                    writer.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+                }
+                body.reserve(length as usize);
+                (&mut reader).take(length).read_to_end(&mut body)?;
+                if (body.len() as u64) < length {
+                    return Err(std::io::ErrorKind::UnexpectedEof.into());
+                }
+                match path {
+                    "/convert" => convert(&body, options),
+                    _ => Response {
+                        status: "200 OK",
+                        content_type: "application/json",
+                        body: Body::Bytes(
+                            serde_json::to_vec(&inspect::inspect_gcode(&body))
+                                .expect("Inspection is always serializable"),
+                        ),
+                    },
+                }
+            }
+        },
+        (_, "/convert" | "/inspect") => Response::text("405 Method Not Allowed", "Use POST"),
+        _ => Response::text("404 Not Found", "Use POST /convert or POST /inspect"),
+    };
+    log::info!("{} {} -> {}", method, target, response.status);
+    response.write_to(&mut writer)
+}
+
+/// Convert uploaded G-code
+///
+/// # Returns
+///
+/// The converted G-code, or the errors and warnings of the conversion
+fn convert<'a>(gcode: &'a [u8], options: &ProcessOptions) -> Response<'a> {
+    logging::collect_problems();
+    let converted = mks_tft_img::prepare(gcode, options, None);
+    let problems = logging::take_problems();
+    match converted {
+        Ok(converted) => Response {
+            status: "200 OK",
+            content_type: "text/x-gcode",
+            body: Body::Gcode(converted),
+        },
+        Err(()) => {
+            let messages: Vec<_> = problems.into_iter().map(|(_, message)| message).collect();
+            Response::text("422 Unprocessable Content", messages.join("\n"))
+        }
+    }
+}
+
+/// Read a line of the request head, without the line ending
+fn read_line(reader: &mut impl BufRead) -> std::io::Result<String> {
+    let mut line = vec![];
+    reader.take(MAX_HEADER_LINE as u64).read_until(b'\n', &mut line)?;
+    if line.last() != Some(&b'\n') {
+        return Err(std::io::Error::new(std::io::ErrorKind::InvalidData, "Invalid request head"));
+    }
+    Ok(String::from_utf8_lossy(&line).trim_end().to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::tests::thumbnail_gcode;
+
+    /// Start the service on a free port
+    fn start(workers: usize, timeout: Duration) -> std::net::SocketAddr {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let limits = Limits {
+            max_body: 64 * 1024,
+            workers,
+            timeout,
+        };
+        std::thread::spawn(move || serve(listener, ProcessOptions::default(), limits));
+        addr
+    }
+
+    /// Send a request and read the response
+    ///
+    /// # Returns
+    ///
+    /// The status line, the headers and the body, without chunked encoding
+    fn request(addr: std::net::SocketAddr, request: &[u8]) -> (String, String, Vec<u8>) {
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream.write_all(request).unwrap();
+        let mut response = vec![];
+        stream.read_to_end(&mut response).unwrap();
+        let end = response.windows(4).position(|window| window == b"\r\n\r\n").unwrap();
+        let head = String::from_utf8(response[..end].to_vec()).unwrap();
+        let (status, headers) = head.split_once("\r\n").unwrap();
+        let mut body = response[end + 4..].to_vec();
+        if headers.contains("Transfer-Encoding: chunked") {
+            body = dechunk(&body);
+        }
+        (status.to_string(), headers.to_string(), body)
+    }
+
+    /// Decode a body sent with chunked transfer encoding
+    fn dechunk(mut chunked: &[u8]) -> Vec<u8> {
+        let mut body = vec![];
+        loop {
+            let line_end = chunked.windows(2).position(|window| window == b"\r\n").unwrap();
+            let size = std::str::from_utf8(&chunked[..line_end]).unwrap();
+            let size = usize::from_str_radix(size, 16).unwrap();
+            chunked = &chunked[line_end + 2..];
+            if size == 0 {
+                assert_eq!(chunked, b"\r\n");
+                return body;
+            }
+            body.extend_from_slice(&chunked[..size]);
+            assert_eq!(&chunked[size..size + 2], b"\r\n");
+            chunked = &chunked[size + 2..];
+        }
+    }
+
+    /// A POST request with a body
+    fn post(path: &str, body: &[u8]) -> Vec<u8> {
+        let head =
+            format!("POST {} HTTP/1.1\r\nHost: x\r\nContent-Length: {}\r\n\r\n", path, body.len());
+        [head.as_bytes(), body].concat()
+    }
+
+    #[test]
+    fn converts_gcode() {
+        let addr = start(2, Duration::from_secs(5));
+        let gcode = thumbnail_gcode(b"", &b"G1 X10\n".repeat(5000));
+        let (status, headers, body) = request(addr, &post("/convert?x=1", &gcode));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(headers.contains("Content-Type: text/x-gcode"));
+        assert_eq!(body, mks_tft_img::convert(&gcode, &ProcessOptions::default()).unwrap());
+
+        let (status, ..) = request(addr, &post("/convert", b"G28\n"));
+        assert_eq!(status, "HTTP/1.1 422 Unprocessable Content");
+
+        let (status, headers, body) = request(addr, &post("/inspect", &gcode));
+        assert_eq!(status, "HTTP/1.1 200 OK");
+        assert!(headers.contains("Content-Type: application/json"));
+        assert!(serde_json::from_slice::<serde_json::Value>(&body).is_ok());
+    }
+
+    #[test]
+    fn refuses_invalid_requests() {
+        let addr = start(2, Duration::from_secs(5));
+        let (status, ..) = request(addr, b"POST /convert HTTP/1.1\r\nHost: x\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 411 Length Required");
+        let (status, _, body) =
+            request(addr, b"POST /convert HTTP/1.1\r\nContent-Length: 65537\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 413 Content Too Large");
+        assert_eq!(body, b"Send at most 65536 bytes\n");
+        let (status, ..) = request(addr, b"GET /convert HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 405 Method Not Allowed");
+        let (status, ..) = request(addr, b"GET / HTTP/1.1\r\n\r\n");
+        assert_eq!(status, "HTTP/1.1 404 Not Found");
+        let mut stream = TcpStream::connect(addr).unwrap();
+        stream
+            .write_all(
+                b"POST /convert HTTP/1.1\r\nContent-Length: 5\r\nExpect: 100-continue\r\n\r\n",
+            )
+            .unwrap();
+        let mut interim = [0; 25];
+        stream.read_exact(&mut interim).unwrap();
+        assert_eq!(&interim, b"HTTP/1.1 100 Continue\r\n\r\n");
+        stream.write_all(b"G28\r\n").unwrap();
+        let mut response = String::new();
+        stream.read_to_string(&mut response).unwrap();
+        assert!(response.starts_with("HTTP/1.1 422 Unprocessable Content\r\n"));
+    }
+
+    #[test]
+    fn refuses_connections_when_busy_and_drops_stalled_clients() {
+        let addr = start(1, Duration::from_millis(500));
+        // The worker waits for the first request, and the second one waits for the worker
+        let mut stalled = TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        let _queued = TcpStream::connect(addr).unwrap();
+        std::thread::sleep(Duration::from_millis(100));
+        // Sent before the request, which would be left unread
+        let (status, ..) = request(addr, b"");
+        assert_eq!(status, "HTTP/1.1 503 Service Unavailable");
+
+        // The stalled client is disconnected after the timeout, without a response
+        let mut response = vec![];
+        stalled.set_read_timeout(Some(Duration::from_secs(5))).unwrap();
+        assert_eq!(stalled.read_to_end(&mut response).unwrap(), 0);
+    }
+
+    #[test]
+    fn answers_a_crash_with_500() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let client = std::thread::spawn(move || request(addr, b"GET / HTTP/1.1\r\n\r\n"));
+        let (stream, _) = listener.accept().unwrap();
+        handle_isolated(stream, |stream| {
+            let mut reader = BufReader::new(stream);
+            while !read_line(&mut reader).unwrap().is_empty() {}
+            panic!("Corrupt thumbnail")
+        });
+        let (status, _, body) = client.join().unwrap();
+        assert_eq!(status, "HTTP/1.1 500 Internal Server Error");
+        assert_eq!(body, b"The request crashed\n");
+    }
+}
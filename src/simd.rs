@@ -0,0 +1,87 @@
+//! SIMD RGB565 conversion for x86_64
+//!
+//! SSE2 is part of the x86_64 baseline, so no runtime feature detection is
+//! needed.
+
+use std::arch::x86_64::*;
+
+/// Number of pixels converted at once
+const LANES: usize = 8;
+
+/// Convert a row of packed RGB8 pixels to RGB565 colors
+///
+/// Produces exactly the same result as calling `rgb565` for every pixel.
+///
+/// # Arguments
+///
+/// * `row` - Packed RGB8 pixels, three bytes per pixel
+/// * `colors` - Where to append the RGB565 colors to
+pub fn rgb565_row(row: &[u8], colors: &mut Vec<u16>) {
+    let mut chunks = row.chunks_exact(LANES * 3);
+    for chunk in chunks.by_ref() {
+        let mut words = [0u16; LANES];
+        // SAFETY: SSE2 is always available on x86_64, and `words` has room
+        // for exactly one 128-bit store.
+        unsafe {
+            let channel = |offset: usize| {
+                _mm_setr_epi16(
+                    chunk[offset] as i16,
+                    chunk[3 + offset] as i16,
+                    chunk[6 + offset] as i16,
+                    chunk[9 + offset] as i16,
+                    chunk[12 + offset] as i16,
+                    chunk[15 + offset] as i16,
+                    chunk[18 + offset] as i16,
+                    chunk[21 + offset] as i16,
+                )
+            };
+            let r = _mm_slli_epi16::<8>(_mm_and_si128(channel(0), _mm_set1_epi16(0xF8)));
+            let g = _mm_slli_epi16::<3>(_mm_and_si128(channel(1), _mm_set1_epi16(0xFC)));
+            let b = _mm_srli_epi16::<3>(channel(2));
+            let color = _mm_or_si128(_mm_or_si128(r, g), b);
+            _mm_storeu_si128(words.as_mut_ptr() as *mut __m128i, color);
+        }
+        colors.extend_from_slice(&words);
+    }
+    for pixel in chunks.remainder().chunks_exact(3) {
+        let r = (pixel[0] as u16) >> 3;
+        let g = (pixel[1] as u16) >> 2;
+        let b = (pixel[2] as u16) >> 3;
+        colors.push(r << 11 | g << 5 | b);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::encoders::rgb565;
+    use image::Rgb;
+
+    #[test]
+    fn matches_the_scalar_conversion() {
+        // Every combination of channel extremes and a few values in between
+        let levels = [0u8, 1, 3, 4, 7, 8, 127, 128, 251, 252, 254, 255];
+        let mut pixels = vec![];
+        for &r in &levels {
+            for &g in &levels {
+                for &b in &levels {
+                    pixels.extend_from_slice(&[r, g, b]);
+                }
+            }
+        }
+        // Also rows that are not a multiple of the lanes
+        for length in (0..=2 * LANES + 1).chain([pixels.len() / 3]) {
+            let row = &pixels[..length * 3];
+            let mut colors = vec![];
+            rgb565_row(row, &mut colors);
+            let expected: Vec<u16> = row
+                .chunks_exact(3)
+                .map(|pixel| {
+                    let (higher, lower) = rgb565(&Rgb([pixel[0], pixel[1], pixel[2]]));
+                    u16::from_be_bytes([higher, lower])
+                })
+                .collect();
+            assert_eq!(colors, expected, "{length} pixels");
+        }
+    }
+}
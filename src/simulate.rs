@@ -0,0 +1,266 @@
+use image::{Rgb, RgbImage};
+use mks_tft_img::options::ProcessOptions;
+use mks_tft_img::{encoders, mmap, render, source, verify};
+use std::path;
+
+/// Space between the tiles and buttons of the file browser
+const GAP: u32 = 2;
+/// Height of the title bar at the top of the screen
+const TITLE_HEIGHT: u32 = 34;
+/// How many files are shown on a page, in 3 columns and 2 rows
+const FILES_PER_PAGE: usize = 6;
+/// Color of the file names and button labels
+const TEXT: Rgb<u8> = Rgb([255, 255, 255]);
+/// Color of the page and back buttons
+const BUTTON: Rgb<u8> = Rgb([64, 64, 64]);
+
+/// Glyphs of the printable ASCII characters, 5 columns of 8 pixels each,
+/// the lowest bit at the top
+const FONT: [[u8; 5]; 95] = [
+    [0x00, 0x00, 0x00, 0x00, 0x00],
+    [0x00, 0x00, 0x5F, 0x00, 0x00],
+    [0x00, 0x07, 0x00, 0x07, 0x00],
+    [0x14, 0x7F, 0x14, 0x7F, 0x14],
+    [0x24, 0x2A, 0x7F, 0x2A, 0x12],
+    [0x23, 0x13, 0x08, 0x64, 0x62],
+    [0x36, 0x49, 0x56, 0x20, 0x50],
+    [0x00, 0x08, 0x07, 0x03, 0x00],
+    [0x00, 0x1C, 0x22, 0x41, 0x00],
+    [0x00, 0x41, 0x22, 0x1C, 0x00],
+    [0x2A, 0x1C, 0x7F, 0x1C, 0x2A],
+    [0x08, 0x08, 0x3E, 0x08, 0x08],
+    [0x00, 0x80, 0x70, 0x30, 0x00],
+    [0x08, 0x08, 0x08, 0x08, 0x08],
+    [0x00, 0x00, 0x60, 0x60, 0x00],
+    [0x20, 0x10, 0x08, 0x04, 0x02],
+    [0x3E, 0x51, 0x49, 0x45, 0x3E],
+    [0x00, 0x42, 0x7F, 0x40, 0x00],
+    [0x72, 0x49, 0x49, 0x49, 0x46],
+    [0x21, 0x41, 0x49, 0x4D, 0x33],
+    [0x18, 0x14, 0x12, 0x7F, 0x10],
+    [0x27, 0x45, 0x45, 0x45, 0x39],
+    [0x3C, 0x4A, 0x49, 0x49, 0x31],
+    [0x41, 0x21, 0x11, 0x09, 0x07],
+    [0x36, 0x49, 0x49, 0x49, 0x36],
+    [0x46, 0x49, 0x49, 0x29, 0x1E],
+    [0x00, 0x00, 0x14, 0x00, 0x00],
+    [0x00, 0x40, 0x34, 0x00, 0x00],
+    [0x00, 0x08, 0x14, 0x22, 0x41],
+    [0x14, 0x14, 0x14, 0x14, 0x14],
+    [0x00, 0x41, 0x22, 0x14, 0x08],
+    [0x02, 0x01, 0x59, 0x09, 0x06],
+    [0x3E, 0x41, 0x5D, 0x59, 0x4E],
+    [0x7C, 0x12, 0x11, 0x12, 0x7C],
+    [0x7F, 0x49, 0x49, 0x49, 0x36],
+    [0x3E, 0x41, 0x41, 0x41, 0x22],
+    [0x7F, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x49, 0x49, 0x49, 0x41],
+    [0x7F, 0x09, 0x09, 0x09, 0x01],
+    [0x3E, 0x41, 0x41, 0x51, 0x73],
+    [0x7F, 0x08, 0x08, 0x08, 0x7F],
+    [0x00, 0x41, 0x7F, 0x41, 0x00],
+    [0x20, 0x40, 0x41, 0x3F, 0x01],
+    [0x7F, 0x08, 0x14, 0x22, 0x41],
+    [0x7F, 0x40, 0x40, 0x40, 0x40],
+    [0x7F, 0x02, 0x1C, 0x02, 0x7F],
+    [0x7F, 0x04, 0x08, 0x10, 0x7F],
+    [0x3E, 0x41, 0x41, 0x41, 0x3E],
+    [0x7F, 0x09, 0x09, 0x09, 0x06],
+    [0x3E, 0x41, 0x51, 0x21, 0x5E],
+    [0x7F, 0x09, 0x19, 0x29, 0x46],
+    [0x26, 0x49, 0x49, 0x49, 0x32],
+    [0x03, 0x01, 0x7F, 0x01, 0x03],
+    [0x3F, 0x40, 0x40, 0x40, 0x3F],
+    [0x1F, 0x20, 0x40, 0x20, 0x1F],
+    [0x3F, 0x40, 0x38, 0x40, 0x3F],
+    [0x63, 0x14, 0x08, 0x14, 0x63],
+    [0x03, 0x04, 0x78, 0x04, 0x03],
+    [0x61, 0x59, 0x49, 0x4D, 0x43],
+    [0x00, 0x7F, 0x41, 0x41, 0x41],
+    [0x02, 0x04, 0x08, 0x10, 0x20],
+    [0x00, 0x41, 0x41, 0x41, 0x7F],
+    [0x04, 0x02, 0x01, 0x02, 0x04],
+    [0x40, 0x40, 0x40, 0x40, 0x40],
+    [0x00, 0x03, 0x07, 0x08, 0x00],
+    [0x20, 0x54, 0x54, 0x78, 0x40],
+    [0x7F, 0x28, 0x44, 0x44, 0x38],
+    [0x38, 0x44, 0x44, 0x44, 0x28],
+    [0x38, 0x44, 0x44, 0x28, 0x7F],
+    [0x38, 0x54, 0x54, 0x54, 0x18],
+    [0x00, 0x08, 0x7E, 0x09, 0x02],
+    [0x18, 0xA4, 0xA4, 0x9C, 0x78],
+    [0x7F, 0x08, 0x04, 0x04, 0x78],
+    [0x00, 0x44, 0x7D, 0x40, 0x00],
+    [0x20, 0x40, 0x40, 0x3D, 0x00],
+    [0x7F, 0x10, 0x28, 0x44, 0x00],
+    [0x00, 0x41, 0x7F, 0x40, 0x00],
+    [0x7C, 0x04, 0x78, 0x04, 0x78],
+    [0x7C, 0x08, 0x04, 0x04, 0x78],
+    [0x38, 0x44, 0x44, 0x44, 0x38],
+    [0xFC, 0x18, 0x24, 0x24, 0x18],
+    [0x18, 0x24, 0x24, 0x18, 0xFC],
+    [0x7C, 0x08, 0x04, 0x04, 0x08],
+    [0x48, 0x54, 0x54, 0x54, 0x24],
+    [0x04, 0x04, 0x3F, 0x44, 0x24],
+    [0x3C, 0x40, 0x40, 0x20, 0x7C],
+    [0x1C, 0x20, 0x40, 0x20, 0x1C],
+    [0x3C, 0x40, 0x30, 0x40, 0x3C],
+    [0x44, 0x28, 0x10, 0x28, 0x44],
+    [0x4C, 0x90, 0x90, 0x90, 0x7C],
+    [0x44, 0x64, 0x54, 0x4C, 0x44],
+    [0x00, 0x08, 0x36, 0x41, 0x00],
+    [0x00, 0x00, 0x77, 0x00, 0x00],
+    [0x00, 0x41, 0x36, 0x08, 0x00],
+    [0x02, 0x01, 0x02, 0x04, 0x02],
+];
+
+/// Draw text, with `?` for characters the font doesn't have
+///
+/// Each character takes 6 columns of 8 rows, multiplied by `scale`.
+fn draw_text(screen: &mut RgbImage, text: &str, x: u32, y: u32, scale: u32) {
+    for (index, c) in text.chars().enumerate() {
+        let glyph = FONT[match c {
+            ' '..='~' => c as usize - ' ' as usize,
+            _ => '?' as usize - ' ' as usize,
+        }];
+        let left = x + index as u32 * 6 * scale;
+        for (column, bits) in glyph.iter().enumerate() {
+            for row in (0..8).filter(|row| bits >> row & 1 == 1) {
+                fill(screen, left + column as u32 * scale, y + row * scale, scale, scale, TEXT);
+            }
+        }
+    }
+}
+
+/// Fill a rectangle, clipped to the screen
+fn fill(screen: &mut RgbImage, x: u32, y: u32, width: u32, height: u32, color: Rgb<u8>) {
+    for y in y..(y + height).min(screen.height()) {
+        for x in x..(x + width).min(screen.width()) {
+            screen.put_pixel(x, y, color);
+        }
+    }
+}
+
+/// Shorten a file name to the characters that fit in a tile, ending it with
+/// `~` like the firmware does
+///
+/// # Arguments
+///
+/// * `path` - The G-code file
+/// * `fits` - How many characters fit in a tile
+fn file_name(path: &path::Path, fits: usize) -> String {
+    let name = path.file_stem().unwrap_or_default().to_string_lossy();
+    match name.chars().count() > fits {
+        true => name.chars().take(fits.saturating_sub(1)).chain(['~']).collect(),
+        false => name.into_owned(),
+    }
+}
+
+/// Get the simage of a G-code file, as the display shows it
+///
+/// The simage of a converted file is read back from the file, the others are
+/// converted the way the tool would convert them.
+///
+/// # Arguments
+///
+/// * `path` - The G-code file
+/// * `options` - How the thumbnail would be converted
+///
+/// # Returns
+///
+/// The simage, or `None` if the file has no thumbnail
+fn simage(path: &path::Path, options: &ProcessOptions) -> Result<Option<RgbImage>, ()> {
+    let gcode =
+        std::fs::read(path).map_err(|e| log::error!("Cannot read `{}`: {}", path.display(), e))?;
+    if gcode.starts_with(b";simage:") {
+        let text = String::from_utf8_lossy(&gcode);
+        let (width, height, words) = verify::read_rgb565_words(&text, ";simage", "M10086 ;", true)
+            .or_else(|_| verify::read_rgb565_words(&text, ";simage", "M10086 ", true))
+            .map_err(|e| log::error!("Cannot read the simage of `{}`: {}", path.display(), e))?;
+        let pixels = words
+            .into_iter()
+            .flat_map(|word| encoders::rgb565_to_rgb((word >> 8) as u8, word as u8).0)
+            .collect();
+        return Ok(RgbImage::from_raw(width, height, pixels));
+    }
+    let (_, image_lines, _) = mmap::split_gcode(&gcode)?;
+    let embedded = source::Embedded {
+        image_lines: &image_lines,
+        strict: options.strict,
+        limits: options.image_limits(),
+    };
+    let Some((thumbnail, _)) = source::first_thumbnail(&[&embedded])? else {
+        return Ok(None);
+    };
+    let (simage, _) = options.resize(&thumbnail.into_image(options.icc));
+    Ok(Some(encoders::quantize_rgb565(&simage)))
+}
+
+/// Save a mock screenshot of the file browser of an MKS TFT display, with
+/// the simages of G-code files on its first page
+///
+/// The layout is the one of a 480x320 display with 100x100 simages, and
+/// grows or shrinks with the simage size.
+///
+/// # Arguments
+///
+/// * `paths` - The G-code files, in the order they are listed
+/// * `output` - The PNG file to save
+/// * `options` - How the thumbnails would be converted
+/// * `background` - Color of the screen
+pub fn run(
+    paths: &[path::PathBuf],
+    output: &path::Path,
+    options: &ProcessOptions,
+    background: [u8; 3],
+) -> Result<(), ()> {
+    if paths.len() > FILES_PER_PAGE {
+        log::warn!(
+            "Only the first {} files fit on a page, the others are left out",
+            FILES_PER_PAGE
+        );
+    }
+    let size = u32::from(options.simage_size);
+    let (tile_width, tile_height) = (size + 17, size + 40);
+    let width = 4 * tile_width + 6 * GAP;
+    let height = TITLE_HEIGHT + 2 * tile_height + 3 * GAP;
+    let scale = if size >= 100 {
+        2
+    } else {
+        1
+    };
+    let mut screen = RgbImage::from_pixel(width, height, Rgb(background));
+    draw_text(&mut screen, "Print", GAP + 4, (TITLE_HEIGHT - 8 * scale) / 2, scale);
+
+    for (index, path) in paths.iter().take(FILES_PER_PAGE).enumerate() {
+        let x = GAP + (index as u32 % 3) * (tile_width + GAP);
+        let y = TITLE_HEIGHT + GAP + (index as u32 / 3) * (tile_height + GAP);
+        let simage = simage(path, options)?.unwrap_or_else(|| {
+            log::warn!("`{}` has no thumbnail, the firmware shows its own icon", path.display());
+            render::placeholder(size).to_rgb8()
+        });
+        let left = x + (tile_width - simage.width().min(tile_width)) / 2;
+        image::imageops::overlay(&mut screen, &simage, left.into(), (y + 4).into());
+        let fits = ((tile_width - 4) / (6 * scale)) as usize;
+        let name = file_name(path, fits);
+        let text_width = name.chars().count() as u32 * 6 * scale;
+        let text_y = y + size + 4 + (36 - 8 * scale) / 2;
+        draw_text(&mut screen, &name, x + (tile_width - text_width) / 2, text_y, scale);
+    }
+
+    let x = 3 * (tile_width + GAP) + 2 * GAP;
+    let button_height = (2 * tile_height + GAP - 2 * GAP) / 3;
+    for (index, label) in ["Up", "Down", "Back"].into_iter().enumerate() {
+        let y = TITLE_HEIGHT + GAP + index as u32 * (button_height + GAP);
+        fill(&mut screen, x, y, tile_width, button_height, BUTTON);
+        let text_width = label.len() as u32 * 6 * scale;
+        let text_y = y + (button_height - 8 * scale) / 2;
+        draw_text(&mut screen, label, x + (tile_width - text_width) / 2, text_y, scale);
+    }
+
+    screen
+        .save_with_format(output, image::ImageFormat::Png)
+        .map_err(|e| log::error!("Failed to save `{}`: {}", output.display(), e))?;
+    log::info!("Saved the simulated file browser to `{}`", output.display());
+    Ok(())
+}
@@ -0,0 +1,189 @@
+use crate::summary::Summary;
+use crate::{storage, upload, zip, Failure};
+use std::cell::RefCell;
+use std::fs::File;
+use std::path;
+
+/// Where the converted G-code goes
+///
+/// The G-code is converted into a temporary file first, which is then
+/// delivered to each sink of a stack in turn, e.g. to replace the original
+/// file and to upload it.
+pub trait OutputSink {
+    /// Name of the processing phase of the delivery, for statistics
+    fn phase(&self) -> &'static str;
+
+    /// Deliver the converted G-code
+    ///
+    /// # Arguments
+    ///
+    /// * `converted` - Where the converted G-code is
+    ///
+    /// # Returns
+    ///
+    /// Where the converted G-code is after the delivery
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure>;
+}
+
+/// Replace the original G-code file
+pub struct ReplaceOriginal<'a> {
+    /// Path to the original G-code file
+    pub path: &'a path::Path,
+    /// Whether to flush the folder to the storage device after the rename
+    pub fsync: bool,
+    /// How many times a failed rename is retried
+    pub retries: u32,
+    /// Whether to keep the modification time, permissions and owner of the
+    /// original file
+    pub preserve_metadata: bool,
+}
+
+impl OutputSink for ReplaceOriginal<'_> {
+    fn phase(&self) -> &'static str {
+        "output"
+    }
+
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure> {
+        log::debug!("Replacing {} with {}", self.path.display(), converted.display());
+        if self.preserve_metadata {
+            if let Err(e) = storage::copy_metadata(self.path, converted) {
+                log::warn!("Failed to keep the metadata of the original file: {}", e);
+            }
+        }
+        storage::retry(self.retries, "replace the original file", || {
+            std::fs::rename(converted, self.path)
+        })
+        .and_then(|_| match self.fsync {
+            true => storage::sync_folder(self.path, self.retries),
+            false => Ok(()),
+        })
+        .map_err(|e| {
+            log::error!("Failed to replace original gcode file: {}", e);
+            Failure::Write
+        })?;
+        Ok(self.path.to_path_buf())
+    }
+}
+
+/// Write a new file, leaving the original one unchanged
+pub struct NewFile<'a> {
+    /// Path to the new file
+    pub path: &'a path::Path,
+    /// Whether to flush the new file to the storage device
+    pub fsync: bool,
+    /// How many times a failed copy is retried
+    pub retries: u32,
+}
+
+impl OutputSink for NewFile<'_> {
+    fn phase(&self) -> &'static str {
+        "output"
+    }
+
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure> {
+        log::debug!("Copying {} to {}", converted.display(), self.path.display());
+        storage::retry(self.retries, "copy", || std::fs::copy(converted, self.path))
+            .and_then(|_| match self.fsync {
+                true => storage::sync_file(self.path, self.retries),
+                false => Ok(()),
+            })
+            .map_err(|e| {
+                log::error!("Failed to write `{}`: {}", self.path.display(), e);
+                Failure::Write
+            })?;
+        Ok(converted.to_path_buf())
+    }
+}
+
+/// Write to the standard output, leaving the original file unchanged
+pub struct Stdout;
+
+impl OutputSink for Stdout {
+    fn phase(&self) -> &'static str {
+        "output"
+    }
+
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure> {
+        log::debug!("Writing {} to stdout", converted.display());
+        File::open(converted)
+            .and_then(|mut file| std::io::copy(&mut file, &mut std::io::stdout().lock()))
+            .map_err(|e| {
+                log::error!("Failed to write gcode to stdout: {}", e);
+                Failure::Write
+            })?;
+        Ok(converted.to_path_buf())
+    }
+}
+
+/// Upload to a printer or a print server
+pub struct Upload {
+    /// Where and how to upload
+    pub options: upload::UploadOptions,
+    /// File name to upload the G-code as
+    pub name: String,
+}
+
+impl OutputSink for Upload {
+    fn phase(&self) -> &'static str {
+        "upload"
+    }
+
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure> {
+        upload::upload(&self.options, converted, &self.name).map_err(|_| Failure::Upload)?;
+        Ok(converted.to_path_buf())
+    }
+}
+
+/// Replace a member of an archive held in memory, see [`zip::Archive::replace`]
+///
+/// The archive itself is delivered to the other sinks once all its members
+/// are replaced.
+pub struct ArchiveMember<'a> {
+    /// The archive
+    pub archive: &'a RefCell<zip::Archive>,
+    /// Name of the member
+    pub name: &'a str,
+}
+
+impl OutputSink for ArchiveMember<'_> {
+    fn phase(&self) -> &'static str {
+        "archive"
+    }
+
+    fn deliver(&self, converted: &path::Path) -> Result<path::PathBuf, Failure> {
+        log::debug!("Replacing `{}` in the archive with {}", self.name, converted.display());
+        let content = std::fs::read(converted).map_err(|e| {
+            log::error!("Cannot read {}: {}", converted.display(), e);
+            Failure::Read
+        })?;
+        self.archive.borrow_mut().replace(self.name, &content).map_err(|_| Failure::Encode)?;
+        Ok(converted.to_path_buf())
+    }
+}
+
+/// Deliver the converted G-code to a stack of sinks
+///
+/// The sinks are delivered to in order, and the delivery stops at the first
+/// failure. The temporary file is removed if no sink took it over.
+///
+/// # Arguments
+///
+/// * `sinks` - The sinks to deliver to
+/// * `converted` - The temporary file with the converted G-code
+/// * `summary` - Statistics to record the delivery phases in
+pub fn deliver(
+    sinks: &[&dyn OutputSink],
+    converted: &path::Path,
+    summary: &mut Summary,
+) -> Result<(), Failure> {
+    let mut location = converted.to_path_buf();
+    let result = sinks.iter().try_for_each(|sink| {
+        summary.phase(sink.phase());
+        location = sink.deliver(&location)?;
+        Ok(())
+    });
+    if location == converted {
+        let _ = std::fs::remove_file(converted);
+    }
+    result
+}
@@ -0,0 +1,268 @@
+use clap::ValueEnum;
+use std::collections::BTreeMap;
+use std::path;
+
+/// Environment variable with the final output name, set by PrusaSlicer (and
+/// its forks) when running post-processing scripts
+pub const OUTPUT_NAME_VAR: &str = "SLIC3R_PP_OUTPUT_NAME";
+
+/// Prefix of environment variables with slicer settings
+const SETTINGS_PREFIX: &str = "SLIC3R_";
+
+/// Slicer settings that are worth keeping in the post-processing info
+pub const FOOTER_SETTINGS: &[&str] = &[
+    "printer_model",
+    "printer_settings_id",
+    "print_settings_id",
+    "filament_settings_id",
+    "filament_type",
+    "layer_height",
+    "estimated_printing_time",
+    "filament_used",
+];
+
+/// Get slicer settings passed to post-processing scripts
+///
+/// PrusaSlicer exports all its settings as `SLIC3R_<NAME>` environment
+/// variables. Names are returned without the prefix, in lower case, the same
+/// way they appear in the slicer config.
+pub fn settings() -> BTreeMap<String, String> {
+    std::env::vars()
+        .filter_map(|(name, value)| {
+            let name = name.strip_prefix(SETTINGS_PREFIX)?.to_lowercase();
+            Some((name, value))
+        })
+        .filter(|(name, value)| name != "pp_output_name" && !value.is_empty())
+        .collect()
+}
+
+/// Get the name the slicer is going to save or upload the G-code as
+pub fn output_name() -> Option<path::PathBuf> {
+    std::env::var_os(OUTPUT_NAME_VAR).map(path::PathBuf::from)
+}
+
+/// Ask the slicer to save or upload the G-code under a different name
+///
+/// PrusaSlicer passes a temporary file to post-processing scripts. If the
+/// script creates `<temporary file>.output_name` with a new name in it, the
+/// slicer uses this name for the final file instead of the original one.
+///
+/// # Arguments
+///
+/// * `gcode_path` - Path to the G-code file passed by the slicer
+/// * `suffix` - What to append to the file stem of the output name
+///
+/// # Returns
+///
+/// The new output name, or `None` if the tool is not run by the slicer
+pub fn rename_output(gcode_path: &path::Path, suffix: &str) -> Result<Option<path::PathBuf>, ()> {
+    let Some(output_name) = output_name() else {
+        log::warn!("{} is not set. The output can only be renamed by the slicer", OUTPUT_NAME_VAR);
+        return Ok(None);
+    };
+    let mut file_name = output_name.file_stem().unwrap_or_default().to_os_string();
+    file_name.push(suffix);
+    if let Some(extension) = output_name.extension() {
+        file_name.push(".");
+        file_name.push(extension);
+    }
+    let new_name = output_name.with_file_name(file_name);
+
+    let mut name_file = gcode_path.as_os_str().to_os_string();
+    name_file.push(".output_name");
+    log::debug!("Asking the slicer to rename output to {}", new_name.display());
+    std::fs::write(&name_file, new_name.to_string_lossy().as_bytes()).map_err(|e| {
+        log::error!("Failed to write {}: {}", path::Path::new(&name_file).display(), e)
+    })?;
+    Ok(Some(new_name))
+}
+
+/// Rejoin paths with spaces that were split into several arguments
+///
+/// PrusaSlicer on Windows runs post-processing scripts without quoting the
+/// path of the G-code file in some setups, so a path like
+/// `C:\Users\John Doe\part.gcode` arrives as `C:\Users\John` and
+/// `Doe\part.gcode`. Arguments that don't exist are joined with the ones
+/// after them, until the joined path exists. Arguments that cannot be
+/// joined into an existing path are kept as they are.
+///
+/// # Arguments
+///
+/// * `paths` - The paths given on the command line
+pub fn rejoin_paths(paths: &[path::PathBuf]) -> Vec<path::PathBuf> {
+    let mut rejoined = vec![];
+    let mut rest = paths;
+    while let Some((first, others)) = rest.split_first() {
+        let joined = match first.exists() {
+            true => None,
+            false => (1..=others.len()).find_map(|count| {
+                let mut joined = first.as_os_str().to_os_string();
+                for part in &others[..count] {
+                    joined.push(" ");
+                    joined.push(part);
+                }
+                let joined = path::PathBuf::from(joined);
+                joined.exists().then_some((joined, count))
+            }),
+        };
+        match joined {
+            Some((joined, count)) => {
+                log::warn!(
+                    "Joined {} arguments into the path `{}`. Put the path in quotes in the \
+                     post-processing script of the slicer",
+                    count + 1,
+                    joined.display()
+                );
+                rejoined.push(joined);
+                rest = &others[count..];
+            }
+            None => {
+                rejoined.push(first.clone());
+                rest = others;
+            }
+        }
+    }
+    rejoined
+}
+
+/// Slicers that can be recognized by the header of the G-code
+#[derive(ValueEnum, Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Generator {
+    /// PrusaSlicer
+    #[value(name = "prusaslicer")]
+    PrusaSlicer,
+    /// SuperSlicer
+    #[value(name = "superslicer")]
+    SuperSlicer,
+    /// OrcaSlicer
+    #[value(name = "orcaslicer")]
+    OrcaSlicer,
+    /// Bambu Studio
+    #[value(name = "bambustudio")]
+    BambuStudio,
+    /// Ultimaker Cura
+    Cura,
+    /// Raise3D ideaMaker
+    #[value(name = "ideamaker")]
+    IdeaMaker,
+}
+
+/// How thumbnails are embedded in the G-code
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ThumbnailStyle {
+    /// `thumbnail begin` and `thumbnail end` lines wrapped in
+    /// `THUMBNAIL_BLOCK_START` and `THUMBNAIL_BLOCK_END` comments
+    Block,
+    /// Only `thumbnail begin` and `thumbnail end` lines
+    Comment,
+}
+
+impl std::fmt::Display for Generator {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Generator::BambuStudio => f.write_str("Bambu Studio"),
+            Generator::Cura => f.write_str("Cura"),
+            generator => f.write_str(generator.name()),
+        }
+    }
+}
+
+impl Generator {
+    /// All recognized slicers
+    const ALL: [Generator; 6] = [
+        Generator::PrusaSlicer,
+        Generator::SuperSlicer,
+        Generator::OrcaSlicer,
+        Generator::BambuStudio,
+        Generator::Cura,
+        Generator::IdeaMaker,
+    ];
+
+    /// Name of the slicer as it appears in the G-code header
+    pub fn name(self) -> &'static str {
+        match self {
+            Generator::PrusaSlicer => "PrusaSlicer",
+            Generator::SuperSlicer => "SuperSlicer",
+            Generator::OrcaSlicer => "OrcaSlicer",
+            Generator::BambuStudio => "BambuStudio",
+            Generator::Cura => "Cura_SteamEngine",
+            Generator::IdeaMaker => "ideaMaker",
+        }
+    }
+
+    /// Recognize the slicer from a header comment
+    ///
+    /// For example, `; generated by PrusaSlicer 2.7.1 on ...`,
+    /// `;Generated with Cura_SteamEngine 5.7.1` or `;Sliced by ideaMaker 4.4.1`.
+    pub fn detect(line: &str) -> Option<Self> {
+        let comment = line.strip_prefix(';')?.trim_start().to_lowercase();
+        let name = ["generated by ", "generated with ", "sliced by "]
+            .iter()
+            .find_map(|prefix| comment.strip_prefix(prefix))?;
+        Self::ALL.into_iter().find(|generator| name.starts_with(&generator.name().to_lowercase()))
+    }
+
+    /// How the slicer embeds thumbnails in the G-code
+    pub fn thumbnail_style(self) -> ThumbnailStyle {
+        match self {
+            Generator::PrusaSlicer
+            | Generator::SuperSlicer
+            | Generator::OrcaSlicer
+            | Generator::BambuStudio => ThumbnailStyle::Block,
+            // Cura thumbnails are added by its "Create Thumbnail" script
+            Generator::Cura | Generator::IdeaMaker => ThumbnailStyle::Comment,
+        }
+    }
+}
+
+/// Check whether a line starts a `thumbnail begin` comment, including the
+/// `thumbnail_QOI begin` and `thumbnail_JPG begin` variants
+pub fn is_thumbnail_begin(line: &str) -> bool {
+    is_thumbnail_marker(line, "begin")
+}
+
+/// Check whether a line ends a `thumbnail begin` comment
+pub fn is_thumbnail_end(line: &str) -> bool {
+    is_thumbnail_marker(line, "end")
+}
+
+/// Check whether a line may belong to a thumbnail block
+///
+/// These are comments with base64 data, `thumbnail` markers or nothing.
+pub fn is_image_line(line: &str) -> bool {
+    let Some(comment) = line.strip_prefix(';') else {
+        return line.trim().is_empty();
+    };
+    let comment = comment.trim();
+    comment.starts_with("thumbnail")
+        || comment.bytes().all(|b| b.is_ascii_alphanumeric() || matches!(b, b'+' | b'/' | b'='))
+}
+
+fn is_thumbnail_marker(line: &str, marker: &str) -> bool {
+    let Some(comment) = line.strip_prefix(';') else {
+        return false;
+    };
+    let mut words = comment.split_whitespace();
+    matches!(
+        (words.next(), words.next()),
+        (Some(thumbnail), Some(word)) if thumbnail.starts_with("thumbnail") && word == marker
+    )
+}
+
+/// Detect the slicer from the header comments of the G-code
+///
+/// Only the comments at the beginning of the file, before the first command,
+/// are checked.
+pub fn detect_generator<'a>(lines: impl IntoIterator<Item = &'a str>) -> Option<Generator> {
+    let generator = lines
+        .into_iter()
+        .map(str::trim)
+        .filter(|line| !line.is_empty())
+        .take_while(|line| line.starts_with(';'))
+        .find_map(Generator::detect);
+    match generator {
+        Some(generator) => log::info!("G-code generated by {}", generator),
+        None => log::debug!("Cannot recognize the slicer the G-code was generated by"),
+    }
+    generator
+}
@@ -0,0 +1,254 @@
+use crate::{color, decode_thumbnail, render};
+use image::io::Reader as ImageReader;
+use image::DynamicImage;
+use std::io::Cursor;
+use std::path;
+
+/// A thumbnail to make the preview from
+pub struct Thumbnail {
+    /// The thumbnail image
+    pub image: DynamicImage,
+    /// Format of the thumbnail, e.g. `png`, or what it was generated as
+    pub format: &'static str,
+    /// The ICC profile the thumbnail is tagged with
+    pub icc_profile: Option<Vec<u8>>,
+}
+
+impl Thumbnail {
+    /// Get the thumbnail image
+    ///
+    /// # Arguments
+    ///
+    /// * `icc` - Whether to convert the image from its ICC profile to sRGB,
+    ///   see [`color::to_srgb`]
+    pub fn into_image(self, icc: bool) -> DynamicImage {
+        match icc {
+            true => color::to_srgb(self.image, self.icc_profile.as_deref()),
+            false => self.image,
+        }
+    }
+}
+
+/// Where the thumbnail for the preview comes from
+pub trait ThumbnailSource {
+    /// Get the thumbnail
+    ///
+    /// # Returns
+    ///
+    /// The thumbnail, or `None` if the source doesn't have one and the next
+    /// source should be tried
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()>;
+
+    /// Describe the thumbnail in the post-processing info
+    fn describe(&self, thumbnail: &Thumbnail) -> String;
+}
+
+/// The thumbnail embedded in the G-code by the slicer
+pub struct Embedded<'a> {
+    /// The image lines returned by `read_gcode`
+    pub image_lines: &'a [String],
+    /// Whether a mismatch with the `thumbnail begin` line is an error
+    pub strict: bool,
+    /// How much memory the decoder may use
+    pub limits: image::io::Limits,
+}
+
+impl ThumbnailSource for Embedded<'_> {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        if self.image_lines.is_empty() {
+            return Ok(None);
+        }
+        let (image, format, icc_profile) =
+            decode_thumbnail(self.image_lines, self.strict, self.limits.clone())?;
+        Ok(Some(Thumbnail {
+            image,
+            format,
+            icc_profile,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(
+            ";  The original {} image was removed from here. Its size was {}x{}\n",
+            thumbnail.format,
+            thumbnail.image.width(),
+            thumbnail.image.height()
+        )
+    }
+}
+
+/// An image file given by the user
+pub struct ImageFile<'a> {
+    /// Path to the image file
+    pub path: &'a path::Path,
+    /// How much memory the decoder may use
+    pub limits: image::io::Limits,
+}
+
+impl ThumbnailSource for ImageFile<'_> {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        log::debug!("Reading thumbnail from `{}`", self.path.display());
+        let mut reader = ImageReader::open(self.path)
+            .and_then(|reader| reader.with_guessed_format())
+            .map_err(|e| log::error!("Cannot open image `{}`: {}", self.path.display(), e))?;
+        reader.limits(self.limits.clone());
+        let format = match reader.format().map(|format| format.extensions_str()) {
+            Some([ext, ..]) => ext,
+            _ => "UNKNOWN",
+        };
+        let (image, icc_profile) = color::decode(reader)
+            .map_err(|e| log::error!("Cannot decode image `{}`: {}", self.path.display(), e))?;
+        Ok(Some(Thumbnail {
+            image,
+            format,
+            icc_profile,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(
+            ";  The preview was made from the {} image `{}`. Its size was {}x{}\n",
+            thumbnail.format,
+            self.path.display(),
+            thumbnail.image.width(),
+            thumbnail.image.height()
+        )
+    }
+}
+
+/// An image file in memory, e.g. from an archive
+pub struct ImageData<'a> {
+    /// Name of the image, for the messages
+    pub name: &'a str,
+    /// Content of the image file
+    pub data: &'a [u8],
+    /// How much memory the decoder may use
+    pub limits: image::io::Limits,
+}
+
+impl ThumbnailSource for ImageData<'_> {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        log::debug!("Reading thumbnail from `{}`", self.name);
+        let mut reader = ImageReader::new(Cursor::new(self.data))
+            .with_guessed_format()
+            .map_err(|e| log::error!("Cannot read image `{}`: {}", self.name, e))?;
+        reader.limits(self.limits.clone());
+        let format = match reader.format().map(|format| format.extensions_str()) {
+            Some([ext, ..]) => ext,
+            _ => "UNKNOWN",
+        };
+        let (image, icc_profile) = color::decode(reader)
+            .map_err(|e| log::error!("Cannot decode image `{}`: {}", self.name, e))?;
+        Ok(Some(Thumbnail {
+            image,
+            format,
+            icc_profile,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(
+            ";  The preview was made from the {} image `{}`. Its size was {}x{}\n",
+            thumbnail.format,
+            self.name,
+            thumbnail.image.width(),
+            thumbnail.image.height()
+        )
+    }
+}
+
+/// Extrusion moves of the G-code rendered
+pub struct Toolpath<'a> {
+    /// Path to the gcode file
+    pub path: &'a path::Path,
+    /// Width and height of the rendered image
+    pub size: u32,
+    /// Where the toolpath is looked at from
+    pub camera: render::Camera,
+}
+
+impl ThumbnailSource for Toolpath<'_> {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        log::warn!("There is no image in gcode file. Rendering the toolpath");
+        Ok(Some(Thumbnail {
+            image: render::render_toolpath(self.path, self.size, self.camera)?,
+            format: "toolpath",
+            icc_profile: None,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(";  There was no image, a {} preview was generated\n", thumbnail.format)
+    }
+}
+
+/// Extrusion moves of the first layer rendered
+pub struct FirstLayer<'a> {
+    /// Path to the gcode file
+    pub path: &'a path::Path,
+    /// Width and height of the rendered image
+    pub size: u32,
+    /// Where the first layer is looked at from
+    pub camera: render::Camera,
+}
+
+impl ThumbnailSource for FirstLayer<'_> {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        log::info!("Rendering the first layer");
+        Ok(Some(Thumbnail {
+            image: render::render_first_layer(self.path, self.size, self.camera)?,
+            format: "first-layer",
+            icc_profile: None,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(
+            ";  The preview was rendered from the first layer. Its size was {}x{}\n",
+            thumbnail.image.width(),
+            thumbnail.image.height()
+        )
+    }
+}
+
+/// A crossed out frame
+pub struct Placeholder {
+    /// Width and height of the placeholder
+    pub size: u32,
+}
+
+impl ThumbnailSource for Placeholder {
+    fn thumbnail(&self) -> Result<Option<Thumbnail>, ()> {
+        log::warn!("There is no image in gcode file. Using a placeholder");
+        Ok(Some(Thumbnail {
+            image: render::placeholder(self.size),
+            format: "placeholder",
+            icc_profile: None,
+        }))
+    }
+
+    fn describe(&self, thumbnail: &Thumbnail) -> String {
+        format!(";  There was no image, a {} preview was generated\n", thumbnail.format)
+    }
+}
+
+/// Get the thumbnail from the first source that has one
+///
+/// # Arguments
+///
+/// * `sources` - The sources in the order of priority
+///
+/// # Returns
+///
+/// The thumbnail and its description, or `None` if no source has one
+pub fn first_thumbnail(
+    sources: &[&dyn ThumbnailSource],
+) -> Result<Option<(Thumbnail, String)>, ()> {
+    for source in sources {
+        if let Some(thumbnail) = source.thumbnail()? {
+            let description = source.describe(&thumbnail);
+            return Ok(Some((thumbnail, description)));
+        }
+    }
+    Ok(None)
+}
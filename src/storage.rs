@@ -0,0 +1,241 @@
+use std::fs::{File, FileTimes};
+use std::io::{self, ErrorKind, Write};
+use std::path;
+use std::time::Duration;
+
+/// Whether retrying cannot fix a failed operation on the output
+fn is_permanent(error: &io::Error) -> bool {
+    matches!(
+        error.kind(),
+        ErrorKind::NotFound
+            | ErrorKind::PermissionDenied
+            | ErrorKind::AlreadyExists
+            | ErrorKind::InvalidInput
+            | ErrorKind::InvalidData
+            | ErrorKind::IsADirectory
+            | ErrorKind::NotADirectory
+            | ErrorKind::ReadOnlyFilesystem
+            | ErrorKind::StorageFull
+            | ErrorKind::QuotaExceeded
+            | ErrorKind::FileTooLarge
+            | ErrorKind::Unsupported
+    )
+}
+
+/// Retry an operation on the output with increasing delays
+///
+/// Network shares and SD cards fail transiently, e.g. when the connection
+/// drops for a moment. Errors that retrying cannot fix, like a full disk, are
+/// returned right away.
+///
+/// # Arguments
+///
+/// * `retries` - How many times the operation is retried
+/// * `what` - What the operation does, for the log
+/// * `operation` - The operation
+pub fn retry<T>(
+    retries: u32,
+    what: &str,
+    mut operation: impl FnMut() -> io::Result<T>,
+) -> io::Result<T> {
+    let mut attempt = 0;
+    loop {
+        match operation() {
+            Err(e) if attempt < retries && !is_permanent(&e) => {
+                let delay = Duration::from_millis(100 << attempt.min(6));
+                attempt += 1;
+                log::warn!(
+                    "Failed to {}: {}. Retrying in {}ms (attempt {} of {})",
+                    what,
+                    e,
+                    delay.as_millis(),
+                    attempt,
+                    retries
+                );
+                std::thread::sleep(delay);
+            }
+            result => return result,
+        }
+    }
+}
+
+/// Writer retrying failed writes, see [`retry`]
+pub struct RetryWriter<W> {
+    inner: W,
+    retries: u32,
+}
+
+impl<W> RetryWriter<W> {
+    pub fn new(inner: W, retries: u32) -> Self {
+        RetryWriter {
+            inner,
+            retries,
+        }
+    }
+}
+
+impl<W: Write> Write for RetryWriter<W> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let inner = &mut self.inner;
+        retry(self.retries, "write", || inner.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        let inner = &mut self.inner;
+        retry(self.retries, "flush", || inner.flush())
+    }
+}
+
+/// Flush a written file to the storage device
+///
+/// # Arguments
+///
+/// * `path` - The file
+/// * `retries` - How many times a failure is retried
+pub fn sync_file(path: &path::Path, retries: u32) -> io::Result<()> {
+    retry(retries, "sync", || File::options().write(true).open(path)?.sync_all())
+}
+
+/// Flush the entries of a folder to the storage device, so a file renamed in
+/// it is not lost on power loss
+///
+/// This is only possible on Unix, elsewhere syncing the file is enough.
+pub fn sync_folder(path: &path::Path, retries: u32) -> io::Result<()> {
+    #[cfg(unix)]
+    retry(retries, "sync", || File::open(folder(path))?.sync_all())?;
+    #[cfg(not(unix))]
+    let _ = (path, retries);
+    Ok(())
+}
+
+/// Give a file the modification time, permissions and, where possible, the
+/// owner of another one
+///
+/// Changing the owner needs privileges a user usually doesn't have, so
+/// failing to do it is only logged.
+///
+/// # Arguments
+///
+/// * `original` - The file to take the metadata from
+/// * `path` - The file to give the metadata to
+pub fn copy_metadata(original: &path::Path, path: &path::Path) -> io::Result<()> {
+    let metadata = std::fs::metadata(original)?;
+    let file = File::options().write(true).open(path)?;
+    let times =
+        FileTimes::new().set_accessed(metadata.accessed()?).set_modified(metadata.modified()?);
+    file.set_times(times)?;
+    file.set_permissions(metadata.permissions())?;
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        let owner = (metadata.uid(), metadata.gid());
+        if let Err(e) = std::os::unix::fs::fchown(&file, Some(owner.0), Some(owner.1)) {
+            log::debug!("Cannot change the owner of `{}`: {}", path.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Get the folder a file is in
+fn folder(path: &path::Path) -> &path::Path {
+    match path.parent() {
+        Some(parent) if !parent.as_os_str().is_empty() => parent,
+        _ => path::Path::new("."),
+    }
+}
+
+/// Check that there is enough free space for a file, before writing it
+///
+/// If the free space cannot be found, e.g. on platforms other than Unix and
+/// Windows, the check passes.
+///
+/// # Arguments
+///
+/// * `path` - The file to be written
+/// * `needed` - Size of the file, in bytes
+pub fn check_free_space(path: &path::Path, needed: u64) -> Result<(), ()> {
+    let folder = folder(path);
+    let Some(available) = free_space(folder) else {
+        return Ok(());
+    };
+    log::debug!("{} bytes are free in `{}`, {} needed", available, folder.display(), needed);
+    if available < needed {
+        log::error!(
+            "There is not enough free space in `{}`: {:.1} MB are needed, {:.1} MB are free",
+            folder.display(),
+            needed as f64 / 1e6,
+            available as f64 / 1e6
+        );
+        return Err(());
+    }
+    Ok(())
+}
+
+/// Get the space available to unprivileged users in a folder, in bytes
+#[cfg(unix)]
+fn free_space(folder: &path::Path) -> Option<u64> {
+    use std::os::unix::ffi::OsStrExt;
+    let folder_c = std::ffi::CString::new(folder.as_os_str().as_bytes()).ok()?;
+    let mut stat = std::mem::MaybeUninit::<libc::statvfs>::uninit();
+    // SAFETY: the path is a valid C string and `stat` is written by `statvfs`
+    // when it succeeds
+    if unsafe { libc::statvfs(folder_c.as_ptr(), stat.as_mut_ptr()) } != 0 {
+        log::debug!(
+            "Cannot get the free space in `{}`: {}",
+            folder.display(),
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    // SAFETY: `statvfs` returned 0, so it filled in `stat`
+    let stat = unsafe { stat.assume_init() };
+    // The field types differ between platforms
+    #[allow(clippy::unnecessary_cast)]
+    Some(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Get the space available to the user in a folder, in bytes
+#[cfg(windows)]
+fn free_space(folder: &path::Path) -> Option<u64> {
+    use std::os::windows::ffi::OsStrExt;
+    #[link(name = "kernel32")]
+    extern "system" {
+        // The `ULARGE_INTEGER` outputs are unions with a `u64`
+        fn GetDiskFreeSpaceExW(
+            directory: *const u16,
+            free_bytes_available: *mut u64,
+            total_bytes: *mut u64,
+            total_free_bytes: *mut u64,
+        ) -> i32;
+    }
+    let folder_w: Vec<u16> = folder.as_os_str().encode_wide().chain(Some(0)).collect();
+    let mut available = 0;
+    // SAFETY: the path is a valid null-terminated wide string, `available` is
+    // valid for writes, and the totals may be null
+    let ok = unsafe {
+        GetDiskFreeSpaceExW(
+            folder_w.as_ptr(),
+            &mut available,
+            std::ptr::null_mut(),
+            std::ptr::null_mut(),
+        )
+    };
+    if ok == 0 {
+        log::debug!(
+            "Cannot get the free space in `{}`: {}",
+            folder.display(),
+            io::Error::last_os_error()
+        );
+        return None;
+    }
+    Some(available)
+}
+
+#[cfg(not(any(unix, windows)))]
+fn free_space(folder: &path::Path) -> Option<u64> {
+    log::warn!(
+        "Cannot check the free space in `{}` on this platform, writing without the check",
+        folder.display()
+    );
+    None
+}
@@ -0,0 +1,87 @@
+use crate::logging;
+use std::time::{Duration, Instant};
+
+/// Statistics of a single run
+#[derive(Debug)]
+pub struct Summary {
+    /// Size of the original G-code file in bytes
+    pub input_size: u64,
+    /// Size of the converted G-code file in bytes
+    pub output_size: u64,
+    /// Format of the original thumbnail
+    pub source_format: String,
+    /// Dimensions of the original thumbnail
+    pub source_dimensions: (u32, u32),
+    /// Names and sizes in bytes of the generated preview blocks
+    pub previews: Vec<(&'static str, usize)>,
+    /// Names and durations of the finished processing phases
+    pub phases: Vec<(&'static str, Duration)>,
+    started: Instant,
+    current_phase: Option<(&'static str, Instant)>,
+}
+
+impl Summary {
+    pub fn new() -> Self {
+        Summary {
+            input_size: 0,
+            output_size: 0,
+            source_format: String::new(),
+            source_dimensions: (0, 0),
+            previews: vec![],
+            phases: vec![],
+            started: Instant::now(),
+            current_phase: None,
+        }
+    }
+
+    /// Finish the current processing phase and start the next one
+    pub fn phase(&mut self, name: &'static str) {
+        self.finish_phase();
+        logging::set_phase(name);
+        self.current_phase = Some((name, Instant::now()));
+    }
+
+    /// Finish the current processing phase
+    pub fn finish_phase(&mut self) {
+        if let Some((name, started)) = self.current_phase.take() {
+            self.phases.push((name, started.elapsed()));
+        }
+    }
+
+    /// Time since the summary was created
+    pub fn total_time(&self) -> Duration {
+        self.started.elapsed()
+    }
+
+    /// Format durations of the processing phases as a table
+    pub fn timings(&self) -> String {
+        let total = self.total_time();
+        let mut table = format!("{:<8} {:>10} {:>6}\n", "phase", "time, ms", "%");
+        for (name, duration) in self.phases.iter().chain([&("total", total)]) {
+            table.push_str(&format!(
+                "{:<8} {:>10.3} {:>6.1}\n",
+                name,
+                duration.as_secs_f64() * 1000.0,
+                duration.as_secs_f64() / total.as_secs_f64().max(f64::EPSILON) * 100.0
+            ));
+        }
+        table
+    }
+}
+
+impl std::fmt::Display for Summary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        writeln!(f, "input_size = {}", self.input_size)?;
+        writeln!(f, "output_size = {}", self.output_size)?;
+        writeln!(f, "size_change = {:+}", self.output_size as i64 - self.input_size as i64)?;
+        let (width, height) = self.source_dimensions;
+        writeln!(f, "source = {} {}x{}", self.source_format, width, height)?;
+        for (name, size) in &self.previews {
+            writeln!(f, "{}_size = {}", name, size)?;
+        }
+        for (name, duration) in &self.phases {
+            writeln!(f, "{}_time = {:.3}s", name, duration.as_secs_f64())?;
+        }
+        write!(f, "total_time = {:.3}s", self.total_time().as_secs_f64())
+    }
+}
@@ -0,0 +1,58 @@
+use image::imageops::FilterType;
+use image::RgbImage;
+use std::fmt::Write;
+
+/// Terminal width used when it cannot be detected
+const DEFAULT_COLUMNS: u32 = 80;
+
+/// Get the width of the terminal in columns
+///
+/// The width is taken from the `COLUMNS` environment variable, which is set by
+/// most shells. [`DEFAULT_COLUMNS`] is used if it's not available.
+pub fn columns() -> u32 {
+    std::env::var("COLUMNS")
+        .ok()
+        .and_then(|columns| columns.parse().ok())
+        .filter(|&columns| columns > 0)
+        .unwrap_or(DEFAULT_COLUMNS)
+}
+
+/// Render an image with 24-bit ANSI colors using half block characters
+///
+/// Every character cell shows two pixels: the upper one as the foreground
+/// color of `▀`, and the lower one as the background color. Images wider than
+/// `max_columns` are downscaled to fit.
+///
+/// # Arguments
+///
+/// * `image` - The image to be rendered
+/// * `max_columns` - The maximum width of the rendered image in characters
+///
+/// # Returns
+///
+/// A string with escape sequences that can be printed to a terminal
+pub fn render_half_blocks(image: &RgbImage, max_columns: u32) -> String {
+    let resized;
+    let image = if image.width() > max_columns {
+        let height = (image.height() * max_columns / image.width()).max(1);
+        resized = image::imageops::resize(image, max_columns, height, FilterType::Triangle);
+        &resized
+    } else {
+        image
+    };
+
+    let mut rendered = String::new();
+    for y in (0..image.height()).step_by(2) {
+        for x in 0..image.width() {
+            let [r, g, b] = image.get_pixel(x, y).0;
+            let _ = write!(rendered, "\x1b[38;2;{};{};{}m", r, g, b);
+            if y + 1 < image.height() {
+                let [r, g, b] = image.get_pixel(x, y + 1).0;
+                let _ = write!(rendered, "\x1b[48;2;{};{};{}m", r, g, b);
+            }
+            rendered.push('▀');
+        }
+        rendered.push_str("\x1b[0m\n");
+    }
+    rendered
+}
@@ -0,0 +1,58 @@
+use crate::zip::Archive;
+use mks_tft_img::options::ProcessOptions;
+use mks_tft_img::source;
+use std::path;
+
+/// Where Bambu Studio and OrcaSlicer put the G-code of the plates
+const PLATE_PREFIX: &str = "Metadata/plate_";
+
+/// Whether a path is a 3MF archive, e.g. `plate_1.gcode.3mf`
+pub fn is_3mf(path: &path::Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("3mf"))
+}
+
+/// Convert the G-code of the plates in a 3MF archive of Bambu Studio or OrcaSlicer
+///
+/// Each `Metadata/plate_<n>.gcode` is converted. If it has no thumbnail, the
+/// preview is made from the plate image `Metadata/plate_<n>.png`. The archive
+/// is not changed, the converted G-code is written back with
+/// [`Archive::replace`], which also updates the MD5 checksum in
+/// `Metadata/plate_<n>.gcode.md5` that Bambu printers check.
+///
+/// # Arguments
+///
+/// * `archive` - The 3MF archive
+/// * `options` - How to convert the thumbnails
+///
+/// # Returns
+///
+/// The names of the converted plate G-code files with their new content
+pub fn convert(archive: &Archive, options: &ProcessOptions) -> Result<Vec<(String, Vec<u8>)>, ()> {
+    let mut converted = vec![];
+    for member in &archive.members {
+        let Some(plate) =
+            member.name.strip_prefix(PLATE_PREFIX).and_then(|name| name.strip_suffix(".gcode"))
+        else {
+            continue;
+        };
+        let gcode = member.content()?;
+        if mks_tft_img::is_converted(&gcode) {
+            log::info!("`{}` is already converted", member.name);
+            continue;
+        }
+        log::info!("Converting `{}`", member.name);
+        let image_name = format!("{}{}.png", PLATE_PREFIX, plate);
+        let plate_image = archive.member(&image_name).map(|image| image.content()).transpose()?;
+        let plate_image = plate_image.as_ref().map(|data| source::ImageData {
+            name: &image_name,
+            data,
+            limits: options.image_limits(),
+        });
+        let fallback = plate_image.as_ref().map(|image| image as &dyn source::ThumbnailSource);
+        match mks_tft_img::convert_with(&gcode, options, fallback) {
+            Ok(gcode) => converted.push((member.name.clone(), gcode)),
+            Err(()) => log::warn!("`{}` is left unchanged", member.name),
+        }
+    }
+    Ok(converted)
+}
@@ -0,0 +1,229 @@
+use base64::prelude::{Engine, BASE64_STANDARD};
+use image::DynamicImage;
+use serde::Serialize;
+use std::io::{self, BufRead};
+
+use crate::encoders::rgb565;
+
+/// Dimensions and pixel checksum of an image
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ImageSummary {
+    pub width: u32,
+    pub height: u32,
+    pub checksum: u32,
+}
+
+impl std::fmt::Display for ImageSummary {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}x{} (checksum {:08x})", self.width, self.height, self.checksum)
+    }
+}
+
+/// Summarize an image as it is expected to be shown on an RGB565 display
+///
+/// The checksum is calculated over RGB565 words, so it can be compared with
+/// the result of [`read_rgb565_image`].
+pub fn summarize_rgb565(image: &DynamicImage) -> ImageSummary {
+    let mut hasher = crc32fast::Hasher::new();
+    for pixel in image.to_rgb8().pixels() {
+        let (higher, lower) = rgb565(pixel);
+        hasher.update(&[higher, lower]);
+    }
+    ImageSummary {
+        width: image.width(),
+        height: image.height(),
+        checksum: hasher.finalize(),
+    }
+}
+
+/// Summarize an image as it is expected to be stored losslessly
+///
+/// The checksum is calculated over RGB8 pixels, so it can be compared with
+/// the result of [`read_thumbnail_image`].
+pub fn summarize_rgb8(image: &DynamicImage) -> ImageSummary {
+    ImageSummary {
+        width: image.width(),
+        height: image.height(),
+        checksum: crc32fast::hash(image.to_rgb8().as_raw()),
+    }
+}
+
+/// Decode hex encoded RGB565 image from the G-code
+///
+/// The image is expected to start with `<prefix>:` and have its rows
+/// separated by the `marker` command. The image ends with a `marker` that is
+/// not followed by pixel data.
+///
+/// # Arguments
+///
+/// * `gcode` - The G-code to search the image in
+/// * `prefix` - The prefix of the image, e.g. `;simage`
+/// * `marker` - The command that separates rows, e.g. `M10086 ;`
+/// * `little_endian` - Whether the lower byte of a pixel goes first
+///
+/// # Returns
+///
+/// Summary of the decoded image
+pub fn read_rgb565_image(
+    gcode: &str,
+    prefix: &str,
+    marker: &str,
+    little_endian: bool,
+) -> Result<ImageSummary, String> {
+    let (width, height, words) = read_rgb565_words(gcode, prefix, marker, little_endian)?;
+    let mut hasher = crc32fast::Hasher::new();
+    for word in words {
+        hasher.update(&word.to_be_bytes());
+    }
+    Ok(ImageSummary {
+        width,
+        height,
+        checksum: hasher.finalize(),
+    })
+}
+
+/// Decode the pixels of a hex encoded RGB565 image from the G-code
+///
+/// See [`read_rgb565_image`] for the arguments.
+///
+/// # Returns
+///
+/// The width and height of the image, and its pixels row by row
+pub fn read_rgb565_words(
+    gcode: &str,
+    prefix: &str,
+    marker: &str,
+    little_endian: bool,
+) -> Result<(u32, u32, Vec<u16>), String> {
+    let start = format!("{}:", prefix);
+    let mut cursor = gcode
+        .find(&start)
+        .map(|pos| pos + start.len())
+        .ok_or_else(|| format!("`{}` is not found", prefix))?;
+
+    let mut words = vec![];
+    let mut width = None;
+    let mut height = 0;
+    loop {
+        let end = gcode[cursor..]
+            .find(marker)
+            .map(|pos| cursor + pos)
+            .ok_or_else(|| format!("`{}` is not terminated with `{}`", prefix, marker))?;
+        let row = gcode[cursor..end].trim();
+        if !row.len().is_multiple_of(4) {
+            return Err(format!("Row {} of `{}` has incomplete pixels", height, prefix));
+        }
+        let row_width = row.len() as u32 / 4;
+        if *width.get_or_insert(row_width) != row_width {
+            return Err(format!("Row {} of `{}` has {} pixels", height, prefix, row_width));
+        }
+        for word in row.as_bytes().chunks(4) {
+            let word = std::str::from_utf8(word)
+                .ok()
+                .and_then(|word| u16::from_str_radix(word, 16).ok())
+                .ok_or_else(|| format!("Row {} of `{}` is not hex encoded", height, prefix))?;
+            words.push(match little_endian {
+                true => word.swap_bytes(),
+                false => word,
+            });
+        }
+        height += 1;
+
+        cursor = end + marker.len();
+        if !gcode[cursor..].starts_with(|c: char| c.is_ascii_hexdigit()) {
+            break;
+        }
+    }
+    Ok((width.unwrap_or(0), height, words))
+}
+
+/// Decode an image from a `; thumbnail begin` style comment block of the G-code
+///
+/// # Arguments
+///
+/// * `gcode` - The G-code to search the image in
+/// * `kind` - The word before `begin` and `end`, e.g. `thumbnail` or `jpg`
+///
+/// # Returns
+///
+/// Summary of the decoded image
+pub fn read_thumbnail_image(gcode: &str, kind: &str) -> Result<ImageSummary, String> {
+    let (begin, end) = (format!("; {} begin", kind), format!("; {} end", kind));
+    let encoded: String = gcode
+        .lines()
+        .skip_while(|line| !line.starts_with(&begin))
+        .skip(1)
+        .take_while(|line| !line.starts_with(&end))
+        .map(|line| line.trim_start_matches(';').trim())
+        .collect();
+    let decoded =
+        BASE64_STANDARD.decode(encoded).map_err(|e| format!("Cannot decode thumbnail: {}", e))?;
+    let image = image::load_from_memory(&decoded)
+        .map_err(|e| format!("Cannot decode thumbnail image: {}", e))?;
+    Ok(summarize_rgb8(&image))
+}
+
+/// Start of the comment with the CRC32 of the preview, which goes right after it
+pub const CRC_TRAILER: &str = "; MKS_TFT_IMG_CRC32=";
+
+/// Create the comment with the CRC32 of the preview
+///
+/// # Arguments
+///
+/// * `preview` - The preview, from the beginning of the G-code
+/// * `line_ending` - What the comment ends with
+pub fn crc_trailer(preview: &[u8], line_ending: &str) -> String {
+    format!("{}{:08x}{}", CRC_TRAILER, crc32fast::hash(preview), line_ending)
+}
+
+/// CRC32 of the preview of a G-code file, as written in its trailer and as
+/// computed from the preview
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+pub struct CrcCheck {
+    pub expected: u32,
+    pub actual: u32,
+}
+
+impl CrcCheck {
+    /// Whether the preview is unchanged since it was written
+    pub fn is_valid(&self) -> bool {
+        self.expected == self.actual
+    }
+}
+
+/// Check the preview of G-code against the CRC32 in its trailer, see [`crc_trailer`]
+///
+/// # Arguments
+///
+/// * `gcode` - The G-code, from its beginning
+///
+/// # Returns
+///
+/// The expected and the actual CRC32, or `None` if there is no trailer
+pub fn check_crc_trailer(mut gcode: impl BufRead) -> io::Result<Option<CrcCheck>> {
+    let trailer = CRC_TRAILER.as_bytes();
+    let mut hasher = crc32fast::Hasher::new();
+    let mut line = vec![];
+    loop {
+        line.clear();
+        if gcode.read_until(b'\n', &mut line)? == 0 {
+            return Ok(None);
+        }
+        // MKS image rows may end with a carriage return only
+        let start = (0..line.len())
+            .find(|&pos| (pos == 0 || line[pos - 1] == b'\r') && line[pos..].starts_with(trailer));
+        let Some(start) = start else {
+            hasher.update(&line);
+            continue;
+        };
+        hasher.update(&line[..start]);
+        let hex = String::from_utf8_lossy(&line[start + trailer.len()..]);
+        let Ok(expected) = u32::from_str_radix(hex.trim(), 16) else {
+            return Err(io::Error::new(io::ErrorKind::InvalidData, "The CRC32 trailer is invalid"));
+        };
+        return Ok(Some(CrcCheck {
+            expected,
+            actual: hasher.finalize(),
+        }));
+    }
+}
@@ -0,0 +1,75 @@
+use crate::upload;
+use clap::ValueEnum;
+use mks_tft_img::{presets, Target};
+
+/// Get the names of the values of a command line enum
+fn names<T: ValueEnum>() -> Vec<String> {
+    T::value_variants()
+        .iter()
+        .filter_map(|value| value.to_possible_value())
+        .map(|value| value.get_name().to_string())
+        .collect()
+}
+
+/// Get the time the tool was built at, in RFC 3339 format
+fn build_date() -> String {
+    env!("MKS_TFT_IMG_BUILD_DATE")
+        .parse()
+        .ok()
+        .and_then(|timestamp| time::OffsetDateTime::from_unix_timestamp(timestamp).ok())
+        .and_then(|date| date.format(&time::format_description::well_known::Rfc3339).ok())
+        .unwrap_or_default()
+}
+
+/// Get the optional features the tool is built with
+fn features() -> Vec<&'static str> {
+    let mut features = vec![];
+    if cfg!(feature = "simd") {
+        features.push("simd");
+    }
+    if cfg!(feature = "async") {
+        features.push("async");
+    }
+    features
+}
+
+/// Print the version and build information
+///
+/// # Arguments
+///
+/// * `json` - Whether to print it as JSON, for scripts checking what is deployed
+pub fn print(json: bool) {
+    let git_hash = match env!("MKS_TFT_IMG_GIT_HASH") {
+        "" => None,
+        hash => Some(hash),
+    };
+    if json {
+        let info = serde_json::json!({
+            "name": env!("CARGO_PKG_NAME"),
+            "version": env!("CARGO_PKG_VERSION"),
+            "git_hash": git_hash,
+            "build_date": build_date(),
+            "os": std::env::consts::OS,
+            "arch": std::env::consts::ARCH,
+            "features": features(),
+            "targets": names::<Target>(),
+            "printers": names::<presets::Printer>(),
+            "uploaders": names::<upload::Uploader>(),
+        });
+        println!("{}", info);
+        return;
+    }
+    print!("{} {}", env!("CARGO_PKG_NAME"), env!("CARGO_PKG_VERSION"));
+    if let Some(hash) = git_hash {
+        print!(" ({})", hash);
+    }
+    println!();
+    println!("built {} for {}-{}", build_date(), std::env::consts::OS, std::env::consts::ARCH);
+    match features().as_slice() {
+        [] => println!("features: none"),
+        features => println!("features: {}", features.join(", ")),
+    }
+    println!("targets: {}", names::<Target>().join(", "));
+    println!("printers: {}", names::<presets::Printer>().join(", "));
+    println!("uploaders: {}", names::<upload::Uploader>().join(", "));
+}
@@ -0,0 +1,82 @@
+//! JavaScript interface of the converter
+//!
+//! Built with `wasm-pack build --target web`, so a browser page or an
+//! Electron based slicer plugin can convert G-code without running the
+//! executable. G-code goes in and out as `Uint8Array`.
+
+use crate::options::{PreviewSize, ProcessOptions, RowEnd};
+use crate::Target;
+use clap::ValueEnum;
+use wasm_bindgen::prelude::*;
+
+/// Options of the conversion
+#[wasm_bindgen(getter_with_clone)]
+#[derive(Clone, Debug)]
+pub struct Options {
+    /// The display the preview is generated for: `mks`, `anycubic`,
+    /// `flashforge`, `creality` or `raw`
+    pub target: String,
+    /// The size of the simage
+    #[wasm_bindgen(js_name = simageSize)]
+    pub simage_size: u16,
+    /// The size of the gimage
+    #[wasm_bindgen(js_name = gimageSize)]
+    pub gimage_size: u16,
+    /// Terminate MKS image rows with `\r\n` instead of `\r`
+    #[wasm_bindgen(js_name = crlfRowEnd)]
+    pub crlf_row_end: bool,
+    /// Fail if the thumbnail doesn't match its `thumbnail begin` line
+    pub strict: bool,
+}
+
+#[wasm_bindgen]
+impl Options {
+    /// Get the default options, the same as the ones of the command line tool
+    #[wasm_bindgen(constructor)]
+    pub fn new() -> Options {
+        let options = ProcessOptions::default();
+        Options {
+            target: options.target.to_string(),
+            simage_size: options.simage_size.get(),
+            gimage_size: options.gimage_size.get(),
+            crlf_row_end: options.row_end == RowEnd::Crlf,
+            strict: options.strict,
+        }
+    }
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Options::new()
+    }
+}
+
+/// Convert the thumbnail of G-code in memory
+///
+/// # Arguments
+///
+/// * `gcode` - The content of the G-code file
+/// * `options` - Options of the conversion, the defaults if not given
+///
+/// # Returns
+///
+/// The converted G-code
+#[wasm_bindgen]
+pub fn convert(gcode: &[u8], options: Option<Options>) -> Result<Vec<u8>, JsError> {
+    let options = options.unwrap_or_default();
+    let target = Target::from_str(&options.target, true)
+        .map_err(|_| JsError::new(&format!("Unknown target `{}`", options.target)))?;
+    let row_end = match options.crlf_row_end {
+        true => RowEnd::Crlf,
+        false => RowEnd::Cr,
+    };
+    let size = |pixels: u16| PreviewSize::try_from(pixels).map_err(|e| JsError::new(&e));
+    let options = ProcessOptions::new()
+        .target(target)
+        .simage_size(size(options.simage_size)?)
+        .gimage_size(size(options.gimage_size)?)
+        .row_end(row_end)
+        .strict(options.strict);
+    crate::convert(gcode, &options)
+        .map_err(|_| JsError::new("There is no thumbnail in the G-code, or it cannot be converted"))
+}
@@ -0,0 +1,523 @@
+use md5::Digest;
+use mks_tft_img::options::ProcessOptions;
+use std::io::{Read, Write};
+use std::path;
+
+/// Signature of a local file header
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+/// Signature of a central directory file header
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+/// Signature of the end of central directory record
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+/// Signature of the ZIP64 end of central directory locator
+const ZIP64_LOCATOR: u32 = 0x0706_4b50;
+/// Signature of the ZIP64 end of central directory record
+const ZIP64_END_OF_CENTRAL_DIRECTORY: u32 = 0x0606_4b50;
+/// Header ID of the ZIP64 extended information extra field
+const ZIP64_EXTRA: u16 = 0x0001;
+
+/// Compression methods of members
+const STORED: u16 = 0;
+const DEFLATED: u16 = 8;
+
+/// General purpose flags of members
+const ENCRYPTED: u16 = 1;
+const DATA_DESCRIPTOR: u16 = 1 << 3;
+
+/// A file in a zip archive
+pub struct Member {
+    /// Path of the file in the archive, with `/` separators
+    pub name: String,
+    /// The compressed content
+    data: Vec<u8>,
+    method: u16,
+    flags: u16,
+    /// Modification time and date, in MS-DOS format
+    time: u16,
+    date: u16,
+    crc: u32,
+    size: u64,
+    /// Version and system the member was made by, and its file attributes
+    made_by: u16,
+    attributes: u32,
+}
+
+impl Member {
+    /// Get the uncompressed content
+    pub fn content(&self) -> Result<Vec<u8>, ()> {
+        let mut content = Vec::with_capacity(self.size as usize);
+        match self.method {
+            STORED => content.extend_from_slice(&self.data),
+            DEFLATED => {
+                flate2::read::DeflateDecoder::new(self.data.as_slice())
+                    .read_to_end(&mut content)
+                    .map_err(|e| log::error!("Cannot decompress `{}`: {}", self.name, e))?;
+            }
+            method => {
+                log::error!("`{}` is compressed with unsupported method {}", self.name, method);
+                return Err(());
+            }
+        }
+        if crc32fast::hash(&content) != self.crc {
+            log::error!("`{}` is corrupted, its CRC doesn't match", self.name);
+            return Err(());
+        }
+        Ok(content)
+    }
+
+    /// Replace the content, compressing it
+    pub fn set_content(&mut self, content: &[u8]) -> Result<(), ()> {
+        let mut encoder =
+            flate2::write::DeflateEncoder::new(Vec::new(), flate2::Compression::default());
+        encoder
+            .write_all(content)
+            .map_err(|e| log::error!("Cannot compress `{}`: {}", self.name, e))?;
+        self.data =
+            encoder.finish().map_err(|e| log::error!("Cannot compress `{}`: {}", self.name, e))?;
+        self.method = DEFLATED;
+        self.flags &= !DATA_DESCRIPTOR;
+        self.crc = crc32fast::hash(content);
+        self.size = content.len() as u64;
+        Ok(())
+    }
+}
+
+/// A zip archive, held in memory
+///
+/// Only what archives of G-code need is supported: stored and deflated
+/// members, without encryption. ZIP64 archives can be read, but are written
+/// without ZIP64 extensions, so the archive must stay below 4 GB.
+pub struct Archive {
+    /// The files in the archive, in the order of the central directory
+    pub members: Vec<Member>,
+}
+
+impl Archive {
+    /// Read a zip archive from a file
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the zip file
+    pub fn open(path: &path::Path) -> Result<Self, ()> {
+        let data = std::fs::read(path)
+            .map_err(|e| log::error!("Cannot read file `{}`: {}", path.display(), e))?;
+        Self::read(&data).map_err(|e| log::error!("Cannot read zip `{}`: {}", path.display(), e))
+    }
+
+    /// Read a zip archive from its content
+    ///
+    /// # Returns
+    ///
+    /// The archive, or why it cannot be read
+    fn read(data: &[u8]) -> Result<Self, String> {
+        // The end of central directory record is followed by a comment of up to 64 KiB
+        let search_start = data.len().saturating_sub(22 + u16::MAX as usize);
+        let end = (search_start..data.len().saturating_sub(21))
+            .rev()
+            .find(|&pos| u32_at(data, pos) == Some(END_OF_CENTRAL_DIRECTORY))
+            .ok_or("It is not a zip archive")?;
+        let field = |offset| u32_at(data, end + offset).ok_or("Truncated end of central directory");
+        let mut count = field(10)? as u64 & 0xffff;
+        let mut offset = field(16)? as u64;
+        if count == 0xffff || offset == 0xffff_ffff {
+            let locator =
+                end.checked_sub(20).filter(|&pos| u32_at(data, pos) == Some(ZIP64_LOCATOR));
+            let record =
+                locator.and_then(|pos| u64_at(data, pos + 8)).ok_or("Missing ZIP64 record")?;
+            let record = record as usize;
+            if u32_at(data, record) != Some(ZIP64_END_OF_CENTRAL_DIRECTORY) {
+                return Err("Invalid ZIP64 end of central directory".to_string());
+            }
+            count = u64_at(data, record + 32).ok_or("Truncated ZIP64 record")?;
+            offset = u64_at(data, record + 48).ok_or("Truncated ZIP64 record")?;
+        }
+
+        let mut members = vec![];
+        let mut pos = offset as usize;
+        for _ in 0..count {
+            let header = data.get(pos..pos + 46).ok_or("Truncated central directory")?;
+            if u32_at(header, 0) != Some(CENTRAL_HEADER) {
+                return Err("Invalid central directory".to_string());
+            }
+            let u16_field = |offset| u16_at(header, offset).unwrap_or_default();
+            let u32_field = |offset| u32_at(header, offset).unwrap_or_default();
+            let (name_len, extra_len, comment_len) =
+                (u16_field(28) as usize, u16_field(30) as usize, u16_field(32) as usize);
+            let name = data.get(pos + 46..pos + 46 + name_len).ok_or("Truncated file name")?;
+            let name = String::from_utf8_lossy(name).into_owned();
+            let extra = data
+                .get(pos + 46 + name_len..pos + 46 + name_len + extra_len)
+                .ok_or("Truncated extra field")?;
+            let mut size = u32_field(24) as u64;
+            let mut compressed_size = u32_field(20) as u64;
+            let mut local_offset = u32_field(42) as u64;
+            // Only the values that don't fit are in the ZIP64 extra field, in this order
+            if let Some(mut zip64) = extra_field(extra, ZIP64_EXTRA) {
+                for value in [&mut size, &mut compressed_size, &mut local_offset] {
+                    if *value == 0xffff_ffff {
+                        *value = u64_at(zip64, 0).ok_or("Truncated ZIP64 extra field")?;
+                        zip64 = &zip64[8..];
+                    }
+                }
+            }
+            let flags = u16_field(8);
+            if flags & ENCRYPTED != 0 {
+                return Err(format!("`{}` is encrypted", name));
+            }
+
+            let local = local_offset as usize;
+            if u32_at(data, local) != Some(LOCAL_HEADER) {
+                return Err(format!("Invalid local header of `{}`", name));
+            }
+            let local_name_len = u16_at(data, local + 26).unwrap_or_default() as usize;
+            let local_extra_len = u16_at(data, local + 28).unwrap_or_default() as usize;
+            let start = local + 30 + local_name_len + local_extra_len;
+            let content = data
+                .get(start..start + compressed_size as usize)
+                .ok_or_else(|| format!("`{}` is truncated", name))?;
+
+            members.push(Member {
+                name,
+                data: content.to_vec(),
+                method: u16_field(10),
+                flags,
+                time: u16_field(12),
+                date: u16_field(14),
+                crc: u32_field(16),
+                size,
+                made_by: u16_field(4),
+                attributes: u32_field(38),
+            });
+            pos += 46 + name_len + extra_len + comment_len;
+        }
+        log::debug!("Read zip archive with {} members", members.len());
+        Ok(Archive {
+            members,
+        })
+    }
+
+    /// Write the archive to a file, replacing it if it exists
+    ///
+    /// # Arguments
+    ///
+    /// * `path` - Path to the zip file
+    pub fn save(&self, path: &path::Path) -> Result<(), ()> {
+        let data = self.write()?;
+        std::fs::write(path, data)
+            .map_err(|e| log::error!("Failed to write `{}`: {}", path.display(), e))
+    }
+
+    /// Get the content of the archive as a zip file
+    fn write(&self) -> Result<Vec<u8>, ()> {
+        let too_large = || log::error!("The archive is too large, it would need ZIP64");
+        let mut data = vec![];
+        let mut central = vec![];
+        for member in &self.members {
+            let offset = u32::try_from(data.len()).map_err(|_| too_large())?;
+            let compressed_size = u32::try_from(member.data.len()).map_err(|_| too_large())?;
+            let size = u32::try_from(member.size).map_err(|_| too_large())?;
+            let name = member.name.as_bytes();
+            // Sizes are known, so there is no data descriptor after the content
+            let flags = member.flags & !DATA_DESCRIPTOR;
+            let version_needed: u16 = if member.method == DEFLATED {
+                20
+            } else {
+                10
+            };
+            let common = [
+                &version_needed.to_le_bytes()[..],
+                &flags.to_le_bytes(),
+                &member.method.to_le_bytes(),
+                &member.time.to_le_bytes(),
+                &member.date.to_le_bytes(),
+                &member.crc.to_le_bytes(),
+                &compressed_size.to_le_bytes(),
+                &size.to_le_bytes(),
+                &(name.len() as u16).to_le_bytes(),
+                &0u16.to_le_bytes(),
+            ]
+            .concat();
+
+            data.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+            data.extend_from_slice(&common);
+            data.extend_from_slice(name);
+            data.extend_from_slice(&member.data);
+
+            central.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+            central.extend_from_slice(&member.made_by.to_le_bytes());
+            central.extend_from_slice(&common);
+            // No comment, disk 0 and no internal attributes
+            central.extend_from_slice(&[0; 6]);
+            central.extend_from_slice(&member.attributes.to_le_bytes());
+            central.extend_from_slice(&offset.to_le_bytes());
+            central.extend_from_slice(name);
+        }
+        let count = u16::try_from(self.members.len()).map_err(|_| too_large())?;
+        let offset = u32::try_from(data.len()).map_err(|_| too_large())?;
+        let central_size = central.len() as u32;
+        data.extend_from_slice(&central);
+        data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&count.to_le_bytes());
+        data.extend_from_slice(&count.to_le_bytes());
+        data.extend_from_slice(&central_size.to_le_bytes());
+        data.extend_from_slice(&offset.to_le_bytes());
+        data.extend_from_slice(&0u16.to_le_bytes());
+        Ok(data)
+    }
+
+    /// Find a member by its name
+    pub fn member(&self, name: &str) -> Option<&Member> {
+        self.members.iter().find(|member| member.name == name)
+    }
+
+    /// Replace the content of a member
+    ///
+    /// If there is a `<name>.md5` member with the MD5 checksum of the member,
+    /// as in the 3MF archives of Bambu Studio, it is updated too, in the case
+    /// it was written in.
+    ///
+    /// # Arguments
+    ///
+    /// * `name` - Name of the member
+    /// * `content` - The new content
+    pub fn replace(&mut self, name: &str, content: &[u8]) -> Result<(), ()> {
+        let checksum_name = format!("{}.md5", name);
+        if let Some(checksum) = self.members.iter_mut().find(|m| m.name == checksum_name) {
+            let old = checksum.content()?;
+            let new = hex(&md5::Md5::digest(content));
+            let new = match old.iter().any(u8::is_ascii_lowercase) {
+                true => new,
+                false => new.to_uppercase(),
+            };
+            checksum.set_content(new.as_bytes())?;
+        }
+        let Some(member) = self.members.iter_mut().find(|m| m.name == name) else {
+            log::error!("There is no `{}` in the archive", name);
+            return Err(());
+        };
+        member.set_content(content)
+    }
+}
+
+/// Whether a path is a zip archive
+pub fn is_zip(path: &path::Path) -> bool {
+    path.extension().is_some_and(|extension| extension.eq_ignore_ascii_case("zip"))
+}
+
+/// Convert every G-code file in a zip archive
+///
+/// Members ending with `.gcode` are converted, in any folder of the archive.
+/// The ones without a thumbnail, or converted before, are left out. The
+/// archive is not changed, the converted G-code is written back with
+/// [`Archive::replace`].
+///
+/// # Arguments
+///
+/// * `archive` - The zip archive
+/// * `options` - How to convert the thumbnails
+///
+/// # Returns
+///
+/// The names of the converted G-code files with their new content
+pub fn convert_gcode(
+    archive: &Archive,
+    options: &ProcessOptions,
+) -> Result<Vec<(String, Vec<u8>)>, ()> {
+    let mut converted = vec![];
+    for member in &archive.members {
+        if !member.name.to_lowercase().ends_with(".gcode") {
+            continue;
+        }
+        let gcode = member.content()?;
+        if mks_tft_img::is_converted(&gcode) {
+            log::info!("`{}` is already converted", member.name);
+            continue;
+        }
+        log::info!("Converting `{}`", member.name);
+        match mks_tft_img::convert(&gcode, options) {
+            Ok(gcode) => converted.push((member.name.clone(), gcode)),
+            Err(()) => log::warn!("`{}` is left unchanged", member.name),
+        }
+    }
+    Ok(converted)
+}
+
+/// Find an extra field of a header by its ID
+fn extra_field(mut extra: &[u8], id: u16) -> Option<&[u8]> {
+    while extra.len() >= 4 {
+        let len = u16_at(extra, 2)? as usize;
+        let field = extra.get(4..4 + len)?;
+        if u16_at(extra, 0)? == id {
+            return Some(field);
+        }
+        extra = &extra[4 + len..];
+    }
+    None
+}
+
+fn u16_at(data: &[u8], pos: usize) -> Option<u16> {
+    Some(u16::from_le_bytes(data.get(pos..pos + 2)?.try_into().ok()?))
+}
+
+fn u32_at(data: &[u8], pos: usize) -> Option<u32> {
+    Some(u32::from_le_bytes(data.get(pos..pos + 4)?.try_into().ok()?))
+}
+
+fn u64_at(data: &[u8], pos: usize) -> Option<u64> {
+    Some(u64::from_le_bytes(data.get(pos..pos + 8)?.try_into().ok()?))
+}
+
+/// Format bytes as lowercase hexadecimal
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A stored member, in the archive as it is
+    fn stored(name: &str, content: &[u8]) -> Member {
+        Member {
+            name: name.to_string(),
+            data: content.to_vec(),
+            method: STORED,
+            flags: 0,
+            time: 0x6b21,
+            date: 0x5a8f,
+            crc: crc32fast::hash(content),
+            size: content.len() as u64,
+            made_by: 0x031e,
+            attributes: 0o100644 << 16,
+        }
+    }
+
+    #[test]
+    fn reads_back_written_archives() {
+        let mut deflated = stored("Metadata/plate_1.gcode", b"");
+        deflated.set_content(&b"G28\nG1 X10\n".repeat(100)).unwrap();
+        let archive = Archive {
+            members: vec![stored("readme.txt", b"Hello"), deflated],
+        };
+        let read = Archive::read(&archive.write().unwrap()).unwrap();
+
+        assert_eq!(read.members.len(), 2);
+        for (read, written) in read.members.iter().zip(&archive.members) {
+            assert_eq!(read.name, written.name);
+            assert_eq!(read.method, written.method);
+            assert_eq!((read.time, read.date), (written.time, written.date));
+            assert_eq!((read.made_by, read.attributes), (written.made_by, written.attributes));
+            assert_eq!(read.content().unwrap(), written.content().unwrap());
+        }
+        assert_eq!(read.members[1].content().unwrap(), b"G28\nG1 X10\n".repeat(100));
+    }
+
+    #[test]
+    fn reads_zip64_archives() {
+        let content = b"G28\n";
+        let name = b"a.gcode";
+        let crc = crc32fast::hash(content);
+        let mut data = vec![];
+        // Local header with the sizes in the ZIP64 extra field
+        data.extend_from_slice(&LOCAL_HEADER.to_le_bytes());
+        data.extend_from_slice(&[45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(&[0xff; 8]);
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&20u16.to_le_bytes());
+        data.extend_from_slice(name);
+        data.extend_from_slice(&ZIP64_EXTRA.to_le_bytes());
+        data.extend_from_slice(&16u16.to_le_bytes());
+        data.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        data.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        data.extend_from_slice(content);
+        // Central directory header with the sizes and offset in the ZIP64 extra field
+        let central = data.len() as u64;
+        data.extend_from_slice(&CENTRAL_HEADER.to_le_bytes());
+        data.extend_from_slice(&[45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&crc.to_le_bytes());
+        data.extend_from_slice(&[0xff; 8]);
+        data.extend_from_slice(&(name.len() as u16).to_le_bytes());
+        data.extend_from_slice(&28u16.to_le_bytes());
+        data.extend_from_slice(&[0; 10]);
+        data.extend_from_slice(&[0xff; 4]);
+        data.extend_from_slice(name);
+        data.extend_from_slice(&ZIP64_EXTRA.to_le_bytes());
+        data.extend_from_slice(&24u16.to_le_bytes());
+        data.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        data.extend_from_slice(&(content.len() as u64).to_le_bytes());
+        data.extend_from_slice(&0u64.to_le_bytes());
+        let central_size = data.len() as u64 - central;
+        // ZIP64 end of central directory record and its locator
+        let record = data.len() as u64;
+        data.extend_from_slice(&ZIP64_END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&44u64.to_le_bytes());
+        data.extend_from_slice(&[45, 3, 45, 0, 0, 0, 0, 0, 0, 0, 0, 0]);
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&1u64.to_le_bytes());
+        data.extend_from_slice(&central_size.to_le_bytes());
+        data.extend_from_slice(&central.to_le_bytes());
+        data.extend_from_slice(&ZIP64_LOCATOR.to_le_bytes());
+        data.extend_from_slice(&0u32.to_le_bytes());
+        data.extend_from_slice(&record.to_le_bytes());
+        data.extend_from_slice(&1u32.to_le_bytes());
+        // End of central directory record pointing to the ZIP64 one
+        data.extend_from_slice(&END_OF_CENTRAL_DIRECTORY.to_le_bytes());
+        data.extend_from_slice(&[0; 4]);
+        data.extend_from_slice(&[0xff; 16]);
+        data.extend_from_slice(&[0; 2]);
+
+        let archive = Archive::read(&data).unwrap();
+        assert_eq!(archive.members.len(), 1);
+        assert_eq!(archive.members[0].name, "a.gcode");
+        assert_eq!(archive.members[0].size, content.len() as u64);
+        assert_eq!(archive.members[0].content().unwrap(), content);
+    }
+
+    #[test]
+    fn rejects_corrupt_members() {
+        let archive = Archive {
+            members: vec![stored("a.gcode", b"G28\n")],
+        };
+        let mut data = archive.write().unwrap();
+        // The content follows the 30 bytes of the local header and the name
+        data[30 + "a.gcode".len()] = b'M';
+        let read = Archive::read(&data).unwrap();
+        assert!(read.members[0].content().is_err());
+
+        assert!(Archive::read(b"G28\n").is_err());
+        let data = archive.write().unwrap();
+        assert!(Archive::read(&data[..data.len() - 30]).is_err());
+    }
+
+    #[test]
+    fn updates_md5_checksums() {
+        // Test suite of RFC 1321, with the checksum in either case
+        for (content, md5) in [
+            (&b""[..], "d41d8cd98f00b204e9800998ecf8427e"),
+            (b"abc", "900150983cd24fb0d6963f7d28e17f72"),
+            (b"message digest", "f96b697d7cb7938d525a2f31aaf161d0"),
+            (
+                b"12345678901234567890123456789012345678901234567890123456789012345678901234567890",
+                "57edf4a22be3c955ac49da2e2107b67a",
+            ),
+        ] {
+            for old in ["0123456789abcdef0123456789abcdef", "0123456789ABCDEF0123456789ABCDEF"] {
+                let mut archive = Archive {
+                    members: vec![
+                        stored("a.gcode", b"G28\n"),
+                        stored("a.gcode.md5", old.as_bytes()),
+                    ],
+                };
+                archive.replace("a.gcode", content).unwrap();
+                assert_eq!(archive.members[0].content().unwrap(), content);
+                let expected = match old.contains('a') {
+                    true => md5.to_string(),
+                    false => md5.to_uppercase(),
+                };
+                assert_eq!(archive.members[1].content().unwrap(), expected.as_bytes());
+            }
+        }
+    }
+}